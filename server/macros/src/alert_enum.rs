@@ -126,6 +126,10 @@ impl ToTokens for AlertEnum {
                                     cookie.to_string()
                                 );
                             }
+
+                            for (name, value) in data.headers {
+                                res = res.header(name, value);
+                            }
                         }
 
                         res.body(poem::Body::from_json(body).unwrap())
@@ -5,10 +5,12 @@ use syn::{parse_macro_input, NestedMeta};
 mod alert_enum;
 mod sql_enum;
 mod test_with_client;
+mod validate;
 
 use alert_enum::AlertEnum;
 use sql_enum::SqlEnum;
 use test_with_client::TestWithClient;
+use validate::Validate;
 
 #[proc_macro_attribute]
 pub fn alert_enum(attrs: TokenStream, input: TokenStream) -> TokenStream {
@@ -38,3 +40,10 @@ pub fn test_with_client(_meta: TokenStream, input: TokenStream) -> TokenStream {
         .to_token_stream()
         .into()
 }
+
+#[proc_macro_derive(Validate, attributes(validate))]
+pub fn validate(input: TokenStream) -> TokenStream {
+    parse_macro_input!(input as Validate)
+        .to_token_stream()
+        .into()
+}
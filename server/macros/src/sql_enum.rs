@@ -34,9 +34,9 @@ impl ToTokens for SqlEnum {
         let generics = &self.enum_.generics;
         let snake_case_name = &self.enum_.ident.to_string().to_case(Case::Snake);
         let variants = &self.variants;
-        let variant_names = variants.into_iter().map(|variant| &variant.rename);
+        let variant_names = variants.iter().map(|variant| &variant.rename);
         tokens.extend(quote! {
-            #[derive(Debug, FromSql, ToSql, Serialize)]
+            #[derive(Debug, Deserialize, FromSql, ToSql, Serialize)]
             #[postgres(name = #snake_case_name)]
             #[serde(rename_all = "snake_case")]
             #(#attrs)*
@@ -27,7 +27,7 @@ impl ToTokens for TestWithClient {
             Span::call_site(),
         );
 
-        assert!(func.sig.inputs.len() == 0);
+        assert!(func.sig.inputs.is_empty());
         assert!(func.sig.variadic.is_none());
         let attrs = &func.attrs;
         let vis = &func.vis;
@@ -0,0 +1,265 @@
+use proc_macro2::TokenStream;
+use quote::{quote, ToTokens};
+use syn::{
+    parenthesized,
+    parse::{Parse, ParseBuffer},
+    punctuated::Punctuated,
+    token::{Comma, Eq},
+    Data, DeriveInput, Expr, Field, GenericArgument, Ident, Lit, LitStr, Meta, NestedMeta,
+    PathArguments, Type,
+};
+
+pub struct Validate {
+    input: DeriveInput,
+    fields: Vec<ValidateField>,
+}
+
+impl Parse for Validate {
+    fn parse(input: &ParseBuffer) -> syn::Result<Self> {
+        let input = input.parse::<DeriveInput>()?;
+        let fields = match &input.data {
+            Data::Struct(data) => data
+                .fields
+                .iter()
+                .map(ValidateField::new)
+                .collect::<syn::Result<_>>()?,
+            _ => panic!("Validate can only be derived for structs"),
+        };
+
+        Ok(Self { input, fields })
+    }
+}
+
+impl ToTokens for Validate {
+    fn to_tokens(&self, tokens: &mut TokenStream) {
+        let ident = &self.input.ident;
+        let (impl_generics, type_generics, where_clause) = self.input.generics.split_for_impl();
+        let fields = &self.fields;
+
+        tokens.extend(quote! {
+            impl #impl_generics crate::validate::Validate for #ident #type_generics #where_clause {
+                #[allow(clippy::unnecessary_cast, unused_variables)]
+                fn validate_fields(
+                    &mut self,
+                    path: &str,
+                    errors: &mut crate::error::ValidationErrors,
+                    config: &crate::config::Config,
+                ) {
+                    #(#fields)*
+                }
+            }
+        });
+    }
+}
+
+struct ValidateField {
+    ident: Ident,
+    name: String,
+    is_option: bool,
+    rules: Vec<Rule>,
+}
+
+impl ValidateField {
+    fn new(field: &Field) -> syn::Result<Self> {
+        let ident = field
+            .ident
+            .clone()
+            .expect("Validate cannot be derived for tuple structs");
+        let mut name = ident.to_string();
+        let mut rules = Vec::new();
+        for attr in &field.attrs {
+            if attr.path.is_ident("validate") {
+                let parsed = attr.parse_args_with(Punctuated::<Rule, Comma>::parse_terminated)?;
+                rules.extend(parsed);
+            } else if attr.path.is_ident("serde") {
+                if let Some(rename) = serde_rename(attr.parse_meta()?) {
+                    name = rename;
+                }
+            }
+        }
+        // Trimming first means the other rules see the stored value
+        rules.sort_by_key(|rule| !matches!(rule, Rule::Trim));
+
+        Ok(Self {
+            ident,
+            name,
+            is_option: is_option(&field.ty),
+            rules,
+        })
+    }
+}
+
+impl ToTokens for ValidateField {
+    fn to_tokens(&self, tokens: &mut TokenStream) {
+        if self.rules.is_empty() {
+            return;
+        }
+        let ident = &self.ident;
+        let name = &self.name;
+        let rules = &self.rules;
+        let binding = if self.is_option {
+            quote! { if let std::option::Option::Some(value) = &mut self.#ident }
+        } else {
+            quote! { let value = &mut self.#ident; }
+        };
+
+        tokens.extend(quote! {
+            #binding {
+                let field = format!("{}{}", path, #name);
+                #(#rules)*
+            }
+        });
+    }
+}
+
+fn serde_rename(meta: Meta) -> Option<String> {
+    let Meta::List(list) = meta else {
+        return None;
+    };
+    list.nested.into_iter().find_map(|nested| match nested {
+        NestedMeta::Meta(Meta::NameValue(name_value)) if name_value.path.is_ident("rename") => {
+            match name_value.lit {
+                Lit::Str(rename) => Some(rename.value()),
+                _ => None,
+            }
+        }
+        _ => None,
+    })
+}
+
+// Validation of an optional field only happens when a value is present
+fn is_option(ty: &Type) -> bool {
+    let Type::Path(path) = ty else {
+        return false;
+    };
+    match path.path.segments.last() {
+        Some(segment) if segment.ident == "Option" => matches!(
+            &segment.arguments,
+            PathArguments::AngleBracketed(args)
+                if matches!(args.args.first(), Some(GenericArgument::Type(_)))
+        ),
+        _ => false,
+    }
+}
+
+enum Rule {
+    Length {
+        min: Option<Expr>,
+        max: Option<Expr>,
+    },
+    Nested,
+    OneOf(Vec<Expr>),
+    Range {
+        min: Option<Expr>,
+        max: Option<Expr>,
+    },
+    Regex(LitStr),
+    Trim,
+}
+
+impl Parse for Rule {
+    fn parse(input: &ParseBuffer) -> syn::Result<Self> {
+        let name = input.parse::<Ident>()?;
+        match name.to_string().as_str() {
+            "length" => {
+                let (min, max) = parse_bounds(input)?;
+                Ok(Self::Length { min, max })
+            }
+            "nested" => Ok(Self::Nested),
+            "one_of" => {
+                let content;
+                parenthesized!(content in input);
+                let values = Punctuated::<Expr, Comma>::parse_terminated(&content)?;
+                Ok(Self::OneOf(values.into_iter().collect()))
+            }
+            "range" => {
+                let (min, max) = parse_bounds(input)?;
+                Ok(Self::Range { min, max })
+            }
+            "regex" => {
+                input.parse::<Eq>()?;
+                Ok(Self::Regex(input.parse()?))
+            }
+            "trim" => Ok(Self::Trim),
+            _ => Err(syn::Error::new(name.span(), "unknown validation rule")),
+        }
+    }
+}
+
+// Parses `(min = <expr>, max = <expr>)`; either bound may be left out and
+// both may refer to `config`
+fn parse_bounds(input: &ParseBuffer) -> syn::Result<(Option<Expr>, Option<Expr>)> {
+    let content;
+    parenthesized!(content in input);
+    let mut min = None;
+    let mut max = None;
+    while !content.is_empty() {
+        let name = content.parse::<Ident>()?;
+        content.parse::<Eq>()?;
+        let value = content.parse::<Expr>()?;
+        match name.to_string().as_str() {
+            "min" => min = Some(value),
+            "max" => max = Some(value),
+            _ => return Err(syn::Error::new(name.span(), "expected `min` or `max`")),
+        }
+        if !content.is_empty() {
+            content.parse::<Comma>()?;
+        }
+    }
+    if min.is_none() && max.is_none() {
+        return Err(content.error("expected `min` or `max`"));
+    }
+    Ok((min, max))
+}
+
+fn optional(expr: &Option<Expr>, cast: bool) -> TokenStream {
+    match expr {
+        Some(expr) if cast => quote! { std::option::Option::Some((#expr) as usize) },
+        Some(expr) => quote! { std::option::Option::Some(#expr) },
+        None => quote! { std::option::Option::None },
+    }
+}
+
+impl ToTokens for Rule {
+    fn to_tokens(&self, tokens: &mut TokenStream) {
+        tokens.extend(match self {
+            Self::Length { min, max } => {
+                let min = optional(min, true);
+                let max = optional(max, true);
+                quote! {
+                    crate::validate::check_length(&field, &*value, #min, #max, errors);
+                }
+            }
+            Self::Nested => quote! {
+                crate::validate::Validate::validate_fields(
+                    value,
+                    &format!("{}.", field),
+                    errors,
+                    config,
+                );
+            },
+            Self::OneOf(values) => quote! {
+                crate::validate::check_one_of(&field, &*value, &[#(#values),*], errors);
+            },
+            Self::Range { min, max } => {
+                let min = optional(min, false);
+                let max = optional(max, false);
+                quote! {
+                    crate::validate::check_range(&field, &*value, #min, #max, errors);
+                }
+            }
+            Self::Regex(regex) => quote! {
+                {
+                    static REGEX: std::sync::OnceLock<regex::Regex> = std::sync::OnceLock::new();
+                    let regex = REGEX.get_or_init(|| regex::Regex::new(#regex).unwrap());
+                    crate::validate::check_regex(&field, &*value, regex, errors);
+                }
+            },
+            Self::Trim => quote! {
+                if value.trim().len() != value.len() {
+                    *value = value.trim().to_owned();
+                }
+            },
+        });
+    }
+}
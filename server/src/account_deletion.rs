@@ -0,0 +1,48 @@
+use deadpool_postgres::Pool;
+
+use crate::{
+    config::Config, error::InternalError, icon::delete_icon_blobs, storage::DynStorage,
+    util::utc_now,
+};
+
+// Hard-deletes the users whose deletion grace period has passed, along with
+// their icons; returns the number of deleted users
+pub async fn purge_deleted_users(
+    db: &Pool,
+    storage: &DynStorage,
+    config: &Config,
+) -> Result<usize, InternalError> {
+    let db = db.get().await.map_err(InternalError::new)?;
+    let deleted = db
+        .query(
+            r#"
+            DELETE FROM "users" WHERE "deletion_scheduled" <= $1
+            RETURNING "icon"
+            "#,
+            &[&utc_now()],
+        )
+        .await
+        .map_err(InternalError::new)?;
+    for user in &deleted {
+        if let Some(icon) = user.get::<_, Option<&str>>("icon") {
+            delete_icon_blobs(icon, storage, config).await;
+        }
+    }
+    Ok(deleted.len())
+}
+
+pub fn spawn_purge_job(db: Pool, storage: DynStorage, config: Config) {
+    tokio::spawn(async move {
+        let mut interval =
+            tokio::time::interval(config.account_deletion.purge_interval.to_std().unwrap());
+        loop {
+            interval.tick().await;
+            // Errors are logged by InternalError, and the next run retries
+            if let Ok(deleted) = purge_deleted_users(&db, &storage, &config).await {
+                if deleted > 0 {
+                    tracing::info!("purged {} deleted users", deleted);
+                }
+            }
+        }
+    });
+}
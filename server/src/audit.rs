@@ -0,0 +1,112 @@
+use std::sync::{Arc, Mutex};
+
+use chrono::{DateTime, Utc};
+use deadpool_postgres::Client;
+use serde_json::{json, Value as JsonValue};
+
+use crate::{
+    error::{AuthError, AuthWarning, InternalError},
+    util::{hash, utc_now, ClientInfo},
+};
+
+struct AuditEvent {
+    created: DateTime<Utc>,
+    event: String,
+    details: JsonValue,
+}
+
+#[derive(Default)]
+struct AuditState {
+    user_id: Option<String>,
+    session_id_hash: Option<Vec<u8>>,
+    events: Vec<AuditEvent>,
+}
+
+// Collects the events of a single request; the AuditLog middleware writes
+// them once the response is ready
+#[derive(Clone, Default)]
+pub struct Audit(Arc<Mutex<AuditState>>);
+
+impl Audit {
+    pub fn set_user(&self, user_id: &str) {
+        self.0.lock().unwrap().user_id = Some(user_id.to_owned());
+    }
+
+    pub fn set_session(&self, session_id_hash: Vec<u8>) {
+        self.0.lock().unwrap().session_id_hash = Some(session_id_hash);
+    }
+
+    // Ties the rest of the request's events to the newly created session
+    pub fn record_login(&self, user_id: &str, session_id: &str, method: &str) {
+        self.set_user(user_id);
+        self.set_session(hash(session_id));
+        self.record("login", json!({ "method": method }));
+    }
+
+    pub fn has_user(&self) -> bool {
+        self.0.lock().unwrap().user_id.is_some()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.lock().unwrap().events.is_empty()
+    }
+
+    pub fn record(&self, event: &str, details: JsonValue) {
+        self.0.lock().unwrap().events.push(AuditEvent {
+            created: utc_now(),
+            event: event.to_owned(),
+            details,
+        });
+    }
+
+    pub fn record_error(&self, error: &AuthError, path: &str) {
+        let details = serde_json::to_value(error)
+            .ok()
+            .and_then(|mut value| value.get_mut("details").map(JsonValue::take));
+        self.record(
+            &format!("auth-error:{error}"),
+            json!({ "path": path, "details": details }),
+        );
+    }
+
+    pub fn record_warnings(&self, warnings: &[AuthWarning]) {
+        for warning in warnings {
+            let details = serde_json::to_value(warning)
+                .ok()
+                .and_then(|mut value| value.get_mut("details").map(JsonValue::take));
+            self.record(&format!("auth-warning:{warning}"), json!({ "details": details }));
+        }
+    }
+
+    pub async fn write(&self, db: &Client, client: &ClientInfo) -> Result<(), InternalError> {
+        let (user_id, session_id_hash, events) = {
+            let mut state = self.0.lock().unwrap();
+            (
+                state.user_id.clone(),
+                state.session_id_hash.clone(),
+                std::mem::take(&mut state.events),
+            )
+        };
+        for event in events {
+            db.execute(
+                r#"
+                INSERT INTO "audit_events"(
+                    "created", "event", "user_id", "session_id", "ip", "user_agent", "details"
+                ) VALUES ($1, $2, $3, $4, $5, $6, $7)
+                "#,
+                &[
+                    &event.created,
+                    &event.event,
+                    &user_id,
+                    &session_id_hash,
+                    &client.ip,
+                    &client.user_agent,
+                    &event.details,
+                ],
+            )
+            .await
+            .map_err(InternalError::new)?;
+        }
+        Ok(())
+    }
+}
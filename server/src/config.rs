@@ -1,14 +1,54 @@
-use std::fmt;
+use std::{
+    collections::HashMap,
+    fmt,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
 
 use aes_gcm_siv::{Aes256GcmSiv, KeyInit};
 use argon2::Argon2;
+use base64::engine::{general_purpose::URL_SAFE as BASE64_URL_SAFE, Engine};
 use chrono::Duration;
 use deadpool_postgres::Config as DbConfig;
 use poem::{http::HeaderValue, web::cookie::SameSite};
 use serde::Deserialize;
 use toml;
 
-use crate::util::{TotpAlgorithm, make_argon2};
+use crate::{
+    mail::Outbox,
+    storage::Blobs,
+    util::{TotpAlgorithm, make_argon2},
+};
+
+#[derive(Deserialize)]
+pub struct AccountDeletionConfigInput {
+    pub grace_period: u32,
+    pub purge_interval: u32,
+}
+
+#[derive(Clone)]
+pub struct AccountDeletionConfig {
+    pub grace_period: Duration,
+    pub purge_interval: Duration,
+}
+
+#[derive(Deserialize)]
+pub struct AccessTokenConfigInput {
+    pub token_bits: u16,
+    pub name_max_length: u16,
+}
+
+#[derive(Clone)]
+pub struct AccessTokenConfig {
+    pub token_length: u16,
+    pub name_max_length: u16,
+}
+
+#[derive(Clone, Deserialize)]
+pub struct AuditConfig {
+    pub default_page_size: i64,
+    pub max_page_size: i64,
+}
 
 #[derive(Deserialize)]
 pub struct ClientConfigInput {
@@ -90,6 +130,189 @@ pub struct DevConfig {
     pub testing: bool,
 }
 
+#[derive(Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MailBackend {
+    File,
+    Memory,
+    Smtp,
+}
+
+#[derive(Deserialize)]
+pub struct MailConfigInput {
+    pub backend: MailBackend,
+    pub from: String,
+    pub file_dir: Option<String>,
+    pub smtp: Option<SmtpConfigInput>,
+}
+
+#[derive(Clone, Deserialize)]
+pub struct SmtpConfigInput {
+    pub host: String,
+    pub port: u16,
+    pub tls: bool,
+    pub username: Option<String>,
+    pub password: Option<String>,
+}
+
+#[derive(Clone)]
+pub enum MailBackendConfig {
+    File { dir: PathBuf },
+    Memory { outbox: Outbox },
+    Smtp(SmtpConfigInput),
+}
+
+#[derive(Deserialize)]
+pub struct DeviceNotificationConfigInput {
+    pub link_path: String,
+    pub token_bits: u16,
+    pub token_lifetime: u32,
+}
+
+#[derive(Clone)]
+pub struct DeviceNotificationConfig {
+    pub link_path: String,
+    pub token_length: u16,
+    pub token_lifetime: Duration,
+}
+
+#[derive(Deserialize)]
+pub struct EmailVerificationConfigInput {
+    pub link_path: String,
+    pub token_bits: u16,
+    pub token_lifetime: u32,
+}
+
+#[derive(Clone)]
+pub struct EmailVerificationConfig {
+    pub link_path: String,
+    pub token_length: u16,
+    pub token_lifetime: Duration,
+}
+
+#[derive(Deserialize)]
+pub struct IconConfigInput {
+    pub sizes: Vec<u32>,
+    pub min_size: u32,
+    pub max_dimension_ratio: u32,
+    pub max_upload_size: usize,
+    pub max_decoded_size: u64,
+    pub cache_max_age: u32,
+}
+
+#[derive(Clone)]
+pub struct IconConfig {
+    pub sizes: Vec<u32>,
+    pub min_size: u32,
+    pub max_dimension_ratio: u32,
+    pub max_upload_size: usize,
+    pub max_decoded_size: u64,
+    pub cache_max_age: u32,
+}
+
+#[derive(Deserialize)]
+pub struct LoginProtectionConfigInput {
+    pub max_failed_attempts: u16,
+    pub lockout_duration: u32,
+    pub max_lockout_duration: u32,
+}
+
+#[derive(Clone)]
+pub struct LoginProtectionConfig {
+    pub max_failed_attempts: i32,
+    pub lockout_duration: Duration,
+    pub max_lockout_duration: Duration,
+}
+
+#[derive(Deserialize)]
+pub struct MagicLinkConfigInput {
+    pub link_path: String,
+    pub token_bits: u16,
+    pub token_lifetime: u32,
+}
+
+#[derive(Clone)]
+pub struct MagicLinkConfig {
+    pub link_path: String,
+    pub token_length: u16,
+    pub token_lifetime: usize,
+}
+
+#[derive(Clone)]
+pub struct MailConfig {
+    pub backend: MailBackendConfig,
+    pub from: String,
+}
+
+#[derive(Deserialize)]
+pub struct OidcConfigInput {
+    pub redirect_path: String,
+    pub state_bits: u16,
+    pub state_lifetime: u32,
+    #[serde(default)]
+    pub providers: Vec<OidcProviderConfig>,
+}
+
+#[derive(Clone, Deserialize)]
+pub struct OidcProviderConfig {
+    pub id: String,
+    pub name: String,
+    pub issuer: String,
+    pub client_id: String,
+    pub client_secret: String,
+    pub authorization_endpoint: String,
+    pub token_endpoint: String,
+    pub jwks_uri: String,
+    pub scopes: Vec<String>,
+}
+
+#[derive(Clone)]
+pub struct OidcConfig {
+    pub redirect_path: String,
+    pub state_length: u16,
+    pub state_lifetime: usize,
+    pub providers: Vec<OidcProviderConfig>,
+}
+
+impl OidcConfig {
+    pub fn provider(&self, id: &str) -> Option<&OidcProviderConfig> {
+        self.providers.iter().find(|provider| provider.id == id)
+    }
+}
+
+#[derive(Clone, Deserialize)]
+pub struct PasswordPolicyConfig {
+    pub min_entropy_bits: f64,
+    pub breached_passwords_file: Option<PathBuf>,
+}
+
+#[derive(Deserialize)]
+pub struct PasswordResetConfigInput {
+    pub link_path: String,
+    pub token_bits: u16,
+    pub token_lifetime: u32,
+}
+
+#[derive(Clone)]
+pub struct PasswordResetConfig {
+    pub link_path: String,
+    pub token_length: u16,
+    pub token_lifetime: Duration,
+}
+
+#[derive(Clone, Deserialize)]
+pub struct RateLimitRule {
+    pub limit: u64,
+    pub seconds: usize,
+}
+
+// Rules are keyed by the endpoint names given to RateLimit::new
+#[derive(Clone, Deserialize)]
+pub struct RateLimitConfig {
+    pub enabled: bool,
+    pub endpoints: HashMap<String, RateLimitRule>,
+}
+
 #[derive(Deserialize)]
 pub struct RedisConfigInput {
     pub url: String,
@@ -120,18 +343,53 @@ pub struct RememberTokenConfig {
     pub separator: String,
 }
 
+#[derive(Deserialize)]
+pub struct AesKeyConfigInput {
+    pub id: u8,
+    pub key: String,
+}
+
+// Ciphertexts are prefixed with the ID of the key that produced them, so old
+// keys can stay around for decryption until everything is re-encrypted. The
+// legacy key reads values written before key IDs existed, which have no prefix.
+#[derive(Clone)]
+pub struct AesKeys {
+    pub active_id: u8,
+    keys: Vec<(u8, Aes256GcmSiv)>,
+    pub legacy: Option<Aes256GcmSiv>,
+}
+
+impl AesKeys {
+    pub fn active(&self) -> &Aes256GcmSiv {
+        self.get(self.active_id).unwrap()
+    }
+
+    pub fn get(&self, id: u8) -> Option<&Aes256GcmSiv> {
+        self.keys.iter().find(|(key_id, _)| *key_id == id).map(|(_, key)| key)
+    }
+}
+
+#[derive(Clone, Deserialize)]
+pub struct RoleConfig {
+    pub name_max_length: u16,
+}
+
 #[derive(Deserialize)]
 pub struct SecurityConfigInput {
-    pub aes_key: String,
+    pub aes_key: Option<String>,
+    pub aes_keys: Vec<AesKeyConfigInput>,
+    pub active_aes_key: u8,
     pub argon2_time_cost: u32,
     pub argon2_memory_cost: u32,
     pub argon2_parallelism: u32,
     pub password_salt_bits: u16,
+    pub legacy_fernet_key: Option<String>,
 }
 
 #[derive(Clone)]
 pub struct SecurityConfig {
     pub password_salt_bytes: usize,
+    pub legacy_fernet_key: Option<Vec<u8>>,
 }
 
 #[derive(Deserialize)]
@@ -150,11 +408,51 @@ pub struct SessionConfig {
     pub sudo_lifetime: Duration,
 }
 
+#[derive(Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StorageBackend {
+    File,
+    Memory,
+    S3,
+}
+
+#[derive(Deserialize)]
+pub struct StorageConfigInput {
+    pub backend: StorageBackend,
+    pub file_dir: Option<String>,
+    pub s3: Option<S3ConfigInput>,
+}
+
+#[derive(Clone, Deserialize)]
+pub struct S3ConfigInput {
+    pub endpoint: String,
+    pub region: String,
+    pub bucket: String,
+    pub access_key: String,
+    pub secret_key: String,
+}
+
+#[derive(Clone)]
+pub enum StorageBackendConfig {
+    File { dir: PathBuf },
+    Memory { blobs: Blobs },
+    S3(S3ConfigInput),
+}
+
+#[derive(Clone)]
+pub struct StorageConfig {
+    pub backend: StorageBackendConfig,
+}
+
 #[derive(Deserialize)]
 pub struct TotpConfigInput {
     pub algorithm: TotpAlgorithm,
     pub digits: u8,
+    pub issuer: String,
     pub key_length: u16,
+    pub new_key_lifetime: u32,
+    pub recovery_code_bits: u16,
+    pub recovery_code_count: u8,
     pub time_step: u16,
     pub time_window: u8,
 }
@@ -163,7 +461,11 @@ pub struct TotpConfigInput {
 pub struct TotpConfig {
     pub algorithm: TotpAlgorithm,
     pub digits: u32,
+    pub issuer: String,
     pub key_length: usize,
+    pub new_key_lifetime: Duration,
+    pub recovery_code_length: u16,
+    pub recovery_code_count: usize,
     pub time_step: u16,
     pub time_window: u8,
 }
@@ -176,6 +478,7 @@ pub struct UserConfigInput {
     pub username_max_length: u8,
     pub password_min_length: u8,
     pub password_max_length: u16,
+    pub email_max_length: u16,
 }
 
 #[derive(Clone)]
@@ -186,6 +489,29 @@ pub struct UserConfig {
     pub username_max_length: u8,
     pub password_min_length: u8,
     pub password_max_length: u16,
+    pub email_max_length: u16,
+}
+
+#[derive(Clone, Deserialize)]
+pub struct UserDirectoryConfig {
+    pub default_page_size: i64,
+    pub max_page_size: i64,
+}
+
+#[derive(Deserialize)]
+pub struct WebauthnConfigInput {
+    pub rp_id: String,
+    pub rp_name: String,
+    pub challenge_bits: u16,
+    pub challenge_lifetime: u32,
+}
+
+#[derive(Clone)]
+pub struct WebauthnConfig {
+    pub rp_id: String,
+    pub rp_name: String,
+    pub challenge_length: u16,
+    pub challenge_lifetime: usize,
 }
 
 #[derive(Deserialize)]
@@ -206,40 +532,74 @@ pub struct WebSocketConfig {
 
 #[derive(Deserialize)]
 pub struct ConfigInput {
+    pub access_token: AccessTokenConfigInput,
+    pub account_deletion: AccountDeletionConfigInput,
+    pub audit: AuditConfig,
     pub client: ClientConfigInput,
     pub cookie: CookieConfigInput,
     pub csrf: CsrfConfigInput,
     pub db: DbConfigInput,
     pub dev: Option<DevConfigInput>,
+    pub device_notification: DeviceNotificationConfigInput,
+    pub email_verification: EmailVerificationConfigInput,
+    pub icon: IconConfigInput,
+    pub login_protection: LoginProtectionConfigInput,
+    pub magic_link: MagicLinkConfigInput,
+    pub mail: MailConfigInput,
+    pub oidc: OidcConfigInput,
+    pub password_policy: PasswordPolicyConfig,
+    pub password_reset: PasswordResetConfigInput,
+    pub rate_limit: RateLimitConfig,
     pub redis: RedisConfigInput,
     pub remember_token: RememberTokenConfigInput,
+    pub role: RoleConfig,
     pub security: SecurityConfigInput,
     pub session: SessionConfigInput,
+    pub storage: StorageConfigInput,
     pub totp: TotpConfigInput,
     pub user: UserConfigInput,
+    pub user_directory: UserDirectoryConfig,
+    pub webauthn: WebauthnConfigInput,
     pub websocket: WebSocketConfigInput,
 }
 
 #[derive(Clone)]
 pub struct Config {
-    pub aes: Aes256GcmSiv,
+    pub access_token: AccessTokenConfig,
+    pub account_deletion: AccountDeletionConfig,
+    pub aes: AesKeys,
     pub argon2: Argon2<'static>,
+    pub audit: AuditConfig,
     pub client: ClientConfig,
     pub cookie: CookieConfig,
     pub csrf: CsrfConfig,
     pub db: DbConfig,
     pub dev: DevConfig,
+    pub device_notification: DeviceNotificationConfig,
+    pub email_verification: EmailVerificationConfig,
+    pub icon: IconConfig,
+    pub login_protection: LoginProtectionConfig,
+    pub magic_link: MagicLinkConfig,
+    pub mail: MailConfig,
+    pub oidc: OidcConfig,
+    pub password_policy: PasswordPolicyConfig,
+    pub password_reset: PasswordResetConfig,
+    pub rate_limit: RateLimitConfig,
     pub redis: RedisConfig,
     pub remember_token: RememberTokenConfig,
+    pub role: RoleConfig,
     pub security: SecurityConfig,
     pub session: SessionConfig,
+    pub storage: StorageConfig,
     pub totp: TotpConfig,
     pub user: UserConfig,
+    pub user_directory: UserDirectoryConfig,
+    pub webauthn: WebauthnConfig,
     pub websocket: WebSocketConfig,
 }
 
 fn bits_to_bytes(bits: u16, name: &str) -> usize {
-    if bits % 8 != 0 {
+    if !bits.is_multiple_of(8) {
         panic!("{name} must be a multiple of 8");
     }
     (bits / 8).into()
@@ -255,15 +615,45 @@ fn alphanum_token_length(bits: u16) -> u16 {
 impl Config {
     pub fn new(input: &ConfigInput) -> Self {
         Self {
+            access_token: AccessTokenConfig {
+                token_length: alphanum_token_length(input.access_token.token_bits),
+                name_max_length: input.access_token.name_max_length,
+            },
+            account_deletion: AccountDeletionConfig {
+                grace_period: Duration::seconds(input.account_deletion.grace_period.into()),
+                purge_interval: {
+                    if input.account_deletion.purge_interval == 0 {
+                        panic!("account_deletion.purge_interval must be greater than 0");
+                    }
+                    Duration::seconds(input.account_deletion.purge_interval.into())
+                },
+            },
             aes: {
-                let aes_key = hex::decode(input.security.aes_key.clone()).unwrap();
-                Aes256GcmSiv::new_from_slice(&aes_key).unwrap()
+                let mut keys = Vec::new();
+                for key in &input.security.aes_keys {
+                    if keys.iter().any(|(id, _)| *id == key.id) {
+                        panic!("duplicate aes key id {}", key.id);
+                    }
+                    let aes_key = hex::decode(&key.key).unwrap();
+                    keys.push((key.id, Aes256GcmSiv::new_from_slice(&aes_key).unwrap()));
+                }
+                if !keys.iter().any(|(id, _)| *id == input.security.active_aes_key) {
+                    panic!("security.active_aes_key must be one of security.aes_keys");
+                }
+                AesKeys {
+                    active_id: input.security.active_aes_key,
+                    keys,
+                    legacy: input.security.aes_key.as_ref().map(|key| {
+                        Aes256GcmSiv::new_from_slice(&hex::decode(key).unwrap()).unwrap()
+                    }),
+                }
             },
             argon2: make_argon2(
                 input.security.argon2_memory_cost,
                 input.security.argon2_time_cost,
                 input.security.argon2_parallelism,
             ).unwrap(),
+            audit: input.audit.clone(),
             client: ClientConfig {
                 origin: HeaderValue::from_str(&input.client.origin).unwrap(),
             },
@@ -297,6 +687,91 @@ impl Config {
             } else {
                 DevConfig::default()
             },
+            device_notification: DeviceNotificationConfig {
+                link_path: input.device_notification.link_path.clone(),
+                token_length: alphanum_token_length(input.device_notification.token_bits),
+                token_lifetime: Duration::seconds(
+                    input.device_notification.token_lifetime.into(),
+                ),
+            },
+            email_verification: EmailVerificationConfig {
+                link_path: input.email_verification.link_path.clone(),
+                token_length: alphanum_token_length(input.email_verification.token_bits),
+                token_lifetime: Duration::seconds(input.email_verification.token_lifetime.into()),
+            },
+            icon: IconConfig {
+                sizes: {
+                    let sizes = &input.icon.sizes;
+                    if sizes.is_empty() || sizes.windows(2).any(|pair| pair[0] >= pair[1]) {
+                        panic!("icon.sizes must be non-empty and in ascending order");
+                    }
+                    if input.icon.min_size == 0 || sizes[0] == 0 {
+                        panic!("icon sizes must be greater than 0");
+                    }
+                    sizes.clone()
+                },
+                min_size: input.icon.min_size,
+                max_dimension_ratio: input.icon.max_dimension_ratio,
+                max_upload_size: input.icon.max_upload_size,
+                max_decoded_size: input.icon.max_decoded_size,
+                cache_max_age: input.icon.cache_max_age,
+            },
+            login_protection: LoginProtectionConfig {
+                max_failed_attempts: input.login_protection.max_failed_attempts.into(),
+                lockout_duration: Duration::seconds(input.login_protection.lockout_duration.into()),
+                max_lockout_duration: Duration::seconds(
+                    input.login_protection.max_lockout_duration.into(),
+                ),
+            },
+            magic_link: MagicLinkConfig {
+                link_path: input.magic_link.link_path.clone(),
+                token_length: alphanum_token_length(input.magic_link.token_bits),
+                token_lifetime: input.magic_link.token_lifetime.try_into().unwrap(),
+            },
+            mail: MailConfig {
+                backend: match input.mail.backend {
+                    MailBackend::File => MailBackendConfig::File {
+                        dir: PathBuf::from(
+                            input
+                                .mail
+                                .file_dir
+                                .as_ref()
+                                .expect("mail.file_dir is required for the file backend"),
+                        ),
+                    },
+                    MailBackend::Memory => MailBackendConfig::Memory {
+                        outbox: Arc::new(Mutex::new(Vec::new())),
+                    },
+                    MailBackend::Smtp => MailBackendConfig::Smtp(
+                        input
+                            .mail
+                            .smtp
+                            .clone()
+                            .expect("mail.smtp is required for the smtp backend"),
+                    ),
+                },
+                from: input.mail.from.clone(),
+            },
+            oidc: OidcConfig {
+                redirect_path: input.oidc.redirect_path.clone(),
+                state_length: alphanum_token_length(input.oidc.state_bits),
+                state_lifetime: input.oidc.state_lifetime.try_into().unwrap(),
+                providers: {
+                    for (i, provider) in input.oidc.providers.iter().enumerate() {
+                        if input.oidc.providers[..i].iter().any(|other| other.id == provider.id) {
+                            panic!("duplicate oidc provider id {}", provider.id);
+                        }
+                    }
+                    input.oidc.providers.clone()
+                },
+            },
+            password_policy: input.password_policy.clone(),
+            password_reset: PasswordResetConfig {
+                link_path: input.password_reset.link_path.clone(),
+                token_length: alphanum_token_length(input.password_reset.token_bits),
+                token_lifetime: Duration::seconds(input.password_reset.token_lifetime.into()),
+            },
+            rate_limit: input.rate_limit.clone(),
             redis: RedisConfig {
                 url: input.redis.url.clone(),
                 key_separator: input.redis.key_separator.clone(),
@@ -308,11 +783,23 @@ impl Config {
                 secret_length: alphanum_token_length(input.remember_token.secret_bits),
                 separator: input.remember_token.separator.clone(),
             },
+            role: input.role.clone(),
             security: SecurityConfig {
                 password_salt_bytes: bits_to_bytes(
                     input.security.password_salt_bits,
                     "security.password_salt_bits",
                 ),
+                legacy_fernet_key: input.security.legacy_fernet_key.as_ref().map(|key| {
+                    // Legacy hashes are told apart by the first byte of a Fernet token
+                    if input.security.aes_keys.iter().any(|aes_key| aes_key.id == b'g') {
+                        panic!("aes key id {} is reserved for legacy password hashes", b'g');
+                    }
+                    let key = BASE64_URL_SAFE.decode(key).unwrap();
+                    if key.len() != 32 {
+                        panic!("security.legacy_fernet_key must be 32 bytes");
+                    }
+                    key
+                }),
             },
             session: SessionConfig {
                 cookie: input.session.cookie.clone(),
@@ -320,10 +807,37 @@ impl Config {
                 lifetime: Duration::seconds(input.session.lifetime.into()),
                 sudo_lifetime: Duration::seconds(input.session.sudo_lifetime.into()),
             },
+            storage: StorageConfig {
+                backend: match input.storage.backend {
+                    StorageBackend::File => StorageBackendConfig::File {
+                        dir: PathBuf::from(
+                            input
+                                .storage
+                                .file_dir
+                                .as_ref()
+                                .expect("storage.file_dir is required for the file backend"),
+                        ),
+                    },
+                    StorageBackend::Memory => StorageBackendConfig::Memory {
+                        blobs: Arc::new(Mutex::new(HashMap::new())),
+                    },
+                    StorageBackend::S3 => StorageBackendConfig::S3(
+                        input
+                            .storage
+                            .s3
+                            .clone()
+                            .expect("storage.s3 is required for the s3 backend"),
+                    ),
+                },
+            },
             totp: TotpConfig {
                 algorithm: input.totp.algorithm.clone(),
                 digits: input.totp.digits.into(),
+                issuer: input.totp.issuer.clone(),
                 key_length: input.totp.key_length.into(),
+                new_key_lifetime: Duration::seconds(input.totp.new_key_lifetime.into()),
+                recovery_code_length: alphanum_token_length(input.totp.recovery_code_bits),
+                recovery_code_count: input.totp.recovery_code_count.into(),
                 time_step: {
                     if input.totp.time_step == 0 {
                         panic!("totp.time_step must be greater than 0");
@@ -339,6 +853,14 @@ impl Config {
                 username_max_length: input.user.username_max_length,
                 password_min_length: input.user.password_min_length,
                 password_max_length: input.user.password_max_length,
+                email_max_length: input.user.email_max_length,
+            },
+            user_directory: input.user_directory.clone(),
+            webauthn: WebauthnConfig {
+                rp_id: input.webauthn.rp_id.clone(),
+                rp_name: input.webauthn.rp_name.clone(),
+                challenge_length: alphanum_token_length(input.webauthn.challenge_bits),
+                challenge_lifetime: input.webauthn.challenge_lifetime.try_into().unwrap(),
             },
             websocket: WebSocketConfig {
                 channel_capacity: input.websocket.channel_capacity.into(),
@@ -1,16 +1,26 @@
 use deadpool_postgres::{
     tokio_postgres::{error::SqlState, NoTls},
-    Config as DbConfig,
+    Client, Config as DbConfig,
 };
 use postgres_types::{FromSql, ToSql};
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 
 use crate::{
     config::Config,
-    util::{encrypt, generate_token, generate_totp_key, hash, hash_encrypt_password},
+    util::{
+        decrypt_with_key_id, encrypt, generate_token, generate_totp_key, hash,
+        hash_encrypt_password, LEGACY_FERNET_PREFIX,
+    },
 };
 use macros::sql_enum;
 
+#[derive(Clone, Copy, Eq, Ord, PartialEq, PartialOrd)]
+#[sql_enum]
+pub enum AccessTokenScope {
+    Read,
+    Write,
+}
+
 #[allow(non_camel_case_types)]
 #[sql_enum]
 pub enum Language {
@@ -24,13 +34,15 @@ pub enum PasswordChangeReason {
     RememberTokenCompromise,
 }
 
-#[derive(PartialEq)]
+#[derive(Clone, Copy, Eq, Ord, PartialEq, PartialOrd)]
 #[sql_enum]
 pub enum Permission {
     ViewUser,
     EditUser,
     DeleteUser,
     IgnoreRateLimits,
+    ViewAuditLog,
+    ManagePermissions,
 }
 
 fn enum_variants(variants: Vec<String>) -> String {
@@ -95,25 +107,39 @@ pub async fn init_db(drop_existing: bool, config: &Config) {
     let pool = init_config.create_pool(None, NoTls).unwrap();
     let db = pool.get().await.unwrap();
 
-    let password_hash_length = hash_encrypt_password("a", config).unwrap().len();
-    let totp_key_length = encrypt(&generate_totp_key(config), &config, &mut rand::thread_rng())
-        .unwrap()
-        .len();
+    let (legacy_totp_key_length, totp_key_length) = encrypted_totp_key_lengths(config);
 
     let languages = enum_variants(Language::variants());
     let password_change_reasons = enum_variants(PasswordChangeReason::variants());
     let permissions = enum_variants(Permission::variants());
+    let access_token_scopes = enum_variants(AccessTokenScope::variants());
 
     if drop_existing {
         db.batch_execute(
             r#"
+            DROP VIEW IF EXISTS "effective_permissions";
             DROP TABLE IF EXISTS
+                "audit_events",
+                "access_tokens",
+                "oidc_identities",
+                "webauthn_credentials",
                 "sessions",
                 "remember_tokens",
                 "new_totp_keys",
+                "password_reset_tokens",
+                "session_revocation_tokens",
+                "known_devices",
+                "email_verification_tokens",
+                "totp_recovery_codes",
+                "user_roles",
+                "roles",
                 "permissions",
                 "users";
-            DROP TYPE IF EXISTS "language", "password_change_reason", "permission";
+            DROP TYPE IF EXISTS
+                "access_token_scope",
+                "language",
+                "password_change_reason",
+                "permission";
             "#,
         )
         .await
@@ -135,25 +161,74 @@ pub async fn init_db(drop_existing: bool, config: &Config) {
         CREATE TABLE IF NOT EXISTS "users" (
             "id" text PRIMARY KEY CHECK (length("id") = {user_id_length}),
             "active" boolean NOT NULL DEFAULT true,
+            -- Set while a deletion is pending; the row is purged after this time
+            "deletion_scheduled" timestamp(0) with time zone,
+            -- Whether logging in cancels the pending deletion
+            "deletion_cancellable" boolean NOT NULL DEFAULT false,
             "username" text NOT NULL CHECK (
                 length("username") >= {username_min_length}
                 AND length("username") <= {username_max_length}
             ),
-            "password" bytea NOT NULL CHECK (length("password") = {password_hash_length}),
-            "totp_key" bytea CHECK (length("totp_key") = {totp_key_length}),
+            "email" text CHECK (length("email") <= {email_max_length}),
+            "email_verified" boolean NOT NULL DEFAULT false,
+            "password" bytea NOT NULL,
+            "totp_key" bytea CHECK (
+                length("totp_key") IN ({legacy_totp_key_length}, {totp_key_length})
+            ),
             "last_totp_time_step" bigint,
+            "failed_login_attempts" integer NOT NULL DEFAULT 0,
+            "locked_until" timestamp(0) with time zone,
             "password_change_reason" password_change_reason,
             "icon" text CHECK (length("icon") = {icon_id_length}),
             "language" language NOT NULL
         );
         CREATE UNIQUE INDEX IF NOT EXISTS "users_username_key" ON "users" (lower("username"));
+        -- Lets username prefix searches use an index regardless of the collation
+        CREATE INDEX IF NOT EXISTS "users_username_prefix_idx"
+            ON "users" (lower("username") text_pattern_ops);
+        CREATE UNIQUE INDEX IF NOT EXISTS "users_email_key" ON "users" (lower("email"));
 
         CREATE TABLE IF NOT EXISTS "new_totp_keys" (
             "user_id" text UNIQUE NOT NULL REFERENCES "users"("id") ON DELETE CASCADE,
-            "key" text NOT NULL CHECK (length("key") = {totp_key_length}),
+            "key" bytea NOT NULL CHECK (
+                length("key") IN ({legacy_totp_key_length}, {totp_key_length})
+            ),
             "expires" timestamp(0) with time zone NOT NULL
         );
 
+        CREATE TABLE IF NOT EXISTS "email_verification_tokens" (
+            "id" bytea PRIMARY KEY CHECK (length("id") = {hash_output_length}),
+            "user_id" text NOT NULL REFERENCES "users"("id") ON DELETE CASCADE,
+            "email" text NOT NULL,
+            "expires" timestamp(0) with time zone NOT NULL
+        );
+
+        CREATE TABLE IF NOT EXISTS "password_reset_tokens" (
+            "id" bytea PRIMARY KEY CHECK (length("id") = {hash_output_length}),
+            "user_id" text NOT NULL REFERENCES "users"("id") ON DELETE CASCADE,
+            "expires" timestamp(0) with time zone NOT NULL
+        );
+
+        CREATE TABLE IF NOT EXISTS "session_revocation_tokens" (
+            "id" bytea PRIMARY KEY CHECK (length("id") = {hash_output_length}),
+            "user_id" text NOT NULL REFERENCES "users"("id") ON DELETE CASCADE,
+            "expires" timestamp(0) with time zone NOT NULL
+        );
+
+        -- Hashes of the user agent and IP pairs a user has logged in from
+        CREATE TABLE IF NOT EXISTS "known_devices" (
+            "user_id" text NOT NULL REFERENCES "users"("id") ON DELETE CASCADE,
+            "id" bytea NOT NULL CHECK (length("id") = {hash_output_length}),
+            "first_seen" timestamp(0) with time zone NOT NULL,
+            "last_seen" timestamp(0) with time zone NOT NULL,
+            PRIMARY KEY ("user_id", "id")
+        );
+
+        CREATE TABLE IF NOT EXISTS "totp_recovery_codes" (
+            "id" bytea PRIMARY KEY CHECK (length("id") = {hash_output_length}),
+            "user_id" text NOT NULL REFERENCES "users"("id") ON DELETE CASCADE
+        );
+
         DO $$ BEGIN
             CREATE TYPE "permission" AS ENUM ({permissions});
         EXCEPTION
@@ -165,26 +240,105 @@ pub async fn init_db(drop_existing: bool, config: &Config) {
             PRIMARY KEY ("user_id", "permission")
         );
 
+        -- Named bundles of permissions that can be granted to users
+        CREATE TABLE IF NOT EXISTS "roles" (
+            "name" text PRIMARY KEY CHECK (
+                length("name") >= 1 AND length("name") <= {role_name_max_length}
+            ),
+            "permissions" permission[] NOT NULL
+        );
+        CREATE TABLE IF NOT EXISTS "user_roles" (
+            "user_id" text REFERENCES "users"("id") ON DELETE CASCADE,
+            "role" text REFERENCES "roles"("name") ON DELETE CASCADE,
+            PRIMARY KEY ("user_id", "role")
+        );
+        -- Direct permissions combined with those of the user's roles
+        CREATE OR REPLACE VIEW "effective_permissions" AS
+            SELECT "user_id", "permission" FROM "permissions"
+            UNION
+            SELECT "user_roles"."user_id", unnest("roles"."permissions") AS "permission"
+            FROM "user_roles" JOIN "roles" ON "roles"."name" = "user_roles"."role";
+
         CREATE TABLE IF NOT EXISTS "remember_tokens" (
             "id" bytea PRIMARY KEY CHECK (length("id") = {hash_output_length}),
             "user_id" text NOT NULL REFERENCES "users"("id") ON DELETE CASCADE,
-            "secret" bytea NOT NULL CHECK (length("secret") = {hash_output_length})
+            "secret" bytea NOT NULL CHECK (length("secret") = {hash_output_length}),
+            "created" timestamp(0) with time zone NOT NULL,
+            "last_used" timestamp(0) with time zone NOT NULL,
+            "user_agent" text,
+            "ip" inet
         );
 
         CREATE TABLE IF NOT EXISTS "sessions" (
             "id" bytea PRIMARY KEY CHECK (length("id") = {hash_output_length}),
             "user_id" text NOT NULL REFERENCES "users"("id") ON DELETE CASCADE,
+            "remember_token_id" bytea REFERENCES "remember_tokens"("id") ON DELETE SET NULL,
             "csrf_token" text NOT NULL CHECK (length("csrf_token") = {csrf_token_length}),
             "expires" timestamp(0) with time zone NOT NULL,
-            "sudo_until" timestamp(0) with time zone
+            "sudo_until" timestamp(0) with time zone,
+            "created" timestamp(0) with time zone NOT NULL,
+            "last_seen" timestamp(0) with time zone NOT NULL,
+            "user_agent" text,
+            "ip" inet
+        );
+
+        DO $$ BEGIN
+            CREATE TYPE "access_token_scope" AS ENUM ({access_token_scopes});
+        EXCEPTION
+            WHEN duplicate_object THEN null;
+        END $$;
+        CREATE TABLE IF NOT EXISTS "access_tokens" (
+            "id" bytea PRIMARY KEY CHECK (length("id") = {hash_output_length}),
+            "user_id" text NOT NULL REFERENCES "users"("id") ON DELETE CASCADE,
+            "name" text NOT NULL CHECK (
+                length("name") >= 1 AND length("name") <= {access_token_name_max_length}
+            ),
+            "scopes" access_token_scope[] NOT NULL,
+            "created" timestamp(0) with time zone NOT NULL,
+            "last_used" timestamp(0) with time zone,
+            "expires" timestamp(0) with time zone
+        );
+
+        CREATE TABLE IF NOT EXISTS "oidc_identities" (
+            "provider" text NOT NULL,
+            "subject" text NOT NULL,
+            "user_id" text NOT NULL REFERENCES "users"("id") ON DELETE CASCADE,
+            "created" timestamp(0) with time zone NOT NULL,
+            PRIMARY KEY ("provider", "subject"),
+            UNIQUE ("user_id", "provider")
         );
+
+        CREATE TABLE IF NOT EXISTS "webauthn_credentials" (
+            "id" bytea PRIMARY KEY,
+            "user_id" text NOT NULL REFERENCES "users"("id") ON DELETE CASCADE,
+            "public_key" bytea NOT NULL CHECK (length("public_key") = 65),
+            "sign_count" bigint NOT NULL,
+            "created" timestamp(0) with time zone NOT NULL,
+            "last_used" timestamp(0) with time zone
+        );
+
+        -- No foreign key to "users" so that the trail outlives deleted accounts
+        CREATE TABLE IF NOT EXISTS "audit_events" (
+            "id" bigint GENERATED ALWAYS AS IDENTITY PRIMARY KEY,
+            "created" timestamp(0) with time zone NOT NULL,
+            "event" text NOT NULL,
+            "user_id" text,
+            "session_id" bytea,
+            "ip" inet,
+            "user_agent" text,
+            "details" jsonb NOT NULL
+        );
+        CREATE INDEX IF NOT EXISTS "audit_events_user_id_idx" ON "audit_events" ("user_id", "id");
         "#,
         user_id_length = config.user.id_length,
         username_min_length = config.user.username_min_length,
         username_max_length = config.user.username_max_length,
+        email_max_length = config.user.email_max_length,
         icon_id_length = config.user.icon_id_length,
         hash_output_length = hash("").len(),
         csrf_token_length = config.csrf.token_length,
+        access_token_name_max_length = config.access_token.name_max_length,
+        role_name_max_length = config.role.name_max_length,
     ))
     .await
     .unwrap();
@@ -195,6 +349,57 @@ pub async fn init_db(drop_existing: bool, config: &Config) {
     )
     .await
     .unwrap();
+    // The audit log is append-only for the application
+    db.batch_execute(&format!(
+        r#"
+        GRANT USAGE ON ALL SEQUENCES IN SCHEMA "public" TO "{user}";
+        REVOKE UPDATE, DELETE, TRUNCATE ON "audit_events" FROM "{user}";
+        "#,
+    ))
+    .await
+    .unwrap();
+
+    migrate_db(&db, config).await;
+}
+
+// Values encrypted before key IDs were added lack the one byte prefix until
+// reencrypt-users rewrites them
+fn encrypted_totp_key_lengths(config: &Config) -> (usize, usize) {
+    let length = encrypt(&generate_totp_key(config), config, &mut rand::thread_rng())
+        .unwrap()
+        .len();
+    if config.dev.debug {
+        (length, length)
+    } else {
+        (length - 1, length)
+    }
+}
+
+// Brings the constraints of tables created by older versions up to date, as
+// CREATE TABLE IF NOT EXISTS leaves existing tables alone; safe to run again.
+// Password hashes are no longer of a fixed length since imported server-py
+// hashes are kept until the next login, and encrypted values carry a key ID.
+pub async fn migrate_db(db: &Client, config: &Config) {
+    let (legacy_totp_key_length, totp_key_length) = encrypted_totp_key_lengths(config);
+    db.batch_execute(&format!(
+        r#"
+        BEGIN;
+        ALTER TABLE "users"
+            DROP CONSTRAINT IF EXISTS "users_password_check",
+            DROP CONSTRAINT IF EXISTS "users_totp_key_check",
+            ADD CONSTRAINT "users_totp_key_check" CHECK (
+                length("totp_key") IN ({legacy_totp_key_length}, {totp_key_length})
+            );
+        ALTER TABLE "new_totp_keys"
+            DROP CONSTRAINT IF EXISTS "new_totp_keys_key_check",
+            ADD CONSTRAINT "new_totp_keys_key_check" CHECK (
+                length("key") IN ({legacy_totp_key_length}, {totp_key_length})
+            );
+        COMMIT;
+        "#,
+    ))
+    .await
+    .unwrap();
 }
 
 pub async fn populate_db(config: &Config) {
@@ -222,3 +427,81 @@ pub async fn populate_db(config: &Config) {
         }
     }
 }
+
+// Moves users.password and users.totp_key over to the active AES key so that
+// retired keys can be removed from the config; returns the number of users updated
+pub async fn reencrypt_users(batch_size: i64, config: &Config) -> usize {
+    if config.dev.debug {
+        panic!("nothing is encrypted when dev.debug is set");
+    }
+
+    let pool = config.db.create_pool(None, NoTls).unwrap();
+    let mut db = pool.get().await.unwrap();
+    let active_id = i32::from(config.aes.active_id);
+    // Legacy values have no key ID prefix, so their first byte says nothing
+    // and every user has to be checked
+    let check_all = config.aes.legacy.is_some();
+    let fernet_pattern = [LEGACY_FERNET_PREFIX, b"%"].concat();
+    // None if the value is already under the active key
+    let reencrypt = |encrypted: &[u8]| {
+        let (key_id, plaintext) = decrypt_with_key_id(encrypted, config).unwrap();
+        if key_id == Some(config.aes.active_id) {
+            return None;
+        }
+        Some(encrypt(&plaintext, config, &mut rand::thread_rng()).unwrap())
+    };
+
+    let mut last_id = String::new();
+    let mut updated = 0;
+    loop {
+        let transaction = db.transaction().await.unwrap();
+        let users = transaction
+            .query(
+                r#"
+                SELECT "id", "password", "totp_key" FROM "users"
+                WHERE "id" > $1 AND (
+                    "password" NOT LIKE $5 AND ($4 OR get_byte("password", 0) <> $2)
+                    OR "totp_key" IS NOT NULL AND ($4 OR get_byte("totp_key", 0) <> $2)
+                )
+                ORDER BY "id" LIMIT $3 FOR UPDATE
+                "#,
+                &[&last_id, &active_id, &batch_size, &check_all, &fernet_pattern],
+            )
+            .await
+            .unwrap();
+        let Some(last_user) = users.last() else {
+            break;
+        };
+        last_id = last_user.get("id");
+
+        for user in &users {
+            let password: &[u8] = user.get("password");
+            // Legacy Fernet password hashes are replaced on the next login instead
+            let password = if password.starts_with(LEGACY_FERNET_PREFIX) {
+                None
+            } else {
+                reencrypt(password)
+            };
+            let totp_key = user.get::<_, Option<&[u8]>>("totp_key").and_then(reencrypt);
+            if password.is_none() && totp_key.is_none() {
+                continue;
+            }
+            transaction
+                .execute(
+                    r#"
+                    UPDATE "users"
+                    SET "password" = coalesce($1, "password"), "totp_key" = coalesce($2, "totp_key")
+                    WHERE "id" = $3
+                    "#,
+                    &[&password, &totp_key, &user.get::<_, &str>("id")],
+                )
+                .await
+                .unwrap();
+            updated += 1;
+        }
+        transaction.commit().await.unwrap();
+
+        tracing::info!("re-encrypted {} users", updated);
+    }
+    updated
+}
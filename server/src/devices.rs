@@ -0,0 +1,148 @@
+use deadpool_postgres::Transaction;
+use poem::Request;
+use serde_json::json;
+
+use crate::{
+    config::Config,
+    error::InternalError,
+    mail::{DynMailer, Email},
+    util::{generate_token, get_db, hash, utc_now, ClientInfo},
+    websocket::{send_to_room, AccountRooms},
+};
+
+fn device_id(client: &ClientInfo) -> Vec<u8> {
+    let ip = client.ip.map(|ip| ip.to_string()).unwrap_or_default();
+    hash(&format!("{}\n{}", client.user_agent.as_deref().unwrap_or_default(), ip))
+}
+
+// Remembers the user agent and IP pair a session is created from. If the user
+// has logged in before but never from this pair, returns a token that revokes
+// all of their sessions.
+pub async fn check_device(
+    transaction: &Transaction<'_>,
+    user_id: &str,
+    client: &ClientInfo,
+    config: &Config,
+) -> Result<Option<String>, InternalError> {
+    let device_id = device_id(client);
+    let now = utc_now();
+    let updated = transaction
+        .execute(
+            r#"UPDATE "known_devices" SET "last_seen" = $1 WHERE "user_id" = $2 AND "id" = $3"#,
+            &[&now, &user_id, &device_id],
+        )
+        .await
+        .map_err(InternalError::new)?;
+    if updated > 0 {
+        return Ok(None);
+    }
+
+    let first_device = transaction
+        .query_one(
+            r#"SELECT count(*) = 0 FROM "known_devices" WHERE "user_id" = $1"#,
+            &[&user_id],
+        )
+        .await
+        .map_err(InternalError::new)?
+        .get::<_, bool>(0);
+    transaction
+        .execute(
+            r#"
+            INSERT INTO "known_devices"("user_id", "id", "first_seen", "last_seen")
+            VALUES ($1, $2, $3, $3) ON CONFLICT DO NOTHING
+            "#,
+            &[&user_id, &device_id, &now],
+        )
+        .await
+        .map_err(InternalError::new)?;
+    if first_device {
+        return Ok(None);
+    }
+
+    let token = generate_token(config.device_notification.token_length);
+    transaction
+        .execute(
+            r#"
+            INSERT INTO "session_revocation_tokens"("id", "user_id", "expires")
+            VALUES ($1, $2, $3)
+            "#,
+            &[
+                &hash(&token),
+                &user_id,
+                &(now + config.device_notification.token_lifetime),
+            ],
+        )
+        .await
+        .map_err(InternalError::new)?;
+    Ok(Some(token))
+}
+
+// Tells the user's connected clients about the login and emails a verified
+// address if there is one
+pub async fn notify_new_device(
+    user_id: &str,
+    revocation_token: &str,
+    client: &ClientInfo,
+    req: &Request,
+    config: &Config,
+) -> Result<(), InternalError> {
+    let mailer = req
+        .data::<DynMailer>()
+        .ok_or_else(|| InternalError::new("no mailer initialized"))?;
+    let rooms = req
+        .data::<AccountRooms>()
+        .ok_or_else(|| InternalError::new("no account rooms initialized"))?;
+    let origin = config.client.origin.to_str().map_err(InternalError::new)?;
+    let link = format!(
+        "{}{}?token={}",
+        origin, config.device_notification.link_path, revocation_token,
+    );
+    let time = utc_now();
+    let ip = client.ip.map(|ip| ip.to_string());
+
+    send_to_room(
+        &format!("user:{user_id}"),
+        json!({
+            "event": "new-device-login",
+            "data": {
+                "time": time.to_rfc3339(),
+                "user_agent": client.user_agent,
+                "ip": ip,
+                "revoke_link": link,
+            },
+        }),
+        rooms,
+    )
+    .await;
+
+    let user = get_db(req)
+        .await?
+        .query_one(
+            r#"SELECT "username", "email", "email_verified" FROM "users" WHERE "id" = $1"#,
+            &[&user_id],
+        )
+        .await
+        .map_err(InternalError::new)?;
+    let Some(email) = user.get::<_, Option<String>>("email") else {
+        return Ok(());
+    };
+    if !user.get::<_, bool>("email_verified") {
+        return Ok(());
+    }
+    mailer
+        .send(Email {
+            to: email,
+            subject: "New login to your account".to_owned(),
+            body: format!(
+                "The account {} was logged in to from a new device.\n\n\
+                Time: {}\nIP address: {}\nBrowser: {}\n\n\
+                If this wasn't you, open the following link to log out all sessions:\n{}\n",
+                user.get::<_, &str>("username"),
+                time.to_rfc3339(),
+                ip.as_deref().unwrap_or("unknown"),
+                client.user_agent.as_deref().unwrap_or("unknown"),
+                link,
+            ),
+        })
+        .await
+}
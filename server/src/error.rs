@@ -6,7 +6,7 @@ use poem::{
         ParsePathError, ParseQueryError, ReadBodyError, ResponseError, UpgradeError,
     },
     http::{
-        header::{self, HeaderValue},
+        header::{self, HeaderName, HeaderValue},
         StatusCode,
     },
     web::cookie::Cookie,
@@ -23,22 +23,82 @@ pub struct ErrorData {
     pub cookies: Vec<Cookie>,
     pub csrf_token: Option<(String, String)>,
     pub details: Option<String>,
+    pub headers: Vec<(HeaderName, HeaderValue)>,
+}
+
+#[alert_enum(response_error)]
+pub enum AccountError {
+    CannotEnableTotpForOthers,
+    CannotUpdatePasswordForOthers,
+    EmailNotAvailable,
+    InvalidCurrentPassword,
+    InvalidEmailVerificationToken,
+    InvalidPasswordResetToken,
+    InvalidSessionRevocationToken,
+    InvalidTotpVerification,
+    InvalidWebauthnRegistration,
+    MissingCurrentPassword,
+    NoChangeInEmail,
+    NoChangeInPassword,
+    NoTotpKeyActive,
+    OidcIdentityAlreadyLinked,
+    TotpAlreadyEnabled,
+    TotpNotEnabled,
+    UsernameNotAvailable,
+}
+
+impl ResponseError for AccountError {
+    fn status(&self) -> StatusCode {
+        match self {
+            Self::CannotEnableTotpForOthers(_) => StatusCode::FORBIDDEN,
+            Self::CannotUpdatePasswordForOthers(_) => StatusCode::FORBIDDEN,
+            Self::EmailNotAvailable(_) => StatusCode::BAD_REQUEST,
+            Self::InvalidCurrentPassword(_) => StatusCode::BAD_REQUEST,
+            Self::InvalidEmailVerificationToken(_) => StatusCode::BAD_REQUEST,
+            Self::InvalidPasswordResetToken(_) => StatusCode::BAD_REQUEST,
+            Self::InvalidSessionRevocationToken(_) => StatusCode::BAD_REQUEST,
+            Self::InvalidTotpVerification(_) => StatusCode::BAD_REQUEST,
+            Self::InvalidWebauthnRegistration(_) => StatusCode::BAD_REQUEST,
+            Self::MissingCurrentPassword(_) => StatusCode::BAD_REQUEST,
+            Self::NoChangeInEmail(_) => StatusCode::BAD_REQUEST,
+            Self::NoChangeInPassword(_) => StatusCode::BAD_REQUEST,
+            Self::NoTotpKeyActive(_) => StatusCode::BAD_REQUEST,
+            Self::OidcIdentityAlreadyLinked(_) => StatusCode::BAD_REQUEST,
+            Self::TotpAlreadyEnabled(_) => StatusCode::BAD_REQUEST,
+            Self::TotpNotEnabled(_) => StatusCode::BAD_REQUEST,
+            Self::UsernameNotAvailable(_) => StatusCode::BAD_REQUEST,
+        }
+    }
+}
+
+#[alert_enum]
+pub enum AccountWarning {
+    NoChangeInPassword,
 }
 
 #[alert_enum(response_error)]
 pub enum AuthError {
     AccountDisabled,
+    AccountLocked,
     AlreadyLoggedIn,
     Forbidden,
+    InvalidAccessToken,
     InvalidCredentials,
+    InvalidMagicLinkToken,
+    InvalidOidcState,
     InvalidRememberToken,
     InvalidTotp,
+    InvalidWebauthnAssertion,
     MissingRememberToken,
     MissingTotp,
     NotLoggedIn,
+    OidcAuthenticationFailed,
+    OidcIdentityNotLinked,
     PasswordChangeRequired,
     RememberTokenSecretMismatch,
     SessionExpired,
+    SessionRequired,
+    SudoRequired,
     TotpReuse,
 }
 
@@ -46,17 +106,26 @@ impl ResponseError for AuthError {
     fn status(&self) -> StatusCode {
         match self {
             Self::AccountDisabled(_) => StatusCode::FORBIDDEN,
+            Self::AccountLocked(_) => StatusCode::FORBIDDEN,
             Self::AlreadyLoggedIn(_) => StatusCode::BAD_REQUEST,
             Self::Forbidden(_) => StatusCode::FORBIDDEN,
+            Self::InvalidAccessToken(_) => StatusCode::UNAUTHORIZED,
             Self::InvalidCredentials(_) => StatusCode::BAD_REQUEST,
+            Self::InvalidMagicLinkToken(_) => StatusCode::BAD_REQUEST,
+            Self::InvalidOidcState(_) => StatusCode::BAD_REQUEST,
             Self::InvalidRememberToken(_) => StatusCode::BAD_REQUEST,
             Self::InvalidTotp(_) => StatusCode::BAD_REQUEST,
+            Self::InvalidWebauthnAssertion(_) => StatusCode::BAD_REQUEST,
             Self::MissingRememberToken(_) => StatusCode::BAD_REQUEST,
             Self::MissingTotp(_) => StatusCode::BAD_REQUEST,
             Self::NotLoggedIn(_) => StatusCode::UNAUTHORIZED,
+            Self::OidcAuthenticationFailed(_) => StatusCode::BAD_REQUEST,
+            Self::OidcIdentityNotLinked(_) => StatusCode::BAD_REQUEST,
             Self::PasswordChangeRequired(_) => StatusCode::BAD_REQUEST,
             Self::RememberTokenSecretMismatch(_) => StatusCode::BAD_REQUEST,
             Self::SessionExpired(_) => StatusCode::FORBIDDEN,
+            Self::SessionRequired(_) => StatusCode::FORBIDDEN,
+            Self::SudoRequired(_) => StatusCode::FORBIDDEN,
             Self::TotpReuse(_) => StatusCode::BAD_REQUEST,
         }
     }
@@ -64,6 +133,8 @@ impl ResponseError for AuthError {
 
 #[alert_enum]
 pub enum AuthWarning {
+    AccountDeletionCancelled,
+    RecoveryCodeUsed,
     UnusedTotp,
 }
 
@@ -85,6 +156,7 @@ impl ResponseError for CsrfError {
 pub enum GeneralError {
     InvalidData,
     NotFound,
+    TooManyRequests,
 }
 
 ////////////////////
@@ -94,10 +166,68 @@ impl ResponseError for GeneralError {
         match self {
             Self::InvalidData(_) => StatusCode::BAD_REQUEST,
             Self::NotFound(_) => StatusCode::NOT_FOUND,
+            Self::TooManyRequests(_) => StatusCode::TOO_MANY_REQUESTS,
+        }
+    }
+}
+
+#[alert_enum(response_error)]
+pub enum IconError {
+    InvalidImage,
+    MultipleFiles,
+    NoFile,
+    TooLarge,
+    TooSmall,
+    TooTall,
+    TooWide,
+}
+
+impl ResponseError for IconError {
+    fn status(&self) -> StatusCode {
+        match self {
+            Self::TooLarge(_) => StatusCode::PAYLOAD_TOO_LARGE,
+            _ => StatusCode::BAD_REQUEST,
+        }
+    }
+}
+
+#[alert_enum(response_error)]
+pub enum PasswordError {
+    Breached,
+    ContainsUserInput,
+    TooWeak,
+}
+
+impl ResponseError for PasswordError {
+    fn status(&self) -> StatusCode {
+        StatusCode::BAD_REQUEST
+    }
+}
+
+#[alert_enum(response_error)]
+pub enum PermissionError {
+    NotHeld,
+    RoleNameNotAvailable,
+}
+
+impl ResponseError for PermissionError {
+    fn status(&self) -> StatusCode {
+        match self {
+            Self::NotHeld(_) => StatusCode::FORBIDDEN,
+            Self::RoleNameNotAvailable(_) => StatusCode::BAD_REQUEST,
         }
     }
 }
 
+#[alert_enum]
+pub enum ValidationError {
+    InvalidFormat,
+    NotAllowed,
+    OutOfRange,
+    TooLong,
+    TooShort,
+}
+
 #[alert_enum]
 pub enum WebSocketError {
     AlreadyInRoom,
@@ -148,7 +278,64 @@ impl ResponseError for InternalError {
     }
 }
 
+// Failed validation rules, each tied to the path of the offending field so
+// that clients can show the alerts next to the matching inputs
+#[derive(Debug, Default, thiserror::Error)]
+#[error("invalid-data")]
+pub struct ValidationErrors {
+    fields: Vec<(String, ValidationError)>,
+    data: Option<ErrorData>,
+}
+
+impl ValidationErrors {
+    pub fn push(&mut self, field: &str, error: ValidationError) {
+        self.fields.push((field.to_owned(), error));
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.fields.is_empty()
+    }
+
+    pub fn has_field(&self, field: &str) -> bool {
+        self.fields.iter().any(|(name, _)| name == field)
+    }
+
+    pub fn alerts(&self) -> Vec<JsonValue> {
+        self.fields
+            .iter()
+            .map(|(field, error)| field_alert(field, error))
+            .collect()
+    }
+
+    pub fn with_data(mut self, data: Option<ErrorData>) -> Self {
+        self.data = data;
+        self
+    }
+}
+
+impl ResponseError for ValidationErrors {
+    fn status(&self) -> StatusCode {
+        StatusCode::BAD_REQUEST
+    }
+
+    fn as_response(&self) -> Response {
+        let errors = self.alerts();
+        let mut res = Response::builder()
+            .status(self.status())
+            .content_type("application/json");
+        if let Some(data) = &self.data {
+            for cookie in &data.cookies {
+                res = res.header(header::SET_COOKIE, cookie.to_string());
+            }
+        }
+        res.body(Body::from_json(json!({ "errors": errors })).unwrap())
+    }
+}
+
 pub async fn error_handler(err: poem::Error) -> Response {
+    if let Some(err) = err.downcast_ref::<AccountError>() {
+        return err.as_response();
+    }
     if let Some(err) = err.downcast_ref::<AuthError>() {
         return err.as_response();
     }
@@ -158,6 +345,12 @@ pub async fn error_handler(err: poem::Error) -> Response {
     if let Some(err) = err.downcast_ref::<GeneralError>() {
         return err.as_response();
     }
+    if let Some(err) = err.downcast_ref::<PasswordError>() {
+        return err.as_response();
+    }
+    if let Some(err) = err.downcast_ref::<ValidationErrors>() {
+        return err.as_response();
+    }
     if let Some(err) = err.downcast_ref::<InternalError>() {
         return err.as_response();
     }
@@ -168,14 +361,12 @@ pub async fn error_handler(err: poem::Error) -> Response {
         }))
         .as_response();
     };
-    if let Some(err) = err.downcast_ref::<ParseJsonError>() {
-        if let ParseJsonError::Parse(err) = err {
-            return GeneralError::InvalidData(Some(ErrorData {
-                details: Some(err.to_string()),
-                ..Default::default()
-            }))
-            .as_response();
-        }
+    if let Some(ParseJsonError::Parse(err)) = err.downcast_ref::<ParseJsonError>() {
+        return GeneralError::InvalidData(Some(ErrorData {
+            details: Some(err.to_string()),
+            ..Default::default()
+        }))
+        .as_response();
     };
     if err.is::<ParsePathError>() {
         return GeneralError::NotFound(None).as_response();
@@ -187,22 +378,16 @@ pub async fn error_handler(err: poem::Error) -> Response {
         }))
         .as_response();
     };
-    if let Some(err) = err.downcast_ref::<ReadBodyError>() {
-        if let ReadBodyError::BodyHasBeenTaken = err {
-            return InternalError::new(err.to_string()).as_response();
-        }
+    if let Some(err @ ReadBodyError::BodyHasBeenTaken) = err.downcast_ref::<ReadBodyError>() {
+        return InternalError::new(err.to_string()).as_response();
     }
-    if let Some(err) = err.downcast_ref::<UpgradeError>() {
-        if let UpgradeError::NoUpgrade = err {
-            return InternalError::new(err.to_string()).as_response();
-        }
+    if let Some(err @ UpgradeError::NoUpgrade) = err.downcast_ref::<UpgradeError>() {
+        return InternalError::new(err.to_string()).as_response();
     }
-    if let Some(err) = err.downcast_ref::<poem::error::WebSocketError>() {
-        if let poem::error::WebSocketError::UpgradeError(err) = err {
-            if let UpgradeError::NoUpgrade = err {
-                return InternalError::new(err.to_string()).as_response();
-            }
-        }
+    if let Some(poem::error::WebSocketError::UpgradeError(err @ UpgradeError::NoUpgrade)) =
+        err.downcast_ref::<poem::error::WebSocketError>()
+    {
+        return InternalError::new(err.to_string()).as_response();
     }
 
     if !err.is::<MethodNotAllowedError>()    // id = "method-not-allowed"
@@ -248,18 +433,25 @@ pub async fn error_handler(err: poem::Error) -> Response {
 
 fn api_alert(source: &str, id: &str, details: Option<String>) -> JsonValue {
     let mut error = json!({ "source": source, "id": id });
-    match details {
-        Some(details) => {
-            error
-                .as_object_mut()
-                .unwrap()
-                .insert("details".to_owned(), JsonValue::String(details));
-        }
-        None => (),
+    if let Some(details) = details {
+        error
+            .as_object_mut()
+            .unwrap()
+            .insert("details".to_owned(), JsonValue::String(details));
     }
     error
 }
 
+// An alert tied to an input field, e.g. in responses that apply some fields
+// and reject others
+pub fn field_alert<T: Serialize>(field: &str, alert: &T) -> JsonValue {
+    let mut alert = serde_json::to_value(alert).unwrap_or_else(|_| json!({}));
+    if let Some(alert) = alert.as_object_mut() {
+        alert.insert("field".to_owned(), JsonValue::String(field.to_owned()));
+    }
+    alert
+}
+
 fn single_error(source: &str, id: &str, details: Option<String>) -> JsonValue {
     json!({ "errors": [api_alert(source, id, details)] })
 }
@@ -0,0 +1,151 @@
+use std::io::Cursor;
+
+use image::{
+    imageops::FilterType, io::Limits, io::Reader as ImageReader, DynamicImage, ImageFormat,
+    ImageOutputFormat,
+};
+use poem::Result;
+
+use crate::{
+    config::Config,
+    error::{ErrorData, IconError, InternalError},
+    storage::DynStorage,
+};
+
+pub const ICON_CONTENT_TYPE: &str = "image/png";
+
+const ACCEPTED_FORMATS: [ImageFormat; 4] = [
+    ImageFormat::Gif,
+    ImageFormat::Jpeg,
+    ImageFormat::Png,
+    ImageFormat::WebP,
+];
+
+pub fn icon_key(icon_id: &str, size: u32) -> String {
+    format!("icons/{icon_id}/{size}.png")
+}
+
+// Removes every size of an icon; failures are logged but otherwise ignored
+// since the user row no longer points to the icon
+pub async fn delete_icon_blobs(icon_id: &str, storage: &DynStorage, config: &Config) {
+    for &size in &config.icon.sizes {
+        if let Err(err) = storage.delete(&icon_key(icon_id, size)).await {
+            tracing::warn!("could not delete icon {} size {}: {:?}", icon_id, size, err);
+        }
+    }
+}
+
+fn with_details(details: String) -> Option<ErrorData> {
+    Some(ErrorData {
+        details: Some(details),
+        ..Default::default()
+    })
+}
+
+fn invalid_image() -> IconError {
+    let formats: Vec<_> = ACCEPTED_FORMATS
+        .iter()
+        .map(|format| format!("{format:?}").to_uppercase())
+        .collect();
+    IconError::InvalidImage(with_details(format!(
+        "Accepted formats: {}",
+        formats.join(", ")
+    )))
+}
+
+// The EXIF orientation tag, if any; 1 means no transformation
+fn exif_orientation(data: &[u8]) -> u32 {
+    exif::Reader::new()
+        .read_from_container(&mut Cursor::new(data))
+        .ok()
+        .and_then(|exif| {
+            exif.get_field(exif::Tag::Orientation, exif::In::PRIMARY)
+                .and_then(|field| field.value.get_uint(0))
+        })
+        .unwrap_or(1)
+}
+
+fn apply_orientation(image: DynamicImage, orientation: u32) -> DynamicImage {
+    match orientation {
+        2 => image.fliph(),
+        3 => image.rotate180(),
+        4 => image.flipv(),
+        5 => image.rotate90().fliph(),
+        6 => image.rotate90(),
+        7 => image.rotate270().fliph(),
+        8 => image.rotate270(),
+        _ => image,
+    }
+}
+
+// Decodes an uploaded image, rotates it upright, crops the center square and
+// encodes it once for every configured size. Sizes larger than the cropped
+// image get the cropped image as is, so it is never scaled up.
+pub fn process_icon(data: &[u8], config: &Config) -> Result<Vec<(u32, Vec<u8>)>> {
+    let mut reader = ImageReader::new(Cursor::new(data))
+        .with_guessed_format()
+        .map_err(|_| invalid_image())?;
+    match reader.format() {
+        Some(format) if ACCEPTED_FORMATS.contains(&format) => {}
+        _ => return Err(invalid_image().into()),
+    }
+    let (width, height) = ImageReader::new(Cursor::new(data))
+        .with_guessed_format()
+        .ok()
+        .and_then(|reader| reader.into_dimensions().ok())
+        .ok_or_else(invalid_image)?;
+    if u64::from(width) * u64::from(height) > config.icon.max_decoded_size {
+        return Err(IconError::TooLarge(with_details(format!(
+            "Maximum size: {} pixels",
+            config.icon.max_decoded_size
+        )))
+        .into());
+    }
+
+    let mut limits = Limits::default();
+    limits.max_image_width = Some(width);
+    limits.max_image_height = Some(height);
+    reader.limits(limits);
+    let image = reader.decode().map_err(|_| invalid_image())?;
+    let image = apply_orientation(image, exif_orientation(data));
+
+    let (width, height) = (image.width(), image.height());
+    let min_size = config.icon.min_size;
+    if width < min_size || height < min_size {
+        return Err(IconError::TooSmall(with_details(format!(
+            "Minimum width and height: {min_size}"
+        )))
+        .into());
+    }
+    let max_ratio = config.icon.max_dimension_ratio;
+    if width > height.saturating_mul(max_ratio) {
+        return Err(
+            IconError::TooWide(with_details(format!("Maximum aspect ratio: {max_ratio}"))).into(),
+        );
+    }
+    if height > width.saturating_mul(max_ratio) {
+        return Err(
+            IconError::TooTall(with_details(format!("Maximum aspect ratio: {max_ratio}"))).into(),
+        );
+    }
+
+    let side = width.min(height);
+    let square = image.crop_imm((width - side) / 2, (height - side) / 2, side, side);
+    config
+        .icon
+        .sizes
+        .iter()
+        .map(|&size| {
+            let resized = if size < side {
+                square.resize_exact(size, size, FilterType::Lanczos3)
+            } else {
+                square.clone()
+            };
+            let mut encoded = Vec::new();
+            resized
+                .write_to(&mut Cursor::new(&mut encoded), ImageOutputFormat::Png)
+                .map_err(InternalError::new)?;
+            Ok((size, encoded))
+        })
+        .collect()
+}
@@ -1,3 +1,7 @@
+// Handlers and most helpers return poem::Result, whose error is large; boxing
+// it would only add an allocation to paths that already end the request
+#![allow(clippy::result_large_err)]
+
 use std::{collections::HashMap, sync::Arc};
 
 use deadpool_postgres::tokio_postgres::NoTls;
@@ -5,21 +9,39 @@ use poem::{middleware::CookieJarManager, Endpoint, EndpointExt, Response, Route}
 use redis::Client as RedisClient;
 use tokio::sync::Mutex;
 
+pub mod account_deletion;
+mod audit;
 pub mod config;
 pub mod db;
+mod devices;
 mod error;
+mod icon;
+pub mod magic_link;
+pub mod mail;
 mod middleware;
+pub mod oidc;
+pub mod password_policy;
 mod routes;
+pub mod storage;
 pub mod util;
+pub mod validate;
+pub mod webauthn;
 mod websocket;
 
+use account_deletion::spawn_purge_job;
 use config::Config;
 use error::error_handler;
+use mail::make_mailer;
+use middleware::AuditLog;
+use storage::make_storage;
 use websocket::{AccountConnections, AccountRooms};
 
 pub async fn create_app(config: Config) -> impl Endpoint<Output = Response> {
     let db = config.db.create_pool(None, NoTls).unwrap();
     let redis = RedisClient::open(config.redis.url.clone()).unwrap();
+    let mailer = make_mailer(&config);
+    let http = reqwest::Client::new();
+    let storage = make_storage(&http, &config);
     let account_rooms: AccountRooms = Arc::new(Mutex::new(HashMap::new()));
     let account_connections: AccountConnections = Arc::new(Mutex::new(HashMap::new()));
 
@@ -29,20 +51,30 @@ pub async fn create_app(config: Config) -> impl Endpoint<Output = Response> {
             db::populate_db(&config).await;
         }
     }
+    // Tests run the purge themselves
+    if !config.dev.testing {
+        spawn_purge_job(db.clone(), storage.clone(), config.clone());
+    }
 
     let mut routes = Route::new()
         .nest("/account", routes::account::routes(&config))
+        .nest("/audit", routes::audit::routes(&config))
         .nest("/auth", routes::auth::routes(&config))
+        .nest("/roles", routes::roles::routes(&config))
         .nest("/users", routes::users::routes(&config));
     if config.dev.debug {
         routes = routes.nest("/test", routes::test::routes(&config))
     };
     routes
+        .with(AuditLog::new(config.clone()))
         .catch_all_error(error_handler)
         .with(CookieJarManager::new())
         .data(config)
         .data(db)
         .data(redis)
+        .data(mailer)
+        .data(http)
+        .data(storage)
         .data(account_rooms)
         .data(account_connections)
 }
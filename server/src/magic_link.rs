@@ -0,0 +1,86 @@
+use redis::{AsyncCommands, Client as RedisClient};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    config::Config,
+    error::InternalError,
+    util::{base64_urlsafe, generate_token, hash, redis_join},
+};
+
+// Stored in Redis under the hashed token
+#[derive(Deserialize, Serialize)]
+pub struct MagicLink {
+    pub user_id: String,
+    pub remember: bool,
+}
+
+fn token_key(token: &str, config: &Config) -> String {
+    redis_join(&["magic-link", &base64_urlsafe(&hash(token))], config)
+}
+
+// Stores a new login token in Redis and returns the link to email to the user
+pub async fn issue_token(
+    user_id: &str,
+    remember: bool,
+    redis: &RedisClient,
+    config: &Config,
+) -> Result<String, InternalError> {
+    let token = generate_token(config.magic_link.token_length);
+    let magic_link = MagicLink {
+        user_id: user_id.to_owned(),
+        remember,
+    };
+
+    let mut redis = redis
+        .get_async_connection()
+        .await
+        .map_err(InternalError::new)?;
+    redis
+        .set_ex::<_, _, ()>(
+            token_key(&token, config),
+            serde_json::to_string(&magic_link).map_err(InternalError::new)?,
+            config.magic_link.token_lifetime,
+        )
+        .await
+        .map_err(InternalError::new)?;
+
+    let origin = config.client.origin.to_str().map_err(InternalError::new)?;
+    Ok(format!("{}{}?token={}", origin, config.magic_link.link_path, token))
+}
+
+// Reads a token without using it up, so that a client can still be asked for a TOTP
+pub async fn peek_token(
+    token: &str,
+    redis: &RedisClient,
+    config: &Config,
+) -> Result<Option<MagicLink>, InternalError> {
+    let mut redis = redis
+        .get_async_connection()
+        .await
+        .map_err(InternalError::new)?;
+    let value: Option<String> = redis
+        .get(token_key(token, config))
+        .await
+        .map_err(InternalError::new)?;
+    match value {
+        Some(value) => Ok(Some(serde_json::from_str(&value).map_err(InternalError::new)?)),
+        None => Ok(None),
+    }
+}
+
+// Returns false if the token was already used or has expired
+pub async fn take_token(
+    token: &str,
+    redis: &RedisClient,
+    config: &Config,
+) -> Result<bool, InternalError> {
+    let mut redis = redis
+        .get_async_connection()
+        .await
+        .map_err(InternalError::new)?;
+    let deleted: usize = redis
+        .del(token_key(token, config))
+        .await
+        .map_err(InternalError::new)?;
+    Ok(deleted > 0)
+}
@@ -0,0 +1,118 @@
+use std::sync::{Arc, Mutex};
+
+use lettre::{
+    message::Mailbox, transport::smtp::authentication::Credentials, AsyncFileTransport,
+    AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor,
+};
+use poem::async_trait;
+
+use crate::{
+    config::{Config, MailBackendConfig},
+    error::InternalError,
+};
+
+#[derive(Clone, Debug)]
+pub struct Email {
+    pub to: String,
+    pub subject: String,
+    pub body: String,
+}
+
+pub type Outbox = Arc<Mutex<Vec<Email>>>;
+
+#[async_trait]
+pub trait Mailer: Send + Sync {
+    async fn send(&self, email: Email) -> Result<(), InternalError>;
+}
+
+pub type DynMailer = Arc<dyn Mailer>;
+
+pub fn make_mailer(config: &Config) -> DynMailer {
+    let from: Mailbox = config.mail.from.parse().unwrap();
+    match &config.mail.backend {
+        MailBackendConfig::File { dir } => {
+            std::fs::create_dir_all(dir).unwrap();
+            Arc::new(FileMailer {
+                from,
+                transport: AsyncFileTransport::new(dir),
+            })
+        }
+        MailBackendConfig::Memory { outbox } => Arc::new(MemoryMailer {
+            outbox: outbox.clone(),
+        }),
+        MailBackendConfig::Smtp(smtp) => {
+            let mut transport = if smtp.tls {
+                AsyncSmtpTransport::<Tokio1Executor>::relay(&smtp.host).unwrap()
+            } else {
+                AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(&smtp.host)
+            }
+            .port(smtp.port);
+            if let (Some(username), Some(password)) = (&smtp.username, &smtp.password) {
+                transport =
+                    transport.credentials(Credentials::new(username.clone(), password.clone()));
+            }
+            Arc::new(SmtpMailer {
+                from,
+                transport: transport.build(),
+            })
+        }
+    }
+}
+
+fn build_message(from: &Mailbox, email: Email) -> Result<Message, InternalError> {
+    Message::builder()
+        .from(from.clone())
+        .to(email.to.parse().map_err(InternalError::new)?)
+        .subject(email.subject)
+        .body(email.body)
+        .map_err(InternalError::new)
+}
+
+pub struct FileMailer {
+    from: Mailbox,
+    transport: AsyncFileTransport<Tokio1Executor>,
+}
+
+#[async_trait]
+impl Mailer for FileMailer {
+    async fn send(&self, email: Email) -> Result<(), InternalError> {
+        let message = build_message(&self.from, email)?;
+        self.transport
+            .send(message)
+            .await
+            .map_err(InternalError::new)?;
+        Ok(())
+    }
+}
+
+pub struct MemoryMailer {
+    outbox: Outbox,
+}
+
+#[async_trait]
+impl Mailer for MemoryMailer {
+    async fn send(&self, email: Email) -> Result<(), InternalError> {
+        self.outbox
+            .lock()
+            .map_err(|_| InternalError::new("mail outbox lock poisoned"))?
+            .push(email);
+        Ok(())
+    }
+}
+
+pub struct SmtpMailer {
+    from: Mailbox,
+    transport: AsyncSmtpTransport<Tokio1Executor>,
+}
+
+#[async_trait]
+impl Mailer for SmtpMailer {
+    async fn send(&self, email: Email) -> Result<(), InternalError> {
+        let message = build_message(&self.from, email)?;
+        self.transport
+            .send(message)
+            .await
+            .map_err(InternalError::new)?;
+        Ok(())
+    }
+}
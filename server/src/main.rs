@@ -1,7 +1,8 @@
-use clap::Parser;
+use clap::{Parser, Subcommand};
+use deadpool_postgres::tokio_postgres::NoTls;
 use poem::{listener::TcpListener, Server};
 
-use dodatok::config::Config;
+use dodatok::{account_deletion, config::Config, db, storage::make_storage};
 
 #[derive(Parser)]
 struct Args {
@@ -10,6 +11,23 @@ struct Args {
 
     #[clap(short, long, default_value_t = 5000)]
     port: u16,
+
+    #[clap(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    #[clap(about = "Update the constraints of tables created by an older version; \
+        run as a database user that owns the tables")]
+    MigrateDb,
+    #[clap(about = "Delete the users whose deletion grace period has passed")]
+    PurgeDeletedUsers,
+    #[clap(about = "Re-encrypt user passwords and TOTP keys with the active AES key")]
+    ReencryptUsers {
+        #[clap(long, default_value_t = 1000)]
+        batch_size: i64,
+    },
 }
 
 #[tokio::main]
@@ -19,7 +37,31 @@ async fn main() -> std::io::Result<()> {
     let args = Args::parse();
     let config = Config::from_file(&args.config);
 
-    Server::new(TcpListener::bind(("0.0.0.0", args.port)))
-        .run(dodatok::create_app(config).await)
-        .await
+    match args.command {
+        Some(Command::MigrateDb) => {
+            let db = config.db.create_pool(None, NoTls).unwrap();
+            db::migrate_db(&db.get().await.unwrap(), &config).await;
+            println!("database migrated");
+            Ok(())
+        }
+        Some(Command::PurgeDeletedUsers) => {
+            let db = config.db.create_pool(None, NoTls).unwrap();
+            let storage = make_storage(&reqwest::Client::new(), &config);
+            let deleted = account_deletion::purge_deleted_users(&db, &storage, &config)
+                .await
+                .unwrap();
+            println!("deleted {deleted} users");
+            Ok(())
+        }
+        Some(Command::ReencryptUsers { batch_size }) => {
+            let updated = db::reencrypt_users(batch_size, &config).await;
+            println!("re-encrypted {updated} users");
+            Ok(())
+        }
+        None => {
+            Server::new(TcpListener::bind(("0.0.0.0", args.port)))
+                .run(dodatok::create_app(config).await)
+                .await
+        }
+    }
 }
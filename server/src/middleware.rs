@@ -1,15 +1,26 @@
 use bitflags::bitflags;
 use chrono::{DateTime, Utc};
-use poem::{async_trait, Endpoint, Middleware, Request, Result};
+use deadpool_postgres::Pool;
+use poem::{
+    async_trait,
+    http::{
+        header::{HeaderValue, RETRY_AFTER},
+        Method,
+    },
+    Endpoint, Middleware, Request, Result,
+};
+use redis::Client as RedisClient;
 use secstr::SecStr;
+use tracing::error;
 
 use crate::{
+    audit::Audit,
     config::Config,
-    db::{Language, PasswordChangeReason, Permission},
-    error::{AuthError, CsrfError, ErrorData, InternalError},
+    db::{AccessTokenScope, Language, PasswordChangeReason, Permission},
+    error::{AuthError, CsrfError, ErrorData, GeneralError, InternalError},
     util::{
-        clear_cookie, generate_token, get_db, get_session, hash, make_cookie, utc_now, Session,
-        SessionError,
+        base64_urlsafe, bearer_token, clear_cookie, client_ip, generate_token, get_db, get_session,
+        hash, make_cookie, redis_join, utc_now, ClientInfo, Session, SessionError,
     },
 };
 
@@ -25,14 +36,22 @@ bitflags! {
         const WITH_PERMISSIONS = 1 << 6;
         const WITH_SUDO_UNTIL = 1 << 7;
         const ALLOW_PASSWORD_CHANGE_REASON = 1 << 8;
+        const REQUIRE_SUDO = 1 << 9;
+        const WITH_EMAIL = 1 << 10;
+        // For routes that act on the current session, which access tokens do not have
+        const SESSION_ONLY = 1 << 11;
     }
 }
 
 #[derive(Default)]
 pub struct CurrentUser {
     pub id: String,
+    // Hash of the access token for requests authenticated with one
     pub session_id_hash: Vec<u8>,
+    pub access_token_scopes: Option<Vec<AccessTokenScope>>,
     pub username: Option<String>,
+    pub email: Option<Option<String>>,
+    pub email_verified: Option<bool>,
     pub password_hash: Option<String>,
     pub totp_enabled: Option<bool>,
     pub password_change_reason: Option<Option<PasswordChangeReason>>,
@@ -82,24 +101,37 @@ impl<E: Endpoint> Endpoint for AuthRequiredImpl<E> {
     type Output = E::Output;
 
     async fn call(&self, mut req: Request) -> Result<Self::Output> {
-        let session_id = req
-            .cookie()
-            .get(&self.config.session.cookie)
-            .ok_or(AuthError::NotLoggedIn(None))?
-            .value_str()
-            .to_owned();
-        let session_id_hash = hash(&session_id);
+        // API clients authenticate with an access token instead of session cookies
+        let access_token = bearer_token(&req).map(str::to_owned);
+        if access_token.is_some() && self.options.contains(AuthRequiredOptions::SESSION_ONLY) {
+            return Err(AuthError::SessionRequired(None).into());
+        }
+        let session_id_hash = match &access_token {
+            Some(token) => hash(token),
+            None => hash(
+                req.cookie()
+                    .get(&self.config.session.cookie)
+                    .ok_or(AuthError::NotLoggedIn(None))?
+                    .value_str(),
+            ),
+        };
 
         let db = get_db(&req).await?;
-        let mut columns = vec![
-            r#""users"."id""#,
-            r#""users"."active""#,
-            r#""sessions"."expires""#,
-        ];
+        let mut columns = vec![r#""users"."id""#, r#""users"."active""#];
+        if access_token.is_some() {
+            columns.push(r#""access_tokens"."expires""#);
+            columns.push(r#""access_tokens"."scopes""#);
+        } else {
+            columns.push(r#""sessions"."expires""#);
+        }
 
         if self.options.contains(AuthRequiredOptions::WITH_USERNAME) {
             columns.push(r#""users"."username""#);
         }
+        if self.options.contains(AuthRequiredOptions::WITH_EMAIL) {
+            columns.push(r#""users"."email""#);
+            columns.push(r#""users"."email_verified""#);
+        }
         if self
             .options
             .contains(AuthRequiredOptions::WITH_PASSWORD_HASH)
@@ -124,34 +156,54 @@ impl<E: Endpoint> Endpoint for AuthRequiredImpl<E> {
         if self.options.contains(AuthRequiredOptions::WITH_LOCALE) {
             columns.push(r#""users"."language""#);
         }
-        if self.options.contains(AuthRequiredOptions::WITH_SUDO_UNTIL) {
-            columns.push(r#""sessions"."sudo_until""#);
+        if self.options.contains(AuthRequiredOptions::WITH_SUDO_UNTIL)
+            || self.options.contains(AuthRequiredOptions::REQUIRE_SUDO)
+        {
+            // Access tokens never grant sudo mode
+            if access_token.is_some() {
+                columns.push(r#"NULL::timestamp with time zone AS "sudo_until""#);
+            } else {
+                columns.push(r#""sessions"."sudo_until""#);
+            }
         }
 
+        let table = if access_token.is_some() {
+            "access_tokens"
+        } else {
+            "sessions"
+        };
         let query = format!(
             r#"
-            SELECT {}
-            FROM "users" JOIN "sessions" ON "users"."id" = "sessions"."user_id"
-            WHERE "sessions"."id" = $1
+            SELECT {columns}
+            FROM "users" JOIN "{table}" ON "users"."id" = "{table}"."user_id"
+            WHERE "{table}"."id" = $1
             "#,
-            columns.join(", "),
+            columns = columns.join(", "),
         );
         let row = db
             .query_opt(&query, &[&session_id_hash])
             .await
             .map_err(InternalError::new)?;
         let Some(row) = row else {
+            if access_token.is_some() {
+                return Err(AuthError::InvalidAccessToken(None).into());
+            }
             return Err(AuthError::NotLoggedIn(Some(ErrorData {
                 cookies: vec![clear_cookie(&self.config.session.cookie, &self.config)],
                 ..Default::default()
             })).into());
         };
 
-        if row.get::<_, DateTime<Utc>>("expires") < utc_now() {
-            return Err(AuthError::SessionExpired(Some(ErrorData {
-                cookies: vec![clear_cookie(&self.config.session.cookie, &self.config)],
-                ..Default::default()
-            })).into());
+        if let Some(expires) = row.get::<_, Option<DateTime<Utc>>>("expires") {
+            if expires < utc_now() {
+                if access_token.is_some() {
+                    return Err(AuthError::InvalidAccessToken(None).into());
+                }
+                return Err(AuthError::SessionExpired(Some(ErrorData {
+                    cookies: vec![clear_cookie(&self.config.session.cookie, &self.config)],
+                    ..Default::default()
+                })).into());
+            }
         }
 
         if !row.get::<_, bool>("active") {
@@ -167,19 +219,69 @@ impl<E: Endpoint> Endpoint for AuthRequiredImpl<E> {
         if !self
             .options
             .contains(AuthRequiredOptions::ALLOW_PASSWORD_CHANGE_REASON)
+            && row
+                .get::<_, Option<PasswordChangeReason>>("password_change_reason")
+                .is_some()
         {
-            if let Some(_) = row.get::<_, Option<PasswordChangeReason>>("password_change_reason") {
-                return Err(AuthError::PasswordChangeRequired(None).into());
+            return Err(AuthError::PasswordChangeRequired(None).into());
+        }
+
+        if self.options.contains(AuthRequiredOptions::REQUIRE_SUDO) {
+            match row.get::<_, Option<DateTime<Utc>>>("sudo_until") {
+                Some(sudo_until) if sudo_until > utc_now() => (),
+                _ => return Err(AuthError::SudoRequired(None).into()),
             }
         }
 
-        let mut user = CurrentUser::default();
-        user.id = row.get("id");
-        user.session_id_hash = session_id_hash;
+        let access_token_scopes = if access_token.is_some() {
+            let scopes = row.get::<_, Vec<AccessTokenScope>>("scopes");
+            let required_scope = if req.method() == Method::GET || req.method() == Method::HEAD {
+                AccessTokenScope::Read
+            } else {
+                AccessTokenScope::Write
+            };
+            if !scopes.contains(&required_scope) {
+                return Err(AuthError::Forbidden(Some(ErrorData {
+                    details: Some(format!(
+                        "access token is missing the {} scope",
+                        serde_json::to_value(required_scope).map_err(InternalError::new)?,
+                    )),
+                    ..Default::default()
+                }))
+                .into());
+            }
+
+            db.execute(
+                r#"UPDATE "access_tokens" SET "last_used" = $1 WHERE "id" = $2"#,
+                &[&utc_now(), &session_id_hash],
+            )
+            .await
+            .map_err(InternalError::new)?;
+            Some(scopes)
+        } else {
+            db.execute(
+                r#"UPDATE "sessions" SET "last_seen" = $1, "ip" = $2 WHERE "id" = $3"#,
+                &[&utc_now(), &client_ip(&req), &session_id_hash],
+            )
+            .await
+            .map_err(InternalError::new)?;
+            None
+        };
+
+        let mut user = CurrentUser {
+            id: row.get("id"),
+            session_id_hash,
+            access_token_scopes,
+            ..Default::default()
+        };
 
         if self.options.contains(AuthRequiredOptions::WITH_USERNAME) {
             user.username = Some(row.get("username"));
         }
+        if self.options.contains(AuthRequiredOptions::WITH_EMAIL) {
+            user.email = Some(row.get("email"));
+            user.email_verified = Some(row.get("email_verified"));
+        }
         if self
             .options
             .contains(AuthRequiredOptions::WITH_PASSWORD_HASH)
@@ -207,7 +309,7 @@ impl<E: Endpoint> Endpoint for AuthRequiredImpl<E> {
 
         if self.options.contains(AuthRequiredOptions::WITH_PERMISSIONS) {
             let query = r#"
-                SELECT "permissions"."permission" FROM "permissions" WHERE "user_id" = $1
+                SELECT "permission" FROM "effective_permissions" WHERE "user_id" = $1
             "#;
             user.permissions = Some(
                 db.query(query, &[&user.id])
@@ -219,17 +321,101 @@ impl<E: Endpoint> Endpoint for AuthRequiredImpl<E> {
             );
         }
 
+        if let Some(audit) = req.data::<Audit>() {
+            audit.set_user(&user.id);
+        }
         req.set_data(user);
         self.endpoint.call(req).await
     }
 }
 
+pub struct AuditLog {
+    config: Config,
+}
+
+impl AuditLog {
+    pub fn new(config: Config) -> Self {
+        Self { config }
+    }
+}
+
+impl<E: Endpoint> Middleware<E> for AuditLog {
+    type Output = AuditLogImpl<E>;
+
+    fn transform(&self, endpoint: E) -> Self::Output {
+        AuditLogImpl {
+            config: self.config.clone(),
+            endpoint,
+        }
+    }
+}
+
+pub struct AuditLogImpl<E> {
+    config: Config,
+    endpoint: E,
+}
+
+#[async_trait]
+impl<E: Endpoint> Endpoint for AuditLogImpl<E> {
+    type Output = E::Output;
+
+    async fn call(&self, mut req: Request) -> Result<Self::Output> {
+        let audit = Audit::default();
+        let session_id_hash = match bearer_token(&req) {
+            Some(token) => Some(hash(token)),
+            None => req
+                .cookie()
+                .get(&self.config.session.cookie)
+                .map(|cookie| hash(cookie.value_str())),
+        };
+        if let Some(session_id_hash) = session_id_hash {
+            audit.set_session(session_id_hash);
+        }
+        let client = ClientInfo::from_request(&req);
+        let path = req.uri().path().to_owned();
+        let db = req.data::<Pool>().cloned();
+        req.set_data(audit.clone());
+
+        let result = self.endpoint.call(req).await;
+
+        // Anyone can cause errors like NotLoggedIn on any route, so only errors
+        // concerning a known user or an auth endpoint are kept
+        if let Some(err) = result
+            .as_ref()
+            .err()
+            .and_then(|err| err.downcast_ref::<AuthError>())
+        {
+            let anonymous = matches!(
+                err,
+                AuthError::NotLoggedIn(_) | AuthError::SessionExpired(_)
+            );
+            if audit.has_user() || (path.starts_with("/auth/") && !anonymous) {
+                audit.record_error(err, &path);
+            }
+        }
+        // A failed write does not undo the response, which has already had its effect
+        if let (Some(db), false) = (db, audit.is_empty()) {
+            let written = match db.get().await {
+                Ok(db) => audit.write(&db, &client).await.is_ok(),
+                Err(err) => {
+                    error!("could not get a database connection: {err}");
+                    false
+                }
+            };
+            if !written {
+                error!("audit events of a request to {path} could not be written");
+            }
+        }
+        result
+    }
+}
+
 async fn csrf_error(
     error: CsrfError,
     req: &Request,
     config: &Config,
 ) -> Result<CsrfError, InternalError> {
-    let (mut cookies, csrf_token) = match get_session(req, &config).await {
+    let (mut cookies, csrf_token) = match get_session(req, config).await {
         Ok(Session { csrf_token }) => (
             vec![make_cookie(
                 &config.csrf.cookie,
@@ -314,6 +500,11 @@ impl<E: Endpoint> Endpoint for CsrfImpl<E> {
     type Output = E::Output;
 
     async fn call(&self, req: Request) -> Result<Self::Output> {
+        // Bearer tokens are never sent automatically by browsers
+        if bearer_token(&req).is_some() {
+            return self.endpoint.call(req).await;
+        }
+
         let csrf_cookie = match req.cookie().get(&self.config.csrf.cookie) {
             Some(cookie) => cookie.value_str().to_owned(),
             None => {
@@ -361,3 +552,139 @@ impl<E: Endpoint> Endpoint for CsrfImpl<E> {
         self.endpoint.call(req).await
     }
 }
+
+// Counts requests per endpoint in fixed windows, keyed by user when the route
+// is wrapped in AuthRequired and by client IP otherwise
+pub struct RateLimit {
+    config: Config,
+    endpoint: &'static str,
+    limit: u64,
+    seconds: usize,
+}
+
+impl RateLimit {
+    pub fn new(endpoint: &'static str, config: Config) -> Self {
+        let Some(rule) = config.rate_limit.endpoints.get(endpoint) else {
+            panic!("rate_limit.endpoints.{endpoint} is missing");
+        };
+        Self {
+            endpoint,
+            limit: rule.limit,
+            seconds: rule.seconds,
+            config,
+        }
+    }
+}
+
+impl<E: Endpoint> Middleware<E> for RateLimit {
+    type Output = RateLimitImpl<E>;
+
+    fn transform(&self, endpoint: E) -> Self::Output {
+        RateLimitImpl {
+            config: self.config.clone(),
+            endpoint,
+            endpoint_name: self.endpoint,
+            limit: self.limit,
+            seconds: self.seconds,
+        }
+    }
+}
+
+pub struct RateLimitImpl<E> {
+    config: Config,
+    endpoint: E,
+    endpoint_name: &'static str,
+    limit: u64,
+    seconds: usize,
+}
+
+#[async_trait]
+impl<E: Endpoint> Endpoint for RateLimitImpl<E> {
+    type Output = E::Output;
+
+    async fn call(&self, req: Request) -> Result<Self::Output> {
+        if !self.config.rate_limit.enabled {
+            return self.endpoint.call(req).await;
+        }
+
+        let redis_key = match req.data::<CurrentUser>() {
+            Some(user) => {
+                if has_permission(user, Permission::IgnoreRateLimits, &req).await? {
+                    return self.endpoint.call(req).await;
+                }
+                redis_join(
+                    &["rate-limit", self.endpoint_name, "user", &user.id],
+                    &self.config,
+                )
+            }
+            None => match client_ip(&req) {
+                Some(ip) => redis_join(
+                    &[
+                        "rate-limit",
+                        self.endpoint_name,
+                        "ip",
+                        &base64_urlsafe(&hash(&ip.to_string())),
+                    ],
+                    &self.config,
+                ),
+                None => return self.endpoint.call(req).await,
+            },
+        };
+
+        let mut redis = req
+            .data::<RedisClient>()
+            .ok_or_else(|| InternalError::new("no redis client initialized"))?
+            .get_async_connection()
+            .await
+            .map_err(InternalError::new)?;
+        let (count, ttl): (u64, i64) = redis::pipe()
+            .atomic()
+            .cmd("SET")
+            .arg(&redis_key)
+            .arg(0)
+            .arg("EX")
+            .arg(self.seconds)
+            .arg("NX")
+            .ignore()
+            .incr(&redis_key, 1)
+            .ttl(&redis_key)
+            .query_async(&mut redis)
+            .await
+            .map_err(InternalError::new)?;
+
+        if count > self.limit {
+            return Err(GeneralError::TooManyRequests(Some(ErrorData {
+                details: Some(format!(
+                    "at most {} requests are allowed in {} seconds",
+                    self.limit, self.seconds,
+                )),
+                headers: vec![(RETRY_AFTER, HeaderValue::from(ttl.max(1)))],
+                ..Default::default()
+            }))
+            .into());
+        }
+        self.endpoint.call(req).await
+    }
+}
+
+async fn has_permission(
+    user: &CurrentUser,
+    permission: Permission,
+    req: &Request,
+) -> Result<bool, InternalError> {
+    if let Some(permissions) = &user.permissions {
+        return Ok(permissions.contains(&permission));
+    }
+    let row = get_db(req)
+        .await?
+        .query_one(
+            r#"
+            SELECT count(*) > 0 FROM "effective_permissions"
+            WHERE "user_id" = $1 AND "permission" = $2
+            "#,
+            &[&user.id, &permission],
+        )
+        .await
+        .map_err(InternalError::new)?;
+    Ok(row.get(0))
+}
@@ -0,0 +1,252 @@
+use jsonwebtoken::{
+    decode, decode_header,
+    jwk::{AlgorithmParameters, JwkSet},
+    Algorithm, DecodingKey, Validation,
+};
+use poem::{async_trait, FromRequest, Request, RequestBody};
+use redis::{AsyncCommands, Client as RedisClient};
+use reqwest::{Client as HttpClient, StatusCode, Url};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    config::{Config, OidcProviderConfig},
+    error::InternalError,
+    util::{base64_urlsafe, generate_token, hash, redis_join},
+};
+
+const CODE_VERIFIER_LENGTH: u16 = 64;
+
+#[derive(Debug, thiserror::Error)]
+pub enum OidcError {
+    #[error("could not reach the identity provider")]
+    Http(#[from] reqwest::Error),
+    #[error("invalid id token: {0}")]
+    InvalidIdToken(#[from] jsonwebtoken::errors::Error),
+    #[error("id token nonce does not match")]
+    NonceMismatch,
+    #[error("token endpoint responded with {0}")]
+    TokenEndpoint(StatusCode),
+    #[error("id token is signed with an unknown key")]
+    UnknownSigningKey,
+    #[error("id token is signed with an unsupported algorithm")]
+    UnsupportedAlgorithm,
+}
+
+// Everything needed to finish a flow, stored in Redis under the hashed state
+#[derive(Deserialize, Serialize)]
+pub struct OidcState {
+    pub provider: String,
+    pub nonce: String,
+    pub code_verifier: String,
+    pub csrf_token_hash: String,
+    pub remember: bool,
+    pub user_id: Option<String>,
+}
+
+impl OidcState {
+    // Flows are bound to the browser that started them through its CSRF cookie
+    pub fn started_by(&self, csrf_token: &str) -> bool {
+        self.csrf_token_hash == base64_urlsafe(&hash(csrf_token))
+    }
+}
+
+pub struct IdTokenClaims {
+    pub subject: String,
+}
+
+// The clients the callbacks finish a flow with
+pub struct OidcClients<'a> {
+    pub http: &'a HttpClient,
+    pub redis: &'a RedisClient,
+}
+
+#[async_trait]
+impl<'a> FromRequest<'a> for OidcClients<'a> {
+    async fn from_request(req: &'a Request, _body: &mut RequestBody) -> poem::Result<Self> {
+        Ok(Self {
+            http: req
+                .data()
+                .ok_or_else(|| InternalError::new("no HTTP client initialized"))?,
+            redis: req
+                .data()
+                .ok_or_else(|| InternalError::new("no Redis client initialized"))?,
+        })
+    }
+}
+
+pub fn redirect_uri(config: &Config) -> Result<String, InternalError> {
+    let origin = config.client.origin.to_str().map_err(InternalError::new)?;
+    Ok(format!("{}{}", origin, config.oidc.redirect_path))
+}
+
+pub fn code_challenge(code_verifier: &str) -> String {
+    base64_urlsafe(&Sha256::digest(code_verifier.as_bytes()))
+}
+
+fn state_key(state: &str, config: &Config) -> String {
+    redis_join(&["oidc-state", &base64_urlsafe(&hash(state))], config)
+}
+
+// Stores a new flow in Redis and returns the provider URL to send the client to
+pub async fn begin_flow(
+    provider: &OidcProviderConfig,
+    csrf_token: &str,
+    remember: bool,
+    user_id: Option<String>,
+    redis: &RedisClient,
+    config: &Config,
+) -> Result<String, InternalError> {
+    let state = generate_token(config.oidc.state_length);
+    let oidc_state = OidcState {
+        provider: provider.id.clone(),
+        nonce: generate_token(config.oidc.state_length),
+        code_verifier: generate_token(CODE_VERIFIER_LENGTH),
+        csrf_token_hash: base64_urlsafe(&hash(csrf_token)),
+        remember,
+        user_id,
+    };
+
+    let mut redis = redis
+        .get_async_connection()
+        .await
+        .map_err(InternalError::new)?;
+    redis
+        .set_ex::<_, _, ()>(
+            state_key(&state, config),
+            serde_json::to_string(&oidc_state).map_err(InternalError::new)?,
+            config.oidc.state_lifetime,
+        )
+        .await
+        .map_err(InternalError::new)?;
+
+    let url = Url::parse_with_params(
+        &provider.authorization_endpoint,
+        &[
+            ("response_type", "code"),
+            ("client_id", &provider.client_id),
+            ("redirect_uri", &redirect_uri(config)?),
+            ("scope", &provider.scopes.join(" ")),
+            ("state", &state),
+            ("nonce", &oidc_state.nonce),
+            ("code_challenge", &code_challenge(&oidc_state.code_verifier)),
+            ("code_challenge_method", "S256"),
+        ],
+    )
+    .map_err(InternalError::new)?;
+    Ok(url.into())
+}
+
+// A state can only be used once, whether or not the flow succeeds
+pub async fn take_state(
+    state: &str,
+    redis: &RedisClient,
+    config: &Config,
+) -> Result<Option<OidcState>, InternalError> {
+    let mut redis = redis
+        .get_async_connection()
+        .await
+        .map_err(InternalError::new)?;
+    let value: Option<String> = redis::cmd("GETDEL")
+        .arg(state_key(state, config))
+        .query_async(&mut redis)
+        .await
+        .map_err(InternalError::new)?;
+    match value {
+        Some(value) => Ok(Some(serde_json::from_str(&value).map_err(InternalError::new)?)),
+        None => Ok(None),
+    }
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    id_token: String,
+}
+
+pub async fn exchange_code(
+    provider: &OidcProviderConfig,
+    code: &str,
+    code_verifier: &str,
+    redirect_uri: &str,
+    http: &HttpClient,
+) -> Result<String, OidcError> {
+    let res = http
+        .post(&provider.token_endpoint)
+        .form(&[
+            ("grant_type", "authorization_code"),
+            ("code", code),
+            ("redirect_uri", redirect_uri),
+            ("client_id", &provider.client_id),
+            ("client_secret", &provider.client_secret),
+            ("code_verifier", code_verifier),
+        ])
+        .send()
+        .await?;
+    if !res.status().is_success() {
+        return Err(OidcError::TokenEndpoint(res.status()));
+    }
+    Ok(res.json::<TokenResponse>().await?.id_token)
+}
+
+#[derive(Deserialize)]
+struct Claims {
+    sub: String,
+    nonce: Option<String>,
+}
+
+pub async fn validate_id_token(
+    provider: &OidcProviderConfig,
+    id_token: &str,
+    nonce: &str,
+    http: &HttpClient,
+) -> Result<IdTokenClaims, OidcError> {
+    let header = decode_header(id_token)?;
+    // Shared-secret algorithms would let anyone holding the client secret mint tokens
+    if matches!(header.alg, Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512) {
+        return Err(OidcError::UnsupportedAlgorithm);
+    }
+
+    let jwks = http
+        .get(&provider.jwks_uri)
+        .send()
+        .await?
+        .error_for_status()?
+        .json::<JwkSet>()
+        .await?;
+    let jwk = match &header.kid {
+        Some(kid) => jwks.find(kid),
+        None if jwks.keys.len() == 1 => jwks.keys.first(),
+        None => None,
+    };
+    let Some(jwk) = jwk else {
+        return Err(OidcError::UnknownSigningKey);
+    };
+    if let AlgorithmParameters::OctetKey(_) = jwk.algorithm {
+        return Err(OidcError::UnsupportedAlgorithm);
+    }
+
+    let mut validation = Validation::new(header.alg);
+    validation.set_issuer(&[&provider.issuer]);
+    validation.set_audience(&[&provider.client_id]);
+    validation.set_required_spec_claims(&["exp", "iss", "aud", "sub"]);
+    let claims = decode::<Claims>(id_token, &DecodingKey::from_jwk(jwk)?, &validation)?.claims;
+
+    if claims.nonce.as_deref() != Some(nonce) {
+        return Err(OidcError::NonceMismatch);
+    }
+    Ok(IdTokenClaims {
+        subject: claims.sub,
+    })
+}
+
+pub async fn finish_flow(
+    provider: &OidcProviderConfig,
+    code: &str,
+    oidc_state: &OidcState,
+    redirect_uri: &str,
+    http: &HttpClient,
+) -> Result<IdTokenClaims, OidcError> {
+    let id_token =
+        exchange_code(provider, code, &oidc_state.code_verifier, redirect_uri, http).await?;
+    validate_id_token(provider, &id_token, &oidc_state.nonce, http).await
+}
@@ -0,0 +1,138 @@
+use std::{
+    cmp::Ordering,
+    fs::File,
+    io::{self, BufRead, BufReader, Seek, SeekFrom},
+    path::Path,
+};
+
+use sha1::{Digest, Sha1};
+
+use crate::{
+    config::Config,
+    error::{ErrorData, InternalError, PasswordError},
+};
+
+// Parts of user inputs shorter than this are not worth looking for
+const MIN_USER_INPUT_LENGTH: usize = 3;
+
+// A rough estimate in the spirit of zxcvbn: each character is worth the bits of
+// the character classes used, except for repeats and runs like "aaa" or "abc"
+pub fn estimate_entropy(password: &str) -> f64 {
+    let chars: Vec<char> = password.chars().collect();
+    let (mut lower, mut upper, mut digit, mut symbol, mut other) =
+        (false, false, false, false, false);
+    for c in &chars {
+        match c {
+            'a'..='z' => lower = true,
+            'A'..='Z' => upper = true,
+            '0'..='9' => digit = true,
+            c if c.is_ascii() => symbol = true,
+            _ => other = true,
+        }
+    }
+    let pool_size = [(lower, 26), (upper, 26), (digit, 10), (symbol, 33), (other, 100)]
+        .iter()
+        .filter(|(used, _)| *used)
+        .map(|(_, size)| size)
+        .sum::<u32>();
+    if pool_size == 0 {
+        return 0.0;
+    }
+    let char_bits = f64::from(pool_size).log2();
+
+    let mut bits = 0.0;
+    for (i, c) in chars.iter().enumerate() {
+        let predictable = i > 0 && {
+            let step = i64::from(u32::from(*c)) - i64::from(u32::from(chars[i - 1]));
+            (-1..=1).contains(&step)
+        };
+        bits += if predictable { 1.0 } else { char_bits };
+    }
+    bits
+}
+
+fn contains_user_input(password: &str, user_inputs: &[&str]) -> bool {
+    let password = password.to_lowercase();
+    user_inputs
+        .iter()
+        .map(|input| input.to_lowercase())
+        .filter(|input| input.chars().count() >= MIN_USER_INPUT_LENGTH)
+        .any(|input| password.contains(&input))
+}
+
+// Returns the first line that starts at or after `offset`, and the offset after it
+fn line_at(reader: &mut BufReader<File>, offset: u64) -> io::Result<Option<(String, u64)>> {
+    let mut line = String::new();
+    let mut position = offset;
+    if offset > 0 {
+        reader.seek(SeekFrom::Start(offset - 1))?;
+        position += reader.read_line(&mut line)? as u64 - 1;
+        line.clear();
+    } else {
+        reader.seek(SeekFrom::Start(0))?;
+    }
+    let read = reader.read_line(&mut line)?;
+    if read == 0 {
+        return Ok(None);
+    }
+    Ok(Some((line, position + read as u64)))
+}
+
+// The corpus is a list of SHA-1 hashes in hex, sorted and one per line, optionally
+// followed by ":count" like the Pwned Passwords downloads
+fn find_hash(path: &Path, hash: &str) -> io::Result<bool> {
+    let file = File::open(path)?;
+    let mut high = file.metadata()?.len();
+    let mut reader = BufReader::new(file);
+    let mut low = 0;
+    while low < high {
+        let middle = low + (high - low) / 2;
+        let Some((line, next)) = line_at(&mut reader, middle)? else {
+            high = middle;
+            continue;
+        };
+        let line_hash = line.split(':').next().unwrap_or_default().trim();
+        match line_hash.to_ascii_uppercase().as_str().cmp(hash) {
+            Ordering::Equal => return Ok(true),
+            Ordering::Less => low = next,
+            Ordering::Greater => high = middle,
+        }
+    }
+    Ok(false)
+}
+
+pub async fn is_breached(password: &str, config: &Config) -> Result<bool, InternalError> {
+    let Some(path) = config.password_policy.breached_passwords_file.clone() else {
+        return Ok(false);
+    };
+    let hash = hex::encode_upper(Sha1::digest(password.as_bytes()));
+    tokio::task::spawn_blocking(move || find_hash(&path, &hash))
+        .await
+        .map_err(InternalError::new)?
+        .map_err(InternalError::new)
+}
+
+// Checks that come on top of the length validation; user inputs such as the
+// username must not appear in the password
+pub async fn password_policy_error(
+    password: &str,
+    user_inputs: &[&str],
+    error_data: impl Fn(Option<String>) -> Option<ErrorData>,
+    config: &Config,
+) -> Result<Option<PasswordError>, InternalError> {
+    if contains_user_input(password, user_inputs) {
+        return Ok(Some(PasswordError::ContainsUserInput(error_data(None))));
+    }
+    let entropy = estimate_entropy(password);
+    if entropy < config.password_policy.min_entropy_bits {
+        return Ok(Some(PasswordError::TooWeak(error_data(Some(format!(
+            "estimated strength is {} bits, at least {} required",
+            entropy.floor(),
+            config.password_policy.min_entropy_bits,
+        ))))));
+    }
+    if is_breached(password, config).await? {
+        return Ok(Some(PasswordError::Breached(error_data(None))));
+    }
+    Ok(None)
+}
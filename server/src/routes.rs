@@ -1,4 +1,6 @@
 pub mod account;
+pub mod audit;
 pub mod auth;
+pub mod roles;
 pub mod test;
 pub mod users;
@@ -1,19 +1,939 @@
+use chrono::{DateTime, Duration, Utc};
+use deadpool_postgres::{tokio_postgres::error::SqlState, Pool};
 use poem::{
-    get, handler, post,
-    web::{websocket::WebSocket, Data},
+    delete, get, handler, post,
+    web::{cookie::CookieJar, websocket::WebSocket, Data, Json, Path},
     EndpointExt, IntoResponse, Response, Result, Route,
 };
 use redis::{AsyncCommands, Client as RedisClient};
+use serde::Deserialize;
 use serde_json::json;
 
+use macros::Validate;
+
 use crate::{
+    audit::Audit,
     config::Config,
-    error::{AuthError, InternalError},
-    middleware::{AuthRequired, Csrf, CurrentUser},
-    util::{base64_urlsafe, generate_token, json_response, redis_join},
-    websocket::{websocket_receiver, AccountConnections, AccountRooms},
+    db::AccessTokenScope,
+    error::{AccountError, AuthError, ErrorData, GeneralError, InternalError},
+    mail::{DynMailer, Email},
+    middleware::{AuthRequired, AuthRequiredOptions, Csrf, CurrentUser, RateLimit},
+    oidc::{self, OidcClients},
+    password_policy::password_policy_error,
+    util::{
+        self, activate_totp_key, base64_urlsafe, decode_base64_urlsafe, email_error, encrypt,
+        generate_token, generate_totp_key, hash, hash_encrypt_password, json_response, qr_code_svg,
+        redis_join, remove_totp, revoke_other_sessions, totp_uri, utc_now, verify_password,
+        verify_second_factor, ActivateTotpKeyError, VerifySecondFactorError,
+    },
+    validate::Validate,
+    webauthn::{self, ChallengePurpose, WebauthnError},
+    websocket::{
+        close_session_connections, websocket_receiver, AccountConnections, AccountRooms,
+    },
 };
 
+#[derive(Deserialize, Validate)]
+#[serde(deny_unknown_fields)]
+struct CreateAccessTokenData {
+    #[validate(trim, length(min = 1, max = config.access_token.name_max_length))]
+    name: String,
+    #[validate(length(min = 1))]
+    scopes: Vec<AccessTokenScope>,
+    #[validate(range(min = 1))]
+    lifetime: Option<u32>,
+}
+
+#[handler]
+async fn create_access_token(
+    audit: Data<&Audit>,
+    config: Data<&Config>,
+    db: Data<&Pool>,
+    user: Data<&CurrentUser>,
+    Json(mut data): Json<CreateAccessTokenData>,
+) -> Result<Response> {
+    data.validate(&config)?;
+    data.scopes.sort();
+    data.scopes.dedup();
+
+    let token = generate_token(config.access_token.token_length);
+    let token_hash = hash(&token);
+    let created = utc_now();
+    let expires = data
+        .lifetime
+        .map(|lifetime| created + Duration::seconds(lifetime.into()));
+
+    let db = db.get().await.map_err(InternalError::new)?;
+    db.execute(
+        r#"
+        INSERT INTO "access_tokens"("id", "user_id", "name", "scopes", "created", "expires")
+        VALUES ($1, $2, $3, $4, $5, $6)
+        "#,
+        &[
+            &token_hash,
+            &user.id,
+            &data.name,
+            &data.scopes,
+            &created,
+            &expires,
+        ],
+    )
+    .await
+    .map_err(InternalError::new)?;
+    audit.record("access-token-created", json!({
+        "id": base64_urlsafe(&token_hash),
+        "name": data.name,
+        "scopes": data.scopes,
+    }));
+
+    json_response(json!({
+        "success": true,
+        "data": {
+            "id": base64_urlsafe(&token_hash),
+            "token": token,
+            "name": data.name,
+            "scopes": data.scopes,
+            "created": created.to_rfc3339(),
+            "expires": expires.map(|expires| expires.to_rfc3339()),
+        },
+    }))
+}
+
+#[handler]
+async fn get_access_tokens(db: Data<&Pool>, user: Data<&CurrentUser>) -> Result<Response> {
+    let db = db.get().await.map_err(InternalError::new)?;
+    let query = r#"
+        SELECT "id", "name", "scopes", "created", "last_used", "expires"
+        FROM "access_tokens"
+        WHERE "user_id" = $1 AND ("expires" IS NULL OR "expires" > $2)
+        ORDER BY "created" DESC
+    "#;
+    let tokens: Vec<_> = db
+        .query(query, &[&user.id, &utc_now()])
+        .await
+        .map_err(InternalError::new)?
+        .into_iter()
+        .map(|row| {
+            json!({
+                "id": base64_urlsafe(row.get::<_, &[u8]>("id")),
+                "name": row.get::<_, &str>("name"),
+                "scopes": row.get::<_, Vec<AccessTokenScope>>("scopes"),
+                "created": row.get::<_, DateTime<Utc>>("created").to_rfc3339(),
+                "last_used": row
+                    .get::<_, Option<DateTime<Utc>>>("last_used")
+                    .map(|last_used| last_used.to_rfc3339()),
+                "expires": row
+                    .get::<_, Option<DateTime<Utc>>>("expires")
+                    .map(|expires| expires.to_rfc3339()),
+            })
+        })
+        .collect();
+
+    json_response(json!({
+        "success": true,
+        "data": tokens,
+    }))
+}
+
+#[handler]
+async fn delete_access_token(
+    audit: Data<&Audit>,
+    db: Data<&Pool>,
+    user: Data<&CurrentUser>,
+    Path(token_id): Path<String>,
+) -> Result<Response> {
+    let Ok(token_id_hash) = decode_base64_urlsafe(&token_id) else {
+        return Err(GeneralError::NotFound(None).into());
+    };
+
+    let db = db.get().await.map_err(InternalError::new)?;
+    let deleted = db
+        .execute(
+            r#"DELETE FROM "access_tokens" WHERE "id" = $1 AND "user_id" = $2"#,
+            &[&token_id_hash, &user.id],
+        )
+        .await
+        .map_err(InternalError::new)?;
+    if deleted == 0 {
+        return Err(GeneralError::NotFound(None).into());
+    }
+    audit.record("access-token-deleted", json!({ "id": token_id }));
+
+    json_response(json!({
+        "success": true,
+        "data": null,
+    }))
+}
+
+#[derive(Deserialize, Validate)]
+#[serde(deny_unknown_fields)]
+struct ChangePasswordData {
+    current_password: String,
+    #[validate(length(
+        min = config.user.password_min_length,
+        max = config.user.password_max_length,
+    ))]
+    new_password: String,
+}
+
+#[handler]
+async fn change_password(
+    audit: Data<&Audit>,
+    config: Data<&Config>,
+    connections: Data<&AccountConnections>,
+    db: Data<&Pool>,
+    rooms: Data<&AccountRooms>,
+    user: Data<&CurrentUser>,
+    Json(mut data): Json<ChangePasswordData>,
+) -> Result<Response> {
+    data.validate(&config)?;
+    if data.new_password == data.current_password {
+        return Err(AccountError::NoChangeInPassword(None).into());
+    }
+
+    let mut db = db.get().await.map_err(InternalError::new)?;
+    let transaction = db.transaction().await.map_err(InternalError::new)?;
+    let row = transaction
+        .query_one(
+            r#"
+            SELECT "username", "email", "password" FROM "users" WHERE "id" = $1 FOR UPDATE
+            "#,
+            &[&user.id],
+        )
+        .await
+        .map_err(InternalError::new)?;
+    if !verify_password(&data.current_password, row.get("password"), &config)? {
+        return Err(AccountError::InvalidCurrentPassword(None).into());
+    }
+    let user_inputs: Vec<&str> = [row.get::<_, Option<&str>>("username"), row.get("email")]
+        .into_iter()
+        .flatten()
+        .collect();
+    let policy_error = password_policy_error(
+        &data.new_password,
+        &user_inputs,
+        |details| Some(ErrorData { details, ..Default::default() }),
+        &config,
+    )
+    .await?;
+    if let Some(err) = policy_error {
+        return Err(err.into());
+    }
+
+    let password_hash = hash_encrypt_password(&data.new_password, &config)?;
+    transaction
+        .execute(
+            r#"
+            UPDATE "users" SET "password" = $1, "password_change_reason" = NULL WHERE "id" = $2
+            "#,
+            &[&password_hash, &user.id],
+        )
+        .await
+        .map_err(InternalError::new)?;
+
+    let revoked_sessions =
+        revoke_other_sessions(&transaction, &user.id, &user.session_id_hash).await?;
+    transaction.commit().await.map_err(InternalError::new)?;
+    audit.record("password-changed", json!({ "sessions_deleted": revoked_sessions.len() }));
+
+    for session_id_hash in revoked_sessions {
+        close_session_connections(&session_id_hash, &connections, &rooms).await;
+    }
+
+    json_response(json!({
+        "success": true,
+        "data": null,
+    }))
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ChangeEmailData {
+    email: Option<String>,
+}
+
+#[handler]
+async fn change_email(
+    audit: Data<&Audit>,
+    config: Data<&Config>,
+    db: Data<&Pool>,
+    mailer: Data<&DynMailer>,
+    user: Data<&CurrentUser>,
+    Json(data): Json<ChangeEmailData>,
+) -> Result<Response> {
+    if let Some(details) = data
+        .email
+        .as_ref()
+        .and_then(|email| email_error(email, &config))
+    {
+        return Err(GeneralError::InvalidData(Some(ErrorData {
+            details: Some(details),
+            ..Default::default()
+        }))
+        .into());
+    }
+    if data.email == *user.email.as_ref().unwrap()
+        && (data.email.is_none() || user.email_verified == Some(true))
+    {
+        return Err(AccountError::NoChangeInEmail(None).into());
+    }
+
+    let mut db = db.get().await.map_err(InternalError::new)?;
+    let transaction = db.transaction().await.map_err(InternalError::new)?;
+    if let Err(err) = transaction
+        .execute(
+            r#"UPDATE "users" SET "email" = $1, "email_verified" = false WHERE "id" = $2"#,
+            &[&data.email, &user.id],
+        )
+        .await
+    {
+        let constraint = err.as_db_error().and_then(|err| err.constraint());
+        if err.code() == Some(&SqlState::UNIQUE_VIOLATION) && constraint == Some("users_email_key")
+        {
+            return Err(AccountError::EmailNotAvailable(None).into());
+        }
+        return Err(InternalError::new(err).into());
+    }
+    transaction
+        .execute(
+            r#"DELETE FROM "email_verification_tokens" WHERE "user_id" = $1"#,
+            &[&user.id],
+        )
+        .await
+        .map_err(InternalError::new)?;
+
+    let Some(email) = data.email else {
+        transaction.commit().await.map_err(InternalError::new)?;
+        audit.record("email-removed", json!({}));
+        return json_response(json!({
+            "success": true,
+            "data": null,
+        }));
+    };
+
+    let token = generate_token(config.email_verification.token_length);
+    let expires = utc_now() + config.email_verification.token_lifetime;
+    transaction
+        .execute(
+            r#"
+            INSERT INTO "email_verification_tokens"("id", "user_id", "email", "expires")
+            VALUES ($1, $2, $3, $4)
+            "#,
+            &[&hash(&token), &user.id, &email, &expires],
+        )
+        .await
+        .map_err(InternalError::new)?;
+    transaction.commit().await.map_err(InternalError::new)?;
+    audit.record("email-changed", json!({}));
+
+    let origin = config.client.origin.to_str().map_err(InternalError::new)?;
+    let link = format!(
+        "{}{}?token={}",
+        origin, config.email_verification.link_path, token,
+    );
+    mailer
+        .send(Email {
+            to: email,
+            subject: "Verify your email address".to_owned(),
+            body: format!(
+                "Open the following link to verify your email address for the account {}:\n\
+                {}\n\n\
+                The link expires in {} hours. If you did not add this address, \
+                you can ignore this message.\n",
+                user.username.as_ref().unwrap(),
+                link,
+                config.email_verification.token_lifetime.num_hours(),
+            ),
+        })
+        .await?;
+
+    json_response(json!({
+        "success": true,
+        "data": null,
+    }))
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct VerifyEmailData {
+    token: String,
+}
+
+#[handler]
+async fn verify_email(
+    audit: Data<&Audit>,
+    db: Data<&Pool>,
+    Json(data): Json<VerifyEmailData>,
+) -> Result<Response> {
+    let mut db = db.get().await.map_err(InternalError::new)?;
+    let transaction = db.transaction().await.map_err(InternalError::new)?;
+    let token = transaction
+        .query_opt(
+            r#"
+            DELETE FROM "email_verification_tokens" WHERE "id" = $1
+            RETURNING "user_id", "email", "expires"
+            "#,
+            &[&hash(&data.token)],
+        )
+        .await
+        .map_err(InternalError::new)?;
+    let Some(token) = token else {
+        return Err(AccountError::InvalidEmailVerificationToken(None).into());
+    };
+    if token.get::<_, DateTime<Utc>>("expires") < utc_now() {
+        transaction.commit().await.map_err(InternalError::new)?;
+        return Err(AccountError::InvalidEmailVerificationToken(None).into());
+    }
+
+    // The token is only valid for the address it was sent to
+    let verified = transaction
+        .execute(
+            r#"
+            UPDATE "users" SET "email_verified" = true
+            WHERE "id" = $1 AND "email" = $2 AND "active"
+            "#,
+            &[
+                &token.get::<_, &str>("user_id"),
+                &token.get::<_, &str>("email"),
+            ],
+        )
+        .await
+        .map_err(InternalError::new)?;
+    transaction.commit().await.map_err(InternalError::new)?;
+    if verified == 0 {
+        return Err(AccountError::InvalidEmailVerificationToken(None).into());
+    }
+    audit.set_user(token.get("user_id"));
+    audit.record("email-verified", json!({}));
+
+    json_response(json!({
+        "success": true,
+        "data": null,
+    }))
+}
+
+#[handler]
+async fn get_oidc_identities(db: Data<&Pool>, user: Data<&CurrentUser>) -> Result<Response> {
+    let db = db.get().await.map_err(InternalError::new)?;
+    let identities: Vec<_> = db
+        .query(
+            r#"
+            SELECT "provider", "created" FROM "oidc_identities"
+            WHERE "user_id" = $1 ORDER BY "created"
+            "#,
+            &[&user.id],
+        )
+        .await
+        .map_err(InternalError::new)?
+        .into_iter()
+        .map(|row| {
+            json!({
+                "provider": row.get::<_, &str>("provider"),
+                "created": row.get::<_, DateTime<Utc>>("created").to_rfc3339(),
+            })
+        })
+        .collect();
+
+    json_response(json!({
+        "success": true,
+        "data": identities,
+    }))
+}
+
+#[handler]
+async fn link_oidc_identity(
+    config: Data<&Config>,
+    cookies: &CookieJar,
+    db: Data<&Pool>,
+    redis: Data<&RedisClient>,
+    user: Data<&CurrentUser>,
+    Path(provider_id): Path<String>,
+) -> Result<Response> {
+    let Some(provider) = config.oidc.provider(&provider_id) else {
+        return Err(GeneralError::NotFound(None).into());
+    };
+
+    let db = db.get().await.map_err(InternalError::new)?;
+    let linked = db
+        .query_opt(
+            r#"SELECT 1 FROM "oidc_identities" WHERE "user_id" = $1 AND "provider" = $2"#,
+            &[&user.id, &provider.id],
+        )
+        .await
+        .map_err(InternalError::new)?;
+    if linked.is_some() {
+        return Err(AccountError::OidcIdentityAlreadyLinked(None).into());
+    }
+
+    let csrf_cookie = cookies
+        .get(&config.csrf.cookie)
+        .ok_or_else(|| InternalError::new("no CSRF cookie in link_oidc_identity"))?;
+    let url = oidc::begin_flow(
+        provider,
+        csrf_cookie.value_str(),
+        false,
+        Some(user.id.clone()),
+        &redis,
+        &config,
+    )
+    .await?;
+
+    json_response(json!({
+        "success": true,
+        "data": { "url": url },
+    }))
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct OidcCallbackData {
+    state: String,
+    code: String,
+}
+
+#[handler]
+async fn oidc_link_callback(
+    audit: Data<&Audit>,
+    config: Data<&Config>,
+    cookies: &CookieJar,
+    db: Data<&Pool>,
+    clients: OidcClients<'_>,
+    user: Data<&CurrentUser>,
+    Json(data): Json<OidcCallbackData>,
+) -> Result<Response> {
+    let csrf_cookie = cookies
+        .get(&config.csrf.cookie)
+        .ok_or_else(|| InternalError::new("no CSRF cookie in oidc_link_callback"))?;
+    let oidc_state = oidc::take_state(&data.state, clients.redis, &config)
+        .await?
+        .filter(|oidc_state| {
+            oidc_state.user_id.as_ref() == Some(&user.id)
+                && oidc_state.started_by(csrf_cookie.value_str())
+        });
+    let Some(oidc_state) = oidc_state else {
+        return Err(AuthError::InvalidOidcState(None).into());
+    };
+    let Some(provider) = config.oidc.provider(&oidc_state.provider) else {
+        return Err(AuthError::InvalidOidcState(None).into());
+    };
+
+    let redirect_uri = oidc::redirect_uri(&config)?;
+    let claims = match oidc::finish_flow(provider, &data.code, &oidc_state, &redirect_uri, clients.http)
+        .await
+    {
+        Ok(claims) => claims,
+        Err(err) => {
+            tracing::warn!("OIDC link with {} failed: {:?}", provider.id, err);
+            return Err(AuthError::OidcAuthenticationFailed(Some(ErrorData {
+                details: Some(err.to_string()),
+                ..Default::default()
+            }))
+            .into());
+        }
+    };
+
+    let db = db.get().await.map_err(InternalError::new)?;
+    let insert_query = r#"
+        INSERT INTO "oidc_identities"("provider", "subject", "user_id", "created")
+        VALUES ($1, $2, $3, $4)
+    "#;
+    if let Err(err) = db
+        .execute(
+            insert_query,
+            &[&provider.id, &claims.subject, &user.id, &utc_now()],
+        )
+        .await
+    {
+        if err.code() == Some(&SqlState::UNIQUE_VIOLATION) {
+            return Err(AccountError::OidcIdentityAlreadyLinked(None).into());
+        }
+        return Err(InternalError::new(err).into());
+    }
+    audit.record("oidc-identity-linked", json!({ "provider": provider.id }));
+
+    json_response(json!({
+        "success": true,
+        "data": null,
+    }))
+}
+
+#[handler]
+async fn unlink_oidc_identity(
+    audit: Data<&Audit>,
+    db: Data<&Pool>,
+    user: Data<&CurrentUser>,
+    Path(provider_id): Path<String>,
+) -> Result<Response> {
+    let db = db.get().await.map_err(InternalError::new)?;
+    let deleted = db
+        .execute(
+            r#"DELETE FROM "oidc_identities" WHERE "user_id" = $1 AND "provider" = $2"#,
+            &[&user.id, &provider_id],
+        )
+        .await
+        .map_err(InternalError::new)?;
+    if deleted == 0 {
+        return Err(GeneralError::NotFound(None).into());
+    }
+    audit.record("oidc-identity-unlinked", json!({ "provider": provider_id }));
+
+    json_response(json!({
+        "success": true,
+        "data": null,
+    }))
+}
+
+#[handler]
+async fn totp_key(
+    config: Data<&Config>,
+    db: Data<&Pool>,
+    user: Data<&CurrentUser>,
+) -> Result<Response> {
+    if user.totp_enabled == Some(true) {
+        return Err(AccountError::TotpAlreadyEnabled(None).into());
+    }
+
+    let key = generate_totp_key(&config);
+    let encrypted_key = encrypt(&key, &config, &mut rand::thread_rng())?;
+    let expires = utc_now() + config.totp.new_key_lifetime;
+
+    let db = db.get().await.map_err(InternalError::new)?;
+    db.execute(
+        r#"
+        INSERT INTO "new_totp_keys"("user_id", "key", "expires") VALUES ($1, $2, $3)
+        ON CONFLICT ("user_id")
+            DO UPDATE SET "key" = "excluded"."key", "expires" = "excluded"."expires"
+        "#,
+        &[&user.id, &encrypted_key, &expires],
+    )
+    .await
+    .map_err(InternalError::new)?;
+
+    let username = user
+        .username
+        .as_ref()
+        .ok_or_else(|| InternalError::new("username not loaded in totp_key"))?;
+    let uri = totp_uri(&key, username, &config);
+    let qr_code = qr_code_svg(&uri)?;
+
+    json_response(json!({
+        "success": true,
+        "data": {
+            "expires": expires.to_rfc3339(),
+            "key": data_encoding::BASE32_NOPAD.encode(&key),
+            "uri": uri,
+            "qr_code": qr_code,
+        },
+    }))
+}
+
+#[derive(Deserialize, Validate)]
+#[serde(deny_unknown_fields)]
+struct EnableTotpData {
+    #[validate(
+        trim,
+        regex = "^[0-9]+$",
+        length(min = config.totp.digits, max = config.totp.digits),
+    )]
+    totp: String,
+}
+
+#[handler]
+async fn enable_totp(
+    audit: Data<&Audit>,
+    config: Data<&Config>,
+    db: Data<&Pool>,
+    user: Data<&CurrentUser>,
+    Json(mut data): Json<EnableTotpData>,
+) -> Result<Response> {
+    if user.totp_enabled == Some(true) {
+        return Err(AccountError::TotpAlreadyEnabled(None).into());
+    }
+    data.validate(&config)?;
+
+    let mut db = db.get().await.map_err(InternalError::new)?;
+    let transaction = db.transaction().await.map_err(InternalError::new)?;
+    let recovery_codes = match activate_totp_key(&transaction, &user.id, &data.totp, &config).await
+    {
+        Ok(recovery_codes) => recovery_codes,
+        Err(ActivateTotpKeyError::NoTotpKeyActive) => {
+            // Deletes an expired key
+            transaction.commit().await.map_err(InternalError::new)?;
+            return Err(AccountError::NoTotpKeyActive(None).into());
+        }
+        Err(ActivateTotpKeyError::InvalidTotp) => {
+            return Err(AccountError::InvalidTotpVerification(None).into())
+        }
+        Err(ActivateTotpKeyError::TotpAlreadyEnabled) => {
+            return Err(AccountError::TotpAlreadyEnabled(None).into())
+        }
+        Err(ActivateTotpKeyError::InternalError(err)) => return Err(err.into()),
+    };
+    transaction.commit().await.map_err(InternalError::new)?;
+    audit.record("totp-enabled", json!({}));
+
+    json_response(json!({
+        "success": true,
+        "data": {
+            "recovery_codes": recovery_codes,
+        },
+    }))
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct DisableTotpData {
+    password: String,
+    totp: String,
+}
+
+#[handler]
+async fn disable_totp(
+    audit: Data<&Audit>,
+    config: Data<&Config>,
+    db: Data<&Pool>,
+    user: Data<&CurrentUser>,
+    Json(data): Json<DisableTotpData>,
+) -> Result<Response> {
+    let mut db = db.get().await.map_err(InternalError::new)?;
+    let transaction = db.transaction().await.map_err(InternalError::new)?;
+    let row = transaction
+        .query_one(
+            r#"
+            SELECT "password", "totp_key", "last_totp_time_step" FROM "users" WHERE "id" = $1
+            FOR UPDATE
+            "#,
+            &[&user.id],
+        )
+        .await
+        .map_err(InternalError::new)?;
+
+    if !verify_password(&data.password, row.get("password"), &config)? {
+        return Err(AccountError::InvalidCurrentPassword(None).into());
+    }
+    let Some(encrypted_totp_key) = row.get("totp_key") else {
+        return Err(AccountError::TotpNotEnabled(None).into());
+    };
+    let second_factor = verify_second_factor(
+        &transaction,
+        &user.id,
+        encrypted_totp_key,
+        row.get("last_totp_time_step"),
+        &data.totp,
+        &config,
+    )
+    .await;
+    match second_factor {
+        Ok(_) => {}
+        Err(VerifySecondFactorError::InvalidTotp) => return Err(AuthError::InvalidTotp(None).into()),
+        Err(VerifySecondFactorError::TotpReuse) => return Err(AuthError::TotpReuse(None).into()),
+        Err(VerifySecondFactorError::InternalError(err)) => return Err(err.into()),
+    }
+
+    remove_totp(&transaction, &user.id).await?;
+    transaction.commit().await.map_err(InternalError::new)?;
+    audit.record("totp-disabled", json!({}));
+
+    json_response(json!({
+        "success": true,
+        "data": null,
+    }))
+}
+
+#[handler]
+async fn webauthn_register_options(
+    config: Data<&Config>,
+    db: Data<&Pool>,
+    redis: Data<&RedisClient>,
+    user: Data<&CurrentUser>,
+) -> Result<Response> {
+    let username = user
+        .username
+        .as_ref()
+        .ok_or_else(|| InternalError::new("username not set in webauthn_register_options"))?;
+
+    let db = db.get().await.map_err(InternalError::new)?;
+    let credential_ids: Vec<_> = db
+        .query(
+            r#"SELECT "id" FROM "webauthn_credentials" WHERE "user_id" = $1"#,
+            &[&user.id],
+        )
+        .await
+        .map_err(InternalError::new)?
+        .into_iter()
+        .map(|row| json!({ "type": "public-key", "id": base64_urlsafe(row.get("id")) }))
+        .collect();
+
+    let challenge =
+        webauthn::begin_challenge(ChallengePurpose::Register, Some(&user.id), &redis, &config)
+            .await?;
+
+    json_response(json!({
+        "success": true,
+        "data": {
+            "challenge": base64_urlsafe(challenge.as_bytes()),
+            "rp": { "id": config.webauthn.rp_id, "name": config.webauthn.rp_name },
+            "user": {
+                "id": base64_urlsafe(user.id.as_bytes()),
+                "name": username,
+                "displayName": username,
+            },
+            "pubKeyCredParams": [{ "type": "public-key", "alg": webauthn::ES256 }],
+            "timeout": config.webauthn.challenge_lifetime * 1000,
+            "attestation": "none",
+            "excludeCredentials": credential_ids,
+            "authenticatorSelection": {
+                "residentKey": "preferred",
+                "userVerification": "preferred",
+            },
+        },
+    }))
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct WebauthnRegisterData {
+    client_data_json: String,
+    attestation_object: String,
+}
+
+#[handler]
+async fn webauthn_register(
+    audit: Data<&Audit>,
+    config: Data<&Config>,
+    db: Data<&Pool>,
+    redis: Data<&RedisClient>,
+    user: Data<&CurrentUser>,
+    Json(data): Json<WebauthnRegisterData>,
+) -> Result<Response> {
+    let invalid = |err: WebauthnError| {
+        AccountError::InvalidWebauthnRegistration(Some(ErrorData {
+            details: Some(err.to_string()),
+            ..Default::default()
+        }))
+    };
+    let decode = |value: &str| {
+        decode_base64_urlsafe(value).map_err(|_| invalid(WebauthnError::InvalidEncoding))
+    };
+    let client_data_json = decode(&data.client_data_json)?;
+    let attestation_object = decode(&data.attestation_object)?;
+
+    let challenge = webauthn::client_data_challenge(&client_data_json).map_err(invalid)?;
+    let challenge_user_id =
+        webauthn::take_challenge(ChallengePurpose::Register, &challenge, &redis, &config).await?;
+    if challenge_user_id != Some(Some(user.id.clone())) {
+        return Err(invalid(WebauthnError::UnknownChallenge).into());
+    }
+    let credential =
+        webauthn::verify_registration(&client_data_json, &attestation_object, &challenge, &config)
+            .map_err(invalid)?;
+
+    let created = utc_now();
+    let db = db.get().await.map_err(InternalError::new)?;
+    let insert_query = r#"
+        INSERT INTO "webauthn_credentials"("id", "user_id", "public_key", "sign_count", "created")
+        VALUES ($1, $2, $3, $4, $5)
+    "#;
+    if let Err(err) = db
+        .execute(
+            insert_query,
+            &[
+                &credential.id,
+                &user.id,
+                &credential.public_key,
+                &i64::from(credential.sign_count),
+                &created,
+            ],
+        )
+        .await
+    {
+        if err.code() == Some(&SqlState::UNIQUE_VIOLATION) {
+            return Err(AccountError::InvalidWebauthnRegistration(Some(ErrorData {
+                details: Some("credential is already registered".to_owned()),
+                ..Default::default()
+            }))
+            .into());
+        }
+        return Err(InternalError::new(err).into());
+    }
+    audit.record(
+        "webauthn-credential-registered",
+        json!({ "id": base64_urlsafe(&credential.id) }),
+    );
+
+    json_response(json!({
+        "success": true,
+        "data": {
+            "id": base64_urlsafe(&credential.id),
+            "created": created.to_rfc3339(),
+        },
+    }))
+}
+
+#[handler]
+async fn get_webauthn_credentials(db: Data<&Pool>, user: Data<&CurrentUser>) -> Result<Response> {
+    let db = db.get().await.map_err(InternalError::new)?;
+    let credentials: Vec<_> = db
+        .query(
+            r#"
+            SELECT "id", "created", "last_used" FROM "webauthn_credentials"
+            WHERE "user_id" = $1 ORDER BY "created"
+            "#,
+            &[&user.id],
+        )
+        .await
+        .map_err(InternalError::new)?
+        .into_iter()
+        .map(|row| {
+            json!({
+                "id": base64_urlsafe(row.get::<_, &[u8]>("id")),
+                "created": row.get::<_, DateTime<Utc>>("created").to_rfc3339(),
+                "last_used": row
+                    .get::<_, Option<DateTime<Utc>>>("last_used")
+                    .map(|last_used| last_used.to_rfc3339()),
+            })
+        })
+        .collect();
+
+    json_response(json!({
+        "success": true,
+        "data": credentials,
+    }))
+}
+
+#[handler]
+async fn delete_webauthn_credential(
+    audit: Data<&Audit>,
+    db: Data<&Pool>,
+    user: Data<&CurrentUser>,
+    Path(credential_id): Path<String>,
+) -> Result<Response> {
+    let Ok(credential_id) = decode_base64_urlsafe(&credential_id) else {
+        return Err(GeneralError::NotFound(None).into());
+    };
+
+    let db = db.get().await.map_err(InternalError::new)?;
+    let deleted = db
+        .execute(
+            r#"DELETE FROM "webauthn_credentials" WHERE "id" = $1 AND "user_id" = $2"#,
+            &[&credential_id, &user.id],
+        )
+        .await
+        .map_err(InternalError::new)?;
+    if deleted == 0 {
+        return Err(GeneralError::NotFound(None).into());
+    }
+    audit.record(
+        "webauthn-credential-deleted",
+        json!({ "id": base64_urlsafe(&credential_id) }),
+    );
+
+    json_response(json!({
+        "success": true,
+        "data": null,
+    }))
+}
+
 #[handler]
 fn websocket(
     config: Data<&Config>,
@@ -56,7 +976,7 @@ async fn websocket_token(
     let redis_key = redis_join(&["websocket-token", "account", &token], &config);
     let redis_value = format!("{}:{}", user.id, base64_urlsafe(&user.session_id_hash));
     redis
-        .set_ex(redis_key, redis_value, config.websocket.token_lifetime)
+        .set_ex::<_, _, ()>(redis_key, redis_value, config.websocket.token_lifetime)
         .await
         .map_err(InternalError::new)?;
     json_response(json!({
@@ -98,13 +1018,170 @@ async fn websocket_clients(
 
 pub fn routes(config: &Config) -> Route {
     Route::new()
+        .at(
+            "/access-tokens",
+            util::get!(get_access_tokens.with(AuthRequired::defaults(config.clone()))).post(
+                create_access_token
+                    .with(RateLimit::new("create-access-token", config.clone()))
+                    .with(AuthRequired::new(
+                        AuthRequiredOptions::REQUIRE_SUDO,
+                        config.clone(),
+                    ))
+                    .with(Csrf::new(config.clone())),
+            ),
+        )
+        .at(
+            "/access-tokens/:id",
+            delete(
+                delete_access_token
+                    .with(AuthRequired::defaults(config.clone()))
+                    .with(Csrf::new(config.clone())),
+            ),
+        )
+        .at(
+            "/email",
+            post(
+                change_email
+                    .with(RateLimit::new("change-email", config.clone()))
+                    .with(AuthRequired::new(
+                        AuthRequiredOptions::WITH_USERNAME
+                            | AuthRequiredOptions::WITH_EMAIL
+                            | AuthRequiredOptions::REQUIRE_SUDO,
+                        config.clone(),
+                    ))
+                    .with(Csrf::new(config.clone())),
+            ),
+        )
+        .at(
+            "/email/verify",
+            post(
+                verify_email
+                    .with(RateLimit::new("verify-email", config.clone()))
+                    .with(Csrf::new(config.clone())),
+            ),
+        )
+        .at(
+            "/oidc",
+            util::get!(get_oidc_identities.with(AuthRequired::defaults(config.clone()))),
+        )
+        .at(
+            "/oidc/:provider",
+            delete(
+                unlink_oidc_identity
+                    .with(AuthRequired::new(
+                        AuthRequiredOptions::REQUIRE_SUDO,
+                        config.clone(),
+                    ))
+                    .with(Csrf::new(config.clone())),
+            ),
+        )
+        .at(
+            "/oidc/callback",
+            post(
+                oidc_link_callback
+                    .with(RateLimit::new("oidc-link-callback", config.clone()))
+                    .with(AuthRequired::defaults(config.clone()))
+                    .with(Csrf::new(config.clone())),
+            ),
+        )
+        .at(
+            "/oidc/link/:provider",
+            post(
+                link_oidc_identity
+                    .with(RateLimit::new("oidc-link", config.clone()))
+                    .with(AuthRequired::new(
+                        AuthRequiredOptions::REQUIRE_SUDO,
+                        config.clone(),
+                    ))
+                    .with(Csrf::new(config.clone())),
+            ),
+        )
+        .at(
+            "/password",
+            post(
+                change_password
+                    .with(RateLimit::new("change-password", config.clone()))
+                    .with(AuthRequired::new(
+                        AuthRequiredOptions::ALLOW_PASSWORD_CHANGE_REASON
+                            | AuthRequiredOptions::SESSION_ONLY,
+                        config.clone(),
+                    ))
+                    .with(Csrf::new(config.clone())),
+            ),
+        )
+        .at(
+            "/totp",
+            post(
+                enable_totp
+                    .with(AuthRequired::new(
+                        AuthRequiredOptions::WITH_TOTP_STATUS | AuthRequiredOptions::REQUIRE_SUDO,
+                        config.clone(),
+                    ))
+                    .with(Csrf::new(config.clone())),
+            )
+            .delete(
+                disable_totp
+                    .with(AuthRequired::defaults(config.clone()))
+                    .with(Csrf::new(config.clone())),
+            ),
+        )
+        .at(
+            "/totp-key",
+            post(
+                totp_key
+                    .with(RateLimit::new("totp-key", config.clone()))
+                    .with(AuthRequired::new(
+                        AuthRequiredOptions::WITH_USERNAME
+                            | AuthRequiredOptions::WITH_TOTP_STATUS
+                            | AuthRequiredOptions::REQUIRE_SUDO,
+                        config.clone(),
+                    ))
+                    .with(Csrf::new(config.clone())),
+            ),
+        )
+        .at(
+            "/webauthn",
+            util::get!(get_webauthn_credentials.with(AuthRequired::defaults(config.clone()))).post(
+                webauthn_register
+                    .with(RateLimit::new("webauthn-register", config.clone()))
+                    .with(AuthRequired::defaults(config.clone()))
+                    .with(Csrf::new(config.clone())),
+            ),
+        )
+        .at(
+            "/webauthn/options",
+            post(
+                webauthn_register_options
+                    .with(RateLimit::new("webauthn-register-options", config.clone()))
+                    .with(AuthRequired::new(
+                        AuthRequiredOptions::WITH_USERNAME | AuthRequiredOptions::REQUIRE_SUDO,
+                        config.clone(),
+                    ))
+                    .with(Csrf::new(config.clone())),
+            ),
+        )
+        .at(
+            "/webauthn/:id",
+            delete(
+                delete_webauthn_credential
+                    .with(AuthRequired::new(
+                        AuthRequiredOptions::REQUIRE_SUDO,
+                        config.clone(),
+                    ))
+                    .with(Csrf::new(config.clone())),
+            ),
+        )
         .at("/socket", get(websocket))
         .at("/socket/clients", get(websocket_clients))
         .at(
             "/socket/token",
             post(
                 websocket_token
-                    .with(AuthRequired::defaults(config.clone()))
+                    .with(RateLimit::new("websocket-token", config.clone()))
+                    .with(AuthRequired::new(
+                        AuthRequiredOptions::SESSION_ONLY,
+                        config.clone(),
+                    ))
                     .with(Csrf::new(config.clone())),
             ),
         )
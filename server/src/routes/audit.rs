@@ -0,0 +1,159 @@
+use std::net::IpAddr;
+
+use chrono::{DateTime, Utc};
+use deadpool_postgres::Pool;
+use poem::{
+    handler,
+    web::{Data, Query},
+    EndpointExt, Response, Result, Route,
+};
+use postgres_types::ToSql;
+use serde::Deserialize;
+use serde_json::{json, Value as JsonValue};
+
+use macros::Validate;
+
+use crate::{
+    config::Config,
+    db::Permission,
+    error::{AuthError, ErrorData, GeneralError, InternalError},
+    middleware::{AuthRequired, AuthRequiredOptions, CurrentUser},
+    util::{base64_urlsafe, get, json_response},
+    validate::Validate,
+};
+
+#[derive(Deserialize, Validate)]
+#[serde(deny_unknown_fields)]
+struct AuditEventsQuery {
+    event: Option<String>,
+    user_id: Option<String>,
+    after: Option<String>,
+    before: Option<String>,
+    // ID of the last event on the previous page
+    cursor: Option<i64>,
+    #[validate(range(min = 1, max = config.audit.max_page_size))]
+    limit: Option<i64>,
+}
+
+fn invalid_query(details: String) -> poem::Error {
+    GeneralError::InvalidData(Some(ErrorData {
+        details: Some(details),
+        ..Default::default()
+    }))
+    .into()
+}
+
+fn parse_time(name: &str, value: &Option<String>) -> Result<Option<DateTime<Utc>>> {
+    match value {
+        Some(value) => match DateTime::parse_from_rfc3339(value) {
+            Ok(time) => Ok(Some(time.with_timezone(&Utc))),
+            Err(err) => Err(invalid_query(format!("{name}: {err}"))),
+        },
+        None => Ok(None),
+    }
+}
+
+#[handler]
+async fn get_audit_events(
+    config: Data<&Config>,
+    db: Data<&Pool>,
+    current_user: Data<&CurrentUser>,
+    Query(mut query): Query<AuditEventsQuery>,
+) -> Result<Response> {
+    if !current_user
+        .permissions
+        .as_ref()
+        .unwrap()
+        .contains(&Permission::ViewAuditLog)
+    {
+        return Err(AuthError::Forbidden(None).into());
+    }
+
+    query.validate(&config)?;
+    let limit = query.limit.unwrap_or(config.audit.default_page_size);
+    let after = parse_time("after", &query.after)?;
+    let before = parse_time("before", &query.before)?;
+
+    let mut conditions = Vec::new();
+    let mut params: Vec<&(dyn ToSql + Sync)> = Vec::new();
+    if let Some(event) = &query.event {
+        params.push(event);
+        conditions.push(format!(r#""event" = ${}"#, params.len()));
+    }
+    if let Some(user_id) = &query.user_id {
+        params.push(user_id);
+        conditions.push(format!(r#""user_id" = ${}"#, params.len()));
+    }
+    if let Some(after) = &after {
+        params.push(after);
+        conditions.push(format!(r#""created" >= ${}"#, params.len()));
+    }
+    if let Some(before) = &before {
+        params.push(before);
+        conditions.push(format!(r#""created" < ${}"#, params.len()));
+    }
+    if let Some(cursor) = &query.cursor {
+        params.push(cursor);
+        conditions.push(format!(r#""id" < ${}"#, params.len()));
+    }
+    // One extra row tells whether there is another page
+    let fetch_limit = limit + 1;
+    params.push(&fetch_limit);
+
+    let where_clause = if conditions.is_empty() {
+        String::new()
+    } else {
+        format!("WHERE {}", conditions.join(" AND "))
+    };
+    let sql = format!(
+        r#"
+        SELECT "id", "created", "event", "user_id", "session_id", "ip", "user_agent", "details"
+        FROM "audit_events" {where_clause}
+        ORDER BY "id" DESC LIMIT ${}
+        "#,
+        params.len(),
+    );
+
+    let db = db.get().await.map_err(InternalError::new)?;
+    let mut rows = db.query(&sql, &params).await.map_err(InternalError::new)?;
+    let next_cursor = if rows.len() as i64 > limit {
+        rows.truncate(limit as usize);
+        rows.last().map(|row| row.get::<_, i64>("id"))
+    } else {
+        None
+    };
+
+    let events: Vec<_> = rows
+        .into_iter()
+        .map(|row| {
+            json!({
+                "id": row.get::<_, i64>("id"),
+                "created": row.get::<_, DateTime<Utc>>("created").to_rfc3339(),
+                "event": row.get::<_, &str>("event"),
+                "user_id": row.get::<_, Option<&str>>("user_id"),
+                "session_id": row.get::<_, Option<&[u8]>>("session_id").map(base64_urlsafe),
+                "ip": row.get::<_, Option<IpAddr>>("ip").map(|ip| ip.to_string()),
+                "user_agent": row.get::<_, Option<&str>>("user_agent"),
+                "details": row.get::<_, JsonValue>("details"),
+            })
+        })
+        .collect();
+
+    json_response(json!({
+        "success": true,
+        "data": {
+            "events": events,
+            "next_cursor": next_cursor,
+        },
+    }))
+}
+
+pub fn routes(config: &Config) -> Route {
+    Route::new().at(
+        "/",
+        get!(get_audit_events).with(AuthRequired::new(
+            AuthRequiredOptions::WITH_PERMISSIONS,
+            config.clone(),
+        )),
+    )
+}
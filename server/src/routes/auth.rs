@@ -1,23 +1,44 @@
-use deadpool_postgres::Pool;
+use std::net::IpAddr;
+
+use chrono::{DateTime, Duration, Utc};
+use deadpool_postgres::{
+    tokio_postgres::{error::SqlState, Row},
+    Client, Pool, Transaction,
+};
 use poem::{
-    handler, post,
-    web::{cookie::CookieJar, Data, Json},
+    delete, handler, post,
+    web::{cookie::CookieJar, Data, Json, Path},
     EndpointExt, Request, Response, Result, Route,
 };
+use redis::Client as RedisClient;
 use secstr::SecStr;
 use serde::Deserialize;
 use serde_json::json;
 
+use macros::Validate;
+
 use crate::{
+    audit::Audit,
     config::Config,
     db::{Language, PasswordChangeReason},
-    error::{AuthError, AuthWarning, ErrorData, InternalError},
-    middleware::{AuthRequired, AuthRequiredOptions, Csrf, CurrentUser},
+    devices::{check_device, notify_new_device},
+    error::{AccountError, AuthError, AuthWarning, ErrorData, GeneralError, InternalError},
+    magic_link,
+    mail::{DynMailer, Email},
+    middleware::{AuthRequired, AuthRequiredOptions, Csrf, CurrentUser, RateLimit},
+    oidc::{self, OidcClients},
+    password_policy::password_policy_error,
     util::{
-        build_json_response, clear_cookie, decrypt, generate_token, get, get_session, hash,
-        optional, remove_cookie, set_cookie, utc_now, verify_password, verify_totp, Session,
-        SessionError, VerifyTotpError,
+        base64_urlsafe, build_json_response, cancel_account_deletion, clear_cookie,
+        decode_base64_urlsafe, generate_token, get, get_session, hash, hash_encrypt_password,
+        insert_session, json_response, optional, password_needs_rehash,
+        remove_cookie, set_cookie, set_session_cookies, utc_now, verify_password,
+        verify_second_factor, ClientInfo, SecondFactor, Session, SessionError,
+        VerifySecondFactorError,
     },
+    validate::Validate,
+    webauthn::{self, AssertionData, AuthenticateError, ChallengePurpose},
+    websocket::{close_session_connections, AccountConnections, AccountRooms},
 };
 
 #[handler]
@@ -59,13 +80,17 @@ struct LoginData {
     password: String,
     #[serde(default, deserialize_with = "optional")]
     totp: Option<String>,
+    #[serde(default, deserialize_with = "optional")]
+    webauthn: Option<AssertionData>,
     remember: bool,
 }
 
 #[handler]
 async fn login(
+    audit: Data<&Audit>,
     config: Data<&Config>,
     db: Data<&Pool>,
+    redis: Data<&RedisClient>,
     req: &Request,
     Json(data): Json<LoginData>,
 ) -> Result<Response> {
@@ -80,19 +105,38 @@ async fn login(
         };
         Err(match error {
             AuthError::AccountDisabled(_) => AuthError::AccountDisabled(data),
+            AuthError::AccountLocked(locked) => {
+                AuthError::AccountLocked(data.map(|data| ErrorData {
+                    details: locked.and_then(|locked| locked.details),
+                    ..data
+                }))
+            }
             AuthError::AlreadyLoggedIn(_) => AuthError::AlreadyLoggedIn(data),
             AuthError::Forbidden(_) => AuthError::Forbidden(data),
+            AuthError::InvalidAccessToken(_) => AuthError::InvalidAccessToken(data),
             AuthError::InvalidCredentials(_) => AuthError::InvalidCredentials(data),
+            AuthError::InvalidMagicLinkToken(_) => AuthError::InvalidMagicLinkToken(data),
+            AuthError::InvalidOidcState(_) => AuthError::InvalidOidcState(data),
             AuthError::InvalidRememberToken(_) => AuthError::InvalidRememberToken(data),
             AuthError::InvalidTotp(_) => AuthError::InvalidTotp(data),
+            AuthError::InvalidWebauthnAssertion(assertion) => {
+                AuthError::InvalidWebauthnAssertion(data.map(|data| ErrorData {
+                    details: assertion.and_then(|assertion| assertion.details),
+                    ..data
+                }))
+            }
             AuthError::MissingRememberToken(_) => AuthError::MissingRememberToken(data),
             AuthError::MissingTotp(_) => AuthError::MissingTotp(data),
             AuthError::NotLoggedIn(_) => AuthError::NotLoggedIn(data),
+            AuthError::OidcAuthenticationFailed(_) => AuthError::OidcAuthenticationFailed(data),
+            AuthError::OidcIdentityNotLinked(_) => AuthError::OidcIdentityNotLinked(data),
             AuthError::PasswordChangeRequired(_) => AuthError::PasswordChangeRequired(data),
             AuthError::RememberTokenSecretMismatch(_) => {
                 AuthError::RememberTokenSecretMismatch(data)
             }
             AuthError::SessionExpired(_) => AuthError::SessionExpired(data),
+            AuthError::SessionRequired(_) => AuthError::SessionRequired(data),
+            AuthError::SudoRequired(_) => AuthError::SudoRequired(data),
             AuthError::TotpReuse(_) => AuthError::TotpReuse(data),
         }
         .into())
@@ -112,10 +156,12 @@ async fn login(
         SELECT
             "id",
             "active",
+            "deletion_cancellable",
             "username",
             "password",
             "totp_key",
             "last_totp_time_step",
+            "locked_until",
             "password_change_reason",
             "icon",
             "language"
@@ -129,315 +175,1082 @@ async fn login(
     let Some(user) = user else {
         return error(AuthError::InvalidCredentials(None), clear_session_cookie);
     };
+    audit.set_user(user.get("id"));
 
-    if !verify_password(&data.password, user.get("password"), &config)? {
-        tracing::warn!("invalid password, returning error");
-        return error(AuthError::InvalidCredentials(None), clear_session_cookie);
+    let locked = |locked_until: DateTime<Utc>| {
+        error(
+            AuthError::AccountLocked(Some(ErrorData {
+                details: Some(locked_until.to_rfc3339()),
+                ..Default::default()
+            })),
+            clear_session_cookie,
+        )
+    };
+    if let Some(locked_until) = lockout_end(&user) {
+        return locked(locked_until);
     }
 
     let user_id = user.get::<_, &str>("id");
     let transaction = db.transaction().await.map_err(InternalError::new)?;
 
-    let totp_enabled = if let Some(encrypted_totp_key) = user.get("totp_key") {
+    if !verify_password(&data.password, user.get("password"), &config)? {
+        tracing::warn!("invalid password, returning error");
+        if let Some(locked_until) = record_failed_login(transaction, user_id, &config).await? {
+            return locked(locked_until);
+        }
+        return error(AuthError::InvalidCredentials(None), clear_session_cookie);
+    }
+
+    if let (Some(_), Some(assertion)) =
+        (user.get::<_, Option<&[u8]>>("totp_key"), &data.webauthn)
+    {
+        // A security key can stand in for the TOTP
+        let result =
+            webauthn::authenticate(assertion, Some(user_id), false, &transaction, &redis, &config)
+                .await;
+        match result {
+            Ok(_) => {}
+            Err(AuthenticateError::Invalid(err)) => {
+                if let Some(locked_until) =
+                    record_failed_login(transaction, user_id, &config).await?
+                {
+                    return locked(locked_until);
+                }
+                let invalid = AuthError::InvalidWebauthnAssertion(Some(ErrorData {
+                    details: Some(err.to_string()),
+                    ..Default::default()
+                }));
+                return error(invalid, clear_session_cookie);
+            }
+            Err(AuthenticateError::InternalError(err)) => return Err(err.into()),
+        }
+    } else if let Some(encrypted_totp_key) = user.get("totp_key") {
         let totp = match data.totp {
             Some(totp) => totp,
             None => return error(AuthError::MissingTotp(None), clear_session_cookie),
         };
-        let totp_key = decrypt(encrypted_totp_key, &config)?;
-        let totp_time_step = verify_totp(&totp_key, &totp, &config);
-        let totp_time_step = match totp_time_step {
-            Ok(totp_time_step) => totp_time_step,
-            Err(VerifyTotpError::InvalidTotp) => {
-                return error(AuthError::InvalidTotp(None), clear_session_cookie)
+        let second_factor = verify_second_factor(
+            &transaction,
+            user_id,
+            encrypted_totp_key,
+            user.get("last_totp_time_step"),
+            &totp,
+            &config,
+        )
+        .await;
+        match second_factor {
+            Ok(SecondFactor::Totp) => {}
+            Ok(SecondFactor::RecoveryCode { remaining }) => {
+                warnings.push(AuthWarning::RecoveryCodeUsed(Some(ErrorData {
+                    details: Some(format!("{remaining} recovery codes remaining")),
+                    ..Default::default()
+                })));
             }
-            Err(VerifyTotpError::InternalError(err)) => return Err(err.into()),
-        };
-
-        if let Some(last_totp_time_step) = user.get::<_, Option<i64>>("last_totp_time_step")
-        {
-            if totp_time_step <= last_totp_time_step {
+            Err(VerifySecondFactorError::InvalidTotp) => {
+                if let Some(locked_until) =
+                    record_failed_login(transaction, user_id, &config).await?
+                {
+                    return locked(locked_until);
+                }
+                return error(AuthError::InvalidTotp(None), clear_session_cookie);
+            }
+            Err(VerifySecondFactorError::TotpReuse) => {
+                if let Some(locked_until) =
+                    record_failed_login(transaction, user_id, &config).await?
+                {
+                    return locked(locked_until);
+                }
                 return error(AuthError::TotpReuse(None), clear_session_cookie);
             }
+            Err(VerifySecondFactorError::InternalError(err)) => return Err(err.into()),
         }
+    } else if data.totp.is_some() {
+        warnings.push(AuthWarning::UnusedTotp(None));
+    }
 
-        let updated = transaction
+    // Raising the Argon2 costs would otherwise only protect new passwords
+    if password_needs_rehash(user.get("password"), &config)? {
+        let password_hash = hash_encrypt_password(&data.password, &config)?;
+        transaction
             .execute(
-                r#"UPDATE "users" SET "last_totp_time_step" = $1 WHERE "id" = $2"#,
-                &[&totp_time_step, &user_id],
+                r#"UPDATE "users" SET "password" = $1 WHERE "id" = $2"#,
+                &[&password_hash, &user_id],
             )
             .await
             .map_err(InternalError::new)?;
-        if updated != 1 {
-            Err(InternalError::new(format!(
-                "last_totp_time_step updated for {} users in login",
-                updated
-            )))?;
-        }
-
-        true
-    } else if data.totp.is_some() {
-        warnings.push(AuthWarning::UnusedTotp(None));
-        false
-    } else {
-        false
-    };
-
-    if !user.get::<_, bool>("active") {
-        return error(AuthError::AccountDisabled(None), clear_session_cookie);
     }
 
-    let session_id = generate_token(config.session.id_length);
-    let csrf_token = generate_token(config.csrf.token_length);
-    let now = utc_now();
-    let session_expires = now + config.session.lifetime;
-    let sudo_until = now + config.session.sudo_lifetime;
+    let success = SuccessfulLogin {
+        user: &user,
+        method: "password",
+        remember: data.remember,
+        warnings,
+        clear_session_cookie,
+    };
+    complete_login(success, transaction, &audit, req, &config).await
+}
 
-    let insert_session_query = r#"
-        INSERT INTO "sessions"("id", "user_id", "csrf_token", "expires", "sudo_until")
-        VALUES ($1, $2, $3, $4, $5)
-    "#;
-    let inserted = transaction
-        .execute(
-            insert_session_query,
-            &[
-                &hash(&session_id),
-                &user_id,
-                &csrf_token,
-                &session_expires,
-                &sudo_until,
-            ],
+// Counts a failed password or TOTP check and locks the account once the
+// limit is reached; each further failure doubles the lockout
+async fn record_failed_login(
+    transaction: Transaction<'_>,
+    user_id: &str,
+    config: &Config,
+) -> Result<Option<DateTime<Utc>>, InternalError> {
+    let failed_attempts = transaction
+        .query_one(
+            r#"
+            UPDATE "users" SET "failed_login_attempts" = "failed_login_attempts" + 1
+            WHERE "id" = $1 RETURNING "failed_login_attempts"
+            "#,
+            &[&user_id],
         )
         .await
-        .map_err(InternalError::new)?;
-    if inserted != 1 {
-        Err(InternalError::new(format!(
-            "{} sessions inserted in login",
-            inserted
-        )))?;
-    }
-
-    let remember_token = if data.remember {
-        let remember_token_id = generate_token(config.remember_token.id_length);
-        let remember_token_secret = generate_token(config.remember_token.secret_length);
-        let remember_token_secret_hash = hash(&remember_token_secret);
+        .map_err(InternalError::new)?
+        .get::<_, i32>("failed_login_attempts");
 
-        let insert_remember_token_query = r#"
-                INSERT INTO "remember_tokens"("id", "user_id", "secret") VALUES ($1, $2, $3)
-            "#;
-        let inserted = transaction
+    let protection = &config.login_protection;
+    let locked_until = if failed_attempts >= protection.max_failed_attempts {
+        let exponent = (failed_attempts - protection.max_failed_attempts).min(32) as u32;
+        let lockout_seconds = protection
+            .lockout_duration
+            .num_seconds()
+            .saturating_mul(1 << exponent)
+            .min(protection.max_lockout_duration.num_seconds());
+        let locked_until = utc_now() + Duration::seconds(lockout_seconds);
+        transaction
             .execute(
-                insert_remember_token_query,
-                &[
-                    &hash(&remember_token_id),
-                    &user_id,
-                    &remember_token_secret_hash,
-                ],
+                r#"UPDATE "users" SET "locked_until" = $1 WHERE "id" = $2"#,
+                &[&locked_until, &user_id],
             )
             .await
             .map_err(InternalError::new)?;
-        if inserted != 1 {
-            Err(InternalError::new(format!(
-                "{} remember tokens inserted in login",
-                inserted
-            )))?;
-        }
-        Some([remember_token_id, remember_token_secret].join(&config.remember_token.separator))
+        Some(locked_until)
     } else {
         None
     };
 
     transaction.commit().await.map_err(InternalError::new)?;
+    Ok(locked_until)
+}
+
+// Errors of logins and registration clear a session cookie that has expired
+// or is invalid
+fn login_error_data(
+    details: Option<String>,
+    clear_session_cookie: bool,
+    config: &Config,
+) -> Option<ErrorData> {
+    let cookies = if clear_session_cookie {
+        vec![clear_cookie(&config.session.cookie, config)]
+    } else {
+        vec![]
+    };
+    Some(ErrorData {
+        cookies,
+        details,
+        ..Default::default()
+    })
+}
+
+// The end of the user's lockout if it hasn't passed yet
+fn lockout_end(user: &Row) -> Option<DateTime<Utc>> {
+    user.get::<_, Option<DateTime<Utc>>>("locked_until")
+        .filter(|locked_until| *locked_until > utc_now())
+}
+
+// A user that has passed the checks of a login method. The row needs the
+// "id", "active", "deletion_cancellable", "username", "totp_key",
+// "password_change_reason", "icon" and "language" columns.
+struct SuccessfulLogin<'a> {
+    user: &'a Row,
+    method: &'a str,
+    remember: bool,
+    warnings: Vec<AuthWarning>,
+    clear_session_cookie: bool,
+}
+
+// Shared by every login method once the credentials have been checked. OIDC
+// and passkey logins don't ask for a TOTP even if the user has one: the
+// identity provider and the user-verifying authenticator are trusted to
+// provide the second factor.
+async fn complete_login(
+    success: SuccessfulLogin<'_>,
+    transaction: Transaction<'_>,
+    audit: &Audit,
+    req: &Request,
+    config: &Config,
+) -> Result<Response> {
+    let SuccessfulLogin {
+        user,
+        method,
+        remember,
+        mut warnings,
+        clear_session_cookie,
+    } = success;
+    let user_id = user.get::<_, &str>("id");
+
+    let deletion_cancellable = user.get::<_, bool>("deletion_cancellable");
+    if !user.get::<_, bool>("active") && !deletion_cancellable {
+        let data = login_error_data(None, clear_session_cookie, config);
+        return Err(AuthError::AccountDisabled(data).into());
+    }
+    if deletion_cancellable {
+        cancel_account_deletion(&transaction, user_id).await?;
+        warnings.push(AuthWarning::AccountDeletionCancelled(None));
+    }
+
+    transaction
+        .execute(
+            r#"
+            UPDATE "users" SET "failed_login_attempts" = 0, "locked_until" = NULL
+            WHERE "id" = $1
+            "#,
+            &[&user_id],
+        )
+        .await
+        .map_err(InternalError::new)?;
+
+    let client = ClientInfo::from_request(req);
+    let session = insert_session(&transaction, user_id, remember, &client, config).await?;
+    let revocation_token = check_device(&transaction, user_id, &client, config).await?;
+    transaction.commit().await.map_err(InternalError::new)?;
+    audit.record_login(user_id, &session.id, method);
+    audit.record_warnings(&warnings);
+    if let Some(token) = revocation_token {
+        audit.record("new-device", json!({}));
+        // The session exists already, so a failed notification is only logged
+        let _ = notify_new_device(user_id, &token, &client, req, config).await;
+    }
 
     let mut response_json = json!({
         "success": true,
-        &config.csrf.response_field: csrf_token,
+        &config.csrf.response_field: session.csrf_token,
         "data": {
-            "id": user.get::<_, &str>("id"),
+            "id": user_id,
             "username": user.get::<_, &str>("username"),
-            "totp_enabled": totp_enabled,
+            "totp_enabled": user.get::<_, Option<&[u8]>>("totp_key").is_some(),
             "password_change_reason": user.get::<_, Option<PasswordChangeReason>>(
                 "password_change_reason"
             ),
             "icon": user.get::<_, Option<&str>>("icon"),
             "language": user.get::<_, Language>("language"),
         },
-        "sudo_until": sudo_until.to_rfc3339(),
+        "sudo_until": session.sudo_until.to_rfc3339(),
     });
-    if warnings.len() > 0 {
+    if !warnings.is_empty() {
         if let Some(map) = response_json.as_object_mut() {
             map.insert("warnings".to_owned(), json!(warnings));
         }
     }
 
     build_json_response(response_json, |res| {
-        let res = set_cookie(res, &config.session.cookie, &session_id, None, &config);
-        let res = set_cookie(
-            res,
-            &config.csrf.cookie,
-            &csrf_token,
-            Some(config.csrf.cookie_lifetime),
-            &config,
-        );
-        if let Some(remember_token) = remember_token {
-            set_cookie(
-                res,
-                &config.remember_token.cookie,
-                &remember_token,
-                Some(config.remember_token.cookie_lifetime),
-                &config,
-            )
-        } else {
-            res
-        }
+        set_session_cookies(res, &session, config)
     })
 }
 
+#[derive(Deserialize, Validate)]
+#[serde(deny_unknown_fields)]
+struct RegisterData {
+    #[validate(length(
+        min = config.user.username_min_length,
+        max = config.user.username_max_length,
+    ))]
+    username: String,
+    #[validate(length(
+        min = config.user.password_min_length,
+        max = config.user.password_max_length,
+    ))]
+    password: String,
+    language: Language,
+    remember: bool,
+}
+
 #[handler]
-async fn logout(
+async fn register(
+    audit: Data<&Audit>,
     config: Data<&Config>,
-    cookies: &CookieJar,
     db: Data<&Pool>,
-    user: Data<&CurrentUser>,
+    req: &Request,
+    Json(mut data): Json<RegisterData>,
 ) -> Result<Response> {
+    let clear_session_cookie = match get_session(req, &config).await {
+        Ok(_) => return Err(AuthError::AlreadyLoggedIn(None).into()),
+        Err(SessionError::ExpiredSession) | Err(SessionError::InvalidSession) => true,
+        Err(SessionError::NoCookie) => false,
+        Err(SessionError::InternalError(err)) => return Err(err.into()),
+    };
+    let error_data =
+        |details: Option<String>| login_error_data(details, clear_session_cookie, &config);
+
+    data.validate(&config)
+        .map_err(|errors| errors.with_data(error_data(None)))?;
+    let policy_error =
+        password_policy_error(&data.password, &[&data.username], &error_data, &config).await?;
+    if let Some(err) = policy_error {
+        return Err(err.into());
+    }
+
+    let user_id = generate_token(config.user.id_length);
+    let password_hash = hash_encrypt_password(&data.password, &config)?;
+
     let mut db = db.get().await.map_err(InternalError::new)?;
     let transaction = db.transaction().await.map_err(InternalError::new)?;
 
-    let deleted = transaction
+    let insert_user_query = r#"
+        INSERT INTO "users"("id", "username", "password", "language") VALUES ($1, $2, $3, $4)
+    "#;
+    if let Err(err) = transaction
         .execute(
-            r#"DELETE FROM "sessions" WHERE "id" = $1"#,
-            &[&user.session_id_hash],
+            insert_user_query,
+            &[&user_id, &data.username, &password_hash, &data.language],
         )
         .await
-        .map_err(InternalError::new)?;
-    if deleted != 1 {
-        Err(InternalError::new(format!(
-            "{} sessions deleted in logout",
-            deleted
-        )))?;
-    }
-
-    let maybe_remember_token = cookies.get(&config.remember_token.cookie);
-    if let Some(ref remember_token) = maybe_remember_token {
-        if let Some((remember_token_id, _)) = remember_token
-            .value_str()
-            .split_once(&config.remember_token.separator)
+    {
+        let constraint = err.as_db_error().and_then(|err| err.constraint());
+        if err.code() == Some(&SqlState::UNIQUE_VIOLATION)
+            && constraint == Some("users_username_key")
         {
-            let deleted = transaction
-                .execute(
-                    r#"DELETE FROM "remember_tokens" WHERE "id" = $1"#,
-                    &[&hash(remember_token_id)],
-                )
-                .await
-                .map_err(InternalError::new)?;
-            if deleted > 1 {
-                Err(InternalError::new(format!(
-                    "{} remember tokens deleted in logout",
-                    deleted
-                )))?;
-            }
+            return Err(AccountError::UsernameNotAvailable(error_data(None)).into());
         }
+        return Err(InternalError::new(err).into());
     }
 
-    transaction.commit().await.map_err(InternalError::new)?;
+    let client = ClientInfo::from_request(req);
+    let session = insert_session(&transaction, &user_id, data.remember, &client, &config).await?;
+    // Later logins from the same device are not reported as new
+    check_device(&transaction, &user_id, &client, &config).await?;
 
-    let csrf_token = generate_token(config.csrf.token_length);
+    transaction.commit().await.map_err(InternalError::new)?;
+    audit.set_user(&user_id);
+    audit.set_session(hash(&session.id));
+    audit.record("register", json!({}));
 
     build_json_response(json!({
         "success": true,
-        "data": null,
-        &config.csrf.response_field: csrf_token,
+        &config.csrf.response_field: session.csrf_token,
+        "data": {
+            "id": user_id,
+            "username": data.username,
+            "totp_enabled": false,
+            "password_change_reason": null,
+            "icon": null,
+            "language": data.language,
+        },
+        "sudo_until": session.sudo_until.to_rfc3339(),
     }), |res| {
-        let res = set_cookie(
-            res,
-            &config.csrf.cookie,
-            &csrf_token,
-            Some(config.csrf.cookie_lifetime),
-            &config,
-        );
-        let res = remove_cookie(res, &config.session.cookie, &config);
-        if maybe_remember_token.is_some() {
-            remove_cookie(res, &config.remember_token.cookie, &config)
-        } else {
-            res
-        }
+        set_session_cookies(res, &session, &config)
     })
 }
 
 #[handler]
-async fn logout_all_sessions(
+fn get_oidc_providers(config: Data<&Config>) -> Result<Response> {
+    let providers: Vec<_> = config
+        .oidc
+        .providers
+        .iter()
+        .map(|provider| json!({ "id": provider.id, "name": provider.name }))
+        .collect();
+    json_response(json!({
+        "success": true,
+        "data": providers,
+    }))
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct OidcLoginData {
+    remember: bool,
+}
+
+#[handler]
+async fn oidc_login(
     config: Data<&Config>,
     cookies: &CookieJar,
-    db: Data<&Pool>,
-    user: Data<&CurrentUser>,
+    redis: Data<&RedisClient>,
+    Path(provider_id): Path<String>,
+    Json(data): Json<OidcLoginData>,
 ) -> Result<Response> {
-    let mut db = db.get().await.map_err(InternalError::new)?;
-    let transaction = db.transaction().await.map_err(InternalError::new)?;
-
-    let deleted = transaction
-        .execute(
-            r#"DELETE FROM "sessions" WHERE "user_id" = $1"#,
-            &[&user.id],
-        )
-        .await
-        .map_err(InternalError::new)?;
-    if deleted == 0 {
-        Err(InternalError::new(format!(
-            "{} sessions deleted in logout_all_sessions",
-            deleted
-        )))?;
+    if cookies.get(&config.session.cookie).is_some() {
+        return Err(AuthError::AlreadyLoggedIn(None).into());
     }
+    let Some(provider) = config.oidc.provider(&provider_id) else {
+        return Err(GeneralError::NotFound(None).into());
+    };
+    let csrf_cookie = cookies
+        .get(&config.csrf.cookie)
+        .ok_or_else(|| InternalError::new("no CSRF cookie in oidc_login"))?;
 
-    transaction
-        .execute(
-            r#"DELETE FROM "remember_tokens" WHERE "user_id" = $1"#,
-            &[&user.id],
-        )
-        .await
-        .map_err(InternalError::new)?;
-
-    transaction.commit().await.map_err(InternalError::new)?;
-
-    let csrf_token = generate_token(config.csrf.token_length);
+    let url = oidc::begin_flow(
+        provider,
+        csrf_cookie.value_str(),
+        data.remember,
+        None,
+        &redis,
+        &config,
+    )
+    .await?;
 
-    build_json_response(json!({
+    json_response(json!({
         "success": true,
-        "data": null,
-        &config.csrf.response_field: csrf_token
-    }), |res| {
-        let mut res = set_cookie(
-            res,
-            &config.csrf.cookie,
-            &csrf_token,
-            Some(config.csrf.cookie_lifetime),
-            &config,
-        );
-        if let Some(_) = cookies.get(&config.remember_token.cookie) {
-            res = remove_cookie(res, &config.remember_token.cookie, &config);
-        }
-        remove_cookie(res, &config.session.cookie, &config)
-    })
+        "data": { "url": url },
+    }))
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct OidcCallbackData {
+    state: String,
+    code: String,
 }
 
 #[handler]
-async fn restore_session(
+async fn oidc_callback(
+    audit: Data<&Audit>,
     config: Data<&Config>,
     cookies: &CookieJar,
     db: Data<&Pool>,
+    clients: OidcClients<'_>,
+    req: &Request,
+    Json(data): Json<OidcCallbackData>,
 ) -> Result<Response> {
-    let error = |error: AuthError, delete_remember_cookie: bool, delete_session_cookie: bool| {
-        let mut cookies = vec![];
-        if delete_remember_cookie {
-            cookies.push(clear_cookie(&config.remember_token.cookie, &config));
-        }
-        if delete_session_cookie {
-            cookies.push(clear_cookie(&config.session.cookie, &config));
-        }
-        Err(match error {
-            AuthError::AccountDisabled(_) => AuthError::AccountDisabled(Some(ErrorData {
-                cookies,
-                ..Default::default()
-            })),
-            AuthError::AlreadyLoggedIn(_) => AuthError::AlreadyLoggedIn(Some(ErrorData {
+    let clear_session_cookie = match get_session(req, &config).await {
+        Ok(_) => return Err(AuthError::AlreadyLoggedIn(None).into()),
+        Err(SessionError::ExpiredSession) | Err(SessionError::InvalidSession) => true,
+        Err(SessionError::NoCookie) => false,
+        Err(SessionError::InternalError(err)) => return Err(err.into()),
+    };
+    let error_data =
+        |details: Option<String>| login_error_data(details, clear_session_cookie, &config);
+
+    let csrf_cookie = cookies
+        .get(&config.csrf.cookie)
+        .ok_or_else(|| InternalError::new("no CSRF cookie in oidc_callback"))?;
+    let oidc_state = oidc::take_state(&data.state, clients.redis, &config)
+        .await?
+        .filter(|oidc_state| {
+            oidc_state.user_id.is_none() && oidc_state.started_by(csrf_cookie.value_str())
+        });
+    let Some(oidc_state) = oidc_state else {
+        return Err(AuthError::InvalidOidcState(error_data(None)).into());
+    };
+    let Some(provider) = config.oidc.provider(&oidc_state.provider) else {
+        return Err(AuthError::InvalidOidcState(error_data(None)).into());
+    };
+
+    let redirect_uri = oidc::redirect_uri(&config)?;
+    let claims = match oidc::finish_flow(provider, &data.code, &oidc_state, &redirect_uri, clients.http)
+        .await
+    {
+        Ok(claims) => claims,
+        Err(err) => {
+            tracing::warn!("OIDC login with {} failed: {:?}", provider.id, err);
+            let details = Some(err.to_string());
+            return Err(AuthError::OidcAuthenticationFailed(error_data(details)).into());
+        }
+    };
+
+    let mut db = db.get().await.map_err(InternalError::new)?;
+    let select_query = r#"
+        SELECT
+            "users"."id",
+            "users"."active",
+            "users"."deletion_cancellable",
+            "users"."username",
+            "users"."totp_key",
+            "users"."locked_until",
+            "users"."password_change_reason",
+            "users"."icon",
+            "users"."language"
+        FROM "oidc_identities" JOIN "users" ON "users"."id" = "oidc_identities"."user_id"
+        WHERE "oidc_identities"."provider" = $1 AND "oidc_identities"."subject" = $2
+    "#;
+    let user = db
+        .query_opt(select_query, &[&provider.id, &claims.subject])
+        .await
+        .map_err(InternalError::new)?;
+    let Some(user) = user else {
+        return Err(AuthError::OidcIdentityNotLinked(error_data(None)).into());
+    };
+    audit.set_user(user.get("id"));
+
+    if let Some(locked_until) = lockout_end(&user) {
+        let details = Some(locked_until.to_rfc3339());
+        return Err(AuthError::AccountLocked(error_data(details)).into());
+    }
+
+    let transaction = db.transaction().await.map_err(InternalError::new)?;
+    let success = SuccessfulLogin {
+        user: &user,
+        method: &format!("oidc:{}", provider.id),
+        remember: oidc_state.remember,
+        warnings: vec![],
+        clear_session_cookie,
+    };
+    complete_login(success, transaction, &audit, req, &config).await
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct WebauthnOptionsData {
+    #[serde(default, deserialize_with = "optional")]
+    username: Option<String>,
+}
+
+// Without a username the options suit discoverable credentials (passkeys);
+// with one they list that user's credentials for use as a second factor
+#[handler]
+async fn webauthn_options(
+    config: Data<&Config>,
+    db: Data<&Pool>,
+    redis: Data<&RedisClient>,
+    Json(data): Json<WebauthnOptionsData>,
+) -> Result<Response> {
+    let db = db.get().await.map_err(InternalError::new)?;
+    let (user_id, credential_ids) = match data.username {
+        Some(username) => {
+            let credentials = db
+                .query(
+                    r#"
+                    SELECT "users"."id" AS "user_id", "webauthn_credentials"."id"
+                    FROM "users" JOIN "webauthn_credentials"
+                        ON "webauthn_credentials"."user_id" = "users"."id"
+                    WHERE lower("users"."username") = lower($1)
+                    "#,
+                    &[&username],
+                )
+                .await
+                .map_err(InternalError::new)?;
+            let user_id = credentials.first().map(|row| row.get::<_, String>("user_id"));
+            let credential_ids: Vec<_> = credentials
+                .iter()
+                .map(|row| base64_urlsafe(row.get::<_, &[u8]>("id")))
+                .collect();
+            (user_id, credential_ids)
+        }
+        None => (None, vec![]),
+    };
+
+    let challenge = webauthn::begin_challenge(
+        ChallengePurpose::Authenticate,
+        user_id.as_deref(),
+        &redis,
+        &config,
+    )
+    .await?;
+
+    json_response(json!({
+        "success": true,
+        "data": {
+            "challenge": base64_urlsafe(challenge.as_bytes()),
+            "rpId": config.webauthn.rp_id,
+            "timeout": config.webauthn.challenge_lifetime * 1000,
+            "userVerification": "preferred",
+            "allowCredentials": credential_ids
+                .into_iter()
+                .map(|id| json!({ "type": "public-key", "id": id }))
+                .collect::<Vec<_>>(),
+        },
+    }))
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct WebauthnLoginData {
+    assertion: AssertionData,
+    remember: bool,
+}
+
+#[handler]
+async fn webauthn_login(
+    audit: Data<&Audit>,
+    config: Data<&Config>,
+    db: Data<&Pool>,
+    redis: Data<&RedisClient>,
+    req: &Request,
+    Json(data): Json<WebauthnLoginData>,
+) -> Result<Response> {
+    let clear_session_cookie = match get_session(req, &config).await {
+        Ok(_) => return Err(AuthError::AlreadyLoggedIn(None).into()),
+        Err(SessionError::ExpiredSession) | Err(SessionError::InvalidSession) => true,
+        Err(SessionError::NoCookie) => false,
+        Err(SessionError::InternalError(err)) => return Err(err.into()),
+    };
+    let error_data =
+        |details: Option<String>| login_error_data(details, clear_session_cookie, &config);
+
+    let mut db = db.get().await.map_err(InternalError::new)?;
+    let transaction = db.transaction().await.map_err(InternalError::new)?;
+
+    // Passwordless logins need the authenticator to have verified the user
+    let user_id =
+        match webauthn::authenticate(&data.assertion, None, true, &transaction, &redis, &config)
+            .await
+        {
+            Ok(user_id) => user_id,
+            Err(AuthenticateError::Invalid(err)) => {
+                let details = Some(err.to_string());
+                return Err(AuthError::InvalidWebauthnAssertion(error_data(details)).into());
+            }
+            Err(AuthenticateError::InternalError(err)) => return Err(err.into()),
+        };
+    audit.set_user(&user_id);
+
+    let select_query = r#"
+        SELECT
+            "id",
+            "active",
+            "deletion_cancellable",
+            "username",
+            "totp_key",
+            "locked_until",
+            "password_change_reason",
+            "icon",
+            "language"
+        FROM "users" WHERE "id" = $1
+    "#;
+    let user = transaction
+        .query_one(select_query, &[&user_id])
+        .await
+        .map_err(InternalError::new)?;
+
+    if let Some(locked_until) = lockout_end(&user) {
+        let details = Some(locked_until.to_rfc3339());
+        return Err(AuthError::AccountLocked(error_data(details)).into());
+    }
+
+    let success = SuccessfulLogin {
+        user: &user,
+        method: "webauthn",
+        remember: data.remember,
+        warnings: vec![],
+        clear_session_cookie,
+    };
+    complete_login(success, transaction, &audit, req, &config).await
+}
+
+// The active user with a verified email address that a username or email
+// address refers to. One user's username can be another one's email address;
+// the email wins.
+async fn find_user_to_email(
+    db: &Client,
+    username_or_email: &str,
+) -> Result<Option<Row>, InternalError> {
+    db.query_opt(
+        r#"
+        SELECT "id", "username", "email" FROM "users"
+        WHERE "active" AND "email_verified"
+            AND (lower("username") = lower($1) OR lower("email") = lower($1))
+        ORDER BY lower("email") = lower($1) DESC LIMIT 1
+        "#,
+        &[&username_or_email],
+    )
+    .await
+    .map_err(InternalError::new)
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct MagicLinkData {
+    username_or_email: String,
+    remember: bool,
+}
+
+#[handler]
+async fn request_magic_link(
+    audit: Data<&Audit>,
+    config: Data<&Config>,
+    db: Data<&Pool>,
+    mailer: Data<&DynMailer>,
+    redis: Data<&RedisClient>,
+    Json(data): Json<MagicLinkData>,
+) -> Result<Response> {
+    let db = db.get().await.map_err(InternalError::new)?;
+    let user = find_user_to_email(&db, &data.username_or_email).await?;
+
+    // Respond identically whether or not the user exists
+    if let Some(user) = user {
+        let link =
+            magic_link::issue_token(user.get("id"), data.remember, &redis, &config).await?;
+        mailer
+            .send(Email {
+                to: user.get("email"),
+                subject: "Log in".to_owned(),
+                body: format!(
+                    "A login link was requested for the account {}.\n\n\
+                    Open the following link to log in:\n{}\n\n\
+                    The link can be used once and expires in {} minutes. If you did not \
+                    request it, you can ignore this message.\n",
+                    user.get::<_, &str>("username"),
+                    link,
+                    config.magic_link.token_lifetime / 60,
+                ),
+            })
+            .await?;
+        audit.set_user(user.get("id"));
+        audit.record("magic-link-requested", json!({}));
+    }
+
+    json_response(json!({
+        "success": true,
+        "data": null,
+    }))
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ConfirmMagicLinkData {
+    token: String,
+    #[serde(default, deserialize_with = "optional")]
+    totp: Option<String>,
+}
+
+#[handler]
+async fn confirm_magic_link(
+    audit: Data<&Audit>,
+    config: Data<&Config>,
+    db: Data<&Pool>,
+    redis: Data<&RedisClient>,
+    req: &Request,
+    Json(data): Json<ConfirmMagicLinkData>,
+) -> Result<Response> {
+    let clear_session_cookie = match get_session(req, &config).await {
+        Ok(_) => return Err(AuthError::AlreadyLoggedIn(None).into()),
+        Err(SessionError::ExpiredSession) | Err(SessionError::InvalidSession) => true,
+        Err(SessionError::NoCookie) => false,
+        Err(SessionError::InternalError(err)) => return Err(err.into()),
+    };
+    let error_data =
+        |details: Option<String>| login_error_data(details, clear_session_cookie, &config);
+
+    let Some(magic_link) = magic_link::peek_token(&data.token, &redis, &config).await? else {
+        return Err(AuthError::InvalidMagicLinkToken(error_data(None)).into());
+    };
+
+    let mut warnings = Vec::<AuthWarning>::new();
+
+    let mut db = db.get().await.map_err(InternalError::new)?;
+    let select_query = r#"
+        SELECT
+            "id",
+            "active",
+            "deletion_cancellable",
+            "username",
+            "totp_key",
+            "last_totp_time_step",
+            "locked_until",
+            "password_change_reason",
+            "icon",
+            "language"
+        FROM "users" WHERE "id" = $1
+    "#;
+    let user = db
+        .query_opt(select_query, &[&magic_link.user_id])
+        .await
+        .map_err(InternalError::new)?;
+    let Some(user) = user else {
+        return Err(AuthError::InvalidMagicLinkToken(error_data(None)).into());
+    };
+    audit.set_user(&magic_link.user_id);
+
+    if let Some(locked_until) = lockout_end(&user) {
+        let details = Some(locked_until.to_rfc3339());
+        return Err(AuthError::AccountLocked(error_data(details)).into());
+    }
+
+    // The link stays usable until a TOTP has been supplied
+    let encrypted_totp_key = user.get::<_, Option<&[u8]>>("totp_key");
+    if encrypted_totp_key.is_some() && data.totp.is_none() {
+        return Err(AuthError::MissingTotp(error_data(None)).into());
+    }
+    if !magic_link::take_token(&data.token, &redis, &config).await? {
+        return Err(AuthError::InvalidMagicLinkToken(error_data(None)).into());
+    }
+
+    let user_id = magic_link.user_id.as_str();
+    let transaction = db.transaction().await.map_err(InternalError::new)?;
+
+    if let (Some(encrypted_totp_key), Some(totp)) = (encrypted_totp_key, &data.totp) {
+        let second_factor = verify_second_factor(
+            &transaction,
+            user_id,
+            encrypted_totp_key,
+            user.get("last_totp_time_step"),
+            totp,
+            &config,
+        )
+        .await;
+        let error = match second_factor {
+            Ok(SecondFactor::Totp) => None,
+            Ok(SecondFactor::RecoveryCode { remaining }) => {
+                warnings.push(AuthWarning::RecoveryCodeUsed(Some(ErrorData {
+                    details: Some(format!("{remaining} recovery codes remaining")),
+                    ..Default::default()
+                })));
+                None
+            }
+            Err(VerifySecondFactorError::InvalidTotp) => {
+                Some(AuthError::InvalidTotp(error_data(None)))
+            }
+            Err(VerifySecondFactorError::TotpReuse) => {
+                Some(AuthError::TotpReuse(error_data(None)))
+            }
+            Err(VerifySecondFactorError::InternalError(err)) => return Err(err.into()),
+        };
+        if let Some(error) = error {
+            if let Some(locked_until) = record_failed_login(transaction, user_id, &config).await? {
+                let details = Some(locked_until.to_rfc3339());
+                return Err(AuthError::AccountLocked(error_data(details)).into());
+            }
+            return Err(error.into());
+        }
+    } else if data.totp.is_some() {
+        warnings.push(AuthWarning::UnusedTotp(None));
+    }
+
+    let success = SuccessfulLogin {
+        user: &user,
+        method: "magic-link",
+        remember: magic_link.remember,
+        warnings,
+        clear_session_cookie,
+    };
+    complete_login(success, transaction, &audit, req, &config).await
+}
+
+#[handler]
+async fn logout(
+    audit: Data<&Audit>,
+    config: Data<&Config>,
+    cookies: &CookieJar,
+    db: Data<&Pool>,
+    user: Data<&CurrentUser>,
+) -> Result<Response> {
+    let mut db = db.get().await.map_err(InternalError::new)?;
+    let transaction = db.transaction().await.map_err(InternalError::new)?;
+
+    let deleted = transaction
+        .execute(
+            r#"DELETE FROM "sessions" WHERE "id" = $1"#,
+            &[&user.session_id_hash],
+        )
+        .await
+        .map_err(InternalError::new)?;
+    if deleted != 1 {
+        Err(InternalError::new(format!(
+            "{} sessions deleted in logout",
+            deleted
+        )))?;
+    }
+
+    let maybe_remember_token = cookies.get(&config.remember_token.cookie);
+    if let Some(ref remember_token) = maybe_remember_token {
+        if let Some((remember_token_id, _)) = remember_token
+            .value_str()
+            .split_once(&config.remember_token.separator)
+        {
+            let deleted = transaction
+                .execute(
+                    r#"DELETE FROM "remember_tokens" WHERE "id" = $1"#,
+                    &[&hash(remember_token_id)],
+                )
+                .await
+                .map_err(InternalError::new)?;
+            if deleted > 1 {
+                Err(InternalError::new(format!(
+                    "{} remember tokens deleted in logout",
+                    deleted
+                )))?;
+            }
+        }
+    }
+
+    transaction.commit().await.map_err(InternalError::new)?;
+    audit.record("logout", json!({}));
+
+    let csrf_token = generate_token(config.csrf.token_length);
+
+    build_json_response(json!({
+        "success": true,
+        "data": null,
+        &config.csrf.response_field: csrf_token,
+    }), |res| {
+        let res = set_cookie(
+            res,
+            &config.csrf.cookie,
+            &csrf_token,
+            Some(config.csrf.cookie_lifetime),
+            &config,
+        );
+        let res = remove_cookie(res, &config.session.cookie, &config);
+        if maybe_remember_token.is_some() {
+            remove_cookie(res, &config.remember_token.cookie, &config)
+        } else {
+            res
+        }
+    })
+}
+
+#[handler]
+async fn logout_all_sessions(
+    audit: Data<&Audit>,
+    config: Data<&Config>,
+    cookies: &CookieJar,
+    db: Data<&Pool>,
+    user: Data<&CurrentUser>,
+) -> Result<Response> {
+    let mut db = db.get().await.map_err(InternalError::new)?;
+    let transaction = db.transaction().await.map_err(InternalError::new)?;
+
+    let deleted = transaction
+        .execute(
+            r#"DELETE FROM "sessions" WHERE "user_id" = $1"#,
+            &[&user.id],
+        )
+        .await
+        .map_err(InternalError::new)?;
+    if deleted == 0 {
+        Err(InternalError::new(format!(
+            "{} sessions deleted in logout_all_sessions",
+            deleted
+        )))?;
+    }
+
+    transaction
+        .execute(
+            r#"DELETE FROM "remember_tokens" WHERE "user_id" = $1"#,
+            &[&user.id],
+        )
+        .await
+        .map_err(InternalError::new)?;
+
+    transaction.commit().await.map_err(InternalError::new)?;
+    audit.record("logout-all-sessions", json!({ "sessions_deleted": deleted }));
+
+    let csrf_token = generate_token(config.csrf.token_length);
+
+    build_json_response(json!({
+        "success": true,
+        "data": null,
+        &config.csrf.response_field: csrf_token
+    }), |res| {
+        let mut res = set_cookie(
+            res,
+            &config.csrf.cookie,
+            &csrf_token,
+            Some(config.csrf.cookie_lifetime),
+            &config,
+        );
+        if cookies.get(&config.remember_token.cookie).is_some() {
+            res = remove_cookie(res, &config.remember_token.cookie, &config);
+        }
+        remove_cookie(res, &config.session.cookie, &config)
+    })
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RevokeSessionsData {
+    token: String,
+}
+
+// Used by the link in new device notifications
+#[handler]
+async fn revoke_sessions(
+    audit: Data<&Audit>,
+    connections: Data<&AccountConnections>,
+    db: Data<&Pool>,
+    rooms: Data<&AccountRooms>,
+    Json(data): Json<RevokeSessionsData>,
+) -> Result<Response> {
+    let mut db = db.get().await.map_err(InternalError::new)?;
+    let transaction = db.transaction().await.map_err(InternalError::new)?;
+    let token = transaction
+        .query_opt(
+            r#"
+            DELETE FROM "session_revocation_tokens" WHERE "id" = $1
+            RETURNING "user_id", "expires"
+            "#,
+            &[&hash(&data.token)],
+        )
+        .await
+        .map_err(InternalError::new)?;
+    let Some(token) = token else {
+        return Err(AccountError::InvalidSessionRevocationToken(None).into());
+    };
+    if token.get::<_, DateTime<Utc>>("expires") < utc_now() {
+        transaction.commit().await.map_err(InternalError::new)?;
+        return Err(AccountError::InvalidSessionRevocationToken(None).into());
+    }
+    let user_id = token.get::<_, &str>("user_id");
+    audit.set_user(user_id);
+
+    let revoked_sessions: Vec<Vec<u8>> = transaction
+        .query(
+            r#"DELETE FROM "sessions" WHERE "user_id" = $1 RETURNING "id""#,
+            &[&user_id],
+        )
+        .await
+        .map_err(InternalError::new)?
+        .into_iter()
+        .map(|row| row.get("id"))
+        .collect();
+    transaction
+        .execute(
+            r#"DELETE FROM "remember_tokens" WHERE "user_id" = $1"#,
+            &[&user_id],
+        )
+        .await
+        .map_err(InternalError::new)?;
+    transaction
+        .execute(
+            r#"DELETE FROM "session_revocation_tokens" WHERE "user_id" = $1"#,
+            &[&user_id],
+        )
+        .await
+        .map_err(InternalError::new)?;
+    transaction.commit().await.map_err(InternalError::new)?;
+    audit.record("sessions-revoked", json!({
+        "reason": "new-device-report",
+        "sessions_deleted": revoked_sessions.len(),
+    }));
+
+    for session_id_hash in revoked_sessions {
+        close_session_connections(&session_id_hash, &connections, &rooms).await;
+    }
+
+    json_response(json!({
+        "success": true,
+        "data": null,
+    }))
+}
+
+#[handler]
+async fn restore_session(
+    audit: Data<&Audit>,
+    config: Data<&Config>,
+    cookies: &CookieJar,
+    db: Data<&Pool>,
+    req: &Request,
+) -> Result<Response> {
+    let error = |error: AuthError, delete_remember_cookie: bool, delete_session_cookie: bool| {
+        let mut cookies = vec![];
+        if delete_remember_cookie {
+            cookies.push(clear_cookie(&config.remember_token.cookie, &config));
+        }
+        if delete_session_cookie {
+            cookies.push(clear_cookie(&config.session.cookie, &config));
+        }
+        Err(match error {
+            AuthError::AccountDisabled(_) => AuthError::AccountDisabled(Some(ErrorData {
+                cookies,
+                ..Default::default()
+            })),
+            AuthError::AccountLocked(_) => AuthError::AccountLocked(Some(ErrorData {
+                cookies,
+                ..Default::default()
+            })),
+            AuthError::AlreadyLoggedIn(_) => AuthError::AlreadyLoggedIn(Some(ErrorData {
                 cookies,
                 ..Default::default()
             })),
@@ -445,10 +1258,24 @@ async fn restore_session(
                 cookies,
                 ..Default::default()
             })),
+            AuthError::InvalidAccessToken(_) => AuthError::InvalidAccessToken(Some(ErrorData {
+                cookies,
+                ..Default::default()
+            })),
             AuthError::InvalidCredentials(_) => AuthError::InvalidCredentials(Some(ErrorData {
                 cookies,
                 ..Default::default()
             })),
+            AuthError::InvalidMagicLinkToken(_) => {
+                AuthError::InvalidMagicLinkToken(Some(ErrorData {
+                    cookies,
+                    ..Default::default()
+                }))
+            }
+            AuthError::InvalidOidcState(_) => AuthError::InvalidOidcState(Some(ErrorData {
+                cookies,
+                ..Default::default()
+            })),
             AuthError::InvalidRememberToken(_) => {
                 AuthError::InvalidRememberToken(Some(ErrorData {
                     cookies,
@@ -459,6 +1286,12 @@ async fn restore_session(
                 cookies,
                 ..Default::default()
             })),
+            AuthError::InvalidWebauthnAssertion(_) => {
+                AuthError::InvalidWebauthnAssertion(Some(ErrorData {
+                    cookies,
+                    ..Default::default()
+                }))
+            }
             AuthError::MissingRememberToken(_) => {
                 AuthError::MissingRememberToken(Some(ErrorData {
                     cookies,
@@ -473,6 +1306,18 @@ async fn restore_session(
                 cookies,
                 ..Default::default()
             })),
+            AuthError::OidcAuthenticationFailed(_) => {
+                AuthError::OidcAuthenticationFailed(Some(ErrorData {
+                    cookies,
+                    ..Default::default()
+                }))
+            }
+            AuthError::OidcIdentityNotLinked(_) => {
+                AuthError::OidcIdentityNotLinked(Some(ErrorData {
+                    cookies,
+                    ..Default::default()
+                }))
+            }
             AuthError::PasswordChangeRequired(_) => {
                 AuthError::PasswordChangeRequired(Some(ErrorData {
                     cookies,
@@ -489,6 +1334,14 @@ async fn restore_session(
                 cookies,
                 ..Default::default()
             })),
+            AuthError::SessionRequired(_) => AuthError::SessionRequired(Some(ErrorData {
+                cookies,
+                ..Default::default()
+            })),
+            AuthError::SudoRequired(_) => AuthError::SudoRequired(Some(ErrorData {
+                cookies,
+                ..Default::default()
+            })),
             AuthError::TotpReuse(_) => AuthError::TotpReuse(Some(ErrorData {
                 cookies,
                 ..Default::default()
@@ -516,112 +1369,560 @@ async fn restore_session(
 
     let mut db = db.get().await.map_err(InternalError::new)?;
     let transaction = db.transaction().await.map_err(InternalError::new)?;
-
-    let query = r#"
-        SELECT
-            "remember_tokens"."id",
-            "remember_tokens"."user_id",
-            "remember_tokens"."secret",
-            "users"."active"
-        FROM "remember_tokens" JOIN "users" ON "remember_tokens"."user_id" = "users"."id"
-        WHERE "remember_tokens"."id" = $1
-    "#;
-    let data = transaction
-        .query_opt(query, &[&remember_token_id_hash])
+
+    let query = r#"
+        SELECT
+            "remember_tokens"."id",
+            "remember_tokens"."user_id",
+            "remember_tokens"."secret",
+            "users"."active"
+        FROM "remember_tokens" JOIN "users" ON "remember_tokens"."user_id" = "users"."id"
+        WHERE "remember_tokens"."id" = $1
+    "#;
+    let data = transaction
+        .query_opt(query, &[&remember_token_id_hash])
+        .await
+        .map_err(InternalError::new)?;
+    let Some(data) = data else {
+        return error(AuthError::InvalidRememberToken(None), true, false);
+    };
+
+    let user_id = data.get::<_, String>("user_id");
+    audit.set_user(&user_id);
+
+    if SecStr::from(hash(remember_token_secret)) != SecStr::from(data.get::<_, &[u8]>("secret")) {
+        // Possible session hijack attempt, invalidate sessions
+        match transaction
+            .execute(
+                r#"DELETE FROM "sessions" WHERE "user_id" = $1"#,
+                &[&user_id],
+            )
+            .await
+        {
+            Ok(deleted) => {
+                audit.record("sessions-revoked", json!({
+                    "reason": PasswordChangeReason::RememberTokenCompromise,
+                    "sessions_deleted": deleted,
+                }));
+            }
+            Err(err) => {
+                InternalError::new(err);
+            }
+        }
+        if let Err(err) = transaction
+            .execute(
+                r#"DELETE FROM "remember_tokens" WHERE "user_id" = $1"#,
+                &[&user_id],
+            )
+            .await
+        {
+            InternalError::new(err);
+        }
+        match transaction
+            .execute(
+                r#"
+                UPDATE "users" SET "password_change_reason" = 'remember_token_compromise'
+                WHERE "id" = $1
+            "#,
+                &[&user_id],
+            )
+            .await
+        {
+            Ok(updated) => {
+                if updated != 1 {
+                    Err(InternalError::new(format!(
+                        "password_change_reason updated for {} users in restore_session",
+                        updated
+                    )))?;
+                }
+            }
+            Err(err) => Err(InternalError::new(err))?,
+        }
+
+        if let Err(err) = transaction.commit().await {
+            InternalError::new(err);
+        }
+        let delete_session_cookie = cookies.get(&config.session.cookie).is_some();
+        return error(
+            AuthError::RememberTokenSecretMismatch(None),
+            true,
+            delete_session_cookie,
+        );
+    }
+
+    if !data.get::<_, bool>("active") {
+        let delete_session_cookie = cookies.get(&config.session.cookie).is_some();
+        return error(
+            AuthError::AccountDisabled(None),
+            true,
+            delete_session_cookie,
+        );
+    }
+
+    // Sessions previously restored with this token are no longer reachable
+    transaction
+        .execute(
+            r#"DELETE FROM "sessions" WHERE "remember_token_id" = $1"#,
+            &[&remember_token_id_hash],
+        )
+        .await
+        .map_err(InternalError::new)?;
+
+    let client = ClientInfo::from_request(req);
+    let csrf_token = generate_token(config.csrf.token_length);
+    let session_id = generate_token(config.session.id_length);
+    let now = utc_now();
+    let session_expires = now + config.session.lifetime;
+    transaction
+        .execute(
+            r#"
+                INSERT INTO "sessions"(
+                    "id", "user_id", "remember_token_id", "csrf_token", "expires", "sudo_until",
+                    "created", "last_seen", "user_agent", "ip"
+                ) VALUES ($1, $2, $3, $4, $5, NULL, $6, $6, $7, $8)
+            "#,
+            &[
+                &hash(&session_id),
+                &user_id,
+                &remember_token_id_hash,
+                &csrf_token,
+                &session_expires,
+                &now,
+                &client.user_agent,
+                &client.ip,
+            ],
+        )
+        .await
+        .map_err(InternalError::new)?;
+
+    let new_secret = generate_token(config.remember_token.secret_length);
+    transaction
+        .execute(
+            r#"
+                UPDATE "remember_tokens"
+                SET "secret" = $1, "last_used" = $2, "user_agent" = $3, "ip" = $4
+                WHERE "id" = $5
+            "#,
+            &[
+                &hash(&new_secret),
+                &now,
+                &client.user_agent,
+                &client.ip,
+                &remember_token_id_hash,
+            ],
+        )
+        .await
+        .map_err(InternalError::new)?;
+    let revocation_token = check_device(&transaction, &user_id, &client, &config).await?;
+
+    transaction.commit().await.map_err(InternalError::new)?;
+    audit.set_session(hash(&session_id));
+    audit.record("session-restored", json!({}));
+    if let Some(token) = revocation_token {
+        audit.record("new-device", json!({}));
+        // The session exists already, so a failed notification is only logged
+        let _ = notify_new_device(&user_id, &token, &client, req, &config).await;
+    }
+
+    build_json_response(json!({ "csrf_token": csrf_token }), |res| {
+        let res = set_cookie(
+            res,
+            &config.csrf.cookie,
+            &csrf_token,
+            Some(config.csrf.cookie_lifetime),
+            &config,
+        );
+        let res = set_cookie(
+            res,
+            &config.remember_token.cookie,
+            &[remember_token_id, &new_secret].join(&config.remember_token.separator),
+            Some(config.remember_token.cookie_lifetime),
+            &config,
+        );
+        set_cookie(res, &config.session.cookie, &session_id, None, &config)
+    })
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct PasswordResetData {
+    username_or_email: String,
+}
+
+#[handler]
+async fn request_password_reset(
+    audit: Data<&Audit>,
+    config: Data<&Config>,
+    db: Data<&Pool>,
+    mailer: Data<&DynMailer>,
+    Json(data): Json<PasswordResetData>,
+) -> Result<Response> {
+    let mut db = db.get().await.map_err(InternalError::new)?;
+    let user = find_user_to_email(&db, &data.username_or_email).await?;
+
+    // Respond identically whether or not the user exists
+    if let Some(user) = user {
+        let user_id = user.get::<_, &str>("id");
+        let token = generate_token(config.password_reset.token_length);
+        let expires = utc_now() + config.password_reset.token_lifetime;
+
+        let transaction = db.transaction().await.map_err(InternalError::new)?;
+        transaction
+            .execute(
+                r#"DELETE FROM "password_reset_tokens" WHERE "user_id" = $1"#,
+                &[&user_id],
+            )
+            .await
+            .map_err(InternalError::new)?;
+        transaction
+            .execute(
+                r#"
+                INSERT INTO "password_reset_tokens"("id", "user_id", "expires")
+                VALUES ($1, $2, $3)
+                "#,
+                &[&hash(&token), &user_id, &expires],
+            )
+            .await
+            .map_err(InternalError::new)?;
+        transaction.commit().await.map_err(InternalError::new)?;
+
+        let origin = config.client.origin.to_str().map_err(InternalError::new)?;
+        let link = format!("{}{}?token={}", origin, config.password_reset.link_path, token);
+        mailer
+            .send(Email {
+                to: user.get("email"),
+                subject: "Password reset".to_owned(),
+                body: format!(
+                    "A password reset was requested for the account {}.\n\n\
+                    Open the following link to choose a new password:\n{}\n\n\
+                    The link expires in {} minutes. If you did not request a reset, \
+                    you can ignore this message.\n",
+                    user.get::<_, &str>("username"),
+                    link,
+                    config.password_reset.token_lifetime.num_minutes(),
+                ),
+            })
+            .await?;
+        audit.set_user(user_id);
+        audit.record("password-reset-requested", json!({}));
+    }
+
+    json_response(json!({
+        "success": true,
+        "data": null,
+    }))
+}
+
+#[derive(Deserialize, Validate)]
+#[serde(deny_unknown_fields)]
+struct ConfirmPasswordResetData {
+    token: String,
+    #[validate(length(
+        min = config.user.password_min_length,
+        max = config.user.password_max_length,
+    ))]
+    password: String,
+}
+
+#[handler]
+async fn confirm_password_reset(
+    audit: Data<&Audit>,
+    config: Data<&Config>,
+    connections: Data<&AccountConnections>,
+    db: Data<&Pool>,
+    rooms: Data<&AccountRooms>,
+    Json(mut data): Json<ConfirmPasswordResetData>,
+) -> Result<Response> {
+    data.validate(&config)?;
+
+    let mut db = db.get().await.map_err(InternalError::new)?;
+    let transaction = db.transaction().await.map_err(InternalError::new)?;
+    let token = transaction
+        .query_opt(
+            r#"
+            DELETE FROM "password_reset_tokens" WHERE "id" = $1
+            RETURNING "user_id", "expires"
+            "#,
+            &[&hash(&data.token)],
+        )
+        .await
+        .map_err(InternalError::new)?;
+    let Some(token) = token else {
+        return Err(AccountError::InvalidPasswordResetToken(None).into());
+    };
+    if token.get::<_, DateTime<Utc>>("expires") < utc_now() {
+        transaction.commit().await.map_err(InternalError::new)?;
+        return Err(AccountError::InvalidPasswordResetToken(None).into());
+    }
+    let user_id = token.get::<_, &str>("user_id");
+    audit.set_user(user_id);
+
+    // Rejecting the password rolls back the transaction, so the token can be reused
+    let user = transaction
+        .query_one(
+            r#"SELECT "username", "email" FROM "users" WHERE "id" = $1"#,
+            &[&user_id],
+        )
+        .await
+        .map_err(InternalError::new)?;
+    let user_inputs: Vec<&str> = [user.get::<_, Option<&str>>("username"), user.get("email")]
+        .into_iter()
+        .flatten()
+        .collect();
+    let policy_error = password_policy_error(
+        &data.password,
+        &user_inputs,
+        |details| Some(ErrorData { details, ..Default::default() }),
+        &config,
+    )
+    .await?;
+    if let Some(err) = policy_error {
+        return Err(err.into());
+    }
+
+    let password_hash = hash_encrypt_password(&data.password, &config)?;
+    transaction
+        .execute(
+            r#"
+            UPDATE "users" SET "password" = $1, "password_change_reason" = NULL WHERE "id" = $2
+            "#,
+            &[&password_hash, &user_id],
+        )
+        .await
+        .map_err(InternalError::new)?;
+    let revoked_sessions: Vec<Vec<u8>> = transaction
+        .query(
+            r#"DELETE FROM "sessions" WHERE "user_id" = $1 RETURNING "id""#,
+            &[&user_id],
+        )
+        .await
+        .map_err(InternalError::new)?
+        .into_iter()
+        .map(|row| row.get("id"))
+        .collect();
+    transaction
+        .execute(
+            r#"DELETE FROM "remember_tokens" WHERE "user_id" = $1"#,
+            &[&user_id],
+        )
+        .await
+        .map_err(InternalError::new)?;
+    transaction
+        .execute(
+            r#"DELETE FROM "password_reset_tokens" WHERE "user_id" = $1"#,
+            &[&user_id],
+        )
+        .await
+        .map_err(InternalError::new)?;
+    transaction.commit().await.map_err(InternalError::new)?;
+    audit.record("password-reset", json!({ "sessions_deleted": revoked_sessions.len() }));
+
+    for session_id_hash in revoked_sessions {
+        close_session_connections(&session_id_hash, &connections, &rooms).await;
+    }
+
+    json_response(json!({
+        "success": true,
+        "data": null,
+    }))
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SudoData {
+    password: String,
+    #[serde(default, deserialize_with = "optional")]
+    totp: Option<String>,
+}
+
+#[handler]
+async fn sudo(
+    audit: Data<&Audit>,
+    config: Data<&Config>,
+    db: Data<&Pool>,
+    user: Data<&CurrentUser>,
+    Json(data): Json<SudoData>,
+) -> Result<Response> {
+    let mut db = db.get().await.map_err(InternalError::new)?;
+    let transaction = db.transaction().await.map_err(InternalError::new)?;
+    let row = transaction
+        .query_one(
+            r#"
+            SELECT "password", "totp_key", "last_totp_time_step" FROM "users" WHERE "id" = $1
+            FOR UPDATE
+            "#,
+            &[&user.id],
+        )
         .await
         .map_err(InternalError::new)?;
-    let Some(data) = data else {
-        return error(AuthError::InvalidRememberToken(None), true, false);
-    };
 
-    let user_id = data.get::<_, String>("user_id");
+    if !verify_password(&data.password, row.get("password"), &config)? {
+        return Err(AuthError::InvalidCredentials(None).into());
+    }
 
-    if SecStr::from(hash(remember_token_secret)) != SecStr::from(data.get::<_, &[u8]>("secret")) {
-        // Possible session hijack attempt, invalidate sessions
-        if let Err(err) = transaction
-            .execute(
-                r#"DELETE FROM "sessions" WHERE "user_id" = $1"#,
-                &[&user_id],
-            )
-            .await
-        {
-            InternalError::new(err);
-        }
-        if let Err(err) = transaction
-            .execute(
-                r#"DELETE FROM "remember_tokens" WHERE "user_id" = $1"#,
-                &[&user_id],
-            )
-            .await
-        {
-            InternalError::new(err);
-        }
-        match transaction
-            .execute(
-                r#"
-                UPDATE "users" SET "password_change_reason" = 'remember_token_compromise'
-                WHERE "id" = $1
-            "#,
-                &[&user_id],
-            )
-            .await
-        {
-            Ok(updated) => {
-                if updated != 1 {
-                    Err(InternalError::new(format!(
-                        "password_change_reason updated for {} users in restore_session",
-                        updated
-                    )))?;
-                }
+    let mut warnings = Vec::<AuthWarning>::new();
+    if let Some(encrypted_totp_key) = row.get("totp_key") {
+        let Some(totp) = data.totp else {
+            return Err(AuthError::MissingTotp(None).into());
+        };
+        let second_factor = verify_second_factor(
+            &transaction,
+            &user.id,
+            encrypted_totp_key,
+            row.get("last_totp_time_step"),
+            &totp,
+            &config,
+        )
+        .await;
+        match second_factor {
+            Ok(SecondFactor::Totp) => {}
+            Ok(SecondFactor::RecoveryCode { remaining }) => {
+                warnings.push(AuthWarning::RecoveryCodeUsed(Some(ErrorData {
+                    details: Some(format!("{remaining} recovery codes remaining")),
+                    ..Default::default()
+                })));
             }
-            Err(err) => Err(InternalError::new(err))?,
+            Err(VerifySecondFactorError::InvalidTotp) => {
+                return Err(AuthError::InvalidTotp(None).into())
+            }
+            Err(VerifySecondFactorError::TotpReuse) => {
+                return Err(AuthError::TotpReuse(None).into())
+            }
+            Err(VerifySecondFactorError::InternalError(err)) => return Err(err.into()),
         }
+    } else if data.totp.is_some() {
+        warnings.push(AuthWarning::UnusedTotp(None));
+    }
 
-        if let Err(err) = transaction.commit().await {
-            InternalError::new(err);
-        }
-        let delete_session_cookie = cookies.get(&config.session.cookie).is_some();
-        return error(
-            AuthError::RememberTokenSecretMismatch(None),
-            true,
-            delete_session_cookie,
-        );
+    let sudo_until = utc_now() + config.session.sudo_lifetime;
+    let updated = transaction
+        .execute(
+            r#"UPDATE "sessions" SET "sudo_until" = $1 WHERE "id" = $2"#,
+            &[&sudo_until, &user.session_id_hash],
+        )
+        .await
+        .map_err(InternalError::new)?;
+    if updated != 1 {
+        Err(InternalError::new(format!(
+            "sudo_until updated for {} sessions in sudo",
+            updated
+        )))?;
     }
+    transaction.commit().await.map_err(InternalError::new)?;
+    audit.record("sudo", json!({}));
+    audit.record_warnings(&warnings);
 
-    if !data.get::<_, bool>("active") {
-        let delete_session_cookie = cookies.get(&config.session.cookie).is_some();
-        return error(
-            AuthError::AccountDisabled(None),
-            true,
-            delete_session_cookie,
-        );
+    let mut response_json = json!({
+        "success": true,
+        "data": null,
+        "sudo_until": sudo_until.to_rfc3339(),
+    });
+    if !warnings.is_empty() {
+        if let Some(map) = response_json.as_object_mut() {
+            map.insert("warnings".to_owned(), json!(warnings));
+        }
     }
+    json_response(response_json)
+}
 
-    let csrf_token = generate_token(config.csrf.token_length);
-    let session_id = generate_token(config.session.id_length);
-    let session_expires = utc_now() + config.session.lifetime;
-    transaction
-        .execute(
+#[handler]
+async fn get_sessions(db: Data<&Pool>, user: Data<&CurrentUser>) -> Result<Response> {
+    let db = db.get().await.map_err(InternalError::new)?;
+    let query = r#"
+        SELECT
+            "id",
+            "remember_token_id" IS NOT NULL AS "remember",
+            "expires",
+            "created",
+            "last_seen",
+            "user_agent",
+            "ip"
+        FROM "sessions"
+        WHERE "user_id" = $1 AND "expires" > $2
+        ORDER BY "last_seen" DESC, "created" DESC
+    "#;
+    let sessions: Vec<_> = db
+        .query(query, &[&user.id, &utc_now()])
+        .await
+        .map_err(InternalError::new)?
+        .into_iter()
+        .map(|row| {
+            let id = row.get::<_, &[u8]>("id");
+            json!({
+                "id": base64_urlsafe(id),
+                "current": id == user.session_id_hash,
+                "remember": row.get::<_, bool>("remember"),
+                "expires": row.get::<_, DateTime<Utc>>("expires").to_rfc3339(),
+                "created": row.get::<_, DateTime<Utc>>("created").to_rfc3339(),
+                "last_seen": row.get::<_, DateTime<Utc>>("last_seen").to_rfc3339(),
+                "user_agent": row.get::<_, Option<&str>>("user_agent"),
+                "ip": row.get::<_, Option<IpAddr>>("ip").map(|ip| ip.to_string()),
+            })
+        })
+        .collect();
+
+    json_response(json!({
+        "success": true,
+        "data": sessions,
+    }))
+}
+
+#[handler]
+async fn delete_session(
+    audit: Data<&Audit>,
+    config: Data<&Config>,
+    connections: Data<&AccountConnections>,
+    db: Data<&Pool>,
+    rooms: Data<&AccountRooms>,
+    user: Data<&CurrentUser>,
+    Path(session_id): Path<String>,
+) -> Result<Response> {
+    let Ok(session_id_hash) = decode_base64_urlsafe(&session_id) else {
+        return Err(GeneralError::NotFound(None).into());
+    };
+
+    let mut db = db.get().await.map_err(InternalError::new)?;
+    let transaction = db.transaction().await.map_err(InternalError::new)?;
+    let deleted = transaction
+        .query_opt(
             r#"
-                INSERT INTO "sessions"("id", "user_id", "csrf_token", "expires", "sudo_until")
-                    VALUES ($1, $2, $3, $4, NULL)
+            DELETE FROM "sessions" WHERE "id" = $1 AND "user_id" = $2
+            RETURNING "remember_token_id"
             "#,
-            &[&hash(&session_id), &user_id, &csrf_token, &session_expires],
+            &[&session_id_hash, &user.id],
         )
         .await
         .map_err(InternalError::new)?;
+    let Some(deleted) = deleted else {
+        return Err(GeneralError::NotFound(None).into());
+    };
+    if let Some(remember_token_id) = deleted.get::<_, Option<&[u8]>>("remember_token_id") {
+        transaction
+            .execute(
+                r#"DELETE FROM "remember_tokens" WHERE "id" = $1"#,
+                &[&remember_token_id],
+            )
+            .await
+            .map_err(InternalError::new)?;
+    }
+    transaction.commit().await.map_err(InternalError::new)?;
+    audit.record("session-deleted", json!({ "session_id": session_id }));
 
-    let new_secret = generate_token(config.remember_token.secret_length);
-    transaction
-        .execute(
-            r#"UPDATE "remember_tokens" SET "secret" = $1 WHERE "id" = $2"#,
-            &[&hash(&new_secret), &remember_token_id_hash],
-        )
-        .await
-        .map_err(InternalError::new)?;
+    close_session_connections(&session_id_hash, &connections, &rooms).await;
 
-    transaction.commit().await.map_err(InternalError::new)?;
-    build_json_response(json!({ "csrf_token": csrf_token }), |res| {
+    if session_id_hash != user.session_id_hash {
+        return json_response(json!({
+            "success": true,
+            "data": null,
+        }));
+    }
+
+    let csrf_token = generate_token(config.csrf.token_length);
+    build_json_response(json!({
+        "success": true,
+        "data": null,
+        &config.csrf.response_field: csrf_token,
+    }), |res| {
         let res = set_cookie(
             res,
             &config.csrf.cookie,
@@ -629,27 +1930,45 @@ async fn restore_session(
             Some(config.csrf.cookie_lifetime),
             &config,
         );
-        let res = set_cookie(
-            res,
-            &config.remember_token.cookie,
-            &[remember_token_id, &new_secret].join(&config.remember_token.separator),
-            Some(config.remember_token.cookie_lifetime),
-            &config,
-        );
-        set_cookie(res, &config.session.cookie, &session_id, None, &config)
+        let res = remove_cookie(res, &config.session.cookie, &config);
+        if deleted.get::<_, Option<&[u8]>>("remember_token_id").is_some() {
+            remove_cookie(res, &config.remember_token.cookie, &config)
+        } else {
+            res
+        }
     })
 }
 
 pub fn routes(config: &Config) -> Route {
     Route::new()
-        .at("/csrf-token", get!(get_csrf_token))
-        .at("/login", post(login.with(Csrf::new(config.clone()))))
+        .at(
+            "/csrf-token",
+            get!(get_csrf_token).with(RateLimit::new("csrf-token", config.clone())),
+        )
+        .at(
+            "/login",
+            post(
+                login
+                    .with(RateLimit::new("login", config.clone()))
+                    .with(Csrf::new(config.clone())),
+            ),
+        )
+        .at(
+            "/register",
+            post(
+                register
+                    .with(RateLimit::new("register", config.clone()))
+                    .with(Csrf::new(config.clone())),
+            ),
+        )
         .at(
             "/logout",
             post(
                 logout
+                    .with(RateLimit::new("logout", config.clone()))
                     .with(AuthRequired::new(
-                        AuthRequiredOptions::ALLOW_PASSWORD_CHANGE_REASON,
+                        AuthRequiredOptions::ALLOW_PASSWORD_CHANGE_REASON
+                            | AuthRequiredOptions::SESSION_ONLY,
                         config.clone(),
                     ))
                     .with(Csrf::new(config.clone())),
@@ -659,12 +1978,116 @@ pub fn routes(config: &Config) -> Route {
             "/logout/all-sessions",
             post(
                 logout
+                    .with(RateLimit::new("logout", config.clone()))
+                    .with(AuthRequired::new(
+                        AuthRequiredOptions::ALLOW_PASSWORD_CHANGE_REASON
+                            | AuthRequiredOptions::SESSION_ONLY,
+                        config.clone(),
+                    ))
+                    .with(Csrf::new(config.clone())),
+            ),
+        )
+        .at(
+            "/revoke-sessions",
+            post(
+                revoke_sessions
+                    .with(RateLimit::new("revoke-sessions", config.clone()))
+                    .with(Csrf::new(config.clone())),
+            ),
+        )
+        .at(
+            "/magic-link",
+            post(
+                request_magic_link
+                    .with(RateLimit::new("magic-link", config.clone()))
+                    .with(Csrf::new(config.clone())),
+            ),
+        )
+        .at(
+            "/magic-link/confirm",
+            post(
+                confirm_magic_link
+                    .with(RateLimit::new("magic-link-confirm", config.clone()))
+                    .with(Csrf::new(config.clone())),
+            ),
+        )
+        .at("/oidc/providers", get!(get_oidc_providers))
+        .at(
+            "/oidc/login/:provider",
+            post(
+                oidc_login
+                    .with(RateLimit::new("oidc-login", config.clone()))
+                    .with(Csrf::new(config.clone())),
+            ),
+        )
+        .at(
+            "/oidc/callback",
+            post(
+                oidc_callback
+                    .with(RateLimit::new("oidc-callback", config.clone()))
+                    .with(Csrf::new(config.clone())),
+            ),
+        )
+        .at(
+            "/password-reset",
+            post(
+                request_password_reset
+                    .with(RateLimit::new("password-reset", config.clone()))
+                    .with(Csrf::new(config.clone())),
+            ),
+        )
+        .at(
+            "/password-reset/confirm",
+            post(
+                confirm_password_reset
+                    .with(RateLimit::new("password-reset-confirm", config.clone()))
+                    .with(Csrf::new(config.clone())),
+            ),
+        )
+        .at(
+            "/restore-session",
+            post(restore_session.with(RateLimit::new("restore-session", config.clone()))),
+        )
+        .at(
+            "/sudo",
+            post(
+                sudo.with(RateLimit::new("sudo", config.clone()))
+                    .with(AuthRequired::new(
+                        AuthRequiredOptions::SESSION_ONLY,
+                        config.clone(),
+                    ))
+                    .with(Csrf::new(config.clone())),
+            ),
+        )
+        .at(
+            "/webauthn/options",
+            post(
+                webauthn_options
+                    .with(RateLimit::new("webauthn-options", config.clone()))
+                    .with(Csrf::new(config.clone())),
+            ),
+        )
+        .at(
+            "/webauthn/login",
+            post(
+                webauthn_login
+                    .with(RateLimit::new("webauthn-login", config.clone()))
+                    .with(Csrf::new(config.clone())),
+            ),
+        )
+        .at(
+            "/sessions",
+            get!(get_sessions).with(AuthRequired::defaults(config.clone())),
+        )
+        .at(
+            "/sessions/:id",
+            delete(
+                delete_session
                     .with(AuthRequired::new(
-                        AuthRequiredOptions::ALLOW_PASSWORD_CHANGE_REASON,
+                        AuthRequiredOptions::SESSION_ONLY,
                         config.clone(),
                     ))
                     .with(Csrf::new(config.clone())),
             ),
         )
-        .at("/restore-session", post(restore_session))
 }
@@ -0,0 +1,247 @@
+use deadpool_postgres::{tokio_postgres::error::SqlState, Pool};
+use poem::{
+    handler,
+    web::{Data, Json, Path},
+    EndpointExt, Response, Result, Route,
+};
+use serde::Deserialize;
+use serde_json::json;
+
+use macros::Validate;
+
+use crate::{
+    audit::Audit,
+    config::Config,
+    db::Permission,
+    error::{AuthError, GeneralError, InternalError, PermissionError},
+    middleware::{AuthRequired, AuthRequiredOptions, Csrf, CurrentUser},
+    util::{check_can_grant, get, json_response},
+    validate::Validate,
+};
+
+#[handler]
+async fn get_roles(db: Data<&Pool>, current_user: Data<&CurrentUser>) -> Result<Response> {
+    if !current_user
+        .permissions
+        .as_ref()
+        .unwrap()
+        .contains(&Permission::ManagePermissions)
+    {
+        return Err(AuthError::Forbidden(None).into());
+    }
+
+    let db = db.get().await.map_err(InternalError::new)?;
+    let roles: Vec<_> = db
+        .query(
+            r#"
+            SELECT "name", "permissions",
+                (SELECT count(*) FROM "user_roles" WHERE "role" = "roles"."name") AS "users"
+            FROM "roles" ORDER BY "name"
+            "#,
+            &[],
+        )
+        .await
+        .map_err(InternalError::new)?
+        .into_iter()
+        .map(|row| {
+            json!({
+                "name": row.get::<_, &str>("name"),
+                "permissions": row.get::<_, Vec<Permission>>("permissions"),
+                "users": row.get::<_, i64>("users"),
+            })
+        })
+        .collect();
+
+    json_response(json!({
+        "success": true,
+        "data": roles,
+    }))
+}
+
+#[derive(Deserialize, Validate)]
+#[serde(deny_unknown_fields)]
+struct CreateRoleData {
+    #[validate(trim, length(min = 1, max = config.role.name_max_length))]
+    name: String,
+    permissions: Vec<Permission>,
+}
+
+#[handler]
+async fn create_role(
+    audit: Data<&Audit>,
+    config: Data<&Config>,
+    db: Data<&Pool>,
+    current_user: Data<&CurrentUser>,
+    Json(mut data): Json<CreateRoleData>,
+) -> Result<Response> {
+    data.validate(&config)?;
+    data.permissions.sort();
+    data.permissions.dedup();
+    check_can_grant(&data.permissions, &current_user)?;
+
+    let db = db.get().await.map_err(InternalError::new)?;
+    if let Err(err) = db
+        .execute(
+            r#"INSERT INTO "roles"("name", "permissions") VALUES ($1, $2)"#,
+            &[&data.name, &data.permissions],
+        )
+        .await
+    {
+        if err.code() == Some(&SqlState::UNIQUE_VIOLATION) {
+            return Err(PermissionError::RoleNameNotAvailable(None).into());
+        }
+        return Err(InternalError::new(err).into());
+    }
+    audit.record(
+        "role-created",
+        json!({ "name": data.name, "permissions": data.permissions }),
+    );
+
+    json_response(json!({
+        "success": true,
+        "data": {
+            "name": data.name,
+            "permissions": data.permissions,
+        },
+    }))
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct UpdateRoleData {
+    permissions: Vec<Permission>,
+}
+
+// Replaces the permissions of a role, which changes them for every user with
+// the role; both the old and the new permissions must be held
+#[handler]
+async fn update_role(
+    audit: Data<&Audit>,
+    db: Data<&Pool>,
+    current_user: Data<&CurrentUser>,
+    Path(name): Path<String>,
+    Json(mut data): Json<UpdateRoleData>,
+) -> Result<Response> {
+    data.permissions.sort();
+    data.permissions.dedup();
+
+    let mut db = db.get().await.map_err(InternalError::new)?;
+    let transaction = db.transaction().await.map_err(InternalError::new)?;
+    let old_permissions: Vec<Permission> = transaction
+        .query_opt(
+            r#"SELECT "permissions" FROM "roles" WHERE "name" = $1 FOR UPDATE"#,
+            &[&name],
+        )
+        .await
+        .map_err(InternalError::new)?
+        .ok_or(GeneralError::NotFound(None))?
+        .get("permissions");
+    let mut changed: Vec<_> = old_permissions
+        .iter()
+        .filter(|permission| !data.permissions.contains(permission))
+        .chain(
+            data.permissions
+                .iter()
+                .filter(|permission| !old_permissions.contains(permission)),
+        )
+        .copied()
+        .collect();
+    changed.sort();
+    check_can_grant(&changed, &current_user)?;
+
+    transaction
+        .execute(
+            r#"UPDATE "roles" SET "permissions" = $1 WHERE "name" = $2"#,
+            &[&data.permissions, &name],
+        )
+        .await
+        .map_err(InternalError::new)?;
+    transaction.commit().await.map_err(InternalError::new)?;
+    audit.record(
+        "role-updated",
+        json!({ "name": name, "old": old_permissions, "new": data.permissions }),
+    );
+
+    json_response(json!({
+        "success": true,
+        "data": {
+            "name": name,
+            "permissions": data.permissions,
+        },
+    }))
+}
+
+// Deleting a role revokes its permissions from everyone who has it
+#[handler]
+async fn delete_role(
+    audit: Data<&Audit>,
+    db: Data<&Pool>,
+    current_user: Data<&CurrentUser>,
+    Path(name): Path<String>,
+) -> Result<Response> {
+    let mut db = db.get().await.map_err(InternalError::new)?;
+    let transaction = db.transaction().await.map_err(InternalError::new)?;
+    let permissions: Vec<Permission> = transaction
+        .query_opt(
+            r#"SELECT "permissions" FROM "roles" WHERE "name" = $1 FOR UPDATE"#,
+            &[&name],
+        )
+        .await
+        .map_err(InternalError::new)?
+        .ok_or(GeneralError::NotFound(None))?
+        .get("permissions");
+    check_can_grant(&permissions, &current_user)?;
+
+    transaction
+        .execute(r#"DELETE FROM "roles" WHERE "name" = $1"#, &[&name])
+        .await
+        .map_err(InternalError::new)?;
+    transaction.commit().await.map_err(InternalError::new)?;
+    audit.record(
+        "role-deleted",
+        json!({ "name": name, "permissions": permissions }),
+    );
+
+    json_response(json!({
+        "success": true,
+        "data": null,
+    }))
+}
+
+pub fn routes(config: &Config) -> Route {
+    Route::new()
+        .at(
+            "/",
+            get!(get_roles.with(AuthRequired::new(
+                AuthRequiredOptions::WITH_PERMISSIONS,
+                config.clone(),
+            )))
+            .post(
+                create_role
+                    .with(AuthRequired::new(
+                        AuthRequiredOptions::WITH_PERMISSIONS,
+                        config.clone(),
+                    ))
+                    .with(Csrf::new(config.clone())),
+            ),
+        )
+        .at(
+            "/:name",
+            poem::put(
+                update_role
+                    .with(AuthRequired::new(
+                        AuthRequiredOptions::WITH_PERMISSIONS,
+                        config.clone(),
+                    ))
+                    .with(Csrf::new(config.clone())),
+            )
+            .delete(
+                delete_role
+                    .with(AuthRequired::new(
+                        AuthRequiredOptions::WITH_PERMISSIONS,
+                        config.clone(),
+                    ))
+                    .with(Csrf::new(config.clone())),
+            ),
+        )
+}
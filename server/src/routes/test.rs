@@ -33,10 +33,11 @@ async fn parse_json(Json(data): Json<SomeData>) -> Result<Response> {
 
 #[handler]
 async fn invalid_data() -> Result<Response> {
-    return Err(GeneralError::InvalidData(Some(ErrorData {
+    Err(GeneralError::InvalidData(Some(ErrorData {
         details: Some("the deets".to_owned()),
         ..Default::default()
-    })).into())
+    }))
+    .into())
 }
 
 #[handler]
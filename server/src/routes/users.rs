@@ -1,25 +1,73 @@
-use deadpool_postgres::Pool;
+use chrono::{DateTime, Utc};
+use deadpool_postgres::{tokio_postgres::error::SqlState, Client, Pool};
 use poem::{
-    handler,
-    web::{Data, Path},
-    EndpointExt, Response, Result, Route,
+    async_trait, handler,
+    http::{header, StatusCode},
+    web::{cookie::CookieJar, Data, Json, Multipart, Path, Query},
+    EndpointExt, FromRequest, Request, RequestBody, Response, Result, Route,
 };
-use serde_json::json;
+use postgres_types::ToSql;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map as JsonMap, Value as JsonValue};
+use tokio::io::AsyncReadExt;
+
+use macros::Validate;
 
 use crate::{
+    audit::Audit,
     config::Config,
     db::{Language, PasswordChangeReason, Permission},
-    error::{AuthError, GeneralError, InternalError},
-    middleware::{AuthRequired, AuthRequiredOptions, CurrentUser},
-    util::{get, json_response},
+    error::{
+        field_alert, AccountError, AccountWarning, AuthError, ErrorData, GeneralError, IconError,
+        InternalError, ValidationErrors,
+    },
+    icon::{delete_icon_blobs, icon_key, process_icon, ICON_CONTENT_TYPE},
+    middleware::{AuthRequired, AuthRequiredOptions, Csrf, CurrentUser, RateLimit},
+    password_policy::password_policy_error,
+    util::{
+        activate_totp_key, base64_urlsafe, build_json_response, check_can_grant,
+        decode_base64_urlsafe, generate_token, get, hash_encrypt_password, json_response,
+        optional, remove_cookie, remove_totp, revoke_other_sessions, set_cookie,
+        user_permissions, utc_now, verify_password, ActivateTotpKeyError,
+    },
+    storage::{Blob, DynStorage},
+    validate::Validate,
+    websocket::{close_session_connections, send_to_room, AccountConnections, AccountRooms},
 };
 
+// Where changes to an account are reported: the audit log and the websocket
+// connections of the account
+struct AccountChanges<'a> {
+    audit: &'a Audit,
+    connections: &'a AccountConnections,
+    rooms: &'a AccountRooms,
+}
+
+#[async_trait]
+impl<'a> FromRequest<'a> for AccountChanges<'a> {
+    async fn from_request(req: &'a Request, _body: &mut RequestBody) -> Result<Self> {
+        Ok(Self {
+            audit: req
+                .data()
+                .ok_or_else(|| InternalError::new("no audit initialized"))?,
+            connections: req
+                .data()
+                .ok_or_else(|| InternalError::new("no account connections initialized"))?,
+            rooms: req
+                .data()
+                .ok_or_else(|| InternalError::new("no account rooms initialized"))?,
+        })
+    }
+}
+
 fn current_user_response(current_user: &CurrentUser) -> Result<Response> {
     json_response(json!({
         "success": true,
         "data": {
             "id": current_user.id,
             "username": current_user.username.as_ref().unwrap(),
+            "email": current_user.email.as_ref().unwrap(),
+            "email_verified": current_user.email_verified.unwrap(),
             "totp_enabled": current_user.totp_enabled,
             "password_change_reason": current_user.password_change_reason,
             "icon": current_user.icon.as_ref().unwrap(),
@@ -34,6 +82,224 @@ async fn get_me(current_user: Data<&CurrentUser>) -> Result<Response> {
     current_user_response(&current_user)
 }
 
+#[derive(Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum UserSort {
+    Id,
+    Username,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum SortOrder {
+    Asc,
+    Desc,
+}
+
+#[derive(Deserialize, Validate)]
+#[serde(deny_unknown_fields)]
+struct UsersQuery {
+    // Case-insensitive username prefix
+    #[validate(length(max = config.user.username_max_length))]
+    search: Option<String>,
+    active: Option<bool>,
+    language: Option<Language>,
+    password_change_reason: Option<PasswordChangeReason>,
+    totp_enabled: Option<bool>,
+    permission: Option<Permission>,
+    sort: Option<UserSort>,
+    order: Option<SortOrder>,
+    // Opaque; from next_cursor of the previous page with the same sort
+    cursor: Option<String>,
+    #[validate(range(min = 1, max = config.user_directory.max_page_size))]
+    limit: Option<i64>,
+}
+
+// The sort key and ID of the last user on a page
+#[derive(Deserialize, Serialize)]
+struct UsersCursor(String, String);
+
+fn invalid_cursor() -> poem::Error {
+    GeneralError::InvalidData(Some(ErrorData {
+        details: Some("cursor: invalid".to_owned()),
+        ..Default::default()
+    }))
+    .into()
+}
+
+// Keyset pagination over (sort key, ID), which the unique indexes on "id" and
+// lower("username") serve without scanning the skipped rows
+#[handler]
+async fn get_users(
+    config: Data<&Config>,
+    db: Data<&Pool>,
+    current_user: Data<&CurrentUser>,
+    Query(mut query): Query<UsersQuery>,
+) -> Result<Response> {
+    if !current_user
+        .permissions
+        .as_ref()
+        .unwrap()
+        .contains(&Permission::ViewUser)
+    {
+        return Err(AuthError::Forbidden(None).into());
+    }
+
+    query.validate(&config)?;
+    let limit = query.limit.unwrap_or(config.user_directory.default_page_size);
+    let sort_key = match query.sort.unwrap_or(UserSort::Username) {
+        UserSort::Id => r#""id""#,
+        UserSort::Username => r#"lower("username")"#,
+    };
+    let (direction, comparison) = match query.order.unwrap_or(SortOrder::Asc) {
+        SortOrder::Asc => ("ASC", ">"),
+        SortOrder::Desc => ("DESC", "<"),
+    };
+    let cursor = match &query.cursor {
+        Some(cursor) => Some(
+            decode_base64_urlsafe(cursor)
+                .ok()
+                .and_then(|cursor| serde_json::from_slice::<UsersCursor>(&cursor).ok())
+                .ok_or_else(invalid_cursor)?,
+        ),
+        None => None,
+    };
+    // LIKE treats these as wildcards
+    let search = query.search.as_ref().map(|search| {
+        let escaped = search
+            .replace('\\', "\\\\")
+            .replace('%', "\\%")
+            .replace('_', "\\_");
+        format!("{escaped}%")
+    });
+
+    let mut conditions = Vec::new();
+    let mut params: Vec<&(dyn ToSql + Sync)> = Vec::new();
+    if let Some(search) = &search {
+        params.push(search);
+        conditions.push(format!(r#"lower("username") LIKE lower(${})"#, params.len()));
+    }
+    if let Some(active) = &query.active {
+        params.push(active);
+        conditions.push(format!(r#""active" = ${}"#, params.len()));
+    }
+    if let Some(language) = &query.language {
+        params.push(language);
+        conditions.push(format!(r#""language" = ${}"#, params.len()));
+    }
+    if let Some(reason) = &query.password_change_reason {
+        params.push(reason);
+        conditions.push(format!(r#""password_change_reason" = ${}"#, params.len()));
+    }
+    if let Some(totp_enabled) = &query.totp_enabled {
+        params.push(totp_enabled);
+        conditions.push(format!(r#"("totp_key" IS NOT NULL) = ${}"#, params.len()));
+    }
+    if let Some(permission) = &query.permission {
+        params.push(permission);
+        conditions.push(format!(
+            r#"
+            EXISTS (
+                SELECT 1 FROM "effective_permissions"
+                WHERE "user_id" = "users"."id" AND "permission" = ${}
+            )
+            "#,
+            params.len(),
+        ));
+    }
+    if let Some(UsersCursor(key, id)) = &cursor {
+        params.push(key);
+        params.push(id);
+        conditions.push(format!(
+            r#"({sort_key}, "id") {comparison} (${}, ${})"#,
+            params.len() - 1,
+            params.len(),
+        ));
+    }
+    // One extra row tells whether there is another page
+    let fetch_limit = limit + 1;
+    params.push(&fetch_limit);
+
+    let where_clause = if conditions.is_empty() {
+        String::new()
+    } else {
+        format!("WHERE {}", conditions.join(" AND "))
+    };
+    let sql = format!(
+        r#"
+        SELECT
+            "id",
+            {sort_key} AS "sort_key",
+            "active",
+            "deletion_scheduled",
+            "username",
+            "email",
+            "email_verified",
+            "totp_key" IS NOT NULL AS "totp_enabled",
+            "locked_until",
+            "password_change_reason",
+            "icon",
+            "language",
+            ARRAY(
+                SELECT "permission" FROM "effective_permissions"
+                WHERE "user_id" = "users"."id" ORDER BY "permission"
+            ) AS "permissions"
+        FROM "users" {where_clause}
+        ORDER BY {sort_key} {direction}, "id" {direction} LIMIT ${}
+        "#,
+        params.len(),
+    );
+
+    let db = db.get().await.map_err(InternalError::new)?;
+    let mut rows = db.query(&sql, &params).await.map_err(InternalError::new)?;
+    let next_cursor = if rows.len() as i64 > limit {
+        rows.truncate(limit as usize);
+        rows.last()
+            .map(|row| {
+                let cursor = UsersCursor(row.get("sort_key"), row.get("id"));
+                serde_json::to_vec(&cursor).map(|cursor| base64_urlsafe(&cursor))
+            })
+            .transpose()
+            .map_err(InternalError::new)?
+    } else {
+        None
+    };
+
+    let users: Vec<_> = rows
+        .into_iter()
+        .map(|row| {
+            json!({
+                "id": row.get::<_, &str>("id"),
+                "active": row.get::<_, bool>("active"),
+                "deletion_scheduled": row
+                    .get::<_, Option<DateTime<Utc>>>("deletion_scheduled")
+                    .map(|datetime| datetime.to_rfc3339()),
+                "username": row.get::<_, &str>("username"),
+                "email": row.get::<_, Option<&str>>("email"),
+                "email_verified": row.get::<_, bool>("email_verified"),
+                "totp_enabled": row.get::<_, bool>("totp_enabled"),
+                "locked_until": row
+                    .get::<_, Option<DateTime<Utc>>>("locked_until")
+                    .map(|datetime| datetime.to_rfc3339()),
+                "password_change_reason": row.get::<_, Option<PasswordChangeReason>>(
+                    "password_change_reason"
+                ),
+                "icon": row.get::<_, Option<&str>>("icon"),
+                "language": row.get::<_, Language>("language"),
+                "permissions": row.get::<_, Vec<Permission>>("permissions"),
+            })
+        })
+        .collect();
+
+    json_response(json!({
+        "success": true,
+        "data": {
+            "users": users,
+            "next_cursor": next_cursor,
+        },
+    }))
+}
+
 #[handler]
 async fn get_user(
     Path(user_id): Path<String>,
@@ -75,31 +341,957 @@ async fn get_user(
     }))
 }
 
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct DeleteUserData {
+    #[serde(default, deserialize_with = "optional")]
+    password: Option<String>,
+}
+
+// Deactivates the account and schedules it for purging once the grace period
+// has passed; users deleting themselves can cancel by logging in before that
+#[handler]
+async fn delete_user(
+    AccountChanges {
+        audit,
+        connections,
+        rooms,
+    }: AccountChanges<'_>,
+    config: Data<&Config>,
+    cookies: &CookieJar,
+    db: Data<&Pool>,
+    current_user: Data<&CurrentUser>,
+    Path(user_id): Path<String>,
+    Json(data): Json<DeleteUserData>,
+) -> Result<Response> {
+    let deleting_self = user_id == current_user.id;
+    if !deleting_self
+        && !current_user
+            .permissions
+            .as_ref()
+            .unwrap()
+            .contains(&Permission::DeleteUser)
+    {
+        return Err(AuthError::Forbidden(None).into());
+    }
+
+    let mut db = db.get().await.map_err(InternalError::new)?;
+    let transaction = db.transaction().await.map_err(InternalError::new)?;
+    let user = transaction
+        .query_opt(
+            r#"SELECT "password", "deletion_scheduled" FROM "users" WHERE "id" = $1 FOR UPDATE"#,
+            &[&user_id],
+        )
+        .await
+        .map_err(InternalError::new)?
+        .ok_or(GeneralError::NotFound(None))?;
+
+    if deleting_self {
+        let Some(password) = &data.password else {
+            return Err(AccountError::MissingCurrentPassword(None).into());
+        };
+        if !verify_password(password, user.get("password"), &config)? {
+            return Err(AccountError::InvalidCurrentPassword(None).into());
+        }
+    }
+
+    // Deleting an account again keeps the original date
+    let deletion_scheduled = user
+        .get::<_, Option<DateTime<Utc>>>("deletion_scheduled")
+        .unwrap_or_else(|| utc_now() + config.account_deletion.grace_period);
+    transaction
+        .execute(
+            r#"
+            UPDATE "users"
+            SET "active" = false, "deletion_scheduled" = $1, "deletion_cancellable" = $2
+            WHERE "id" = $3
+            "#,
+            &[&deletion_scheduled, &deleting_self, &user_id],
+        )
+        .await
+        .map_err(InternalError::new)?;
+    let revoked_sessions: Vec<Vec<u8>> = transaction
+        .query(
+            r#"DELETE FROM "sessions" WHERE "user_id" = $1 RETURNING "id""#,
+            &[&user_id],
+        )
+        .await
+        .map_err(InternalError::new)?
+        .into_iter()
+        .map(|row| row.get("id"))
+        .collect();
+    transaction
+        .execute(
+            r#"DELETE FROM "remember_tokens" WHERE "user_id" = $1"#,
+            &[&user_id],
+        )
+        .await
+        .map_err(InternalError::new)?;
+    transaction.commit().await.map_err(InternalError::new)?;
+    audit.record(
+        "account-deletion-scheduled",
+        json!({
+            "user_id": user_id,
+            "deletion_scheduled": deletion_scheduled.to_rfc3339(),
+            "sessions_deleted": revoked_sessions.len(),
+        }),
+    );
+
+    for session_id_hash in revoked_sessions {
+        close_session_connections(&session_id_hash, connections, rooms).await;
+    }
+
+    let data = json!({ "deletion_scheduled": deletion_scheduled.to_rfc3339() });
+    if !deleting_self {
+        return json_response(json!({ "success": true, "data": data }));
+    }
+    let csrf_token = generate_token(config.csrf.token_length);
+    build_json_response(
+        json!({
+            "success": true,
+            "data": data,
+            &config.csrf.response_field: csrf_token,
+        }),
+        |res| {
+            let mut res = set_cookie(
+                res,
+                &config.csrf.cookie,
+                &csrf_token,
+                Some(config.csrf.cookie_lifetime),
+                &config,
+            );
+            if cookies.get(&config.remember_token.cookie).is_some() {
+                res = remove_cookie(res, &config.remember_token.cookie, &config);
+            }
+            remove_cookie(res, &config.session.cookie, &config)
+        },
+    )
+}
+
+#[handler]
+async fn get_user_permissions(
+    db: Data<&Pool>,
+    current_user: Data<&CurrentUser>,
+    Path(user_id): Path<String>,
+) -> Result<Response> {
+    if user_id != current_user.id
+        && !current_user
+            .permissions
+            .as_ref()
+            .unwrap()
+            .contains(&Permission::ViewUser)
+    {
+        return Err(AuthError::Forbidden(None).into());
+    }
+
+    let db = db.get().await.map_err(InternalError::new)?;
+    db.query_opt(r#"SELECT 1 FROM "users" WHERE "id" = $1"#, &[&user_id])
+        .await
+        .map_err(InternalError::new)?
+        .ok_or(GeneralError::NotFound(None))?;
+    json_response(json!({
+        "success": true,
+        "data": user_permissions(&db, &user_id).await?,
+    }))
+}
+
+// Granting something the user already has is not an error
+#[handler]
+async fn grant_permission(
+    audit: Data<&Audit>,
+    db: Data<&Pool>,
+    current_user: Data<&CurrentUser>,
+    Path((user_id, permission)): Path<(String, Permission)>,
+) -> Result<Response> {
+    check_can_grant(&[permission], &current_user)?;
+
+    let db = db.get().await.map_err(InternalError::new)?;
+    let granted = match db
+        .execute(
+            r#"
+            INSERT INTO "permissions"("user_id", "permission") VALUES ($1, $2)
+            ON CONFLICT DO NOTHING
+            "#,
+            &[&user_id, &permission],
+        )
+        .await
+    {
+        Ok(granted) => granted,
+        Err(err) if err.code() == Some(&SqlState::FOREIGN_KEY_VIOLATION) => {
+            return Err(GeneralError::NotFound(None).into());
+        }
+        Err(err) => return Err(InternalError::new(err).into()),
+    };
+    if granted > 0 {
+        audit.record(
+            "permission-granted",
+            json!({ "user_id": user_id, "permission": permission }),
+        );
+    }
+
+    json_response(json!({
+        "success": true,
+        "data": user_permissions(&db, &user_id).await?,
+    }))
+}
+
+// Only removes the direct permission; the user keeps it if a role grants it
+#[handler]
+async fn revoke_permission(
+    audit: Data<&Audit>,
+    db: Data<&Pool>,
+    current_user: Data<&CurrentUser>,
+    Path((user_id, permission)): Path<(String, Permission)>,
+) -> Result<Response> {
+    check_can_grant(&[permission], &current_user)?;
+
+    let db = db.get().await.map_err(InternalError::new)?;
+    let revoked = db
+        .execute(
+            r#"DELETE FROM "permissions" WHERE "user_id" = $1 AND "permission" = $2"#,
+            &[&user_id, &permission],
+        )
+        .await
+        .map_err(InternalError::new)?;
+    if revoked == 0 {
+        return Err(GeneralError::NotFound(None).into());
+    }
+    audit.record(
+        "permission-revoked",
+        json!({ "user_id": user_id, "permission": permission }),
+    );
+
+    json_response(json!({
+        "success": true,
+        "data": user_permissions(&db, &user_id).await?,
+    }))
+}
+
+async fn role_permissions(db: &Client, role: &str) -> Result<Vec<Permission>> {
+    Ok(db
+        .query_opt(r#"SELECT "permissions" FROM "roles" WHERE "name" = $1"#, &[&role])
+        .await
+        .map_err(InternalError::new)?
+        .ok_or(GeneralError::NotFound(None))?
+        .get("permissions"))
+}
+
+#[handler]
+async fn grant_role(
+    audit: Data<&Audit>,
+    db: Data<&Pool>,
+    current_user: Data<&CurrentUser>,
+    Path((user_id, role)): Path<(String, String)>,
+) -> Result<Response> {
+    let db = db.get().await.map_err(InternalError::new)?;
+    check_can_grant(&role_permissions(&db, &role).await?, &current_user)?;
+
+    // A foreign key violation means that the user or the role no longer exists
+    let granted = match db
+        .execute(
+            r#"
+            INSERT INTO "user_roles"("user_id", "role") VALUES ($1, $2)
+            ON CONFLICT DO NOTHING
+            "#,
+            &[&user_id, &role],
+        )
+        .await
+    {
+        Ok(granted) => granted,
+        Err(err) if err.code() == Some(&SqlState::FOREIGN_KEY_VIOLATION) => {
+            return Err(GeneralError::NotFound(None).into());
+        }
+        Err(err) => return Err(InternalError::new(err).into()),
+    };
+    if granted > 0 {
+        audit.record("role-granted", json!({ "user_id": user_id, "role": role }));
+    }
+
+    json_response(json!({
+        "success": true,
+        "data": user_permissions(&db, &user_id).await?,
+    }))
+}
+
+#[handler]
+async fn revoke_role(
+    audit: Data<&Audit>,
+    db: Data<&Pool>,
+    current_user: Data<&CurrentUser>,
+    Path((user_id, role)): Path<(String, String)>,
+) -> Result<Response> {
+    let db = db.get().await.map_err(InternalError::new)?;
+    check_can_grant(&role_permissions(&db, &role).await?, &current_user)?;
+
+    let revoked = db
+        .execute(
+            r#"DELETE FROM "user_roles" WHERE "user_id" = $1 AND "role" = $2"#,
+            &[&user_id, &role],
+        )
+        .await
+        .map_err(InternalError::new)?;
+    if revoked == 0 {
+        return Err(GeneralError::NotFound(None).into());
+    }
+    audit.record("role-revoked", json!({ "user_id": user_id, "role": role }));
+
+    json_response(json!({
+        "success": true,
+        "data": user_permissions(&db, &user_id).await?,
+    }))
+}
+
+fn check_can_edit(user_id: &str, current_user: &CurrentUser) -> Result<(), AuthError> {
+    if user_id != current_user.id
+        && !current_user
+            .permissions
+            .as_ref()
+            .unwrap()
+            .contains(&Permission::EditUser)
+    {
+        return Err(AuthError::Forbidden(None));
+    }
+    Ok(())
+}
+
+#[derive(Deserialize, Validate)]
+#[serde(deny_unknown_fields)]
+struct UpdateUserData {
+    #[serde(default, deserialize_with = "optional")]
+    current_password: Option<String>,
+    #[serde(default, deserialize_with = "optional")]
+    #[validate(
+        trim,
+        length(
+            min = config.user.username_min_length,
+            max = config.user.username_max_length,
+        ),
+    )]
+    username: Option<String>,
+    #[serde(default, deserialize_with = "optional")]
+    #[validate(length(
+        min = config.user.password_min_length,
+        max = config.user.password_max_length,
+    ))]
+    new_password: Option<String>,
+    #[serde(default, deserialize_with = "optional")]
+    language: Option<Language>,
+    // null disables TOTP, a code enables the key from POST /account/totp-key
+    #[serde(default, deserialize_with = "optional")]
+    totp: Option<Option<String>>,
+}
+
+// Every field is applied or rejected on its own; the response lists the
+// applied changes along with the errors and warnings of the other fields
+#[handler]
+async fn update_user(
+    AccountChanges {
+        audit,
+        connections,
+        rooms,
+    }: AccountChanges<'_>,
+    config: Data<&Config>,
+    db: Data<&Pool>,
+    current_user: Data<&CurrentUser>,
+    Path(user_id): Path<String>,
+    Json(mut data): Json<UpdateUserData>,
+) -> Result<Response> {
+    let updating_self = user_id == current_user.id;
+    check_can_edit(&user_id, &current_user)?;
+
+    let invalid_fields = match data.validate(&config) {
+        Ok(()) => ValidationErrors::default(),
+        Err(errors) => errors,
+    };
+    let mut errors = invalid_fields.alerts();
+    let mut warnings = Vec::new();
+    let mut updated = JsonMap::new();
+
+    let mut db = db.get().await.map_err(InternalError::new)?;
+    let mut transaction = db.transaction().await.map_err(InternalError::new)?;
+    let row = transaction
+        .query_opt(
+            r#"
+            SELECT "username", "email", "password", "totp_key" IS NOT NULL AS "totp_enabled"
+            FROM "users" WHERE "id" = $1 FOR UPDATE
+            "#,
+            &[&user_id],
+        )
+        .await
+        .map_err(InternalError::new)?
+        .ok_or(GeneralError::NotFound(None))?;
+    let old_username = row.get::<_, String>("username");
+    let password_hash = row.get::<_, Vec<u8>>("password");
+
+    let in_sudo =
+        matches!(current_user.sudo_until, Some(Some(sudo_until)) if sudo_until > utc_now());
+    // Checked at most once, when the first field that needs it comes up
+    let mut current_password_valid = in_sudo.then_some(true);
+    let mut check_current_password = |errors: &mut Vec<JsonValue>| -> Result<bool> {
+        if let Some(valid) = current_password_valid {
+            return Ok(valid);
+        }
+        let valid = match &data.current_password {
+            Some(password) => verify_password(password, &password_hash, &config)?,
+            None => false,
+        };
+        if !valid {
+            let error = match data.current_password {
+                Some(_) => AccountError::InvalidCurrentPassword(None),
+                None => AccountError::MissingCurrentPassword(None),
+            };
+            errors.push(field_alert("current_password", &error));
+        }
+        current_password_valid = Some(valid);
+        Ok(valid)
+    };
+
+    let mut new_username = None;
+    let username = data
+        .username
+        .as_ref()
+        .filter(|_| !invalid_fields.has_field("username"));
+    if let Some(username) = username {
+        if *username != old_username {
+            // Changes in case alone don't need the current password
+            let allowed = !updating_self
+                || username.to_lowercase() == old_username.to_lowercase()
+                || check_current_password(&mut errors)?;
+            if allowed {
+                let savepoint = transaction
+                    .transaction()
+                    .await
+                    .map_err(InternalError::new)?;
+                let result = savepoint
+                    .execute(
+                        r#"UPDATE "users" SET "username" = $1 WHERE "id" = $2"#,
+                        &[username, &user_id],
+                    )
+                    .await;
+                match result {
+                    Ok(_) => {
+                        savepoint.commit().await.map_err(InternalError::new)?;
+                        updated.insert("username".to_owned(), json!(username));
+                        new_username = Some(username.clone());
+                    }
+                    Err(err) => {
+                        let constraint = err.as_db_error().and_then(|err| err.constraint());
+                        if err.code() != Some(&SqlState::UNIQUE_VIOLATION)
+                            || constraint != Some("users_username_key")
+                        {
+                            return Err(InternalError::new(err).into());
+                        }
+                        let error = AccountError::UsernameNotAvailable(None);
+                        errors.push(field_alert("username", &error));
+                    }
+                }
+            }
+        }
+    }
+
+    let mut revoked_sessions = None;
+    let new_password = data
+        .new_password
+        .as_ref()
+        .filter(|_| !invalid_fields.has_field("new_password"));
+    if let Some(new_password) = new_password {
+        if !updating_self {
+            let error = AccountError::CannotUpdatePasswordForOthers(None);
+            errors.push(field_alert("new_password", &error));
+        } else if check_current_password(&mut errors)? {
+            if verify_password(new_password, &password_hash, &config)? {
+                let warning = AccountWarning::NoChangeInPassword(None);
+                warnings.push(field_alert("new_password", &warning));
+            } else {
+                let username = new_username.as_ref().unwrap_or(&old_username);
+                let user_inputs: Vec<&str> = [Some(username.as_str()), row.get("email")]
+                    .into_iter()
+                    .flatten()
+                    .collect();
+                let policy_error = password_policy_error(
+                    new_password,
+                    &user_inputs,
+                    |details| {
+                        Some(ErrorData {
+                            details,
+                            ..Default::default()
+                        })
+                    },
+                    &config,
+                )
+                .await?;
+                if let Some(err) = policy_error {
+                    errors.push(field_alert("new_password", &err));
+                } else {
+                    let new_hash = hash_encrypt_password(new_password, &config)?;
+                    transaction
+                        .execute(
+                            r#"
+                            UPDATE "users" SET "password" = $1, "password_change_reason" = NULL
+                            WHERE "id" = $2
+                            "#,
+                            &[&new_hash, &user_id],
+                        )
+                        .await
+                        .map_err(InternalError::new)?;
+                    revoked_sessions = Some(
+                        revoke_other_sessions(
+                            &transaction,
+                            &user_id,
+                            &current_user.session_id_hash,
+                        )
+                        .await?,
+                    );
+                    updated.insert("password_updated".to_owned(), json!(true));
+                }
+            }
+        }
+    }
+
+    if let Some(language) = &data.language {
+        transaction
+            .execute(
+                r#"UPDATE "users" SET "language" = $1 WHERE "id" = $2"#,
+                &[language, &user_id],
+            )
+            .await
+            .map_err(InternalError::new)?;
+        updated.insert("language".to_owned(), json!(language));
+    }
+
+    let totp_enabled = row.get::<_, bool>("totp_enabled");
+    let mut recovery_codes = None;
+    match &data.totp {
+        None => {}
+        Some(None) if !totp_enabled => {
+            errors.push(field_alert("totp", &AccountError::TotpNotEnabled(None)));
+        }
+        Some(Some(_)) if !updating_self => {
+            let error = AccountError::CannotEnableTotpForOthers(None);
+            errors.push(field_alert("totp", &error));
+        }
+        Some(Some(_)) if totp_enabled => {
+            errors.push(field_alert("totp", &AccountError::TotpAlreadyEnabled(None)));
+        }
+        // Any change to the second factor needs sudo mode, even on other users
+        Some(_) if !in_sudo => {
+            errors.push(field_alert("totp", &AuthError::SudoRequired(None)));
+        }
+        Some(None) => {
+            remove_totp(&transaction, &user_id).await?;
+            updated.insert("totp_enabled".to_owned(), json!(false));
+        }
+        Some(Some(totp)) => {
+            let savepoint = transaction
+                .transaction()
+                .await
+                .map_err(InternalError::new)?;
+            let error = match activate_totp_key(&savepoint, &user_id, totp, &config).await {
+                Ok(codes) => {
+                    savepoint.commit().await.map_err(InternalError::new)?;
+                    updated.insert("totp_enabled".to_owned(), json!(true));
+                    recovery_codes = Some(codes);
+                    None
+                }
+                Err(ActivateTotpKeyError::NoTotpKeyActive) => {
+                    Some(AccountError::NoTotpKeyActive(None))
+                }
+                Err(ActivateTotpKeyError::InvalidTotp) => {
+                    Some(AccountError::InvalidTotpVerification(None))
+                }
+                Err(ActivateTotpKeyError::TotpAlreadyEnabled) => {
+                    Some(AccountError::TotpAlreadyEnabled(None))
+                }
+                Err(ActivateTotpKeyError::InternalError(err)) => return Err(err.into()),
+            };
+            if let Some(error) = error {
+                errors.push(field_alert("totp", &error));
+            }
+        }
+    }
+    transaction.commit().await.map_err(InternalError::new)?;
+
+    if let Some(username) = &new_username {
+        audit.record(
+            "username-changed",
+            json!({ "user_id": user_id, "old": old_username, "new": username }),
+        );
+    }
+    if let Some(revoked_sessions) = &revoked_sessions {
+        audit.record(
+            "password-changed",
+            json!({ "user_id": user_id, "sessions_deleted": revoked_sessions.len() }),
+        );
+        for session_id_hash in revoked_sessions {
+            close_session_connections(session_id_hash, connections, rooms).await;
+        }
+    }
+    if let Some(language) = &data.language {
+        audit.record(
+            "language-changed",
+            json!({ "user_id": user_id, "language": language }),
+        );
+    }
+    match updated.get("totp_enabled") {
+        Some(JsonValue::Bool(true)) => audit.record("totp-enabled", json!({ "user_id": user_id })),
+        Some(_) => audit.record("totp-disabled", json!({ "user_id": user_id })),
+        None => {}
+    }
+
+    if !updated.is_empty() {
+        send_to_room(
+            &format!("user:{user_id}"),
+            json!({ "event": "user-updated", "data": updated }),
+            rooms,
+        )
+        .await;
+    }
+    // Only for the response; other connections must not see them
+    if let Some(codes) = recovery_codes {
+        updated.insert("recovery_codes".to_owned(), json!(codes));
+    }
+
+    let status = if errors.is_empty() {
+        StatusCode::OK
+    } else {
+        StatusCode::BAD_REQUEST
+    };
+    let mut body = json!({
+        "success": errors.is_empty(),
+        "data": updated,
+    });
+    if let Some(body) = body.as_object_mut() {
+        if !errors.is_empty() {
+            body.insert("errors".to_owned(), json!(errors));
+        }
+        if !warnings.is_empty() {
+            body.insert("warnings".to_owned(), json!(warnings));
+        }
+    }
+    build_json_response(body, |res| res.status(status))
+}
+
+// Expects a multipart/form-data body with the image in a single "icon" field
+#[handler]
+async fn put_icon(
+    AccountChanges { audit, rooms, .. }: AccountChanges<'_>,
+    config: Data<&Config>,
+    db: Data<&Pool>,
+    storage: Data<&DynStorage>,
+    current_user: Data<&CurrentUser>,
+    Path(user_id): Path<String>,
+    mut multipart: Multipart,
+) -> Result<Response> {
+    check_can_edit(&user_id, &current_user)?;
+
+    let mut upload = None;
+    while let Some(field) = multipart.next_field().await? {
+        if field.name() != Some("icon") {
+            continue;
+        }
+        if upload.is_some() {
+            return Err(IconError::MultipleFiles(None).into());
+        }
+        let max_size = config.icon.max_upload_size;
+        let mut data = Vec::new();
+        field
+            .into_async_read()
+            .take(u64::try_from(max_size).unwrap_or(u64::MAX).saturating_add(1))
+            .read_to_end(&mut data)
+            .await
+            .map_err(|err| {
+                GeneralError::InvalidData(Some(ErrorData {
+                    details: Some(err.to_string()),
+                    ..Default::default()
+                }))
+            })?;
+        if data.len() > max_size {
+            return Err(IconError::TooLarge(Some(ErrorData {
+                details: Some(format!("Maximum size: {max_size} bytes")),
+                ..Default::default()
+            }))
+            .into());
+        }
+        upload = Some(data);
+    }
+    let Some(upload) = upload else {
+        return Err(IconError::NoFile(None).into());
+    };
+
+    let processing_config = config.clone();
+    let icons = tokio::task::spawn_blocking(move || process_icon(&upload, &processing_config))
+        .await
+        .map_err(InternalError::new)??;
+
+    // The images are stored under a new ID before the row is locked, so the
+    // transaction only swaps the column
+    let icon_id = generate_token(config.user.icon_id_length);
+    for (size, data) in icons {
+        let blob = Blob {
+            content_type: ICON_CONTENT_TYPE.to_owned(),
+            data,
+        };
+        if let Err(err) = storage.put(&icon_key(&icon_id, size), blob).await {
+            delete_icon_blobs(&icon_id, &storage, &config).await;
+            return Err(err.into());
+        }
+    }
+    let swapped: Result<Option<String>> = async {
+        let mut db = db.get().await.map_err(InternalError::new)?;
+        let transaction = db.transaction().await.map_err(InternalError::new)?;
+        let old_icon = transaction
+            .query_opt(
+                r#"SELECT "icon" FROM "users" WHERE "id" = $1 FOR UPDATE"#,
+                &[&user_id],
+            )
+            .await
+            .map_err(InternalError::new)?
+            .ok_or(GeneralError::NotFound(None))?
+            .get("icon");
+        transaction
+            .execute(
+                r#"UPDATE "users" SET "icon" = $1 WHERE "id" = $2"#,
+                &[&icon_id, &user_id],
+            )
+            .await
+            .map_err(InternalError::new)?;
+        transaction.commit().await.map_err(InternalError::new)?;
+        Ok(old_icon)
+    }
+    .await;
+    let old_icon = match swapped {
+        Ok(old_icon) => old_icon,
+        Err(err) => {
+            delete_icon_blobs(&icon_id, &storage, &config).await;
+            return Err(err);
+        }
+    };
+    if let Some(old_icon) = old_icon {
+        delete_icon_blobs(&old_icon, &storage, &config).await;
+    }
+    audit.record("icon-changed", json!({ "user_id": user_id }));
+
+    let data = json!({ "icon": icon_id });
+    send_to_room(
+        &format!("user:{user_id}"),
+        json!({ "event": "user-updated", "data": data }),
+        rooms,
+    )
+    .await;
+    json_response(json!({ "success": true, "data": data }))
+}
+
+#[handler]
+async fn delete_icon(
+    audit: Data<&Audit>,
+    config: Data<&Config>,
+    db: Data<&Pool>,
+    rooms: Data<&AccountRooms>,
+    storage: Data<&DynStorage>,
+    current_user: Data<&CurrentUser>,
+    Path(user_id): Path<String>,
+) -> Result<Response> {
+    check_can_edit(&user_id, &current_user)?;
+
+    let mut db = db.get().await.map_err(InternalError::new)?;
+    let transaction = db.transaction().await.map_err(InternalError::new)?;
+    let old_icon: Option<String> = transaction
+        .query_opt(
+            r#"SELECT "icon" FROM "users" WHERE "id" = $1 FOR UPDATE"#,
+            &[&user_id],
+        )
+        .await
+        .map_err(InternalError::new)?
+        .ok_or(GeneralError::NotFound(None))?
+        .get("icon");
+
+    let data = json!({ "icon": null });
+    let Some(old_icon) = old_icon else {
+        return json_response(json!({ "success": true, "data": data }));
+    };
+    transaction
+        .execute(
+            r#"UPDATE "users" SET "icon" = NULL WHERE "id" = $1"#,
+            &[&user_id],
+        )
+        .await
+        .map_err(InternalError::new)?;
+    transaction.commit().await.map_err(InternalError::new)?;
+    delete_icon_blobs(&old_icon, &storage, &config).await;
+    audit.record("icon-removed", json!({ "user_id": user_id }));
+
+    send_to_room(
+        &format!("user:{user_id}"),
+        json!({ "event": "user-updated", "data": data }),
+        &rooms,
+    )
+    .await;
+    json_response(json!({ "success": true, "data": data }))
+}
+
+// Icon IDs change on every upload, so the stored images never change and can
+// be cached indefinitely
+#[handler]
+async fn get_icon(
+    req: &Request,
+    config: Data<&Config>,
+    storage: Data<&DynStorage>,
+    Path((icon_id, size)): Path<(String, u32)>,
+) -> Result<Response> {
+    if icon_id.len() != usize::from(config.user.icon_id_length)
+        || !icon_id.chars().all(|c| c.is_ascii_alphanumeric())
+        || !config.icon.sizes.contains(&size)
+    {
+        return Err(GeneralError::NotFound(None).into());
+    }
+    let etag = format!("\"{icon_id}-{size}\"");
+    let cache_control = format!("public, max-age={}, immutable", config.icon.cache_max_age);
+    let not_modified = req
+        .headers()
+        .get(header::IF_NONE_MATCH)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| {
+            value
+                .split(',')
+                .any(|tag| tag.trim() == etag || tag.trim() == "*")
+        });
+    let res = Response::builder()
+        .header(header::CACHE_CONTROL, cache_control)
+        .header(header::ETAG, etag);
+    // The ETag only depends on the icon ID, so a cached copy is confirmed
+    // without reading it from storage
+    if not_modified {
+        return Ok(res.status(StatusCode::NOT_MODIFIED).finish());
+    }
+
+    let Some(blob) = storage.get(&icon_key(&icon_id, size)).await? else {
+        return Err(GeneralError::NotFound(None).into());
+    };
+    Ok(res.content_type(blob.content_type).body(blob.data))
+}
+
 pub fn routes(config: &Config) -> Route {
     Route::new()
+        .at(
+            "/",
+            get!(get_users).with(AuthRequired::new(
+                AuthRequiredOptions::WITH_PERMISSIONS,
+                config.clone(),
+            )),
+        )
         .at(
             "/:user_id",
-            get!(get_user).with(AuthRequired::new(
+            get!(get_user.with(AuthRequired::new(
                 AuthRequiredOptions::WITH_USERNAME
+                    | AuthRequiredOptions::WITH_EMAIL
                     | AuthRequiredOptions::WITH_TOTP_STATUS
                     | AuthRequiredOptions::WITH_ICON
                     | AuthRequiredOptions::WITH_LOCALE
                     | AuthRequiredOptions::WITH_PERMISSIONS
                     | AuthRequiredOptions::WITH_SUDO_UNTIL,
                 config.clone(),
-            )),
+            )))
+            .delete(
+                delete_user
+                    .with(RateLimit::new("delete-user", config.clone()))
+                    .with(AuthRequired::new(
+                        AuthRequiredOptions::WITH_PERMISSIONS,
+                        config.clone(),
+                    ))
+                    .with(Csrf::new(config.clone())),
+            )
+            .put(
+                update_user
+                    .with(RateLimit::new("update-user", config.clone()))
+                    .with(AuthRequired::new(
+                        AuthRequiredOptions::WITH_PERMISSIONS
+                            | AuthRequiredOptions::WITH_SUDO_UNTIL
+                            | AuthRequiredOptions::SESSION_ONLY,
+                        config.clone(),
+                    ))
+                    .with(Csrf::new(config.clone())),
+            ),
         )
         .at(
-            "/me",
-            get!(get_me).with(AuthRequired::new(
-                AuthRequiredOptions::WITH_USERNAME
-                    | AuthRequiredOptions::WITH_TOTP_STATUS
-                    | AuthRequiredOptions::WITH_ICON
-                    | AuthRequiredOptions::WITH_LOCALE
-                    | AuthRequiredOptions::WITH_PERMISSIONS
-                    | AuthRequiredOptions::WITH_SUDO_UNTIL
-                    | AuthRequiredOptions::ALLOW_PASSWORD_CHANGE_REASON,
+            "/:user_id/icon",
+            poem::put(
+                put_icon
+                    .with(RateLimit::new("put-user-icon", config.clone()))
+                    .with(AuthRequired::new(
+                        AuthRequiredOptions::WITH_PERMISSIONS,
+                        config.clone(),
+                    ))
+                    .with(Csrf::new(config.clone())),
+            )
+            .delete(
+                delete_icon
+                    .with(AuthRequired::new(
+                        AuthRequiredOptions::WITH_PERMISSIONS,
+                        config.clone(),
+                    ))
+                    .with(Csrf::new(config.clone())),
+            ),
+        )
+        .at(
+            "/:user_id/permissions",
+            get!(get_user_permissions.with(AuthRequired::new(
+                AuthRequiredOptions::WITH_PERMISSIONS,
                 config.clone(),
-            )),
+            ))),
+        )
+        .at(
+            "/:user_id/permissions/:permission",
+            poem::put(
+                grant_permission
+                    .with(AuthRequired::new(
+                        AuthRequiredOptions::WITH_PERMISSIONS,
+                        config.clone(),
+                    ))
+                    .with(Csrf::new(config.clone())),
+            )
+            .delete(
+                revoke_permission
+                    .with(AuthRequired::new(
+                        AuthRequiredOptions::WITH_PERMISSIONS,
+                        config.clone(),
+                    ))
+                    .with(Csrf::new(config.clone())),
+            ),
+        )
+        .at(
+            "/:user_id/roles/:role",
+            poem::put(
+                grant_role
+                    .with(AuthRequired::new(
+                        AuthRequiredOptions::WITH_PERMISSIONS,
+                        config.clone(),
+                    ))
+                    .with(Csrf::new(config.clone())),
+            )
+            .delete(
+                revoke_role
+                    .with(AuthRequired::new(
+                        AuthRequiredOptions::WITH_PERMISSIONS,
+                        config.clone(),
+                    ))
+                    .with(Csrf::new(config.clone())),
+            ),
+        )
+        .at("/icons/:icon_id/:size", get!(get_icon))
+        .at(
+            "/me",
+            get!(get_me)
+                .with(RateLimit::new("users-me", config.clone()))
+                .with(AuthRequired::new(
+                    AuthRequiredOptions::WITH_USERNAME
+                        | AuthRequiredOptions::WITH_EMAIL
+                        | AuthRequiredOptions::WITH_TOTP_STATUS
+                        | AuthRequiredOptions::WITH_ICON
+                        | AuthRequiredOptions::WITH_LOCALE
+                        | AuthRequiredOptions::WITH_PERMISSIONS
+                        | AuthRequiredOptions::WITH_SUDO_UNTIL
+                        | AuthRequiredOptions::ALLOW_PASSWORD_CHANGE_REASON,
+                    config.clone(),
+                )),
         )
 }
@@ -0,0 +1,301 @@
+use std::{
+    collections::HashMap,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use hmac::{Hmac, Mac};
+use percent_encoding::{utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use poem::async_trait;
+use reqwest::{Client as HttpClient, Method, StatusCode, Url};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    config::{Config, S3ConfigInput, StorageBackendConfig},
+    error::InternalError,
+    util::utc_now,
+};
+
+#[derive(Clone, Debug)]
+pub struct Blob {
+    pub content_type: String,
+    pub data: Vec<u8>,
+}
+
+pub type Blobs = Arc<Mutex<HashMap<String, Blob>>>;
+
+// Keys are slash-separated paths made of URL-safe characters
+#[async_trait]
+pub trait Storage: Send + Sync {
+    async fn put(&self, key: &str, blob: Blob) -> Result<(), InternalError>;
+    async fn get(&self, key: &str) -> Result<Option<Blob>, InternalError>;
+    // Deleting a missing key is not an error
+    async fn delete(&self, key: &str) -> Result<(), InternalError>;
+}
+
+pub type DynStorage = Arc<dyn Storage>;
+
+pub fn make_storage(http: &HttpClient, config: &Config) -> DynStorage {
+    match &config.storage.backend {
+        StorageBackendConfig::File { dir } => {
+            std::fs::create_dir_all(dir).unwrap();
+            Arc::new(FileStorage { dir: dir.clone() })
+        }
+        StorageBackendConfig::Memory { blobs } => Arc::new(MemoryStorage {
+            blobs: blobs.clone(),
+        }),
+        StorageBackendConfig::S3(s3) => Arc::new(S3Storage {
+            http: http.clone(),
+            endpoint: Url::parse(&s3.endpoint).unwrap(),
+            config: s3.clone(),
+        }),
+    }
+}
+
+fn check_key(key: &str) -> Result<(), InternalError> {
+    let valid = !key.is_empty()
+        && key.split('/').all(|part| {
+            !part.is_empty()
+                && part != "."
+                && part != ".."
+                && part
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+        });
+    if !valid {
+        return Err(InternalError::new(format!("invalid storage key {key:?}")));
+    }
+    Ok(())
+}
+
+// Content types are stored next to the data in a file with this suffix
+const CONTENT_TYPE_SUFFIX: &str = ".content-type";
+
+pub struct FileStorage {
+    dir: PathBuf,
+}
+
+impl FileStorage {
+    fn path(&self, key: &str) -> Result<PathBuf, InternalError> {
+        check_key(key)?;
+        if key.ends_with(CONTENT_TYPE_SUFFIX) {
+            return Err(InternalError::new(format!("invalid storage key {key:?}")));
+        }
+        Ok(self.dir.join(key))
+    }
+}
+
+fn content_type_path(path: &Path) -> PathBuf {
+    let mut path = path.as_os_str().to_owned();
+    path.push(CONTENT_TYPE_SUFFIX);
+    path.into()
+}
+
+async fn remove_if_exists(path: &Path) -> Result<(), InternalError> {
+    match tokio::fs::remove_file(path).await {
+        Err(err) if err.kind() != ErrorKind::NotFound => Err(InternalError::new(err)),
+        _ => Ok(()),
+    }
+}
+
+#[async_trait]
+impl Storage for FileStorage {
+    async fn put(&self, key: &str, blob: Blob) -> Result<(), InternalError> {
+        let path = self.path(key)?;
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent)
+                .await
+                .map_err(InternalError::new)?;
+        }
+        tokio::fs::write(content_type_path(&path), blob.content_type)
+            .await
+            .map_err(InternalError::new)?;
+        tokio::fs::write(&path, blob.data)
+            .await
+            .map_err(InternalError::new)?;
+        Ok(())
+    }
+
+    async fn get(&self, key: &str) -> Result<Option<Blob>, InternalError> {
+        let path = self.path(key)?;
+        let data = match tokio::fs::read(&path).await {
+            Ok(data) => data,
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(InternalError::new(err)),
+        };
+        let content_type = tokio::fs::read_to_string(content_type_path(&path))
+            .await
+            .map_err(InternalError::new)?;
+        Ok(Some(Blob { content_type, data }))
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), InternalError> {
+        let path = self.path(key)?;
+        remove_if_exists(&path).await?;
+        remove_if_exists(&content_type_path(&path)).await
+    }
+}
+
+pub struct MemoryStorage {
+    blobs: Blobs,
+}
+
+impl MemoryStorage {
+    fn blobs(&self) -> Result<std::sync::MutexGuard<'_, HashMap<String, Blob>>, InternalError> {
+        self.blobs
+            .lock()
+            .map_err(|_| InternalError::new("storage blobs lock poisoned"))
+    }
+}
+
+#[async_trait]
+impl Storage for MemoryStorage {
+    async fn put(&self, key: &str, blob: Blob) -> Result<(), InternalError> {
+        check_key(key)?;
+        self.blobs()?.insert(key.to_owned(), blob);
+        Ok(())
+    }
+
+    async fn get(&self, key: &str) -> Result<Option<Blob>, InternalError> {
+        check_key(key)?;
+        Ok(self.blobs()?.get(key).cloned())
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), InternalError> {
+        check_key(key)?;
+        self.blobs()?.remove(key);
+        Ok(())
+    }
+}
+
+// Talks to S3-compatible services with path-style URLs and Signature Version 4;
+// see https://docs.aws.amazon.com/AmazonS3/latest/API/sig-v4-header-based-auth.html
+pub struct S3Storage {
+    http: HttpClient,
+    endpoint: Url,
+    config: S3ConfigInput,
+}
+
+// Everything except the unreserved characters of RFC 3986
+const S3_PATH_SEGMENT: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'.')
+    .remove(b'_')
+    .remove(b'~');
+
+fn hmac_sha256(key: &[u8], data: &str) -> Result<Vec<u8>, InternalError> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).map_err(InternalError::new)?;
+    mac.update(data.as_bytes());
+    Ok(mac.finalize().into_bytes().to_vec())
+}
+
+impl S3Storage {
+    async fn send(
+        &self,
+        method: Method,
+        key: &str,
+        body: Option<Blob>,
+    ) -> Result<reqwest::Response, InternalError> {
+        check_key(key)?;
+        let mut path = self.endpoint.path().trim_end_matches('/').to_owned();
+        for segment in [self.config.bucket.as_str()]
+            .into_iter()
+            .chain(key.split('/'))
+        {
+            path.push('/');
+            path.extend(utf8_percent_encode(segment, S3_PATH_SEGMENT));
+        }
+        let mut url = self.endpoint.clone();
+        url.set_path(&path);
+        let host = match (url.host_str(), url.port()) {
+            (Some(host), Some(port)) => format!("{host}:{port}"),
+            (Some(host), None) => host.to_owned(),
+            (None, _) => return Err(InternalError::new("storage.s3.endpoint has no host")),
+        };
+
+        let now = utc_now();
+        let timestamp = now.format("%Y%m%dT%H%M%SZ").to_string();
+        let date = now.format("%Y%m%d").to_string();
+        let payload_hash = hex::encode(Sha256::digest(
+            body.as_ref().map(|blob| &blob.data[..]).unwrap_or_default(),
+        ));
+        let signed_headers = "host;x-amz-content-sha256;x-amz-date";
+        let canonical_request = format!(
+            "{method}\n{path}\n\nhost:{host}\nx-amz-content-sha256:{payload_hash}\n\
+             x-amz-date:{timestamp}\n\n{signed_headers}\n{payload_hash}"
+        );
+        let scope = format!("{date}/{}/s3/aws4_request", self.config.region);
+        let string_to_sign = format!(
+            "AWS4-HMAC-SHA256\n{timestamp}\n{scope}\n{}",
+            hex::encode(Sha256::digest(canonical_request.as_bytes())),
+        );
+        let mut signing_key = format!("AWS4{}", self.config.secret_key).into_bytes();
+        for part in [date.as_str(), &self.config.region, "s3", "aws4_request"] {
+            signing_key = hmac_sha256(&signing_key, part)?;
+        }
+        let signature = hex::encode(hmac_sha256(&signing_key, &string_to_sign)?);
+        let authorization = format!(
+            "AWS4-HMAC-SHA256 Credential={}/{scope}, SignedHeaders={signed_headers}, \
+             Signature={signature}",
+            self.config.access_key,
+        );
+
+        let mut req = self
+            .http
+            .request(method, url)
+            .header("authorization", authorization)
+            .header("x-amz-content-sha256", payload_hash)
+            .header("x-amz-date", timestamp);
+        if let Some(blob) = body {
+            req = req
+                .header("content-type", blob.content_type)
+                .body(blob.data);
+        }
+        req.send().await.map_err(InternalError::new)
+    }
+}
+
+async fn s3_error(res: reqwest::Response) -> InternalError {
+    let status = res.status();
+    let body = res.text().await.unwrap_or_default();
+    InternalError::new(format!("S3 request failed with {status}: {body}"))
+}
+
+#[async_trait]
+impl Storage for S3Storage {
+    async fn put(&self, key: &str, blob: Blob) -> Result<(), InternalError> {
+        let res = self.send(Method::PUT, key, Some(blob)).await?;
+        if !res.status().is_success() {
+            return Err(s3_error(res).await);
+        }
+        Ok(())
+    }
+
+    async fn get(&self, key: &str) -> Result<Option<Blob>, InternalError> {
+        let res = self.send(Method::GET, key, None).await?;
+        if res.status() == StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+        if !res.status().is_success() {
+            return Err(s3_error(res).await);
+        }
+        let content_type = res
+            .headers()
+            .get("content-type")
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or("application/octet-stream")
+            .to_owned();
+        let data = res.bytes().await.map_err(InternalError::new)?.to_vec();
+        Ok(Some(Blob { content_type, data }))
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), InternalError> {
+        let res = self.send(Method::DELETE, key, None).await?;
+        // S3 answers 204 whether or not the object existed
+        if !res.status().is_success() && res.status() != StatusCode::NOT_FOUND {
+            return Err(s3_error(res).await);
+        }
+        Ok(())
+    }
+}
@@ -1,25 +1,37 @@
-use std::str::from_utf8;
+use std::net::IpAddr;
 
-use aes_gcm_siv::{aead::Aead, Nonce};
+use aes::cipher::{block_padding::Pkcs7, BlockDecryptMut, KeyIvInit};
+
+use aes_gcm_siv::{aead::Aead, Aes256GcmSiv, Nonce};
 use argon2::Argon2;
-use base64::engine::{general_purpose::URL_SAFE_NO_PAD as BASE64_URL_SAFE_NO_PAD, Engine};
+use base64::engine::{
+    general_purpose::{URL_SAFE as BASE64_URL_SAFE, URL_SAFE_NO_PAD as BASE64_URL_SAFE_NO_PAD},
+    Engine,
+};
 use chrono::{DateTime, Duration, SubsecRound, Utc};
-use deadpool_postgres::{Client, Pool};
+use deadpool_postgres::{Client, Pool, Transaction};
+use hmac::{Hmac, Mac};
 use password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
-use poem::{http::header, web::cookie::Cookie, Body, Request, Response, ResponseBuilder};
+use poem::{
+    http::header, web::cookie::Cookie, Addr, Body, Request, Response, ResponseBuilder,
+};
 use rand::{
     distributions::{Alphanumeric, Standard},
     thread_rng, Rng,
 };
 use secstr::SecStr;
 use serde::{Deserialize, Deserializer, Serialize};
-use serde_json::json;
+use serde_json::{json, Value as JsonValue};
+use sha2::Sha256;
+use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
+use qrcode::{render::svg, QrCode};
 use totp_lite::totp_custom;
 
 use crate::{
     config::Config,
     db::{Language, PasswordChangeReason, Permission},
-    error::InternalError,
+    error::{AuthError, ErrorData, InternalError, PermissionError},
+    middleware::CurrentUser,
 };
 
 // COOKIE UTILS
@@ -72,6 +84,10 @@ pub fn base64_urlsafe(data: &[u8]) -> String {
     BASE64_URL_SAFE_NO_PAD.encode(data)
 }
 
+pub fn decode_base64_urlsafe(data: &str) -> Result<Vec<u8>, base64::DecodeError> {
+    BASE64_URL_SAFE_NO_PAD.decode(data)
+}
+
 pub fn hash(text: &str) -> Vec<u8> {
     blake3::hash(text.as_bytes()).as_bytes().to_vec()
 }
@@ -91,31 +107,66 @@ pub fn encrypt<R: Rng>(
     rng.try_fill(&mut nonce).map_err(InternalError::new)?;
     let nonce = Nonce::from_slice(&nonce);
 
-    let mut encrypted = config
-        .aes
-        .encrypt(nonce, plaintext)
-        .map_err(|_| InternalError::new("encryption failed"))?;
+    let mut encrypted = vec![config.aes.active_id];
+    encrypted.extend(
+        config
+            .aes
+            .active()
+            .encrypt(nonce, plaintext)
+            .map_err(|_| InternalError::new("encryption failed"))?,
+    );
     encrypted.extend_from_slice(nonce);
     Ok(encrypted)
 }
 
-pub fn decrypt(ciphertext_with_nonce: &[u8], config: &Config) -> Result<Vec<u8>, InternalError> {
+pub fn decrypt(encrypted: &[u8], config: &Config) -> Result<Vec<u8>, InternalError> {
+    decrypt_with_key_id(encrypted, config).map(|(_, plaintext)| plaintext)
+}
+
+// None if the data is too short or does not authenticate under the key
+fn decrypt_with_key(key: &Aes256GcmSiv, ciphertext_with_nonce: &[u8]) -> Option<Vec<u8>> {
+    let ciphertext_length = ciphertext_with_nonce
+        .len()
+        .checked_sub(AES_GCM_SIV_NONCE_BYTES)?;
+    let (ciphertext, nonce) = ciphertext_with_nonce.split_at(ciphertext_length);
+    key.decrypt(Nonce::from_slice(nonce), ciphertext).ok()
+}
+
+// Also returns the ID of the key that decrypted the value, or None for values
+// written under the legacy key without a key ID prefix. A legacy value can
+// start with a byte that happens to be a key ID, so the legacy key is tried
+// whenever the prefixed key does not authenticate the data.
+pub fn decrypt_with_key_id(
+    encrypted: &[u8],
+    config: &Config,
+) -> Result<(Option<u8>, Vec<u8>), InternalError> {
     if config.dev.debug {
-        return Ok(ciphertext_with_nonce.to_vec());
+        return Ok((None, encrypted.to_vec()));
     }
 
-    let input_length = ciphertext_with_nonce.len();
-    if input_length < AES_GCM_SIV_NONCE_BYTES {
-        return Err(InternalError::new(
-            "encrypted data too short to contain nonce",
-        ));
+    let Some((&key_id, ciphertext_with_nonce)) = encrypted.split_first() else {
+        return Err(InternalError::new("encrypted data is empty"));
+    };
+    let key = config.aes.get(key_id);
+    if let Some(plaintext) = key.and_then(|key| decrypt_with_key(key, ciphertext_with_nonce)) {
+        return Ok((Some(key_id), plaintext));
     }
-    let (ciphertext, nonce) =
-        ciphertext_with_nonce.split_at(input_length - AES_GCM_SIV_NONCE_BYTES);
-    Ok(config
+    if let Some(plaintext) = config
         .aes
-        .decrypt(Nonce::from_slice(nonce), ciphertext.as_ref())
-        .map_err(|_| InternalError::new("decryption failed"))?)
+        .legacy
+        .as_ref()
+        .and_then(|legacy| decrypt_with_key(legacy, encrypted))
+    {
+        return Ok((None, plaintext));
+    }
+    match key {
+        Some(_) => Err(InternalError::new("decryption failed")),
+        None => Err(InternalError::new(format!("unknown aes key id {key_id}"))),
+    }
+}
+
+pub fn encryption_key_id(encrypted: &[u8]) -> Option<u8> {
+    encrypted.first().copied()
 }
 
 pub fn generate_token(length: u16) -> String {
@@ -126,8 +177,72 @@ pub fn generate_token(length: u16) -> String {
         .collect()
 }
 
+// EMAIL UTILS
+
+pub fn email_error(email: &str, config: &Config) -> Option<String> {
+    if email.chars().count() > config.user.email_max_length.into() {
+        Some(format!(
+            "email must be at most {} characters long",
+            config.user.email_max_length,
+        ))
+    } else if email.parse::<lettre::Address>().is_err() {
+        Some("invalid email address".to_owned())
+    } else {
+        None
+    }
+}
+
 // ENDPOINT INPUT UTILS
 
+pub struct ClientInfo {
+    pub ip: Option<IpAddr>,
+    pub user_agent: Option<String>,
+}
+
+impl ClientInfo {
+    pub fn from_request(req: &Request) -> Self {
+        Self {
+            ip: client_ip(req),
+            user_agent: req
+                .headers()
+                .get(header::USER_AGENT)
+                .and_then(|value| value.to_str().ok())
+                .map(ToOwned::to_owned),
+        }
+    }
+}
+
+pub fn bearer_token(req: &Request) -> Option<&str> {
+    req.headers()
+        .get(header::AUTHORIZATION)?
+        .to_str()
+        .ok()?
+        .strip_prefix("Bearer ")
+}
+
+// The reverse proxy adds the address it sees as a separate X-Forwarded-For
+// line after any the client sent, so only the last entry of the last line can
+// be trusted
+pub fn client_ip(req: &Request) -> Option<IpAddr> {
+    if let Some(forwarded_for) = req
+        .headers()
+        .get_all("X-Forwarded-For")
+        .iter()
+        .next_back()
+    {
+        return forwarded_for
+            .to_str()
+            .ok()?
+            .rsplit(',')
+            .next()
+            .and_then(|ip| ip.trim().parse().ok());
+    }
+    match req.remote_addr().0 {
+        Addr::SocketAddr(addr) => Some(addr.ip()),
+        _ => None,
+    }
+}
+
 pub async fn get_db(req: &Request) -> Result<Client, InternalError> {
     req.data::<Pool>()
         .ok_or_else(|| InternalError::new("no database initialized"))?
@@ -157,7 +272,7 @@ pub async fn get_session(req: &Request, config: &Config) -> Result<Session, Sess
 
     let db = get_db(req)
         .await
-        .map_err(|err| SessionError::InternalError(err))?;
+        .map_err(SessionError::InternalError)?;
     let query = r#"SELECT "csrf_token", "expires" FROM "sessions" WHERE "id" = $1"#;
     let row = db
         .query_opt(query, &[&hash(&session_cookie)])
@@ -219,7 +334,7 @@ pub async fn restore_session(
 
     let mut db = get_db(req)
         .await
-        .map_err(|err| RestoreSessionError::InternalError(err))?;
+        .map_err(RestoreSessionError::InternalError)?;
     let transaction = db
         .transaction()
         .await
@@ -305,7 +420,7 @@ pub async fn restore_session(
             JOIN "users" ON "remember_tokens"."user_id" = "users"."id"
         WHERE "remember_tokens"."id" = $1
     "#;
-    let remember_token_id_hash = hash(&remember_token_id);
+    let remember_token_id_hash = hash(remember_token_id);
     let row = transaction
         .query_opt(query, &[&remember_token_id_hash])
         .await
@@ -361,16 +476,29 @@ pub async fn restore_session(
         return Err(RestoreSessionError::AccountDisabled);
     }
 
+    let client = ClientInfo::from_request(req);
     let csrf_token = generate_token(config.csrf.token_length);
     let session_id = generate_token(config.session.id_length);
-    let session_expires = utc_now() + config.session.lifetime;
+    let now = utc_now();
+    let session_expires = now + config.session.lifetime;
     transaction
         .execute(
             r#"
-                INSERT INTO "sessions"("id", "user_id", "csrf_token", "expires", "sudo_until")
-                    VALUES ($1, $2, $3, $4, NULL)
+                INSERT INTO "sessions"(
+                    "id", "user_id", "remember_token_id", "csrf_token", "expires", "sudo_until",
+                    "created", "last_seen", "user_agent", "ip"
+                ) VALUES ($1, $2, $3, $4, $5, NULL, $6, $6, $7, $8)
             "#,
-            &[&hash(&session_id), &user_id, &csrf_token, &session_expires],
+            &[
+                &hash(&session_id),
+                &user_id,
+                &remember_token_id_hash,
+                &csrf_token,
+                &session_expires,
+                &now,
+                &client.user_agent,
+                &client.ip,
+            ],
         )
         .await
         .map_err(|err| RestoreSessionError::InternalError(InternalError::new(err)))?;
@@ -378,8 +506,18 @@ pub async fn restore_session(
     let new_secret = generate_token(config.remember_token.secret_length);
     transaction
         .execute(
-            r#"UPDATE "remember_tokens" SET "secret" = $1 WHERE "id" = $2"#,
-            &[&hash(&new_secret), &remember_token_id_hash],
+            r#"
+                UPDATE "remember_tokens"
+                SET "secret" = $1, "last_used" = $2, "user_agent" = $3, "ip" = $4
+                WHERE "id" = $5
+            "#,
+            &[
+                &hash(&new_secret),
+                &now,
+                &client.user_agent,
+                &client.ip,
+                &remember_token_id_hash,
+            ],
         )
         .await
         .map_err(|err| RestoreSessionError::InternalError(InternalError::new(err)))?;
@@ -445,7 +583,49 @@ pub fn hash_encrypt_password(password: &str, config: &Config) -> Result<Vec<u8>,
         .map_err(InternalError::new)?
         .serialize();
 
-    encrypt(hash.as_bytes(), &config, &mut thread_rng())
+    encrypt(hash.as_bytes(), config, &mut thread_rng())
+}
+
+// Password hashes imported from server-py are Fernet tokens rather than
+// values from encrypt; all Fernet tokens start with this prefix
+pub const LEGACY_FERNET_PREFIX: &[u8] = b"gAAAAA";
+
+fn is_legacy_password_hash(encrypted: &[u8], config: &Config) -> bool {
+    config.security.legacy_fernet_key.is_some() && encrypted.starts_with(LEGACY_FERNET_PREFIX)
+}
+
+fn decrypt_fernet(token: &[u8], key: &[u8]) -> Result<Vec<u8>, InternalError> {
+    const HMAC_BYTES: usize = 32;
+    const IV_START: usize = 9;
+    const CIPHERTEXT_START: usize = IV_START + 16;
+
+    let data = BASE64_URL_SAFE.decode(token).map_err(InternalError::new)?;
+    if data.len() < CIPHERTEXT_START + HMAC_BYTES || data[0] != 0x80 {
+        return Err(InternalError::new("invalid Fernet token"));
+    }
+    let (signed, tag) = data.split_at(data.len() - HMAC_BYTES);
+    let (signing_key, encryption_key) = key.split_at(16);
+
+    let mut mac = Hmac::<Sha256>::new_from_slice(signing_key).map_err(InternalError::new)?;
+    mac.update(signed);
+    mac.verify_slice(tag)
+        .map_err(|_| InternalError::new("invalid Fernet token signature"))?;
+
+    cbc::Decryptor::<aes::Aes128>::new_from_slices(
+        encryption_key,
+        &signed[IV_START..CIPHERTEXT_START],
+    )
+    .map_err(InternalError::new)?
+    .decrypt_padded_vec_mut::<Pkcs7>(&signed[CIPHERTEXT_START..])
+    .map_err(|_| InternalError::new("Fernet decryption failed"))
+}
+
+fn decrypt_password_hash(encrypted: &[u8], config: &Config) -> Result<String, InternalError> {
+    let decrypted = match &config.security.legacy_fernet_key {
+        Some(key) if is_legacy_password_hash(encrypted, config) => decrypt_fernet(encrypted, key)?,
+        _ => decrypt(encrypted, config)?,
+    };
+    String::from_utf8(decrypted).map_err(InternalError::new)
 }
 
 pub fn verify_password(
@@ -453,10 +633,9 @@ pub fn verify_password(
     encrypted_hash_and_nonce: &[u8],
     config: &Config,
 ) -> Result<bool, InternalError> {
-    let decrypted = decrypt(&encrypted_hash_and_nonce, config)?;
-    let hash = from_utf8(&decrypted).map_err(InternalError::new)?;
+    let hash = decrypt_password_hash(encrypted_hash_and_nonce, config)?;
     let parsed_hash =
-        PasswordHash::parse(hash, password_hash::Encoding::B64).map_err(InternalError::new)?;
+        PasswordHash::parse(&hash, password_hash::Encoding::B64).map_err(InternalError::new)?;
     match config
         .argon2
         .verify_password(password.as_bytes(), &parsed_hash)
@@ -467,6 +646,24 @@ pub fn verify_password(
     }
 }
 
+// True if the hash was made with other settings than the current config, such as
+// lower costs or the legacy format; only meaningful once the password has been verified
+pub fn password_needs_rehash(encrypted: &[u8], config: &Config) -> Result<bool, InternalError> {
+    if is_legacy_password_hash(encrypted, config) {
+        return Ok(true);
+    }
+    let hash = decrypt_password_hash(encrypted, config)?;
+    let parsed_hash =
+        PasswordHash::parse(&hash, password_hash::Encoding::B64).map_err(InternalError::new)?;
+    let params = argon2::Params::try_from(&parsed_hash).map_err(InternalError::new)?;
+    let current = config.argon2.params();
+    Ok(parsed_hash.algorithm != argon2::Algorithm::default().ident()
+        || parsed_hash.version != Some(argon2::Version::default().into())
+        || params.m_cost() != current.m_cost()
+        || params.t_cost() != current.t_cost()
+        || params.p_cost() != current.p_cost())
+}
+
 // REDIS UTILS
 
 pub fn redis_join(parts: &[&str], config: &Config) -> String {
@@ -476,7 +673,7 @@ pub fn redis_join(parts: &[&str], config: &Config) -> String {
 // RESPONSE UTILS
 
 pub fn json_response<T: Serialize>(data: T) -> poem::Result<Response> {
-    let body = Body::from_json(data).map_err(|err| InternalError::new(err))?;
+    let body = Body::from_json(data).map_err(InternalError::new)?;
     Ok(Response::builder()
         .content_type("application/json")
         .body(body))
@@ -494,7 +691,7 @@ where
 // ROUTE UTILS
 
 macro_rules! get {
-    ($endpoint:ident) => {
+    ($endpoint:expr) => {
         poem::get($endpoint).head($endpoint)
     };
 }
@@ -510,6 +707,248 @@ where
     Deserialize::deserialize(deserializer).map(Some)
 }
 
+// SESSION UTILS
+
+pub struct NewSession {
+    pub id: String,
+    pub csrf_token: String,
+    pub sudo_until: DateTime<Utc>,
+    pub remember_token: Option<String>,
+}
+
+pub async fn insert_session(
+    transaction: &Transaction<'_>,
+    user_id: &str,
+    remember: bool,
+    client: &ClientInfo,
+    config: &Config,
+) -> Result<NewSession, InternalError> {
+    let session_id = generate_token(config.session.id_length);
+    let csrf_token = generate_token(config.csrf.token_length);
+    let now = utc_now();
+    let session_expires = now + config.session.lifetime;
+    let sudo_until = now + config.session.sudo_lifetime;
+
+    let (remember_token, remember_token_id_hash) = if remember {
+        let remember_token_id = generate_token(config.remember_token.id_length);
+        let remember_token_id_hash = hash(&remember_token_id);
+        let remember_token_secret = generate_token(config.remember_token.secret_length);
+        let remember_token_secret_hash = hash(&remember_token_secret);
+
+        let insert_remember_token_query = r#"
+            INSERT INTO "remember_tokens"(
+                "id", "user_id", "secret", "created", "last_used", "user_agent", "ip"
+            ) VALUES ($1, $2, $3, $4, $4, $5, $6)
+        "#;
+        let inserted = transaction
+            .execute(
+                insert_remember_token_query,
+                &[
+                    &remember_token_id_hash,
+                    &user_id,
+                    &remember_token_secret_hash,
+                    &now,
+                    &client.user_agent,
+                    &client.ip,
+                ],
+            )
+            .await
+            .map_err(InternalError::new)?;
+        if inserted != 1 {
+            return Err(InternalError::new(format!(
+                "{} remember tokens inserted in insert_session",
+                inserted
+            )));
+        }
+        (
+            Some([remember_token_id, remember_token_secret].join(&config.remember_token.separator)),
+            Some(remember_token_id_hash),
+        )
+    } else {
+        (None, None)
+    };
+
+    let insert_session_query = r#"
+        INSERT INTO "sessions"(
+            "id", "user_id", "remember_token_id", "csrf_token", "expires", "sudo_until",
+            "created", "last_seen", "user_agent", "ip"
+        ) VALUES ($1, $2, $3, $4, $5, $6, $7, $7, $8, $9)
+    "#;
+    let inserted = transaction
+        .execute(
+            insert_session_query,
+            &[
+                &hash(&session_id),
+                &user_id,
+                &remember_token_id_hash,
+                &csrf_token,
+                &session_expires,
+                &sudo_until,
+                &now,
+                &client.user_agent,
+                &client.ip,
+            ],
+        )
+        .await
+        .map_err(InternalError::new)?;
+    if inserted != 1 {
+        return Err(InternalError::new(format!(
+            "{} sessions inserted in insert_session",
+            inserted
+        )));
+    }
+
+    Ok(NewSession {
+        id: session_id,
+        csrf_token,
+        sudo_until,
+        remember_token,
+    })
+}
+
+pub fn set_session_cookies(
+    res: ResponseBuilder,
+    session: &NewSession,
+    config: &Config,
+) -> ResponseBuilder {
+    let res = set_cookie(res, &config.session.cookie, &session.id, None, config);
+    let res = set_cookie(
+        res,
+        &config.csrf.cookie,
+        &session.csrf_token,
+        Some(config.csrf.cookie_lifetime),
+        config,
+    );
+    if let Some(remember_token) = &session.remember_token {
+        set_cookie(
+            res,
+            &config.remember_token.cookie,
+            remember_token,
+            Some(config.remember_token.cookie_lifetime),
+            config,
+        )
+    } else {
+        res
+    }
+}
+
+// Deletes the user's sessions and remember tokens except the ones of the
+// current session; returns the hashed IDs of the deleted sessions
+pub async fn revoke_other_sessions(
+    transaction: &Transaction<'_>,
+    user_id: &str,
+    session_id_hash: &[u8],
+) -> Result<Vec<Vec<u8>>, InternalError> {
+    let revoked_sessions = transaction
+        .query(
+            r#"
+            DELETE FROM "sessions" WHERE "user_id" = $1 AND "id" != $2
+            RETURNING "id"
+            "#,
+            &[&user_id, &session_id_hash],
+        )
+        .await
+        .map_err(InternalError::new)?
+        .into_iter()
+        .map(|row| row.get("id"))
+        .collect();
+    transaction
+        .execute(
+            r#"
+            DELETE FROM "remember_tokens"
+            WHERE "user_id" = $1 AND "id" IS DISTINCT FROM (
+                SELECT "remember_token_id" FROM "sessions" WHERE "id" = $2
+            )
+            "#,
+            &[&user_id, &session_id_hash],
+        )
+        .await
+        .map_err(InternalError::new)?;
+    Ok(revoked_sessions)
+}
+
+// Logging in while a self-requested deletion is pending reactivates the
+// account
+pub async fn cancel_account_deletion(
+    transaction: &Transaction<'_>,
+    user_id: &str,
+) -> Result<(), InternalError> {
+    transaction
+        .execute(
+            r#"
+            UPDATE "users"
+            SET "active" = true, "deletion_scheduled" = NULL, "deletion_cancellable" = false
+            WHERE "id" = $1
+            "#,
+            &[&user_id],
+        )
+        .await
+        .map_err(InternalError::new)?;
+    Ok(())
+}
+
+// PERMISSION UTILS
+
+// Granting or revoking permissions, directly or through a role, requires
+// ManagePermissions and holding every permission involved
+pub fn check_can_grant(
+    permissions: &[Permission],
+    current_user: &CurrentUser,
+) -> poem::Result<()> {
+    let held = current_user
+        .permissions
+        .as_ref()
+        .ok_or_else(|| InternalError::new("permissions not loaded in check_can_grant"))?;
+    if !held.contains(&Permission::ManagePermissions) {
+        return Err(AuthError::Forbidden(None).into());
+    }
+    let not_held: Vec<_> = permissions
+        .iter()
+        .filter(|permission| !held.contains(permission))
+        .map(|permission| json!(permission).as_str().unwrap().to_owned())
+        .collect();
+    if !not_held.is_empty() {
+        return Err(PermissionError::NotHeld(Some(ErrorData {
+            details: Some(format!("Not held: {}", not_held.join(", "))),
+            ..Default::default()
+        }))
+        .into());
+    }
+    Ok(())
+}
+
+// The direct permissions, roles and effective permissions of a user
+pub async fn user_permissions(
+    db: &Client,
+    user_id: &str,
+) -> Result<JsonValue, InternalError> {
+    let row = db
+        .query_one(
+            r#"
+            SELECT
+                ARRAY(
+                    SELECT "permission" FROM "permissions" WHERE "user_id" = $1
+                    ORDER BY "permission"
+                ) AS "permissions",
+                ARRAY(
+                    SELECT "role" FROM "user_roles" WHERE "user_id" = $1 ORDER BY "role"
+                ) AS "roles",
+                ARRAY(
+                    SELECT "permission" FROM "effective_permissions" WHERE "user_id" = $1
+                    ORDER BY "permission"
+                ) AS "effective_permissions"
+            "#,
+            &[&user_id],
+        )
+        .await
+        .map_err(InternalError::new)?;
+    Ok(json!({
+        "permissions": row.get::<_, Vec<Permission>>("permissions"),
+        "roles": row.get::<_, Vec<String>>("roles"),
+        "effective_permissions": row.get::<_, Vec<Permission>>("effective_permissions"),
+    }))
+}
+
 // TIME UTILS
 
 pub fn utc_now() -> DateTime<Utc> {
@@ -535,6 +974,27 @@ pub fn generate_totp_key(config: &Config) -> Vec<u8> {
         .collect()
 }
 
+pub fn totp_uri(key: &[u8], username: &str, config: &Config) -> String {
+    let algorithm = match config.totp.algorithm {
+        TotpAlgorithm::Sha1 => "SHA1",
+        TotpAlgorithm::Sha256 => "SHA256",
+        TotpAlgorithm::Sha512 => "SHA512",
+    };
+    let issuer = utf8_percent_encode(&config.totp.issuer, NON_ALPHANUMERIC);
+    format!(
+        "otpauth://totp/{issuer}:{username}?secret={secret}&issuer={issuer}&algorithm={algorithm}&digits={digits}&period={period}",
+        username = utf8_percent_encode(username, NON_ALPHANUMERIC),
+        secret = data_encoding::BASE32_NOPAD.encode(key),
+        digits = config.totp.digits,
+        period = config.totp.time_step,
+    )
+}
+
+pub fn qr_code_svg(data: &str) -> Result<String, InternalError> {
+    let code = QrCode::new(data).map_err(InternalError::new)?;
+    Ok(code.render::<svg::Color>().build())
+}
+
 pub fn generate_totp(key: &[u8], time: u64, config: &Config) -> String {
     let totp_fn = match config.totp.algorithm {
         TotpAlgorithm::Sha1 => totp_custom::<totp_lite::Sha1>,
@@ -586,3 +1046,193 @@ pub fn verify_totp(key: &[u8], totp: &str, config: &Config) -> Result<i64, Verif
     }
     Err(VerifyTotpError::InvalidTotp)
 }
+
+pub async fn replace_recovery_codes(
+    transaction: &Transaction<'_>,
+    user_id: &str,
+    config: &Config,
+) -> Result<Vec<String>, InternalError> {
+    transaction
+        .execute(
+            r#"DELETE FROM "totp_recovery_codes" WHERE "user_id" = $1"#,
+            &[&user_id],
+        )
+        .await
+        .map_err(InternalError::new)?;
+
+    let codes: Vec<_> = (0..config.totp.recovery_code_count)
+        .map(|_| generate_token(config.totp.recovery_code_length))
+        .collect();
+    for code in &codes {
+        transaction
+            .execute(
+                r#"INSERT INTO "totp_recovery_codes"("id", "user_id") VALUES ($1, $2)"#,
+                &[&hash(code), &user_id],
+            )
+            .await
+            .map_err(InternalError::new)?;
+    }
+    Ok(codes)
+}
+
+pub enum ActivateTotpKeyError {
+    InvalidTotp,
+    NoTotpKeyActive,
+    TotpAlreadyEnabled,
+    InternalError(InternalError),
+}
+
+impl From<InternalError> for ActivateTotpKeyError {
+    fn from(err: InternalError) -> Self {
+        Self::InternalError(err)
+    }
+}
+
+// Takes the key from new_totp_keys into use if the TOTP matches it; returns
+// the new recovery codes. The pending key is deleted even on failure, so the
+// caller decides whether to commit.
+pub async fn activate_totp_key(
+    transaction: &Transaction<'_>,
+    user_id: &str,
+    totp: &str,
+    config: &Config,
+) -> Result<Vec<String>, ActivateTotpKeyError> {
+    let new_key = transaction
+        .query_opt(
+            r#"
+            DELETE FROM "new_totp_keys" WHERE "user_id" = $1
+            RETURNING "key", "expires"
+            "#,
+            &[&user_id],
+        )
+        .await
+        .map_err(InternalError::new)?;
+    let Some(new_key) = new_key else {
+        return Err(ActivateTotpKeyError::NoTotpKeyActive);
+    };
+    if new_key.get::<_, DateTime<Utc>>("expires") < utc_now() {
+        return Err(ActivateTotpKeyError::NoTotpKeyActive);
+    }
+
+    let encrypted_key = new_key.get::<_, &[u8]>("key");
+    let key = decrypt(encrypted_key, config)?;
+    let totp_time_step = match verify_totp(&key, totp, config) {
+        Ok(totp_time_step) => totp_time_step,
+        Err(VerifyTotpError::InvalidTotp) => return Err(ActivateTotpKeyError::InvalidTotp),
+        Err(VerifyTotpError::InternalError(err)) => return Err(err.into()),
+    };
+
+    let updated = transaction
+        .execute(
+            r#"
+            UPDATE "users" SET "totp_key" = $1, "last_totp_time_step" = $2
+            WHERE "id" = $3 AND "totp_key" IS NULL
+            "#,
+            &[&encrypted_key, &totp_time_step, &user_id],
+        )
+        .await
+        .map_err(InternalError::new)?;
+    if updated != 1 {
+        return Err(ActivateTotpKeyError::TotpAlreadyEnabled);
+    }
+    Ok(replace_recovery_codes(transaction, user_id, config).await?)
+}
+
+pub async fn remove_totp(
+    transaction: &Transaction<'_>,
+    user_id: &str,
+) -> Result<(), InternalError> {
+    transaction
+        .execute(
+            r#"
+            UPDATE "users" SET "totp_key" = NULL, "last_totp_time_step" = NULL WHERE "id" = $1
+            "#,
+            &[&user_id],
+        )
+        .await
+        .map_err(InternalError::new)?;
+    transaction
+        .execute(
+            r#"DELETE FROM "totp_recovery_codes" WHERE "user_id" = $1"#,
+            &[&user_id],
+        )
+        .await
+        .map_err(InternalError::new)?;
+    Ok(())
+}
+
+pub enum SecondFactor {
+    Totp,
+    RecoveryCode { remaining: i64 },
+}
+
+pub enum VerifySecondFactorError {
+    InvalidTotp,
+    TotpReuse,
+    InternalError(InternalError),
+}
+
+impl From<InternalError> for VerifySecondFactorError {
+    fn from(err: InternalError) -> Self {
+        Self::InternalError(err)
+    }
+}
+
+// Accepts either a TOTP or a recovery code; a used recovery code is deleted
+pub async fn verify_second_factor(
+    transaction: &Transaction<'_>,
+    user_id: &str,
+    encrypted_totp_key: &[u8],
+    last_totp_time_step: Option<i64>,
+    code: &str,
+    config: &Config,
+) -> Result<SecondFactor, VerifySecondFactorError> {
+    let totp_key = decrypt(encrypted_totp_key, config)?;
+    match verify_totp(&totp_key, code, config) {
+        Ok(totp_time_step) => {
+            if let Some(last_totp_time_step) = last_totp_time_step {
+                if totp_time_step <= last_totp_time_step {
+                    return Err(VerifySecondFactorError::TotpReuse);
+                }
+            }
+
+            let updated = transaction
+                .execute(
+                    r#"UPDATE "users" SET "last_totp_time_step" = $1 WHERE "id" = $2"#,
+                    &[&totp_time_step, &user_id],
+                )
+                .await
+                .map_err(InternalError::new)?;
+            if updated != 1 {
+                Err(InternalError::new(format!(
+                    "last_totp_time_step updated for {} users in verify_second_factor",
+                    updated
+                )))?;
+            }
+            Ok(SecondFactor::Totp)
+        }
+        Err(VerifyTotpError::InvalidTotp) => {
+            let deleted = transaction
+                .execute(
+                    r#"DELETE FROM "totp_recovery_codes" WHERE "id" = $1 AND "user_id" = $2"#,
+                    &[&hash(code), &user_id],
+                )
+                .await
+                .map_err(InternalError::new)?;
+            if deleted == 0 {
+                return Err(VerifySecondFactorError::InvalidTotp);
+            }
+
+            let remaining = transaction
+                .query_one(
+                    r#"SELECT count(*) FROM "totp_recovery_codes" WHERE "user_id" = $1"#,
+                    &[&user_id],
+                )
+                .await
+                .map_err(InternalError::new)?
+                .get(0);
+            Ok(SecondFactor::RecoveryCode { remaining })
+        }
+        Err(VerifyTotpError::InternalError(err)) => Err(err.into()),
+    }
+}
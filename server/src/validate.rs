@@ -0,0 +1,100 @@
+use std::fmt::Display;
+
+use regex::Regex;
+
+use crate::{
+    config::Config,
+    error::{ErrorData, ValidationError, ValidationErrors},
+};
+
+// Implemented with #[derive(Validate)]; the rules are given per field, e.g.
+// #[validate(trim, length(min = 1, max = config.user.username_max_length))]
+pub trait Validate {
+    fn validate_fields(&mut self, path: &str, errors: &mut ValidationErrors, config: &Config);
+
+    fn validate(&mut self, config: &Config) -> Result<(), ValidationErrors> {
+        let mut errors = ValidationErrors::default();
+        self.validate_fields("", &mut errors, config);
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+}
+
+pub trait Length {
+    fn length(&self) -> usize;
+}
+
+impl Length for String {
+    fn length(&self) -> usize {
+        self.chars().count()
+    }
+}
+
+impl<T> Length for Vec<T> {
+    fn length(&self) -> usize {
+        self.len()
+    }
+}
+
+fn error_data(details: String) -> Option<ErrorData> {
+    Some(ErrorData {
+        details: Some(details),
+        ..Default::default()
+    })
+}
+
+pub fn check_length<T: Length>(
+    field: &str,
+    value: &T,
+    min: Option<usize>,
+    max: Option<usize>,
+    errors: &mut ValidationErrors,
+) {
+    let length = value.length();
+    if let Some(min) = min.filter(|&min| length < min) {
+        let details = format!("minimum length is {min}");
+        errors.push(field, ValidationError::TooShort(error_data(details)));
+    }
+    if let Some(max) = max.filter(|&max| length > max) {
+        let details = format!("maximum length is {max}");
+        errors.push(field, ValidationError::TooLong(error_data(details)));
+    }
+}
+
+pub fn check_one_of<T, U>(field: &str, value: &T, allowed: &[U], errors: &mut ValidationErrors)
+where
+    T: PartialEq<U>,
+    U: Display,
+{
+    if !allowed.iter().any(|allowed| value == allowed) {
+        let allowed: Vec<_> = allowed.iter().map(ToString::to_string).collect();
+        let details = format!("must be one of: {}", allowed.join(", "));
+        errors.push(field, ValidationError::NotAllowed(error_data(details)));
+    }
+}
+
+pub fn check_range<T: Display + PartialOrd>(
+    field: &str,
+    value: &T,
+    min: Option<T>,
+    max: Option<T>,
+    errors: &mut ValidationErrors,
+) {
+    if let Some(min) = min.filter(|min| value < min) {
+        let details = format!("minimum value is {min}");
+        errors.push(field, ValidationError::OutOfRange(error_data(details)));
+    } else if let Some(max) = max.filter(|max| value > max) {
+        let details = format!("maximum value is {max}");
+        errors.push(field, ValidationError::OutOfRange(error_data(details)));
+    }
+}
+
+pub fn check_regex(field: &str, value: &str, regex: &Regex, errors: &mut ValidationErrors) {
+    if !regex.is_match(value) {
+        let details = format!("must match {}", regex.as_str());
+        errors.push(field, ValidationError::InvalidFormat(error_data(details)));
+    }
+}
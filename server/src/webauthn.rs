@@ -0,0 +1,403 @@
+use ciborium::value::Value as CborValue;
+use deadpool_postgres::Transaction;
+use p256::ecdsa::{signature::Verifier, Signature, VerifyingKey};
+use redis::{AsyncCommands, Client as RedisClient};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+
+use crate::{
+    config::Config,
+    error::InternalError,
+    util::{base64_urlsafe, decode_base64_urlsafe, generate_token, redis_join, utc_now},
+};
+
+// COSE algorithm identifier for ECDSA with P-256 and SHA-256
+pub const ES256: i32 = -7;
+
+const FLAG_USER_PRESENT: u8 = 0x01;
+const FLAG_USER_VERIFIED: u8 = 0x04;
+const FLAG_ATTESTED_CREDENTIAL_DATA: u8 = 0x40;
+
+#[derive(Debug, thiserror::Error)]
+pub enum WebauthnError {
+    #[error("challenge does not match")]
+    ChallengeMismatch,
+    #[error("invalid attestation object")]
+    InvalidAttestationObject,
+    #[error("invalid authenticator data")]
+    InvalidAuthenticatorData,
+    #[error("invalid client data")]
+    InvalidClientData,
+    #[error("invalid base64 encoding")]
+    InvalidEncoding,
+    #[error("invalid signature")]
+    InvalidSignature,
+    #[error("origin does not match")]
+    OriginMismatch,
+    #[error("relying party ID does not match")]
+    RpIdMismatch,
+    #[error("signature counter went backwards; the authenticator may be cloned")]
+    SignCountMismatch,
+    #[error("unknown or expired challenge")]
+    UnknownChallenge,
+    #[error("unknown credential")]
+    UnknownCredential,
+    #[error("only ES256 credentials are supported")]
+    UnsupportedKey,
+    #[error("user presence was not confirmed")]
+    UserNotPresent,
+    #[error("user was not verified")]
+    UserNotVerified,
+}
+
+#[derive(Clone, Copy)]
+pub enum ChallengePurpose {
+    Authenticate,
+    Register,
+}
+
+impl ChallengePurpose {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Self::Authenticate => "authenticate",
+            Self::Register => "register",
+        }
+    }
+}
+
+fn challenge_key(purpose: ChallengePurpose, challenge: &str, config: &Config) -> String {
+    redis_join(&["webauthn-challenge", purpose.as_str(), challenge], config)
+}
+
+// The value stored with a challenge is the user it was issued to, or empty
+// for passwordless logins where the user is not known yet
+pub async fn begin_challenge(
+    purpose: ChallengePurpose,
+    user_id: Option<&str>,
+    redis: &RedisClient,
+    config: &Config,
+) -> Result<String, InternalError> {
+    let challenge = generate_token(config.webauthn.challenge_length);
+    let mut redis = redis
+        .get_async_connection()
+        .await
+        .map_err(InternalError::new)?;
+    redis
+        .set_ex::<_, _, ()>(
+            challenge_key(purpose, &challenge, config),
+            user_id.unwrap_or(""),
+            config.webauthn.challenge_lifetime,
+        )
+        .await
+        .map_err(InternalError::new)?;
+    Ok(challenge)
+}
+
+pub async fn take_challenge(
+    purpose: ChallengePurpose,
+    challenge: &str,
+    redis: &RedisClient,
+    config: &Config,
+) -> Result<Option<Option<String>>, InternalError> {
+    let mut redis = redis
+        .get_async_connection()
+        .await
+        .map_err(InternalError::new)?;
+    let value: Option<String> = redis::cmd("GETDEL")
+        .arg(challenge_key(purpose, challenge, config))
+        .query_async(&mut redis)
+        .await
+        .map_err(InternalError::new)?;
+    Ok(value.map(|user_id| Some(user_id).filter(|user_id| !user_id.is_empty())))
+}
+
+#[derive(Deserialize)]
+struct ClientData {
+    #[serde(rename = "type")]
+    type_: String,
+    challenge: String,
+    origin: String,
+}
+
+// The challenge is read before verification so it can be looked up in Redis
+pub fn client_data_challenge(client_data_json: &[u8]) -> Result<String, WebauthnError> {
+    let client_data: ClientData =
+        serde_json::from_slice(client_data_json).map_err(|_| WebauthnError::InvalidClientData)?;
+    let challenge = decode_base64_urlsafe(&client_data.challenge)
+        .map_err(|_| WebauthnError::InvalidClientData)?;
+    String::from_utf8(challenge).map_err(|_| WebauthnError::InvalidClientData)
+}
+
+fn verify_client_data(
+    client_data_json: &[u8],
+    type_: &str,
+    challenge: &str,
+    config: &Config,
+) -> Result<(), WebauthnError> {
+    let client_data: ClientData =
+        serde_json::from_slice(client_data_json).map_err(|_| WebauthnError::InvalidClientData)?;
+    if client_data.type_ != type_ {
+        return Err(WebauthnError::InvalidClientData);
+    }
+    if client_data.challenge != base64_urlsafe(challenge.as_bytes()) {
+        return Err(WebauthnError::ChallengeMismatch);
+    }
+    if config.client.origin != client_data.origin.as_str() {
+        return Err(WebauthnError::OriginMismatch);
+    }
+    Ok(())
+}
+
+struct AuthenticatorData<'a> {
+    flags: u8,
+    sign_count: u32,
+    attested_credential_data: &'a [u8],
+}
+
+fn parse_authenticator_data<'a>(
+    data: &'a [u8],
+    require_user_verification: bool,
+    config: &Config,
+) -> Result<AuthenticatorData<'a>, WebauthnError> {
+    if data.len() < 37 {
+        return Err(WebauthnError::InvalidAuthenticatorData);
+    }
+    if data[..32] != Sha256::digest(config.webauthn.rp_id.as_bytes())[..] {
+        return Err(WebauthnError::RpIdMismatch);
+    }
+    let flags = data[32];
+    if flags & FLAG_USER_PRESENT == 0 {
+        return Err(WebauthnError::UserNotPresent);
+    }
+    if require_user_verification && flags & FLAG_USER_VERIFIED == 0 {
+        return Err(WebauthnError::UserNotVerified);
+    }
+    Ok(AuthenticatorData {
+        flags,
+        sign_count: u32::from_be_bytes([data[33], data[34], data[35], data[36]]),
+        attested_credential_data: &data[37..],
+    })
+}
+
+fn cbor_map_get(map: &[(CborValue, CborValue)], key: i64) -> Option<&CborValue> {
+    map.iter()
+        .find(|(k, _)| matches!(k, CborValue::Integer(k) if i128::from(*k) == key.into()))
+        .map(|(_, v)| v)
+}
+
+// Converts a COSE_Key to an uncompressed SEC1 point
+fn cose_es256_public_key(cose_key: &CborValue) -> Result<Vec<u8>, WebauthnError> {
+    let Some(map) = cose_key.as_map() else {
+        return Err(WebauthnError::UnsupportedKey);
+    };
+    let int = |key| match cbor_map_get(map, key) {
+        Some(CborValue::Integer(value)) => Some(i128::from(*value)),
+        _ => None,
+    };
+    let bytes = |key| match cbor_map_get(map, key) {
+        Some(CborValue::Bytes(value)) if value.len() == 32 => Some(value.as_slice()),
+        _ => None,
+    };
+    // kty 2 = EC2, alg -7 = ES256, crv 1 = P-256
+    if int(1) != Some(2) || int(3) != Some(ES256.into()) || int(-1) != Some(1) {
+        return Err(WebauthnError::UnsupportedKey);
+    }
+    let (Some(x), Some(y)) = (bytes(-2), bytes(-3)) else {
+        return Err(WebauthnError::UnsupportedKey);
+    };
+
+    let public_key = [&[0x04], x, y].concat();
+    VerifyingKey::from_sec1_bytes(&public_key).map_err(|_| WebauthnError::UnsupportedKey)?;
+    Ok(public_key)
+}
+
+pub struct NewCredential {
+    pub id: Vec<u8>,
+    pub public_key: Vec<u8>,
+    pub sign_count: u32,
+}
+
+// Attestation statements are not verified, which is equivalent to requesting
+// the "none" attestation conveyance
+pub fn verify_registration(
+    client_data_json: &[u8],
+    attestation_object: &[u8],
+    challenge: &str,
+    config: &Config,
+) -> Result<NewCredential, WebauthnError> {
+    verify_client_data(client_data_json, "webauthn.create", challenge, config)?;
+
+    let attestation: CborValue = ciborium::de::from_reader(attestation_object)
+        .map_err(|_| WebauthnError::InvalidAttestationObject)?;
+    let auth_data = attestation
+        .as_map()
+        .and_then(|map| {
+            map.iter()
+                .find(|(key, _)| key.as_text() == Some("authData"))
+                .and_then(|(_, value)| value.as_bytes())
+        })
+        .ok_or(WebauthnError::InvalidAttestationObject)?;
+
+    let auth_data = parse_authenticator_data(auth_data, false, config)?;
+    if auth_data.flags & FLAG_ATTESTED_CREDENTIAL_DATA == 0 {
+        return Err(WebauthnError::InvalidAuthenticatorData);
+    }
+    // AAGUID (16 bytes), credential ID length (2 bytes), credential ID, COSE key
+    let data = auth_data.attested_credential_data;
+    if data.len() < 18 {
+        return Err(WebauthnError::InvalidAuthenticatorData);
+    }
+    let id_length: usize = u16::from_be_bytes([data[16], data[17]]).into();
+    if data.len() < 18 + id_length {
+        return Err(WebauthnError::InvalidAuthenticatorData);
+    }
+    let id = data[18..18 + id_length].to_vec();
+    let cose_key: CborValue = ciborium::de::from_reader(&data[18 + id_length..])
+        .map_err(|_| WebauthnError::InvalidAuthenticatorData)?;
+
+    Ok(NewCredential {
+        id,
+        public_key: cose_es256_public_key(&cose_key)?,
+        sign_count: auth_data.sign_count,
+    })
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AssertionData {
+    pub credential_id: String,
+    pub client_data_json: String,
+    pub authenticator_data: String,
+    pub signature: String,
+}
+
+pub struct Assertion<'a> {
+    pub client_data_json: &'a [u8],
+    pub authenticator_data: &'a [u8],
+    pub signature: &'a [u8],
+}
+
+// Returns the new signature counter to store for the credential
+pub fn verify_assertion(
+    assertion: &Assertion,
+    challenge: &str,
+    public_key: &[u8],
+    stored_sign_count: u32,
+    require_user_verification: bool,
+    config: &Config,
+) -> Result<u32, WebauthnError> {
+    verify_client_data(assertion.client_data_json, "webauthn.get", challenge, config)?;
+    let auth_data =
+        parse_authenticator_data(assertion.authenticator_data, require_user_verification, config)?;
+
+    let public_key =
+        VerifyingKey::from_sec1_bytes(public_key).map_err(|_| WebauthnError::UnsupportedKey)?;
+    let signature =
+        Signature::from_der(assertion.signature).map_err(|_| WebauthnError::InvalidSignature)?;
+    let signed_data = [
+        assertion.authenticator_data,
+        &Sha256::digest(assertion.client_data_json),
+    ]
+    .concat();
+    public_key
+        .verify(&signed_data, &signature)
+        .map_err(|_| WebauthnError::InvalidSignature)?;
+
+    // Authenticators that do not keep a counter always report zero
+    if (auth_data.sign_count != 0 || stored_sign_count != 0)
+        && auth_data.sign_count <= stored_sign_count
+    {
+        return Err(WebauthnError::SignCountMismatch);
+    }
+    Ok(auth_data.sign_count)
+}
+
+pub enum AuthenticateError {
+    Invalid(WebauthnError),
+    InternalError(InternalError),
+}
+
+impl From<InternalError> for AuthenticateError {
+    fn from(err: InternalError) -> Self {
+        Self::InternalError(err)
+    }
+}
+
+impl From<WebauthnError> for AuthenticateError {
+    fn from(err: WebauthnError) -> Self {
+        Self::Invalid(err)
+    }
+}
+
+// Checks an assertion against a stored credential and returns its owner;
+// as a second factor, user_id restricts it to that user's credentials
+pub async fn authenticate(
+    data: &AssertionData,
+    user_id: Option<&str>,
+    require_user_verification: bool,
+    transaction: &Transaction<'_>,
+    redis: &RedisClient,
+    config: &Config,
+) -> Result<String, AuthenticateError> {
+    let decode =
+        |value: &str| decode_base64_urlsafe(value).map_err(|_| WebauthnError::InvalidEncoding);
+    let credential_id = decode(&data.credential_id)?;
+    let client_data_json = decode(&data.client_data_json)?;
+    let authenticator_data = decode(&data.authenticator_data)?;
+    let signature = decode(&data.signature)?;
+
+    let challenge = client_data_challenge(&client_data_json)?;
+    let Some(challenge_user_id) =
+        take_challenge(ChallengePurpose::Authenticate, &challenge, redis, config).await?
+    else {
+        return Err(WebauthnError::UnknownChallenge.into());
+    };
+
+    let credential = transaction
+        .query_opt(
+            r#"
+            SELECT "user_id", "public_key", "sign_count" FROM "webauthn_credentials"
+            WHERE "id" = $1
+            "#,
+            &[&credential_id],
+        )
+        .await
+        .map_err(InternalError::new)?;
+    let Some(credential) = credential else {
+        return Err(WebauthnError::UnknownCredential.into());
+    };
+    let credential_user_id = credential.get::<_, String>("user_id");
+    if user_id.is_some_and(|user_id| user_id != credential_user_id)
+        || challenge_user_id.is_some_and(|user_id| user_id != credential_user_id)
+    {
+        return Err(WebauthnError::UnknownCredential.into());
+    }
+
+    let sign_count = verify_assertion(
+        &Assertion {
+            client_data_json: &client_data_json,
+            authenticator_data: &authenticator_data,
+            signature: &signature,
+        },
+        &challenge,
+        credential.get("public_key"),
+        credential
+            .get::<_, i64>("sign_count")
+            .try_into()
+            .map_err(InternalError::new)?,
+        require_user_verification,
+        config,
+    )?;
+
+    transaction
+        .execute(
+            r#"
+            UPDATE "webauthn_credentials" SET "sign_count" = $1, "last_used" = $2
+            WHERE "id" = $3
+            "#,
+            &[&i64::from(sign_count), &utc_now(), &credential_id],
+        )
+        .await
+        .map_err(InternalError::new)?;
+    Ok(credential_user_id)
+}
@@ -13,7 +13,7 @@ use tokio::{
 use crate::{
     config::Config,
     error::{AuthError, ErrorData, GeneralError, InternalError, WebSocketError},
-    util::{generate_token, redis_join},
+    util::{base64_urlsafe, generate_token, redis_join},
 };
 
 pub type AccountConnections = Arc<Mutex<HashMap<String, HashMap<String, WebSocketConnection>>>>;
@@ -141,6 +141,10 @@ impl WebSocketConnection {
         }
     }
 
+    pub async fn close(&self) {
+        let _ = self.sink.lock().await.close().await;
+    }
+
     pub async fn send(&self, message: JsonValue) -> std::io::Result<()> {
         self.sink
             .lock()
@@ -158,6 +162,30 @@ impl WebSocketConnection {
     }
 }
 
+pub async fn close_session_connections(
+    session_id_hash: &[u8],
+    connections: &AccountConnections,
+    rooms: &AccountRooms,
+) {
+    let session_connections = connections
+        .lock()
+        .await
+        .remove(&base64_urlsafe(session_id_hash));
+    if let Some(session_connections) = session_connections {
+        for (_, mut connection) in session_connections {
+            connection.disconnect(rooms).await;
+            connection.close().await;
+        }
+    }
+}
+
+pub async fn send_to_room(room: &str, message: JsonValue, rooms: &AccountRooms) {
+    if let Some(sender) = rooms.lock().await.get(room) {
+        // Only fails when nobody is listening
+        let _ = sender.send(message.to_string());
+    }
+}
+
 fn get_event(message: String) -> Result<AccountEvent, GeneralError> {
     serde_json::from_str::<AccountEvent>(&message)
         .map_err(|err| GeneralError::InvalidData(Some(ErrorData {
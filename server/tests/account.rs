@@ -0,0 +1,1715 @@
+use async_trait::async_trait;
+use std::io::Cursor;
+
+use chrono::{DateTime, Duration, Utc};
+use deadpool_postgres::{tokio_postgres::NoTls, ClientWrapper};
+use image::{ImageOutputFormat, Rgb, RgbImage};
+use poem::{
+    http::{
+        header::{AUTHORIZATION, CACHE_CONTROL, COOKIE, IF_NONE_MATCH},
+        StatusCode,
+    },
+    test::{TestClient, TestForm, TestFormField, TestResponse},
+    Endpoint, Response,
+};
+use serde_json::json;
+use test_context::{test_context, AsyncTestContext};
+
+use dodatok::{
+    account_deletion,
+    config::Config,
+    db::{AccessTokenScope, Permission},
+    storage::{make_storage, Blob},
+    util::{base64_urlsafe, generate_token, generate_totp, hash, utc_now, verify_password},
+    webauthn::{self, Assertion, WebauthnError},
+};
+use macros::test_with_client;
+
+mod setup;
+mod util;
+
+use util::{
+    assert_error, assert_error_with_details, assert_validation_errors, check_response,
+    delete_with_session, get_with_session, get_with_token, post_with_csrf, post_with_session,
+    post_with_token, put_form_with_session, put_with_session, take_outbox, token_from_email,
+};
+
+#[test_with_client]
+async fn totp_key_sudo_required() {
+    let client = TestClient::new(&ctx.endpoint);
+    let user = setup::add_user('a', false, &ctx.config).await;
+    let session = setup::add_session(&user, false, &ctx.config).await;
+
+    let res = post_with_session(
+        &client,
+        "/account/totp-key",
+        json!({}),
+        &session,
+        &ctx.config,
+    )
+    .await;
+    check_response(&res, StatusCode::FORBIDDEN);
+    assert_error(res, "auth", "sudo-required").await;
+
+    setup::set_sudo_until(&session.0, utc_now() - Duration::seconds(1), &ctx.config).await;
+    let res = post_with_session(
+        &client,
+        "/account/totp-key",
+        json!({}),
+        &session,
+        &ctx.config,
+    )
+    .await;
+    check_response(&res, StatusCode::FORBIDDEN);
+    assert_error(res, "auth", "sudo-required").await;
+}
+
+#[test_with_client]
+async fn totp_key_already_enabled() {
+    let client = TestClient::new(&ctx.endpoint);
+    let user = setup::add_user('a', true, &ctx.config).await;
+    let session = setup::add_session(&user, false, &ctx.config).await;
+    setup::set_sudo_until(&session.0, utc_now() + Duration::hours(1), &ctx.config).await;
+
+    let res = post_with_session(
+        &client,
+        "/account/totp-key",
+        json!({}),
+        &session,
+        &ctx.config,
+    )
+    .await;
+    check_response(&res, StatusCode::BAD_REQUEST);
+    assert_error(res, "account", "totp-already-enabled").await;
+}
+
+#[test_with_client]
+async fn enable_totp_no_key_active() {
+    let client = TestClient::new(&ctx.endpoint);
+    let user = setup::add_user('a', false, &ctx.config).await;
+    let session = setup::add_session(&user, false, &ctx.config).await;
+    setup::set_sudo_until(&session.0, utc_now() + Duration::hours(1), &ctx.config).await;
+
+    let res = post_with_session(
+        &client,
+        "/account/totp",
+        json!({ "totp": "000000" }),
+        &session,
+        &ctx.config,
+    )
+    .await;
+    check_response(&res, StatusCode::BAD_REQUEST);
+    assert_error(res, "account", "no-totp-key-active").await;
+
+    // An expired key is treated as missing
+    let res = post_with_session(
+        &client,
+        "/account/totp-key",
+        json!({}),
+        &session,
+        &ctx.config,
+    )
+    .await;
+    check_response(&res, StatusCode::OK);
+    ctx.db
+        .execute(
+            r#"UPDATE "new_totp_keys" SET "expires" = $1"#,
+            &[&(utc_now() - Duration::seconds(1))],
+        )
+        .await
+        .unwrap();
+    let res = post_with_session(
+        &client,
+        "/account/totp",
+        json!({ "totp": "000000" }),
+        &session,
+        &ctx.config,
+    )
+    .await;
+    check_response(&res, StatusCode::BAD_REQUEST);
+    assert_error(res, "account", "no-totp-key-active").await;
+}
+
+#[test_with_client]
+async fn enable_totp_success() {
+    let client = TestClient::new(&ctx.endpoint);
+    let user = setup::add_user('a', false, &ctx.config).await;
+    let session = setup::add_session(&user, false, &ctx.config).await;
+    setup::set_sudo_until(&session.0, utc_now() + Duration::hours(1), &ctx.config).await;
+
+    let res = post_with_session(
+        &client,
+        "/account/totp-key",
+        json!({}),
+        &session,
+        &ctx.config,
+    )
+    .await;
+    check_response(&res, StatusCode::OK);
+    let json = res.json().await;
+    let data = json.value().object().get("data").object();
+    data.assert_len(4);
+    let uri = data.get("uri").string();
+    assert!(uri.starts_with("otpauth://totp/"));
+    assert!(uri.contains(&format!("secret={}", data.get("key").string())));
+    assert!(data.get("qr_code").string().starts_with("<?xml"));
+
+    // The test config disables encryption, so the stored key is the plain key
+    let row = ctx
+        .db
+        .query_one(
+            r#"SELECT "key" FROM "new_totp_keys" WHERE "user_id" = $1"#,
+            &[&user.id],
+        )
+        .await
+        .unwrap();
+    let key = row.get::<_, Vec<u8>>("key");
+
+    let res = post_with_session(
+        &client,
+        "/account/totp",
+        json!({ "totp": "invalid" }),
+        &session,
+        &ctx.config,
+    )
+    .await;
+    check_response(&res, StatusCode::BAD_REQUEST);
+    assert_validation_errors(res, &[("totp", "invalid-format"), ("totp", "too-long")]).await;
+
+    let wrong_totp = generate_totp(&key, utc_now().timestamp() as u64 - 3600, &ctx.config);
+    let res = post_with_session(
+        &client,
+        "/account/totp",
+        json!({ "totp": wrong_totp }),
+        &session,
+        &ctx.config,
+    )
+    .await;
+    check_response(&res, StatusCode::BAD_REQUEST);
+    assert_error(res, "account", "invalid-totp-verification").await;
+
+    let totp = generate_totp(&key, utc_now().timestamp() as u64, &ctx.config);
+    let res = post_with_session(
+        &client,
+        "/account/totp",
+        json!({ "totp": totp }),
+        &session,
+        &ctx.config,
+    )
+    .await;
+    check_response(&res, StatusCode::OK);
+    let json = res.json().await;
+    let recovery_codes = json
+        .value()
+        .object()
+        .get("data")
+        .object()
+        .get("recovery_codes")
+        .string_array();
+    assert_eq!(recovery_codes.len(), ctx.config.totp.recovery_code_count);
+    for code in recovery_codes {
+        let row = ctx
+            .db
+            .query_one(
+                r#"SELECT "user_id" FROM "totp_recovery_codes" WHERE "id" = $1"#,
+                &[&hash(code)],
+            )
+            .await
+            .unwrap();
+        assert_eq!(row.get::<_, &str>("user_id"), user.id);
+    }
+
+    let row = ctx
+        .db
+        .query_one(
+            r#"SELECT "totp_key", "last_totp_time_step" FROM "users" WHERE "id" = $1"#,
+            &[&user.id],
+        )
+        .await
+        .unwrap();
+    assert_eq!(row.get::<_, Vec<u8>>("totp_key"), key);
+    assert!(row.get::<_, Option<i64>>("last_totp_time_step").is_some());
+    let row = ctx
+        .db
+        .query_one(r#"SELECT count(*) FROM "new_totp_keys""#, &[])
+        .await
+        .unwrap();
+    assert_eq!(row.get::<_, i64>(0), 0);
+}
+
+#[test_with_client]
+async fn disable_totp() {
+    let client = TestClient::new(&ctx.endpoint);
+    let user = setup::add_user('a', true, &ctx.config).await;
+    let session = setup::add_session(&user, false, &ctx.config).await;
+    let recovery_code = generate_token(ctx.config.totp.recovery_code_length);
+    ctx.db
+        .execute(
+            r#"INSERT INTO "totp_recovery_codes"("id", "user_id") VALUES ($1, $2)"#,
+            &[&hash(&recovery_code), &user.id],
+        )
+        .await
+        .unwrap();
+
+    let res = delete_with_session(
+        &client,
+        "/account/totp",
+        json!({ "password": "wrong password", "totp": recovery_code }),
+        &session,
+        &ctx.config,
+    )
+    .await;
+    check_response(&res, StatusCode::BAD_REQUEST);
+    assert_error(res, "account", "invalid-current-password").await;
+
+    let res = delete_with_session(
+        &client,
+        "/account/totp",
+        json!({ "password": user.password, "totp": "000000000" }),
+        &session,
+        &ctx.config,
+    )
+    .await;
+    check_response(&res, StatusCode::BAD_REQUEST);
+    assert_error(res, "auth", "invalid-totp").await;
+
+    // A recovery code works in place of a TOTP
+    let res = delete_with_session(
+        &client,
+        "/account/totp",
+        json!({ "password": user.password, "totp": recovery_code }),
+        &session,
+        &ctx.config,
+    )
+    .await;
+    check_response(&res, StatusCode::OK);
+    res.assert_json(json!({ "success": true, "data": null }))
+        .await;
+
+    let row = ctx
+        .db
+        .query_one(
+            r#"SELECT "totp_key" FROM "users" WHERE "id" = $1"#,
+            &[&user.id],
+        )
+        .await
+        .unwrap();
+    assert!(row.get::<_, Option<Vec<u8>>>("totp_key").is_none());
+    let row = ctx
+        .db
+        .query_one(r#"SELECT count(*) FROM "totp_recovery_codes""#, &[])
+        .await
+        .unwrap();
+    assert_eq!(row.get::<_, i64>(0), 0);
+
+    let totp = generate_totp(
+        user.totp_key.unwrap().as_bytes(),
+        utc_now().timestamp() as u64,
+        &ctx.config,
+    );
+    let res = delete_with_session(
+        &client,
+        "/account/totp",
+        json!({ "password": user.password, "totp": totp }),
+        &session,
+        &ctx.config,
+    )
+    .await;
+    check_response(&res, StatusCode::BAD_REQUEST);
+    assert_error(res, "account", "totp-not-enabled").await;
+}
+
+#[test_with_client]
+async fn change_password_invalid() {
+    let client = TestClient::new(&ctx.endpoint);
+    let user = setup::add_user('a', false, &ctx.config).await;
+    let session = setup::add_session(&user, false, &ctx.config).await;
+
+    let res = post_with_session(
+        &client,
+        "/account/password",
+        json!({ "current_password": "wrong password", "new_password": "new password" }),
+        &session,
+        &ctx.config,
+    )
+    .await;
+    check_response(&res, StatusCode::BAD_REQUEST);
+    assert_error(res, "account", "invalid-current-password").await;
+
+    let res = post_with_session(
+        &client,
+        "/account/password",
+        json!({ "current_password": user.password, "new_password": user.password }),
+        &session,
+        &ctx.config,
+    )
+    .await;
+    check_response(&res, StatusCode::BAD_REQUEST);
+    assert_error(res, "account", "no-change-in-password").await;
+
+    let res = post_with_session(
+        &client,
+        "/account/password",
+        json!({ "current_password": user.password, "new_password": "a" }),
+        &session,
+        &ctx.config,
+    )
+    .await;
+    check_response(&res, StatusCode::BAD_REQUEST);
+    assert_validation_errors(res, &[("new_password", "too-short")]).await;
+
+    let res = post_with_session(
+        &client,
+        "/account/password",
+        json!({ "current_password": user.password, "new_password": "abcdefgh12345678" }),
+        &session,
+        &ctx.config,
+    )
+    .await;
+    check_response(&res, StatusCode::BAD_REQUEST);
+    assert_error_with_details(res, "password", "too-weak").await;
+
+    let res = post_with_session(
+        &client,
+        "/account/password",
+        json!({ "current_password": user.password, "new_password": "P@ssw0rd123!" }),
+        &session,
+        &ctx.config,
+    )
+    .await;
+    check_response(&res, StatusCode::BAD_REQUEST);
+    assert_error(res, "password", "breached").await;
+}
+
+#[test_with_client]
+async fn change_password_success() {
+    let client = TestClient::new(&ctx.endpoint);
+    let user = setup::add_user('a', false, &ctx.config).await;
+    let session = setup::add_session(&user, false, &ctx.config).await;
+    setup::add_session(&user, false, &ctx.config).await;
+    ctx.db
+        .execute(
+            r#"UPDATE "users" SET "password_change_reason" = 'remember_token_compromise'"#,
+            &[],
+        )
+        .await
+        .unwrap();
+
+    let res = post_with_session(
+        &client,
+        "/account/password",
+        json!({ "current_password": user.password, "new_password": "new password" }),
+        &session,
+        &ctx.config,
+    )
+    .await;
+    check_response(&res, StatusCode::OK);
+    res.assert_json(json!({ "success": true, "data": null }))
+        .await;
+
+    let row = ctx
+        .db
+        .query_one(
+            r#"SELECT "password", "password_change_reason" IS NULL FROM "users""#,
+            &[],
+        )
+        .await
+        .unwrap();
+    assert!(verify_password("new password", row.get(0), &ctx.config).unwrap());
+    assert!(row.get::<_, bool>(1));
+
+    let rows = ctx
+        .db
+        .query(r#"SELECT "id" FROM "sessions""#, &[])
+        .await
+        .unwrap();
+    assert_eq!(rows.len(), 1);
+    assert_eq!(rows[0].get::<_, Vec<u8>>("id"), hash(&session.0));
+}
+
+#[test_with_client]
+async fn change_email_invalid() {
+    let client = TestClient::new(&ctx.endpoint);
+    let user = setup::add_user('a', false, &ctx.config).await;
+    let other_user = setup::add_user('b', false, &ctx.config).await;
+    let session = setup::add_session(&user, false, &ctx.config).await;
+    setup::set_sudo_until(&session.0, utc_now() + Duration::hours(1), &ctx.config).await;
+    ctx.db
+        .execute(
+            r#"UPDATE "users" SET "email" = 'b@example.com' WHERE "id" = $1"#,
+            &[&other_user.id],
+        )
+        .await
+        .unwrap();
+
+    let res = post_with_session(
+        &client,
+        "/account/email",
+        json!({ "email": "not an address" }),
+        &session,
+        &ctx.config,
+    )
+    .await;
+    check_response(&res, StatusCode::BAD_REQUEST);
+    assert_error_with_details(res, "general", "invalid-data").await;
+
+    let res = post_with_session(
+        &client,
+        "/account/email",
+        json!({ "email": "B@example.com" }),
+        &session,
+        &ctx.config,
+    )
+    .await;
+    check_response(&res, StatusCode::BAD_REQUEST);
+    assert_error(res, "account", "email-not-available").await;
+
+    let res = post_with_session(
+        &client,
+        "/account/email",
+        json!({ "email": null }),
+        &session,
+        &ctx.config,
+    )
+    .await;
+    check_response(&res, StatusCode::BAD_REQUEST);
+    assert_error(res, "account", "no-change-in-email").await;
+
+    assert!(take_outbox(&ctx.config).is_empty());
+}
+
+#[test_with_client]
+async fn change_email_success() {
+    let client = TestClient::new(&ctx.endpoint);
+    let user = setup::add_user('a', false, &ctx.config).await;
+    let session = setup::add_session(&user, false, &ctx.config).await;
+
+    let res = post_with_session(
+        &client,
+        "/account/email",
+        json!({ "email": "a@example.com" }),
+        &session,
+        &ctx.config,
+    )
+    .await;
+    check_response(&res, StatusCode::FORBIDDEN);
+    assert_error(res, "auth", "sudo-required").await;
+
+    setup::set_sudo_until(&session.0, utc_now() + Duration::hours(1), &ctx.config).await;
+    for email in ["old@example.com", "a@example.com"] {
+        let res = post_with_session(
+            &client,
+            "/account/email",
+            json!({ "email": email }),
+            &session,
+            &ctx.config,
+        )
+        .await;
+        check_response(&res, StatusCode::OK);
+    }
+    let emails = take_outbox(&ctx.config);
+    assert_eq!(emails.len(), 2);
+    assert_eq!(emails[1].to, "a@example.com");
+
+    // Changing the address invalidates earlier tokens
+    let res = post_with_csrf(
+        &client,
+        "/account/email/verify",
+        json!({ "token": token_from_email(&emails[0]) }),
+        &ctx.config,
+    )
+    .await;
+    check_response(&res, StatusCode::BAD_REQUEST);
+    assert_error(res, "account", "invalid-email-verification-token").await;
+
+    let res = post_with_csrf(
+        &client,
+        "/account/email/verify",
+        json!({ "token": token_from_email(&emails[1]) }),
+        &ctx.config,
+    )
+    .await;
+    check_response(&res, StatusCode::OK);
+
+    let row = ctx
+        .db
+        .query_one(
+            r#"SELECT "email", "email_verified" FROM "users" WHERE "id" = $1"#,
+            &[&user.id],
+        )
+        .await
+        .unwrap();
+    assert_eq!(row.get::<_, &str>("email"), "a@example.com");
+    assert!(row.get::<_, bool>("email_verified"));
+
+    let res = post_with_session(
+        &client,
+        "/account/email",
+        json!({ "email": "a@example.com" }),
+        &session,
+        &ctx.config,
+    )
+    .await;
+    check_response(&res, StatusCode::BAD_REQUEST);
+    assert_error(res, "account", "no-change-in-email").await;
+
+    let res = post_with_session(
+        &client,
+        "/account/email",
+        json!({ "email": null }),
+        &session,
+        &ctx.config,
+    )
+    .await;
+    check_response(&res, StatusCode::OK);
+    let row = ctx
+        .db
+        .query_one(
+            r#"SELECT "email", "email_verified" FROM "users" WHERE "id" = $1"#,
+            &[&user.id],
+        )
+        .await
+        .unwrap();
+    assert!(row.get::<_, Option<&str>>("email").is_none());
+    assert!(!row.get::<_, bool>("email_verified"));
+}
+
+#[test_with_client]
+async fn access_token_create() {
+    let client = TestClient::new(&ctx.endpoint);
+    let user = setup::add_user('a', false, &ctx.config).await;
+    let session = setup::add_session(&user, false, &ctx.config).await;
+
+    let body = json!({ "name": " CI  ", "scopes": ["read"], "lifetime": 3600 });
+    let res = post_with_session(
+        &client,
+        "/account/access-tokens",
+        body.clone(),
+        &session,
+        &ctx.config,
+    )
+    .await;
+    check_response(&res, StatusCode::FORBIDDEN);
+    assert_error(res, "auth", "sudo-required").await;
+
+    setup::set_sudo_until(&session.0, utc_now() + Duration::hours(1), &ctx.config).await;
+    let too_long_name = "a".repeat(usize::from(ctx.config.access_token.name_max_length) + 1);
+    for (invalid, expected) in [
+        (
+            json!({ "name": "  ", "scopes": ["read"] }),
+            &[("name", "too-short")][..],
+        ),
+        (
+            json!({ "name": too_long_name, "scopes": ["read"] }),
+            &[("name", "too-long")],
+        ),
+        (
+            json!({ "name": "CI", "scopes": [] }),
+            &[("scopes", "too-short")],
+        ),
+        (
+            json!({ "name": "CI", "scopes": ["read"], "lifetime": 0 }),
+            &[("lifetime", "out-of-range")],
+        ),
+        (
+            json!({ "name": "", "scopes": [], "lifetime": 0 }),
+            &[
+                ("name", "too-short"),
+                ("scopes", "too-short"),
+                ("lifetime", "out-of-range"),
+            ],
+        ),
+    ] {
+        let res = post_with_session(
+            &client,
+            "/account/access-tokens",
+            invalid,
+            &session,
+            &ctx.config,
+        )
+        .await;
+        check_response(&res, StatusCode::BAD_REQUEST);
+        assert_validation_errors(res, expected).await;
+    }
+
+    let res = post_with_session(
+        &client,
+        "/account/access-tokens",
+        body,
+        &session,
+        &ctx.config,
+    )
+    .await;
+    check_response(&res, StatusCode::OK);
+    let json = res.json().await;
+    let data = json.value().object().get("data").object();
+    let token = data.get("token").string();
+    assert_eq!(data.get("name").string(), "CI");
+    assert_eq!(data.get("scopes").string_array(), ["read"]);
+
+    let row = ctx
+        .db
+        .query_one(
+            r#"SELECT "user_id", "expires" FROM "access_tokens" WHERE "id" = $1"#,
+            &[&hash(token)],
+        )
+        .await
+        .unwrap();
+    assert_eq!(row.get::<_, &str>("user_id"), user.id);
+    let expires = row.get::<_, DateTime<Utc>>("expires");
+    assert!(expires > utc_now() && expires <= utc_now() + Duration::hours(1));
+    assert_eq!(data.get("expires").string(), expires.to_rfc3339());
+
+    let res = get_with_session(&client, "/account/access-tokens", &session, &ctx.config).await;
+    check_response(&res, StatusCode::OK);
+    let json = res.json().await;
+    let tokens = json.value().object().get("data").object_array();
+    assert_eq!(tokens.len(), 1);
+    assert_eq!(tokens[0].get("id").string(), data.get("id").string());
+    tokens[0].get("last_used").assert_null();
+}
+
+#[test_with_client]
+async fn access_token_authentication() {
+    let client = TestClient::new(&ctx.endpoint);
+    let user = setup::add_user('a', false, &ctx.config).await;
+    let session = setup::add_session(&user, false, &ctx.config).await;
+    let read_token = setup::add_access_token(&user, &[AccessTokenScope::Read], &ctx.config).await;
+    let write_token = setup::add_access_token(
+        &user,
+        &[AccessTokenScope::Read, AccessTokenScope::Write],
+        &ctx.config,
+    )
+    .await;
+
+    let res = get_with_token(&client, "/users/me", &read_token).await;
+    check_response(&res, StatusCode::OK);
+    let json = res.json().await;
+    assert_eq!(
+        json.value()
+            .object()
+            .get("data")
+            .object()
+            .get("id")
+            .string(),
+        user.id
+    );
+
+    let other_token = setup::add_access_token(&user, &[AccessTokenScope::Read], &ctx.config).await;
+    let other_token_path = format!(
+        "/account/access-tokens/{}",
+        base64_urlsafe(&hash(&other_token))
+    );
+    let res = client
+        .delete(&other_token_path)
+        .header(AUTHORIZATION, format!("Bearer {read_token}"))
+        .send()
+        .await;
+    check_response(&res, StatusCode::FORBIDDEN);
+    assert_error_with_details(res, "auth", "forbidden").await;
+
+    // Access tokens never grant sudo mode
+    let res = post_with_token(
+        &client,
+        "/account/email",
+        json!({ "email": "a@example.com" }),
+        &write_token,
+    )
+    .await;
+    check_response(&res, StatusCode::FORBIDDEN);
+    assert_error(res, "auth", "sudo-required").await;
+
+    // Routes acting on the current session cannot be used with a token,
+    // including password changes, which revoke every other session
+    for path in [
+        "/auth/logout",
+        "/auth/logout/all-sessions",
+        "/auth/sudo",
+        "/account/password",
+        "/account/socket/token",
+    ] {
+        let res = post_with_token(&client, path, json!({}), &write_token).await;
+        check_response(&res, StatusCode::FORBIDDEN);
+        assert_error(res, "auth", "session-required").await;
+    }
+    let res = client
+        .delete(format!("/auth/sessions/{}", base64_urlsafe(&hash(&session.0))))
+        .header(AUTHORIZATION, format!("Bearer {write_token}"))
+        .send()
+        .await;
+    check_response(&res, StatusCode::FORBIDDEN);
+    assert_error(res, "auth", "session-required").await;
+    let res = client
+        .put(format!("/users/{}", user.id))
+        .header(AUTHORIZATION, format!("Bearer {write_token}"))
+        .body_json(&json!({ "language": "fi" }))
+        .send()
+        .await;
+    check_response(&res, StatusCode::FORBIDDEN);
+    assert_error(res, "auth", "session-required").await;
+
+    // No CSRF token is needed with bearer authentication
+    let res = client
+        .delete(&other_token_path)
+        .header(AUTHORIZATION, format!("Bearer {write_token}"))
+        .send()
+        .await;
+    check_response(&res, StatusCode::OK);
+
+    let row = ctx
+        .db
+        .query_one(
+            r#"SELECT "last_used" FROM "access_tokens" WHERE "id" = $1"#,
+            &[&hash(&write_token)],
+        )
+        .await
+        .unwrap();
+    assert!(row.get::<_, Option<DateTime<Utc>>>("last_used").is_some());
+
+    // Session cookies are not used when a bearer token is present
+    let res = client
+        .get("/users/me")
+        .header(AUTHORIZATION, "Bearer invalid")
+        .header(
+            COOKIE,
+            format!("{}={}", ctx.config.session.cookie, session.0),
+        )
+        .send()
+        .await;
+    check_response(&res, StatusCode::UNAUTHORIZED);
+    assert_error(res, "auth", "invalid-access-token").await;
+
+    ctx.db
+        .execute(
+            r#"UPDATE "access_tokens" SET "expires" = $1 WHERE "id" = $2"#,
+            &[&(utc_now() - Duration::seconds(1)), &hash(&read_token)],
+        )
+        .await
+        .unwrap();
+    let res = get_with_token(&client, "/users/me", &read_token).await;
+    check_response(&res, StatusCode::UNAUTHORIZED);
+    assert_error(res, "auth", "invalid-access-token").await;
+}
+
+#[test_with_client]
+async fn access_token_delete() {
+    let client = TestClient::new(&ctx.endpoint);
+    let user = setup::add_user('a', false, &ctx.config).await;
+    let other_user = setup::add_user('b', false, &ctx.config).await;
+    let session = setup::add_session(&user, false, &ctx.config).await;
+    let token = setup::add_access_token(&user, &[AccessTokenScope::Read], &ctx.config).await;
+    let other_token =
+        setup::add_access_token(&other_user, &[AccessTokenScope::Read], &ctx.config).await;
+
+    let path = format!(
+        "/account/access-tokens/{}",
+        base64_urlsafe(&hash(&other_token))
+    );
+    let res = delete_with_session(&client, &path, json!({}), &session, &ctx.config).await;
+    check_response(&res, StatusCode::NOT_FOUND);
+    assert_error(res, "general", "not-found").await;
+
+    let path = format!("/account/access-tokens/{}", base64_urlsafe(&hash(&token)));
+    let res = delete_with_session(&client, &path, json!({}), &session, &ctx.config).await;
+    check_response(&res, StatusCode::OK);
+
+    let res = get_with_token(&client, "/users/me", &token).await;
+    check_response(&res, StatusCode::UNAUTHORIZED);
+    let res = get_with_token(&client, "/users/me", &other_token).await;
+    check_response(&res, StatusCode::OK);
+}
+
+#[test_with_client]
+async fn webauthn_registration() {
+    let authenticator = setup::TestAuthenticator::new();
+    let attestation_object = authenticator.attestation_object(&ctx.config);
+
+    let client_data = setup::TestAuthenticator::client_data("webauthn.create", "abc", &ctx.config);
+    let credential =
+        webauthn::verify_registration(&client_data, &attestation_object, "abc", &ctx.config)
+            .unwrap();
+    assert_eq!(credential.id, authenticator.credential_id);
+    assert_eq!(credential.public_key.len(), 65);
+    assert_eq!(credential.sign_count, 0);
+
+    let result =
+        webauthn::verify_registration(&client_data, &attestation_object, "xyz", &ctx.config);
+    assert!(matches!(result, Err(WebauthnError::ChallengeMismatch)));
+
+    let client_data = setup::TestAuthenticator::client_data("webauthn.get", "abc", &ctx.config);
+    let result =
+        webauthn::verify_registration(&client_data, &attestation_object, "abc", &ctx.config);
+    assert!(matches!(result, Err(WebauthnError::InvalidClientData)));
+
+    let client_data = serde_json::to_vec(&json!({
+        "type": "webauthn.create",
+        "challenge": base64_urlsafe(b"abc"),
+        "origin": "https://evil.example",
+    }))
+    .unwrap();
+    let result =
+        webauthn::verify_registration(&client_data, &attestation_object, "abc", &ctx.config);
+    assert!(matches!(result, Err(WebauthnError::OriginMismatch)));
+}
+
+#[test_with_client]
+async fn webauthn_assertion() {
+    let mut authenticator = setup::TestAuthenticator::new();
+    let client_data = setup::TestAuthenticator::client_data("webauthn.create", "abc", &ctx.config);
+    let credential = webauthn::verify_registration(
+        &client_data,
+        &authenticator.attestation_object(&ctx.config),
+        "abc",
+        &ctx.config,
+    )
+    .unwrap();
+
+    let client_data = setup::TestAuthenticator::client_data("webauthn.get", "def", &ctx.config);
+    let (authenticator_data, signature) = authenticator.assert(&client_data, true, &ctx.config);
+    let assertion = Assertion {
+        client_data_json: &client_data,
+        authenticator_data: &authenticator_data,
+        signature: &signature,
+    };
+    let sign_count =
+        webauthn::verify_assertion(&assertion, "def", &credential.public_key, 0, true, &ctx.config)
+            .unwrap();
+    assert_eq!(sign_count, 1);
+
+    // A replayed assertion does not advance the counter
+    let result =
+        webauthn::verify_assertion(&assertion, "def", &credential.public_key, 1, true, &ctx.config);
+    assert!(matches!(result, Err(WebauthnError::SignCountMismatch)));
+
+    let (authenticator_data, signature) = authenticator.assert(&client_data, false, &ctx.config);
+    let assertion = Assertion {
+        client_data_json: &client_data,
+        authenticator_data: &authenticator_data,
+        signature: &signature,
+    };
+    let result =
+        webauthn::verify_assertion(&assertion, "def", &credential.public_key, 1, true, &ctx.config);
+    assert!(matches!(result, Err(WebauthnError::UserNotVerified)));
+    let sign_count = webauthn::verify_assertion(
+        &assertion,
+        "def",
+        &credential.public_key,
+        1,
+        false,
+        &ctx.config,
+    )
+    .unwrap();
+    assert_eq!(sign_count, 2);
+
+    // Signatures from a different key are rejected
+    let other = setup::TestAuthenticator::new();
+    let client_data = setup::TestAuthenticator::client_data("webauthn.create", "abc", &ctx.config);
+    let other_credential = webauthn::verify_registration(
+        &client_data,
+        &other.attestation_object(&ctx.config),
+        "abc",
+        &ctx.config,
+    )
+    .unwrap();
+    let result = webauthn::verify_assertion(
+        &assertion,
+        "def",
+        &other_credential.public_key,
+        0,
+        false,
+        &ctx.config,
+    );
+    assert!(matches!(result, Err(WebauthnError::InvalidSignature)));
+}
+
+#[test_with_client]
+async fn update_user_self() {
+    let client = TestClient::new(&ctx.endpoint);
+    let user = setup::add_user('a', false, &ctx.config).await;
+    setup::add_user('c', false, &ctx.config).await;
+    let session = setup::add_session(&user, false, &ctx.config).await;
+    setup::add_session(&user, false, &ctx.config).await;
+    let path = format!("/users/{}", user.id);
+    let new_password = "correct horse battery staple";
+
+    // Invalid fields and ones missing the current password are skipped
+    let res = put_with_session(
+        &client,
+        &path,
+        json!({ "username": "b", "new_password": "", "language": "fi" }),
+        &session,
+        &ctx.config,
+    )
+    .await;
+    check_response(&res, StatusCode::BAD_REQUEST);
+    let json = res.json().await;
+    let json = json.value().object();
+    json.get("success").assert_bool(false);
+    let data = json.get("data").object();
+    data.assert_len(1);
+    data.get("language").assert_string("fi");
+    let errors = json.get("errors").object_array();
+    assert_eq!(errors.len(), 2);
+    errors[0].get("field").assert_string("new_password");
+    errors[0].get("id").assert_string("too-short");
+    errors[1].get("field").assert_string("current_password");
+    errors[1]
+        .get("id")
+        .assert_string("missing-current-password");
+
+    // Changing the case of the username needs no password
+    let res = put_with_session(
+        &client,
+        &path,
+        json!({ "username": "A" }),
+        &session,
+        &ctx.config,
+    )
+    .await;
+    check_response(&res, StatusCode::OK);
+    let json = res.json().await;
+    json.value()
+        .object()
+        .get("data")
+        .object()
+        .get("username")
+        .assert_string("A");
+
+    let res = put_with_session(
+        &client,
+        &path,
+        json!({ "username": "b", "current_password": "wrong password" }),
+        &session,
+        &ctx.config,
+    )
+    .await;
+    check_response(&res, StatusCode::BAD_REQUEST);
+    let json = res.json().await;
+    let errors = json.value().object().get("errors").object_array();
+    assert_eq!(errors.len(), 1);
+    errors[0]
+        .get("id")
+        .assert_string("invalid-current-password");
+
+    let res = put_with_session(
+        &client,
+        &path,
+        json!({
+            "username": " b ",
+            "new_password": new_password,
+            "current_password": user.password,
+        }),
+        &session,
+        &ctx.config,
+    )
+    .await;
+    check_response(&res, StatusCode::OK);
+    let json = res.json().await;
+    let json = json.value().object();
+    json.get("success").assert_bool(true);
+    let data = json.get("data").object();
+    data.get("username").assert_string("b");
+    data.get("password_updated").assert_bool(true);
+
+    let row = ctx
+        .db
+        .query_one(
+            r#"SELECT "username", "password" FROM "users" WHERE "id" = $1"#,
+            &[&user.id],
+        )
+        .await
+        .unwrap();
+    assert_eq!(row.get::<_, &str>("username"), "b");
+    assert!(verify_password(new_password, row.get("password"), &ctx.config).unwrap());
+    let row = ctx
+        .db
+        .query_one(r#"SELECT count(*) FROM "sessions""#, &[])
+        .await
+        .unwrap();
+    assert_eq!(row.get::<_, i64>(0), 1);
+
+    let res = put_with_session(
+        &client,
+        &path,
+        json!({
+            "username": "C",
+            "new_password": new_password,
+            "current_password": new_password,
+        }),
+        &session,
+        &ctx.config,
+    )
+    .await;
+    check_response(&res, StatusCode::BAD_REQUEST);
+    let json = res.json().await;
+    let json = json.value().object();
+    json.get("data").object().assert_len(0);
+    let errors = json.get("errors").object_array();
+    assert_eq!(errors.len(), 1);
+    errors[0].get("field").assert_string("username");
+    errors[0].get("id").assert_string("username-not-available");
+    let warnings = json.get("warnings").object_array();
+    assert_eq!(warnings.len(), 1);
+    warnings[0].get("field").assert_string("new_password");
+    warnings[0].get("id").assert_string("no-change-in-password");
+}
+
+#[test_with_client]
+async fn update_other_user() {
+    let client = TestClient::new(&ctx.endpoint);
+    let user = setup::add_user('a', false, &ctx.config).await;
+    let other_user = setup::add_user('b', true, &ctx.config).await;
+    let session = setup::add_session(&user, false, &ctx.config).await;
+    let path = format!("/users/{}", other_user.id);
+    let body = json!({ "username": "c", "new_password": "correct horse", "totp": null });
+
+    let res = put_with_session(&client, &path, body.clone(), &session, &ctx.config).await;
+    check_response(&res, StatusCode::FORBIDDEN);
+    assert_error(res, "auth", "forbidden").await;
+
+    ctx.db
+        .execute(
+            r#"INSERT INTO "permissions"("user_id", "permission") VALUES ($1, $2)"#,
+            &[&user.id, &Permission::EditUser],
+        )
+        .await
+        .unwrap();
+    let res = put_with_session(&client, &path, body, &session, &ctx.config).await;
+    check_response(&res, StatusCode::BAD_REQUEST);
+    let json = res.json().await;
+    let json = json.value().object();
+    let data = json.get("data").object();
+    data.assert_len(1);
+    data.get("username").assert_string("c");
+    let errors = json.get("errors").object_array();
+    assert_eq!(errors.len(), 2);
+    errors[0].get("field").assert_string("new_password");
+    errors[0]
+        .get("id")
+        .assert_string("cannot-update-password-for-others");
+    errors[1].get("field").assert_string("totp");
+    errors[1].get("id").assert_string("sudo-required");
+
+    // Clearing another user's second factor needs sudo mode too
+    setup::set_sudo_until(&session.0, utc_now() + Duration::hours(1), &ctx.config).await;
+    let res = put_with_session(
+        &client,
+        &path,
+        json!({ "totp": null }),
+        &session,
+        &ctx.config,
+    )
+    .await;
+    check_response(&res, StatusCode::OK);
+    let json = res.json().await;
+    let data = json.value().object().get("data").object();
+    data.assert_len(1);
+    data.get("totp_enabled").assert_bool(false);
+
+    let row = ctx
+        .db
+        .query_one(
+            r#"SELECT "username", "password", "totp_key" FROM "users" WHERE "id" = $1"#,
+            &[&other_user.id],
+        )
+        .await
+        .unwrap();
+    assert_eq!(row.get::<_, &str>("username"), "c");
+    assert!(verify_password(&other_user.password, row.get("password"), &ctx.config).unwrap());
+    assert!(row.get::<_, Option<&[u8]>>("totp_key").is_none());
+
+    let res = put_with_session(&client, "/users/unknown", json!({}), &session, &ctx.config).await;
+    check_response(&res, StatusCode::NOT_FOUND);
+    assert_error(res, "general", "not-found").await;
+}
+
+fn png(width: u32, height: u32) -> Vec<u8> {
+    let image = RgbImage::from_pixel(width, height, Rgb([200, 100, 50]));
+    let mut data = Vec::new();
+    image
+        .write_to(&mut Cursor::new(&mut data), ImageOutputFormat::Png)
+        .unwrap();
+    data
+}
+
+fn icon_form(data: Vec<u8>) -> TestForm {
+    TestForm::new().field(
+        TestFormField::bytes(data)
+            .name("icon")
+            .filename("icon.png")
+            .content_type("image/png"),
+    )
+}
+
+#[test_with_client]
+async fn user_icon() {
+    let client = TestClient::new(&ctx.endpoint);
+    let user = setup::add_user('a', false, &ctx.config).await;
+    let session = setup::add_session(&user, false, &ctx.config).await;
+    let path = format!("/users/{}/icon", user.id);
+
+    let res = put_form_with_session(
+        &client,
+        &path,
+        icon_form(png(100, 50)),
+        &session,
+        &ctx.config,
+    )
+    .await;
+    check_response(&res, StatusCode::OK);
+    let json = res.json().await;
+    let icon_id = json
+        .value()
+        .object()
+        .get("data")
+        .object()
+        .get("icon")
+        .string()
+        .to_owned();
+    assert_eq!(icon_id.len(), usize::from(ctx.config.user.icon_id_length));
+
+    // Cropped square and never scaled up
+    for (size, expected) in [(32, 32), (512, 50)] {
+        let res = client
+            .get(format!("/users/icons/{icon_id}/{size}"))
+            .send()
+            .await;
+        res.assert_status_is_ok();
+        res.assert_content_type("image/png");
+        res.assert_header(
+            CACHE_CONTROL,
+            format!("public, max-age={}, immutable", ctx.config.icon.cache_max_age),
+        );
+        let image = image::load_from_memory(&res.0.into_body().into_vec().await.unwrap()).unwrap();
+        assert_eq!((image.width(), image.height()), (expected, expected));
+    }
+    let res = client
+        .get(format!("/users/icons/{icon_id}/32"))
+        .header(IF_NONE_MATCH, format!("\"{icon_id}-32\""))
+        .send()
+        .await;
+    res.assert_status(StatusCode::NOT_MODIFIED);
+    let res = client.get(format!("/users/icons/{icon_id}/33")).send().await;
+    check_response(&res, StatusCode::NOT_FOUND);
+
+    let res = put_form_with_session(
+        &client,
+        &path,
+        icon_form(png(100, 100)),
+        &session,
+        &ctx.config,
+    )
+    .await;
+    check_response(&res, StatusCode::OK);
+    let json = res.json().await;
+    let new_icon_id = json
+        .value()
+        .object()
+        .get("data")
+        .object()
+        .get("icon")
+        .string()
+        .to_owned();
+    assert_ne!(new_icon_id, icon_id);
+    let res = client.get(format!("/users/icons/{icon_id}/32")).send().await;
+    check_response(&res, StatusCode::NOT_FOUND);
+
+    let res = delete_with_session(&client, &path, json!({}), &session, &ctx.config).await;
+    check_response(&res, StatusCode::OK);
+    let json = res.json().await;
+    json.value()
+        .object()
+        .get("data")
+        .object()
+        .get("icon")
+        .assert_null();
+    let res = client
+        .get(format!("/users/icons/{new_icon_id}/32"))
+        .send()
+        .await;
+    check_response(&res, StatusCode::NOT_FOUND);
+    let row = ctx
+        .db
+        .query_one(r#"SELECT "icon" FROM "users" WHERE "id" = $1"#, &[&user.id])
+        .await
+        .unwrap();
+    assert!(row.get::<_, Option<&str>>("icon").is_none());
+}
+
+#[test_with_client]
+async fn user_icon_invalid() {
+    let client = TestClient::new(&ctx.endpoint);
+    let user = setup::add_user('a', false, &ctx.config).await;
+    let other_user = setup::add_user('b', false, &ctx.config).await;
+    let session = setup::add_session(&user, false, &ctx.config).await;
+    let path = format!("/users/{}/icon", user.id);
+
+    for (form, status, id) in [
+        (TestForm::new(), StatusCode::BAD_REQUEST, "no-file"),
+        (
+            icon_form(png(40, 40)).field(TestFormField::bytes(png(40, 40)).name("icon")),
+            StatusCode::BAD_REQUEST,
+            "multiple-files",
+        ),
+        (icon_form(png(10, 40)), StatusCode::BAD_REQUEST, "too-small"),
+        (icon_form(png(400, 100)), StatusCode::BAD_REQUEST, "too-wide"),
+        (icon_form(png(100, 400)), StatusCode::BAD_REQUEST, "too-tall"),
+        (
+            icon_form(vec![0; ctx.config.icon.max_upload_size + 1]),
+            StatusCode::PAYLOAD_TOO_LARGE,
+            "too-large",
+        ),
+    ] {
+        let res = put_form_with_session(&client, &path, form, &session, &ctx.config).await;
+        check_response(&res, status);
+        let json = res.json().await;
+        let errors = json.value().object().get("errors").object_array();
+        assert_eq!(errors.len(), 1);
+        errors[0].get("source").assert_string("icon");
+        errors[0].get("id").assert_string(id);
+    }
+
+    let res = put_form_with_session(
+        &client,
+        &path,
+        icon_form(b"not an image".to_vec()),
+        &session,
+        &ctx.config,
+    )
+    .await;
+    check_response(&res, StatusCode::BAD_REQUEST);
+    assert_error_with_details(res, "icon", "invalid-image").await;
+
+    let res = put_form_with_session(
+        &client,
+        &format!("/users/{}/icon", other_user.id),
+        icon_form(png(40, 40)),
+        &session,
+        &ctx.config,
+    )
+    .await;
+    check_response(&res, StatusCode::FORBIDDEN);
+    assert_error(res, "auth", "forbidden").await;
+}
+
+#[test_with_client]
+async fn delete_user_self() {
+    let client = TestClient::new(&ctx.endpoint);
+    let user = setup::add_user('a', false, &ctx.config).await;
+    let session = setup::add_session(&user, false, &ctx.config).await;
+    setup::add_session(&user, false, &ctx.config).await;
+    let path = format!("/users/{}", user.id);
+
+    let res = delete_with_session(&client, &path, json!({}), &session, &ctx.config).await;
+    check_response(&res, StatusCode::BAD_REQUEST);
+    assert_error(res, "account", "missing-current-password").await;
+
+    let body = json!({ "password": "wrong" });
+    let res = delete_with_session(&client, &path, body, &session, &ctx.config).await;
+    check_response(&res, StatusCode::BAD_REQUEST);
+    assert_error(res, "account", "invalid-current-password").await;
+
+    let body = json!({ "password": user.password });
+    let res = delete_with_session(&client, &path, body, &session, &ctx.config).await;
+    check_response(&res, StatusCode::OK);
+    let json = res.json().await;
+    let deletion_scheduled = json
+        .value()
+        .object()
+        .get("data")
+        .object()
+        .get("deletion_scheduled")
+        .string()
+        .to_owned();
+    let deletion_scheduled = DateTime::parse_from_rfc3339(&deletion_scheduled).unwrap();
+    assert!(deletion_scheduled > utc_now() + Duration::days(1));
+
+    let row = ctx
+        .db
+        .query_one(
+            r#"
+            SELECT "active", "deletion_cancellable",
+                (SELECT count(*) FROM "sessions" WHERE "user_id" = $1) AS "sessions"
+            FROM "users" WHERE "id" = $1
+            "#,
+            &[&user.id],
+        )
+        .await
+        .unwrap();
+    assert!(!row.get::<_, bool>("active"));
+    assert!(row.get::<_, bool>("deletion_cancellable"));
+    assert_eq!(row.get::<_, i64>("sessions"), 0);
+
+    let res = get_with_session(&client, "/users/me", &session, &ctx.config).await;
+    check_response(&res, StatusCode::UNAUTHORIZED);
+
+    // Logging in during the grace period cancels the deletion
+    let res = post_with_csrf(
+        &client,
+        "/auth/login",
+        json!({ "username": user.username, "password": user.password, "remember": false }),
+        &ctx.config,
+    )
+    .await;
+    check_response(&res, StatusCode::OK);
+    let json = res.json().await;
+    let warnings = json.value().object().get("warnings").object_array();
+    assert_eq!(warnings.len(), 1);
+    warnings[0].get("id").assert_string("account-deletion-cancelled");
+    let row = ctx
+        .db
+        .query_one(
+            r#"SELECT "active", "deletion_scheduled" FROM "users" WHERE "id" = $1"#,
+            &[&user.id],
+        )
+        .await
+        .unwrap();
+    assert!(row.get::<_, bool>("active"));
+    assert!(row
+        .get::<_, Option<DateTime<Utc>>>("deletion_scheduled")
+        .is_none());
+}
+
+#[test_with_client]
+async fn delete_other_user() {
+    let client = TestClient::new(&ctx.endpoint);
+    let user = setup::add_user('a', false, &ctx.config).await;
+    let other_user = setup::add_user('b', false, &ctx.config).await;
+    let session = setup::add_session(&user, false, &ctx.config).await;
+    let path = format!("/users/{}", other_user.id);
+
+    let res = delete_with_session(&client, &path, json!({}), &session, &ctx.config).await;
+    check_response(&res, StatusCode::FORBIDDEN);
+    assert_error(res, "auth", "forbidden").await;
+
+    ctx.db
+        .execute(
+            r#"INSERT INTO "permissions"("user_id", "permission") VALUES ($1, $2)"#,
+            &[&user.id, &Permission::DeleteUser],
+        )
+        .await
+        .unwrap();
+    let res = delete_with_session(&client, &path, json!({}), &session, &ctx.config).await;
+    check_response(&res, StatusCode::OK);
+
+    // Only users deleting themselves can cancel
+    let res = post_with_csrf(
+        &client,
+        "/auth/login",
+        json!({
+            "username": other_user.username,
+            "password": other_user.password,
+            "remember": false,
+        }),
+        &ctx.config,
+    )
+    .await;
+    check_response(&res, StatusCode::FORBIDDEN);
+    assert_error(res, "auth", "account-disabled").await;
+
+    let icon_id = generate_token(ctx.config.user.icon_id_length);
+    let storage = make_storage(&reqwest::Client::new(), &ctx.config);
+    let icon_key = format!("icons/{icon_id}/32.png");
+    let blob = Blob {
+        content_type: "image/png".to_owned(),
+        data: png(32, 32),
+    };
+    storage.put(&icon_key, blob).await.unwrap();
+    ctx.db
+        .execute(
+            r#"UPDATE "users" SET "icon" = $1 WHERE "id" = $2"#,
+            &[&icon_id, &other_user.id],
+        )
+        .await
+        .unwrap();
+
+    let db = ctx.config.db.create_pool(None, NoTls).unwrap();
+    let purged = account_deletion::purge_deleted_users(&db, &storage, &ctx.config)
+        .await
+        .unwrap();
+    assert_eq!(purged, 0);
+
+    ctx.db
+        .execute(
+            r#"UPDATE "users" SET "deletion_scheduled" = $1 WHERE "id" = $2"#,
+            &[&(utc_now() - Duration::seconds(1)), &other_user.id],
+        )
+        .await
+        .unwrap();
+    let purged = account_deletion::purge_deleted_users(&db, &storage, &ctx.config)
+        .await
+        .unwrap();
+    assert_eq!(purged, 1);
+    let row = ctx
+        .db
+        .query_opt(r#"SELECT 1 FROM "users" WHERE "id" = $1"#, &[&other_user.id])
+        .await
+        .unwrap();
+    assert!(row.is_none());
+    assert!(storage.get(&icon_key).await.unwrap().is_none());
+}
+
+async fn usernames<E: Endpoint>(
+    client: &TestClient<E>,
+    path: &str,
+    session: &(String, String),
+    config: &Config,
+) -> (Vec<String>, Option<String>) {
+    let res = get_with_session(client, path, session, config).await;
+    check_response(&res, StatusCode::OK);
+    let json = res.json().await;
+    let data = json.value().object().get("data").object();
+    let usernames = data
+        .get("users")
+        .object_array()
+        .iter()
+        .map(|user| user.get("username").string().to_owned())
+        .collect();
+    let next_cursor = data.get("next_cursor").deserialize::<Option<String>>();
+    (usernames, next_cursor)
+}
+
+#[test_with_client]
+async fn user_directory() {
+    let client = TestClient::new(&ctx.endpoint);
+    let admin = setup::add_user('a', false, &ctx.config).await;
+    setup::add_user('b', false, &ctx.config).await;
+    let inactive_user = setup::add_user('c', false, &ctx.config).await;
+    setup::add_user('d', true, &ctx.config).await;
+    let session = setup::add_session(&admin, false, &ctx.config).await;
+
+    let res = get_with_session(&client, "/users", &session, &ctx.config).await;
+    check_response(&res, StatusCode::FORBIDDEN);
+    assert_error(res, "auth", "forbidden").await;
+
+    ctx.db
+        .execute(
+            r#"INSERT INTO "permissions"("user_id", "permission") VALUES ($1, $2)"#,
+            &[&admin.id, &Permission::ViewUser],
+        )
+        .await
+        .unwrap();
+    ctx.db
+        .execute(
+            r#"UPDATE "users" SET "active" = false WHERE "id" = $1"#,
+            &[&inactive_user.id],
+        )
+        .await
+        .unwrap();
+
+    let (page, cursor) = usernames(&client, "/users?limit=3", &session, &ctx.config).await;
+    assert_eq!(page, ["a", "b", "c"]);
+    let path = format!("/users?limit=3&cursor={}", cursor.unwrap());
+    let (page, cursor) = usernames(&client, &path, &session, &ctx.config).await;
+    assert_eq!(page, ["d"]);
+    assert!(cursor.is_none());
+
+    let (page, cursor) =
+        usernames(&client, "/users?order=desc&limit=2", &session, &ctx.config).await;
+    assert_eq!(page, ["d", "c"]);
+    let path = format!("/users?order=desc&limit=2&cursor={}", cursor.unwrap());
+    let (page, _) = usernames(&client, &path, &session, &ctx.config).await;
+    assert_eq!(page, ["b", "a"]);
+
+    for (query, expected) in [
+        ("search=B", vec!["b"]),
+        ("search=%25", vec![]),
+        ("active=false", vec!["c"]),
+        ("totp_enabled=true", vec!["d"]),
+        ("permission=view_user", vec!["a"]),
+        ("language=fi", vec![]),
+    ] {
+        let path = format!("/users?{query}");
+        let (page, _) = usernames(&client, &path, &session, &ctx.config).await;
+        assert_eq!(page, expected, "{query}");
+    }
+
+    let res = get_with_session(&client, "/users?cursor=invalid", &session, &ctx.config).await;
+    check_response(&res, StatusCode::BAD_REQUEST);
+    assert_error_with_details(res, "general", "invalid-data").await;
+}
+
+async fn permissions_data(res: TestResponse) -> (Vec<String>, Vec<String>, Vec<String>) {
+    check_response(&res, StatusCode::OK);
+    let json = res.json().await;
+    let data = json.value().object().get("data").object();
+    let strings = |key: &str| -> Vec<String> {
+        data.get(key)
+            .string_array()
+            .into_iter()
+            .map(str::to_owned)
+            .collect()
+    };
+    (
+        strings("permissions"),
+        strings("roles"),
+        strings("effective_permissions"),
+    )
+}
+
+#[test_with_client]
+async fn manage_permissions() {
+    let client = TestClient::new(&ctx.endpoint);
+    let admin = setup::add_user('a', false, &ctx.config).await;
+    let user = setup::add_user('b', false, &ctx.config).await;
+    let admin_session = setup::add_session(&admin, false, &ctx.config).await;
+    let user_session = setup::add_session(&user, false, &ctx.config).await;
+    let user_path = format!("/users/{}", user.id);
+
+    let res = put_with_session(
+        &client,
+        &format!("{user_path}/permissions/view_user"),
+        json!({}),
+        &admin_session,
+        &ctx.config,
+    )
+    .await;
+    check_response(&res, StatusCode::FORBIDDEN);
+    assert_error(res, "auth", "forbidden").await;
+
+    for permission in [Permission::ManagePermissions, Permission::ViewUser] {
+        ctx.db
+            .execute(
+                r#"INSERT INTO "permissions"("user_id", "permission") VALUES ($1, $2)"#,
+                &[&admin.id, &permission],
+            )
+            .await
+            .unwrap();
+    }
+
+    // Nobody can hand out permissions they do not hold
+    let res = put_with_session(
+        &client,
+        &format!("{user_path}/permissions/edit_user"),
+        json!({}),
+        &admin_session,
+        &ctx.config,
+    )
+    .await;
+    check_response(&res, StatusCode::FORBIDDEN);
+    assert_error_with_details(res, "permission", "not-held").await;
+    let res = post_with_session(
+        &client,
+        "/roles",
+        json!({ "name": "auditor", "permissions": ["view_audit_log"] }),
+        &admin_session,
+        &ctx.config,
+    )
+    .await;
+    check_response(&res, StatusCode::FORBIDDEN);
+    assert_error_with_details(res, "permission", "not-held").await;
+
+    let res = put_with_session(
+        &client,
+        &format!("{user_path}/permissions/view_user"),
+        json!({}),
+        &admin_session,
+        &ctx.config,
+    )
+    .await;
+    let (permissions, roles, effective) = permissions_data(res).await;
+    assert_eq!(permissions, ["view_user"]);
+    assert!(roles.is_empty());
+    assert_eq!(effective, ["view_user"]);
+
+    let res = post_with_session(
+        &client,
+        "/roles",
+        json!({ "name": "viewer", "permissions": ["view_user", "view_user"] }),
+        &admin_session,
+        &ctx.config,
+    )
+    .await;
+    check_response(&res, StatusCode::OK);
+    let res = post_with_session(
+        &client,
+        "/roles",
+        json!({ "name": "viewer", "permissions": [] }),
+        &admin_session,
+        &ctx.config,
+    )
+    .await;
+    check_response(&res, StatusCode::BAD_REQUEST);
+    assert_error(res, "permission", "role-name-not-available").await;
+
+    let res = put_with_session(
+        &client,
+        &format!("{user_path}/roles/viewer"),
+        json!({}),
+        &admin_session,
+        &ctx.config,
+    )
+    .await;
+    let (_, roles, _) = permissions_data(res).await;
+    assert_eq!(roles, ["viewer"]);
+
+    // The role still grants the permission after the direct one is revoked
+    let res = delete_with_session(
+        &client,
+        &format!("{user_path}/permissions/view_user"),
+        json!({}),
+        &admin_session,
+        &ctx.config,
+    )
+    .await;
+    let (permissions, _, effective) = permissions_data(res).await;
+    assert!(permissions.is_empty());
+    assert_eq!(effective, ["view_user"]);
+    let res = get_with_session(&client, "/users", &user_session, &ctx.config).await;
+    check_response(&res, StatusCode::OK);
+
+    let res = get_with_session(&client, "/roles", &admin_session, &ctx.config).await;
+    check_response(&res, StatusCode::OK);
+    let json = res.json().await;
+    let roles = json.value().object().get("data").object_array();
+    assert_eq!(roles.len(), 1);
+    assert_eq!(roles[0].get("name").string(), "viewer");
+    assert_eq!(roles[0].get("permissions").string_array(), ["view_user"]);
+    assert_eq!(roles[0].get("users").i64(), 1);
+
+    let res = delete_with_session(
+        &client,
+        "/roles/viewer",
+        json!({}),
+        &admin_session,
+        &ctx.config,
+    )
+    .await;
+    check_response(&res, StatusCode::OK);
+    let res = get_with_session(
+        &client,
+        &format!("{user_path}/permissions"),
+        &user_session,
+        &ctx.config,
+    )
+    .await;
+    let (permissions, roles, effective) = permissions_data(res).await;
+    assert!(permissions.is_empty() && roles.is_empty() && effective.is_empty());
+    let res = get_with_session(&client, "/users", &user_session, &ctx.config).await;
+    check_response(&res, StatusCode::FORBIDDEN);
+
+    let events: Vec<String> = ctx
+        .db
+        .query(
+            r#"
+            SELECT "event" FROM "audit_events"
+            WHERE "event" LIKE 'permission-%' OR "event" LIKE 'role-%' ORDER BY "id"
+            "#,
+            &[],
+        )
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|row| row.get("event"))
+        .collect();
+    assert_eq!(
+        events,
+        [
+            "permission-granted",
+            "role-created",
+            "role-granted",
+            "permission-revoked",
+            "role-deleted",
+        ]
+    );
+}
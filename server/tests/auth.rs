@@ -1,9 +1,17 @@
+use aes_gcm_siv::{aead::Aead, Aes256GcmSiv, KeyInit, Nonce};
+use argon2::{
+    password_hash::{PasswordHasher, SaltString},
+    Argon2,
+};
 use async_trait::async_trait;
 use chrono::{DateTime, Utc};
-use deadpool_postgres::{tokio_postgres::NoTls, ClientWrapper};
+use deadpool_postgres::{
+    tokio_postgres::{error::SqlState, NoTls},
+    ClientWrapper, Config as DbConfig,
+};
 use poem::{
     http::{
-        header::{CONTENT_TYPE, COOKIE, SET_COOKIE},
+        header::{CONTENT_TYPE, COOKIE, SET_COOKIE, USER_AGENT},
         StatusCode,
     },
     test::{TestClient, TestResponse},
@@ -15,7 +23,14 @@ use test_context::{test_context, AsyncTestContext};
 
 use dodatok::{
     config::Config,
-    util::{generate_token, generate_totp, utc_now},
+    db::{self, Permission},
+    oidc::{self, code_challenge, OidcError, OidcState},
+    password_policy,
+    util::{
+        base64_urlsafe, decrypt, decrypt_with_key_id, encrypt, encryption_key_id, generate_token,
+        generate_totp, generate_totp_key, hash, hash_encrypt_password, password_needs_rehash,
+        utc_now, verify_password,
+    },
 };
 use macros::test_with_client;
 
@@ -23,8 +38,9 @@ mod setup;
 mod util;
 
 use util::{
-    assert_error, assert_error_with_details, check_csrf_cookie, check_response,
-    check_session_cookie_removed,
+    assert_error, assert_error_with_details, assert_validation_errors, check_csrf_cookie,
+    check_response, check_session_cookie_removed, delete_with_session, get_with_session,
+    post_with_csrf, post_with_session, take_outbox, token_from_email,
 };
 
 #[test_with_client]
@@ -229,14 +245,14 @@ async fn csrf_mismatch() {
     let client = TestClient::new(&ctx.endpoint);
     let csrf_cookie = Cookie::new_with_str(
         &ctx.config.csrf.cookie,
-        &generate_token(ctx.config.csrf.token_length),
+        generate_token(ctx.config.csrf.token_length),
     );
     let res = client
         .post("/auth/login")
         .header(COOKIE, csrf_cookie.to_string())
         .header(
             &ctx.config.csrf.header,
-            &generate_token(ctx.config.csrf.token_length),
+            generate_token(ctx.config.csrf.token_length),
         )
         .send()
         .await;
@@ -761,6 +777,63 @@ async fn login_invalid_totp() {
     assert_error(res, "auth", "invalid-totp").await;
 }
 
+#[test_with_client]
+async fn login_recovery_code() {
+    let client = TestClient::new(&ctx.endpoint);
+    let user = setup::add_user('a', true, &ctx.config).await;
+    let csrf_token = generate_token(ctx.config.csrf.token_length);
+    let csrf_cookie = Cookie::new_with_str(&ctx.config.csrf.cookie, &csrf_token);
+    let recovery_codes = [
+        generate_token(ctx.config.totp.recovery_code_length),
+        generate_token(ctx.config.totp.recovery_code_length),
+    ];
+    for code in &recovery_codes {
+        ctx.db
+            .execute(
+                r#"INSERT INTO "totp_recovery_codes"("id", "user_id") VALUES ($1, $2)"#,
+                &[&hash(code), &user.id],
+            )
+            .await
+            .unwrap();
+    }
+
+    let res = client
+        .post("/auth/login")
+        .body_json(&json!({
+            "username": user.username,
+            "password": user.password,
+            "remember": false,
+            "totp": recovery_codes[0],
+        }))
+        .header(COOKIE, csrf_cookie.to_string())
+        .header(&ctx.config.csrf.header, &csrf_token)
+        .send()
+        .await;
+    check_response(&res, StatusCode::OK);
+    let json = res.json().await;
+    let warnings = json.value().object().get("warnings").object_array();
+    assert_eq!(warnings.len(), 1);
+    assert_eq!(warnings[0].get("source").string(), "auth");
+    assert_eq!(warnings[0].get("id").string(), "recovery-code-used");
+    assert_eq!(warnings[0].get("details").string(), "1 recovery codes remaining");
+
+    // Recovery codes are single-use
+    let res = client
+        .post("/auth/login")
+        .body_json(&json!({
+            "username": user.username,
+            "password": user.password,
+            "remember": false,
+            "totp": recovery_codes[0],
+        }))
+        .header(COOKIE, csrf_cookie.to_string())
+        .header(&ctx.config.csrf.header, &csrf_token)
+        .send()
+        .await;
+    check_response(&res, StatusCode::BAD_REQUEST);
+    assert_error(res, "auth", "invalid-totp").await;
+}
+
 #[test_with_client]
 async fn login_totp_reuse() {
     let client = TestClient::new(&ctx.endpoint);
@@ -829,6 +902,130 @@ async fn login_disabled() {
     assert_error(res, "auth", "account-disabled").await;
 }
 
+#[test_with_client]
+async fn login_lockout() {
+    let client = TestClient::new(&ctx.endpoint);
+    let user = setup::add_user('a', true, &ctx.config).await;
+    let max_failed_attempts = ctx.config.login_protection.max_failed_attempts;
+
+    for _ in 1..max_failed_attempts {
+        let res = post_with_csrf(
+            &client,
+            "/auth/login",
+            json!({ "username": user.username, "password": "wrong", "remember": false }),
+            &ctx.config,
+        )
+        .await;
+        check_response(&res, StatusCode::BAD_REQUEST);
+        assert_error(res, "auth", "invalid-credentials").await;
+    }
+
+    // Invalid TOTPs count towards the same limit
+    let res = post_with_csrf(
+        &client,
+        "/auth/login",
+        json!({
+            "username": user.username,
+            "password": user.password,
+            "remember": false,
+            "totp": "000000000",
+        }),
+        &ctx.config,
+    )
+    .await;
+    check_response(&res, StatusCode::FORBIDDEN);
+    assert_error_with_details(res, "auth", "account-locked").await;
+
+    let row = ctx
+        .db
+        .query_one(
+            r#"SELECT "failed_login_attempts", "locked_until" FROM "users" WHERE "id" = $1"#,
+            &[&user.id],
+        )
+        .await
+        .unwrap();
+    assert_eq!(row.get::<_, i32>("failed_login_attempts"), max_failed_attempts);
+    let locked_until = row.get::<_, DateTime<Utc>>("locked_until");
+    assert!(locked_until > utc_now());
+    assert!(locked_until <= utc_now() + ctx.config.login_protection.lockout_duration);
+
+    let now = utc_now().timestamp() as u64;
+    let totp = generate_totp(user.totp_key.as_ref().unwrap().as_bytes(), now, &ctx.config);
+    let res = post_with_csrf(
+        &client,
+        "/auth/login",
+        json!({
+            "username": user.username,
+            "password": user.password,
+            "remember": false,
+            "totp": totp,
+        }),
+        &ctx.config,
+    )
+    .await;
+    check_response(&res, StatusCode::FORBIDDEN);
+    let json = res.json().await;
+    let errors = json.value().object().get("errors").object_array();
+    assert_eq!(errors[0].get("id").string(), "account-locked");
+    assert_eq!(errors[0].get("details").string(), locked_until.to_rfc3339());
+
+    // Further failures after the lockout expires double its length
+    ctx.db
+        .execute(
+            r#"UPDATE "users" SET "locked_until" = $1 WHERE "id" = $2"#,
+            &[&utc_now(), &user.id],
+        )
+        .await
+        .unwrap();
+    let res = post_with_csrf(
+        &client,
+        "/auth/login",
+        json!({ "username": user.username, "password": "wrong", "remember": false }),
+        &ctx.config,
+    )
+    .await;
+    check_response(&res, StatusCode::FORBIDDEN);
+    let row = ctx
+        .db
+        .query_one(r#"SELECT "locked_until" FROM "users" WHERE "id" = $1"#, &[&user.id])
+        .await
+        .unwrap();
+    let locked_until = row.get::<_, DateTime<Utc>>("locked_until");
+    assert!(locked_until > utc_now() + ctx.config.login_protection.lockout_duration);
+
+    // A successful login resets the counter
+    ctx.db
+        .execute(
+            r#"UPDATE "users" SET "locked_until" = $1 WHERE "id" = $2"#,
+            &[&utc_now(), &user.id],
+        )
+        .await
+        .unwrap();
+    let res = post_with_csrf(
+        &client,
+        "/auth/login",
+        json!({
+            "username": user.username,
+            "password": user.password,
+            "remember": false,
+            "totp": totp,
+        }),
+        &ctx.config,
+    )
+    .await;
+    check_response(&res, StatusCode::OK);
+    let row = ctx
+        .db
+        .query_one(
+            r#"SELECT "failed_login_attempts", "locked_until" FROM "users" WHERE "id" = $1"#,
+            &[&user.id],
+        )
+        .await
+        .unwrap();
+    assert_eq!(row.get::<_, i32>("failed_login_attempts"), 0);
+    assert!(row.get::<_, Option<DateTime<Utc>>>("locked_until").is_none());
+}
+
 #[test_with_client]
 async fn login_success_without_totp() {
     let client = TestClient::new(&ctx.endpoint);
@@ -962,3 +1159,1221 @@ async fn login_success_with_totp() {
     }))
     .await;
 }
+
+#[test_with_client]
+async fn register_invalid_length() {
+    let client = TestClient::new(&ctx.endpoint);
+    let csrf_token = generate_token(ctx.config.csrf.token_length);
+    let csrf_cookie = Cookie::new_with_str(&ctx.config.csrf.cookie, &csrf_token);
+
+    let too_long_username = "a".repeat(usize::from(ctx.config.user.username_max_length) + 1);
+    let too_short_password = "a".repeat(usize::from(ctx.config.user.password_min_length) - 1);
+    for (username, password, expected) in [
+        ("", "password", &[("username", "too-short")][..]),
+        (
+            too_long_username.as_str(),
+            "password",
+            &[("username", "too-long")],
+        ),
+        (
+            "a",
+            too_short_password.as_str(),
+            &[("password", "too-short")],
+        ),
+        (
+            "",
+            too_short_password.as_str(),
+            &[("username", "too-short"), ("password", "too-short")],
+        ),
+    ] {
+        let res = client
+            .post("/auth/register")
+            .body_json(&json!({
+                "username": username,
+                "password": password,
+                "language": "en-US",
+                "remember": false,
+            }))
+            .header(COOKIE, csrf_cookie.to_string())
+            .header(&ctx.config.csrf.header, &csrf_token)
+            .send()
+            .await;
+        check_response(&res, StatusCode::BAD_REQUEST);
+        res.assert_header_is_not_exist(SET_COOKIE);
+        assert_validation_errors(res, expected).await;
+    }
+
+    let row = ctx
+        .db
+        .query_one(r#"SELECT count(*) FROM "users""#, &[])
+        .await
+        .unwrap();
+    assert_eq!(row.get::<_, i64>(0), 0);
+}
+
+#[test_with_client]
+async fn register_weak_password() {
+    let client = TestClient::new(&ctx.endpoint);
+    let csrf_token = generate_token(ctx.config.csrf.token_length);
+    let csrf_cookie = Cookie::new_with_str(&ctx.config.csrf.cookie, &csrf_token);
+
+    let register = |username: &'static str, password: &'static str| {
+        client
+            .post("/auth/register")
+            .body_json(&json!({
+                "username": username,
+                "password": password,
+                "language": "en-US",
+                "remember": false,
+            }))
+            .header(COOKIE, csrf_cookie.to_string())
+            .header(&ctx.config.csrf.header, &csrf_token)
+            .send()
+    };
+
+    let res = register("zebra", "Zebra quartz violin").await;
+    check_response(&res, StatusCode::BAD_REQUEST);
+    assert_error(res, "password", "contains-user-input").await;
+
+    let res = register("a", "aaaaaaaaaaaaaaaa").await;
+    check_response(&res, StatusCode::BAD_REQUEST);
+    assert_error_with_details(res, "password", "too-weak").await;
+
+    let res = register("a", "Tr0ub4dor&3").await;
+    check_response(&res, StatusCode::BAD_REQUEST);
+    res.assert_header_is_not_exist(SET_COOKIE);
+    assert_error(res, "password", "breached").await;
+
+    let row = ctx
+        .db
+        .query_one(r#"SELECT count(*) FROM "users""#, &[])
+        .await
+        .unwrap();
+    assert_eq!(row.get::<_, i64>(0), 0);
+}
+
+#[test_with_client]
+async fn password_policy_breached_passwords() {
+    for password in ["password", "123456", "P@ssw0rd123!", "Tr0ub4dor&3", "letmein1"] {
+        assert!(password_policy::is_breached(password, &ctx.config).await.unwrap());
+    }
+    for password in ["zebra quartz violin", "Password", ""] {
+        assert!(!password_policy::is_breached(password, &ctx.config).await.unwrap());
+    }
+
+    assert!(password_policy::estimate_entropy("aaaaaaaaaaaaaaaa") < 20.0);
+    assert!(password_policy::estimate_entropy("abcdefgh12345678") < 30.0);
+    assert!(password_policy::estimate_entropy("zebra quartz violin") > 80.0);
+}
+
+#[test_with_client]
+async fn register_username_not_available() {
+    let client = TestClient::new(&ctx.endpoint);
+    let user = setup::add_user('a', false, &ctx.config).await;
+    let csrf_token = generate_token(ctx.config.csrf.token_length);
+    let csrf_cookie = Cookie::new_with_str(&ctx.config.csrf.cookie, &csrf_token);
+
+    let res = client
+        .post("/auth/register")
+        .body_json(&json!({
+            "username": user.username.to_uppercase(),
+            "password": "zebra quartz violin",
+            "language": "en-US",
+            "remember": false,
+        }))
+        .header(COOKIE, csrf_cookie.to_string())
+        .header(&ctx.config.csrf.header, &csrf_token)
+        .send()
+        .await;
+    check_response(&res, StatusCode::BAD_REQUEST);
+    res.assert_header_is_not_exist(SET_COOKIE);
+    assert_error(res, "account", "username-not-available").await;
+}
+
+#[test_with_client]
+async fn register_success() {
+    let client = TestClient::new(&ctx.endpoint);
+    let csrf_token = generate_token(ctx.config.csrf.token_length);
+    let csrf_cookie = Cookie::new_with_str(&ctx.config.csrf.cookie, &csrf_token);
+
+    let res = client
+        .post("/auth/register")
+        .body_json(&json!({
+            "username": "new user",
+            "password": "zebra quartz violin",
+            "language": "fi",
+            "remember": true,
+        }))
+        .header(COOKIE, csrf_cookie.to_string())
+        .header(&ctx.config.csrf.header, &csrf_token)
+        .send()
+        .await;
+    check_response(&res, StatusCode::OK);
+
+    // Check that Set-Cookie sets session, CSRF token and remember token
+    let headers = res.0.headers().clone();
+    let cookies: Vec<_> = headers.get_all(SET_COOKIE).iter().collect();
+    assert_eq!(cookies.len(), 3);
+    let cookie_jar = CookieJar::default();
+    for cookie in cookies {
+        cookie_jar.add(Cookie::parse(cookie.to_str().unwrap()).unwrap());
+    }
+
+    let row = ctx
+        .db
+        .query_one(
+            r#"
+            SELECT "users"."id", "sessions"."csrf_token", "sessions"."sudo_until"
+            FROM "users" JOIN "sessions" ON "users"."id" = "sessions"."user_id"
+            WHERE "users"."username" = 'new user'
+            "#,
+            &[],
+        )
+        .await
+        .unwrap();
+    let new_csrf_token = row.get::<_, &str>("csrf_token");
+    check_csrf_cookie(
+        &cookie_jar.get(&ctx.config.csrf.cookie).unwrap(),
+        new_csrf_token,
+        &ctx.config,
+    );
+    assert!(cookie_jar.get(&ctx.config.session.cookie).is_some());
+    assert!(cookie_jar.get(&ctx.config.remember_token.cookie).is_some());
+
+    res.assert_json(json!({
+        "success": true,
+        "csrf_token": new_csrf_token,
+        "data": {
+            "id": row.get::<_, &str>("id"),
+            "username": "new user",
+            "totp_enabled": false,
+            "password_change_reason": null,
+            "icon": null,
+            "language": "fi",
+        },
+        "sudo_until": row.get::<_, DateTime<Utc>>("sudo_until").to_rfc3339(),
+    }))
+    .await;
+
+    let row = ctx
+        .db
+        .query_one(r#"SELECT count(*) FROM "remember_tokens""#, &[])
+        .await
+        .unwrap();
+    assert_eq!(row.get::<_, i64>(0), 1);
+}
+
+#[test_with_client]
+async fn get_sessions() {
+    let client = TestClient::new(&ctx.endpoint);
+    let user = setup::add_user('a', false, &ctx.config).await;
+    let other_user = setup::add_user('b', false, &ctx.config).await;
+    let session = setup::add_session(&user, false, &ctx.config).await;
+    let (other_session_id, _) = setup::add_session(&user, false, &ctx.config).await;
+    setup::add_session(&user, true, &ctx.config).await;
+    setup::add_session(&other_user, false, &ctx.config).await;
+
+    let res = get_with_session(&client, "/auth/sessions", &session, &ctx.config).await;
+    check_response(&res, StatusCode::OK);
+    let json = res.json().await;
+    let sessions = json.value().object().get("data").object_array();
+    assert_eq!(sessions.len(), 2);
+
+    let mut ids: Vec<_> = sessions
+        .iter()
+        .map(|session| {
+            session.assert_len(8);
+            (
+                session.get("id").string().to_owned(),
+                session.get("current").bool(),
+            )
+        })
+        .collect();
+    ids.sort();
+    let mut expected = [
+        (base64_urlsafe(&hash(&session.0)), true),
+        (base64_urlsafe(&hash(&other_session_id)), false),
+    ];
+    expected.sort();
+    assert_eq!(ids, expected);
+}
+
+#[test_with_client]
+async fn delete_session_not_found() {
+    let client = TestClient::new(&ctx.endpoint);
+    let user = setup::add_user('a', false, &ctx.config).await;
+    let other_user = setup::add_user('b', false, &ctx.config).await;
+    let session = setup::add_session(&user, false, &ctx.config).await;
+    let (other_session_id, _) = setup::add_session(&other_user, false, &ctx.config).await;
+
+    for id in ["invalid*base64", &base64_urlsafe(&hash(&other_session_id))] {
+        let res = delete_with_session(
+            &client,
+            &format!("/auth/sessions/{id}"),
+            json!({}),
+            &session,
+            &ctx.config,
+        )
+        .await;
+        check_response(&res, StatusCode::NOT_FOUND);
+        assert_error(res, "general", "not-found").await;
+    }
+
+    let row = ctx
+        .db
+        .query_one(r#"SELECT count(*) FROM "sessions""#, &[])
+        .await
+        .unwrap();
+    assert_eq!(row.get::<_, i64>(0), 2);
+}
+
+#[test_with_client]
+async fn delete_session_success() {
+    let client = TestClient::new(&ctx.endpoint);
+    let user = setup::add_user('a', false, &ctx.config).await;
+    let session = setup::add_session(&user, false, &ctx.config).await;
+    let (other_session_id, _) = setup::add_session(&user, false, &ctx.config).await;
+
+    let res = delete_with_session(
+        &client,
+        &format!("/auth/sessions/{}", base64_urlsafe(&hash(&other_session_id))),
+        json!({}),
+        &session,
+        &ctx.config,
+    )
+    .await;
+    check_response(&res, StatusCode::OK);
+    res.assert_header_is_not_exist(SET_COOKIE);
+    res.assert_json(json!({ "success": true, "data": null })).await;
+
+    let rows = ctx
+        .db
+        .query(r#"SELECT "id" FROM "sessions""#, &[])
+        .await
+        .unwrap();
+    assert_eq!(rows.len(), 1);
+    assert_eq!(rows[0].get::<_, Vec<u8>>("id"), hash(&session.0));
+
+    // Revoking the current session also clears its cookie
+    let res = delete_with_session(
+        &client,
+        &format!("/auth/sessions/{}", base64_urlsafe(&hash(&session.0))),
+        json!({}),
+        &session,
+        &ctx.config,
+    )
+    .await;
+    check_response(&res, StatusCode::OK);
+    let headers = res.0.headers().clone();
+    let cookie_jar = CookieJar::default();
+    for cookie in headers.get_all(SET_COOKIE) {
+        cookie_jar.add(Cookie::parse(cookie.to_str().unwrap()).unwrap());
+    }
+    check_session_cookie_removed(
+        &cookie_jar.get(&ctx.config.session.cookie).unwrap(),
+        &ctx.config,
+    );
+
+    let row = ctx
+        .db
+        .query_one(r#"SELECT count(*) FROM "sessions""#, &[])
+        .await
+        .unwrap();
+    assert_eq!(row.get::<_, i64>(0), 0);
+}
+
+#[test_with_client]
+async fn sudo_invalid_credentials() {
+    let client = TestClient::new(&ctx.endpoint);
+    let user = setup::add_user('a', true, &ctx.config).await;
+    let session = setup::add_session(&user, false, &ctx.config).await;
+
+    let res = post_with_session(
+        &client,
+        "/auth/sudo",
+        json!({ "password": "wrong password" }),
+        &session,
+        &ctx.config,
+    )
+    .await;
+    check_response(&res, StatusCode::BAD_REQUEST);
+    assert_error(res, "auth", "invalid-credentials").await;
+
+    let res = post_with_session(
+        &client,
+        "/auth/sudo",
+        json!({ "password": user.password }),
+        &session,
+        &ctx.config,
+    )
+    .await;
+    check_response(&res, StatusCode::BAD_REQUEST);
+    assert_error(res, "auth", "missing-totp").await;
+
+    let res = post_with_session(
+        &client,
+        "/auth/sudo",
+        json!({ "password": user.password, "totp": "000000000" }),
+        &session,
+        &ctx.config,
+    )
+    .await;
+    check_response(&res, StatusCode::BAD_REQUEST);
+    assert_error(res, "auth", "invalid-totp").await;
+
+    let row = ctx
+        .db
+        .query_one(r#"SELECT "sudo_until" FROM "sessions""#, &[])
+        .await
+        .unwrap();
+    assert!(row.get::<_, Option<DateTime<Utc>>>("sudo_until").is_none());
+}
+
+#[test_with_client]
+async fn sudo_success() {
+    let client = TestClient::new(&ctx.endpoint);
+    let user = setup::add_user('a', true, &ctx.config).await;
+    let session = setup::add_session(&user, false, &ctx.config).await;
+
+    let res = post_with_session(&client, "/account/totp-key", json!({}), &session, &ctx.config)
+        .await;
+    check_response(&res, StatusCode::FORBIDDEN);
+    assert_error(res, "auth", "sudo-required").await;
+
+    let now = utc_now().timestamp() as u64;
+    let totp = generate_totp(user.totp_key.unwrap().as_bytes(), now, &ctx.config);
+    let res = post_with_session(
+        &client,
+        "/auth/sudo",
+        json!({ "password": user.password, "totp": totp }),
+        &session,
+        &ctx.config,
+    )
+    .await;
+    check_response(&res, StatusCode::OK);
+
+    let row = ctx
+        .db
+        .query_one(r#"SELECT "sudo_until" FROM "sessions""#, &[])
+        .await
+        .unwrap();
+    let sudo_until = row.get::<_, DateTime<Utc>>("sudo_until");
+    assert!(sudo_until > utc_now());
+    res.assert_json(json!({
+        "success": true,
+        "data": null,
+        "sudo_until": sudo_until.to_rfc3339(),
+    }))
+    .await;
+
+    // Sudo mode now allows sensitive endpoints
+    let res = post_with_session(&client, "/account/totp-key", json!({}), &session, &ctx.config)
+        .await;
+    check_response(&res, StatusCode::BAD_REQUEST);
+    assert_error(res, "account", "totp-already-enabled").await;
+}
+
+#[test_with_client]
+async fn audit_events_recorded() {
+    let client = TestClient::new(&ctx.endpoint);
+    let user = setup::add_user('a', false, &ctx.config).await;
+    let session = setup::add_session(&user, false, &ctx.config).await;
+
+    // Errors from anonymous requests would let anyone fill the log
+    let res = client.get("/users/me").send().await;
+    check_response(&res, StatusCode::UNAUTHORIZED);
+    let res = client.get("/auth/sessions").send().await;
+    check_response(&res, StatusCode::UNAUTHORIZED);
+
+    let body = json!({ "username": user.username, "password": "wrong password", "remember": false });
+    let res = post_with_csrf(&client, "/auth/login", body, &ctx.config).await;
+    check_response(&res, StatusCode::BAD_REQUEST);
+    let res = post_with_session(&client, "/auth/logout", json!({}), &session, &ctx.config).await;
+    check_response(&res, StatusCode::OK);
+
+    let rows = ctx
+        .db
+        .query(
+            r#"SELECT "event", "user_id", "session_id", "details" FROM "audit_events" ORDER BY "id""#,
+            &[],
+        )
+        .await
+        .unwrap();
+    assert_eq!(rows.len(), 2);
+    assert_eq!(rows[0].get::<_, &str>("event"), "auth-error:invalid-credentials");
+    assert_eq!(rows[0].get::<_, Option<&str>>("user_id"), Some(user.id.as_str()));
+    assert_eq!(rows[0].get::<_, JsonValue>("details")["path"], "/auth/login");
+    assert_eq!(rows[1].get::<_, &str>("event"), "logout");
+    assert_eq!(rows[1].get::<_, Option<&str>>("user_id"), Some(user.id.as_str()));
+    assert_eq!(
+        rows[1].get::<_, Option<Vec<u8>>>("session_id"),
+        Some(hash(&session.0))
+    );
+
+    // The application may only append to the log
+    assert!(ctx.db.execute(r#"DELETE FROM "audit_events""#, &[]).await.is_err());
+    assert!(ctx
+        .db
+        .execute(r#"UPDATE "audit_events" SET "event" = 'edited'"#, &[])
+        .await
+        .is_err());
+}
+
+// Clients can send X-Forwarded-For themselves; HAProxy adds its own line last
+#[test_with_client]
+async fn client_ip_from_proxy() {
+    let client = TestClient::new(&ctx.endpoint);
+    let user = setup::add_user('a', false, &ctx.config).await;
+
+    let csrf_token = generate_token(ctx.config.csrf.token_length);
+    let res = client
+        .post("/auth/login")
+        .body_json(&json!({ "username": user.username, "password": "wrong", "remember": false }))
+        .header(
+            COOKIE,
+            Cookie::new_with_str(&ctx.config.csrf.cookie, &csrf_token).to_string(),
+        )
+        .header(&ctx.config.csrf.header, &csrf_token)
+        .header("X-Forwarded-For", "192.0.2.1")
+        .header("X-Forwarded-For", "192.0.2.2, 198.51.100.7")
+        .send()
+        .await;
+    check_response(&res, StatusCode::BAD_REQUEST);
+
+    let ip: Option<String> = ctx
+        .db
+        .query_one(r#"SELECT host("ip") FROM "audit_events""#, &[])
+        .await
+        .unwrap()
+        .get(0);
+    assert_eq!(ip.as_deref(), Some("198.51.100.7"));
+}
+
+#[test_with_client]
+async fn get_audit_events() {
+    let client = TestClient::new(&ctx.endpoint);
+    let user = setup::add_user('a', false, &ctx.config).await;
+    let session = setup::add_session(&user, false, &ctx.config).await;
+
+    let res = get_with_session(&client, "/audit", &session, &ctx.config).await;
+    check_response(&res, StatusCode::FORBIDDEN);
+    assert_error(res, "auth", "forbidden").await;
+
+    ctx.db
+        .execute(
+            r#"INSERT INTO "permissions"("user_id", "permission") VALUES ($1, $2)"#,
+            &[&user.id, &Permission::ViewAuditLog],
+        )
+        .await
+        .unwrap();
+    for _ in 0..3 {
+        ctx.db
+            .execute(
+                r#"
+                INSERT INTO "audit_events"("created", "event", "details")
+                VALUES ($1, 'test', '{}')
+                "#,
+                &[&utc_now()],
+            )
+            .await
+            .unwrap();
+    }
+
+    let res = get_with_session(&client, "/audit?event=test&limit=2", &session, &ctx.config).await;
+    check_response(&res, StatusCode::OK);
+    let json = res.json().await;
+    let data = json.value().object().get("data").object();
+    let events = data.get("events").object_array();
+    assert_eq!(events.len(), 2);
+    let ids: Vec<_> = events.iter().map(|event| event.get("id").i64()).collect();
+    assert!(ids[0] > ids[1]);
+    let cursor = data.get("next_cursor").i64();
+    assert_eq!(cursor, ids[1]);
+
+    let path = format!("/audit?event=test&limit=2&cursor={cursor}");
+    let res = get_with_session(&client, &path, &session, &ctx.config).await;
+    check_response(&res, StatusCode::OK);
+    let json = res.json().await;
+    let data = json.value().object().get("data").object();
+    let events = data.get("events").object_array();
+    assert_eq!(events.len(), 1);
+    assert!(events[0].get("id").i64() < cursor);
+    data.get("next_cursor").assert_null();
+
+    let path = format!("/audit?user_id={}&event=auth-error:forbidden", user.id);
+    let res = get_with_session(&client, &path, &session, &ctx.config).await;
+    check_response(&res, StatusCode::OK);
+    let json = res.json().await;
+    let events = json.value().object().get("data").object().get("events").object_array();
+    assert_eq!(events.len(), 1);
+    events[0].get("user_id").assert_string(&user.id);
+    events[0]
+        .get("session_id")
+        .assert_string(&base64_urlsafe(&hash(&session.0)));
+
+    let res = get_with_session(&client, "/audit?limit=0", &session, &ctx.config).await;
+    check_response(&res, StatusCode::BAD_REQUEST);
+    assert_validation_errors(res, &[("limit", "out-of-range")]).await;
+
+    let res = get_with_session(&client, "/audit?after=yesterday", &session, &ctx.config).await;
+    check_response(&res, StatusCode::BAD_REQUEST);
+    assert_error_with_details(res, "general", "invalid-data").await;
+}
+
+#[test_with_client]
+async fn new_device_login_notification() {
+    let client = TestClient::new(&ctx.endpoint);
+    let user = setup::add_user('a', false, &ctx.config).await;
+    ctx.db
+        .execute(
+            r#"
+            UPDATE "users" SET "email" = 'a@example.com', "email_verified" = true
+            WHERE "id" = $1
+            "#,
+            &[&user.id],
+        )
+        .await
+        .unwrap();
+
+    let csrf_token = generate_token(ctx.config.csrf.token_length);
+    let csrf_cookie = Cookie::new_with_str(&ctx.config.csrf.cookie, &csrf_token);
+    let login = |user_agent: &'static str| {
+        client
+            .post("/auth/login")
+            .body_json(&json!({
+                "username": user.username,
+                "password": user.password,
+                "remember": false,
+            }))
+            .header(COOKIE, csrf_cookie.to_string())
+            .header(&ctx.config.csrf.header, &csrf_token)
+            .header(USER_AGENT, user_agent)
+            .send()
+    };
+
+    // Neither the first device nor a known one triggers a notification
+    for user_agent in ["Browser A", "Browser A"] {
+        let res = login(user_agent).await;
+        check_response(&res, StatusCode::OK);
+        assert!(take_outbox(&ctx.config).is_empty());
+    }
+
+    let res = login("Browser B").await;
+    check_response(&res, StatusCode::OK);
+    let emails = take_outbox(&ctx.config);
+    assert_eq!(emails.len(), 1);
+    assert_eq!(emails[0].to, "a@example.com");
+    assert!(emails[0].body.contains("Browser B"));
+    let token = token_from_email(&emails[0]);
+
+    let row = ctx
+        .db
+        .query_one(r#"SELECT count(*) FROM "sessions""#, &[])
+        .await
+        .unwrap();
+    assert_eq!(row.get::<_, i64>(0), 3);
+
+    let body = json!({ "token": token });
+    let res = post_with_csrf(&client, "/auth/revoke-sessions", body.clone(), &ctx.config).await;
+    check_response(&res, StatusCode::OK);
+    let row = ctx
+        .db
+        .query_one(r#"SELECT count(*) FROM "sessions""#, &[])
+        .await
+        .unwrap();
+    assert_eq!(row.get::<_, i64>(0), 0);
+
+    let res = post_with_csrf(&client, "/auth/revoke-sessions", body, &ctx.config).await;
+    check_response(&res, StatusCode::BAD_REQUEST);
+    assert_error(res, "account", "invalid-session-revocation-token").await;
+}
+
+#[test_with_client]
+async fn login_rehashes_password() {
+    let client = TestClient::new(&ctx.endpoint);
+    let user = setup::add_user('a', false, &ctx.config).await;
+    let old_config = setup::config_with(|config_data| {
+        config_data.security.argon2_time_cost -= 1;
+    });
+    let old_hash = hash_encrypt_password(&user.password, &old_config).unwrap();
+    assert!(password_needs_rehash(&old_hash, &ctx.config).unwrap());
+    ctx.db
+        .execute(
+            r#"UPDATE "users" SET "password" = $1 WHERE "id" = $2"#,
+            &[&old_hash, &user.id],
+        )
+        .await
+        .unwrap();
+
+    let login = || {
+        post_with_csrf(
+            &client,
+            "/auth/login",
+            json!({ "username": user.username, "password": user.password, "remember": false }),
+            &ctx.config,
+        )
+    };
+    let get_hash = || async {
+        ctx.db
+            .query_one(r#"SELECT "password" FROM "users" WHERE "id" = $1"#, &[&user.id])
+            .await
+            .unwrap()
+            .get::<_, Vec<u8>>("password")
+    };
+
+    check_response(&login().await, StatusCode::OK);
+    let new_hash = get_hash().await;
+    assert_ne!(new_hash, old_hash);
+    assert!(!password_needs_rehash(&new_hash, &ctx.config).unwrap());
+    assert!(verify_password(&user.password, &new_hash, &ctx.config).unwrap());
+
+    // Up-to-date hashes are left alone
+    check_response(&login().await, StatusCode::OK);
+    assert_eq!(get_hash().await, new_hash);
+}
+
+#[test]
+fn legacy_password_hash() {
+    const FERNET_KEY: &str = "cw_0x689RpI-jtRR7oE8h_eQsKImvJapLeSbXpwF4e4=";
+    let config = setup::config_with(|config_data| {
+        config_data.security.legacy_fernet_key = Some(FERNET_KEY.to_owned());
+    });
+
+    // server-py hashed with the argon2-cffi defaults
+    let salt = SaltString::generate(&mut rand::thread_rng());
+    let hash = Argon2::default()
+        .hash_password(b"password", &salt)
+        .unwrap()
+        .to_string();
+    let legacy_hash = setup::fernet_encrypt(hash.as_bytes(), FERNET_KEY);
+
+    assert!(verify_password("password", &legacy_hash, &config).unwrap());
+    assert!(!verify_password("Password", &legacy_hash, &config).unwrap());
+    assert!(password_needs_rehash(&legacy_hash, &config).unwrap());
+
+    let mut tampered = legacy_hash.clone();
+    let last = tampered.len() - 3;
+    tampered[last] = if tampered[last] == b'A' { b'B' } else { b'A' };
+    assert!(verify_password("password", &tampered, &config).is_err());
+
+    // Without the key the hash cannot be read at all
+    assert!(verify_password("password", &legacy_hash, &setup::config_with(|_| {})).is_err());
+}
+
+#[test_with_client]
+async fn reencrypt_users_skips_legacy_password_hashes() {
+    const OLD_KEY: &str = "87428fc522803d31065e7bce3cf03fe475096631e5e07bbd7a0fde60c4cf25c7";
+    const NEW_KEY: &str = "0b1c3a9d0f4e5c6b7a8f9e0d1c2b3a4f5e6d7c8b9a0f1e2d3c4b5a6f7e8d9c0b";
+    const FERNET_KEY: &str = "cw_0x689RpI-jtRR7oE8h_eQsKImvJapLeSbXpwF4e4=";
+    let mut old_config = ctx.config.clone();
+    old_config.dev.debug = false;
+    old_config.aes = setup::encryption_config(&[(1, OLD_KEY)], 1).aes;
+    let mut new_config = old_config.clone();
+    new_config.aes = setup::encryption_config(&[(1, OLD_KEY), (2, NEW_KEY)], 2).aes;
+
+    let user = setup::add_user('a', false, &ctx.config).await;
+    let legacy_user = setup::add_user('b', false, &ctx.config).await;
+    let password_hash = hash_encrypt_password("password", &old_config).unwrap();
+    let legacy_hash = setup::fernet_encrypt(b"legacy hash", FERNET_KEY);
+    for (user_id, password) in [(&user.id, &password_hash), (&legacy_user.id, &legacy_hash)] {
+        ctx.db
+            .execute(
+                r#"UPDATE "users" SET "password" = $1, "totp_key" = NULL WHERE "id" = $2"#,
+                &[password, user_id],
+            )
+            .await
+            .unwrap();
+    }
+
+    assert_eq!(db::reencrypt_users(10, &new_config).await, 1);
+    let get_password = |user_id: String| async move {
+        ctx.db
+            .query_one(r#"SELECT "password" FROM "users" WHERE "id" = $1"#, &[&user_id])
+            .await
+            .unwrap()
+            .get::<_, Vec<u8>>(0)
+    };
+    let reencrypted = get_password(user.id.clone()).await;
+    assert_eq!(encryption_key_id(&reencrypted), Some(2));
+    assert!(verify_password("password", &reencrypted, &new_config).unwrap());
+    assert_eq!(get_password(legacy_user.id.clone()).await, legacy_hash);
+
+    // Nothing is left to do
+    assert_eq!(db::reencrypt_users(10, &new_config).await, 0);
+}
+
+#[test_with_client]
+async fn migrate_db_from_unprefixed_schema() {
+    const KEY: &str = "87428fc522803d31065e7bce3cf03fe475096631e5e07bbd7a0fde60c4cf25c7";
+    const FERNET_KEY: &str = "cw_0x689RpI-jtRR7oE8h_eQsKImvJapLeSbXpwF4e4=";
+    let mut config = ctx.config.clone();
+    config.dev.debug = false;
+    config.aes = setup::encryption_config(&[(1, KEY)], 1).aes;
+    // The tables belong to the user that created them
+    let owner_config = DbConfig {
+        dbname: config.db.dbname.clone(),
+        ..config.dev.init_db.clone().unwrap()
+    };
+    let owner_db = owner_config.create_pool(None, NoTls).unwrap();
+    let owner_db = owner_db.get().await.unwrap();
+
+    let user = setup::add_user('a', false, &ctx.config).await;
+    let password_hash = hash_encrypt_password("password", &config).unwrap();
+    let totp_key = encrypt(
+        &generate_totp_key(&config),
+        &config,
+        &mut rand::thread_rng(),
+    )
+    .unwrap();
+    owner_db
+        .execute(
+            r#"ALTER TABLE "users" DROP CONSTRAINT "users_totp_key_check""#,
+            &[],
+        )
+        .await
+        .unwrap();
+    ctx.db
+        .execute(
+            r#"UPDATE "users" SET "password" = $1, "totp_key" = $2 WHERE "id" = $3"#,
+            &[&&password_hash[1..], &&totp_key[1..], &user.id],
+        )
+        .await
+        .unwrap();
+    // The constraints from before encrypted values had a key ID prefix
+    owner_db
+        .batch_execute(&format!(
+            r#"
+            ALTER TABLE "users"
+                ADD CONSTRAINT "users_password_check" CHECK (length("password") = {}),
+                ADD CONSTRAINT "users_totp_key_check" CHECK (length("totp_key") = {});
+            "#,
+            password_hash.len() - 1,
+            totp_key.len() - 1,
+        ))
+        .await
+        .unwrap();
+    let update_user = |password: Vec<u8>, totp_key: Vec<u8>| {
+        let user_id = user.id.clone();
+        async move {
+            ctx.db
+                .execute(
+                    r#"UPDATE "users" SET "password" = $1, "totp_key" = $2 WHERE "id" = $3"#,
+                    &[&password, &totp_key, &user_id],
+                )
+                .await
+        }
+    };
+    let err = update_user(password_hash.clone(), totp_key.clone())
+        .await
+        .unwrap_err();
+    assert_eq!(err.code(), Some(&SqlState::CHECK_VIOLATION));
+
+    db::init_db(false, &config).await;
+    update_user(password_hash.clone(), totp_key.clone())
+        .await
+        .unwrap();
+    // Values that reencrypt-users has not rewritten yet stay valid
+    update_user(password_hash[1..].to_vec(), totp_key[1..].to_vec())
+        .await
+        .unwrap();
+    let fernet_hash = setup::fernet_encrypt(b"legacy hash", FERNET_KEY);
+    update_user(fernet_hash, totp_key).await.unwrap();
+    let new_totp_key = encrypt(
+        &generate_totp_key(&config),
+        &config,
+        &mut rand::thread_rng(),
+    )
+    .unwrap();
+    ctx.db
+        .execute(
+            r#"INSERT INTO "new_totp_keys"("user_id", "key", "expires") VALUES ($1, $2, $3)"#,
+            &[&user.id, &new_totp_key, &utc_now()],
+        )
+        .await
+        .unwrap();
+}
+
+#[test_with_client]
+async fn magic_link_unknown_user() {
+    let client = TestClient::new(&ctx.endpoint);
+    let user = setup::add_user('a', false, &ctx.config).await;
+    ctx.db
+        .execute(
+            r#"UPDATE "users" SET "email" = 'a@example.com' WHERE "id" = $1"#,
+            &[&user.id],
+        )
+        .await
+        .unwrap();
+
+    // Users without a verified email address are treated like unknown users
+    for username_or_email in [user.username.as_str(), "a@example.com", "nobody@example.com"] {
+        let res = post_with_csrf(
+            &client,
+            "/auth/magic-link",
+            json!({ "username_or_email": username_or_email, "remember": false }),
+            &ctx.config,
+        )
+        .await;
+        check_response(&res, StatusCode::OK);
+        res.assert_json(json!({ "success": true, "data": null })).await;
+    }
+
+    assert!(take_outbox(&ctx.config).is_empty());
+}
+
+#[test_with_client]
+async fn password_reset_unknown_user() {
+    let client = TestClient::new(&ctx.endpoint);
+    let user = setup::add_user('a', false, &ctx.config).await;
+    ctx.db
+        .execute(
+            r#"UPDATE "users" SET "email" = 'a@example.com' WHERE "id" = $1"#,
+            &[&user.id],
+        )
+        .await
+        .unwrap();
+
+    // Users without a verified email address are treated like unknown users
+    for username_or_email in [user.username.as_str(), "a@example.com", "nobody@example.com"] {
+        let res = post_with_csrf(
+            &client,
+            "/auth/password-reset",
+            json!({ "username_or_email": username_or_email }),
+            &ctx.config,
+        )
+        .await;
+        check_response(&res, StatusCode::OK);
+        res.assert_json(json!({ "success": true, "data": null })).await;
+    }
+
+    assert!(take_outbox(&ctx.config).is_empty());
+    let row = ctx
+        .db
+        .query_one(r#"SELECT count(*) FROM "password_reset_tokens""#, &[])
+        .await
+        .unwrap();
+    assert_eq!(row.get::<_, i64>(0), 0);
+}
+
+#[test_with_client]
+async fn email_matching_username() {
+    let client = TestClient::new(&ctx.endpoint);
+    let user = setup::add_user('a', false, &ctx.config).await;
+    let other_user = setup::add_user('b', false, &ctx.config).await;
+    ctx.db
+        .execute(
+            r#"
+            UPDATE "users" SET
+                "username" = CASE WHEN "id" = $1 THEN "username" ELSE 'a@example.com' END,
+                "email" = CASE WHEN "id" = $1 THEN 'a@example.com' ELSE 'b@example.com' END,
+                "email_verified" = true
+            WHERE "id" IN ($1, $2)
+            "#,
+            &[&user.id, &other_user.id],
+        )
+        .await
+        .unwrap();
+
+    let res = post_with_csrf(
+        &client,
+        "/auth/password-reset",
+        json!({ "username_or_email": "a@example.com" }),
+        &ctx.config,
+    )
+    .await;
+    check_response(&res, StatusCode::OK);
+    let outbox = take_outbox(&ctx.config);
+    assert_eq!(outbox.len(), 1);
+    assert_eq!(outbox[0].to, "a@example.com");
+}
+
+#[test_with_client]
+async fn password_reset_invalid_token() {
+    let client = TestClient::new(&ctx.endpoint);
+    let user = setup::add_user('a', false, &ctx.config).await;
+    ctx.db
+        .execute(
+            r#"
+            UPDATE "users" SET "email" = 'a@example.com', "email_verified" = true
+            WHERE "id" = $1
+            "#,
+            &[&user.id],
+        )
+        .await
+        .unwrap();
+
+    let res = post_with_csrf(
+        &client,
+        "/auth/password-reset/confirm",
+        json!({ "token": "invalid", "password": "new password" }),
+        &ctx.config,
+    )
+    .await;
+    check_response(&res, StatusCode::BAD_REQUEST);
+    assert_error(res, "account", "invalid-password-reset-token").await;
+
+    let res = post_with_csrf(
+        &client,
+        "/auth/password-reset",
+        json!({ "username_or_email": "A@example.com" }),
+        &ctx.config,
+    )
+    .await;
+    check_response(&res, StatusCode::OK);
+    let token = token_from_email(&take_outbox(&ctx.config)[0]);
+    ctx.db
+        .execute(
+            r#"UPDATE "password_reset_tokens" SET "expires" = $1"#,
+            &[&(utc_now() - ctx.config.password_reset.token_lifetime)],
+        )
+        .await
+        .unwrap();
+
+    let res = post_with_csrf(
+        &client,
+        "/auth/password-reset/confirm",
+        json!({ "token": token, "password": "new password" }),
+        &ctx.config,
+    )
+    .await;
+    check_response(&res, StatusCode::BAD_REQUEST);
+    assert_error(res, "account", "invalid-password-reset-token").await;
+}
+
+#[test_with_client]
+async fn password_reset_success() {
+    let client = TestClient::new(&ctx.endpoint);
+    let user = setup::add_user('a', false, &ctx.config).await;
+    let session = setup::add_session(&user, false, &ctx.config).await;
+    ctx.db
+        .execute(
+            r#"
+            UPDATE "users" SET "email" = 'a@example.com', "email_verified" = true
+            WHERE "id" = $1
+            "#,
+            &[&user.id],
+        )
+        .await
+        .unwrap();
+
+    // A second request invalidates the first token
+    for _ in 0..2 {
+        let res = post_with_csrf(
+            &client,
+            "/auth/password-reset",
+            json!({ "username_or_email": user.username }),
+            &ctx.config,
+        )
+        .await;
+        check_response(&res, StatusCode::OK);
+    }
+    let emails = take_outbox(&ctx.config);
+    assert_eq!(emails.len(), 2);
+    assert_eq!(emails[1].to, "a@example.com");
+    let old_token = token_from_email(&emails[0]);
+    let token = token_from_email(&emails[1]);
+
+    let res = post_with_csrf(
+        &client,
+        "/auth/password-reset/confirm",
+        json!({ "token": old_token, "password": "new password" }),
+        &ctx.config,
+    )
+    .await;
+    check_response(&res, StatusCode::BAD_REQUEST);
+    assert_error(res, "account", "invalid-password-reset-token").await;
+
+    let res = post_with_csrf(
+        &client,
+        "/auth/password-reset/confirm",
+        json!({ "token": token, "password": "" }),
+        &ctx.config,
+    )
+    .await;
+    check_response(&res, StatusCode::BAD_REQUEST);
+    assert_validation_errors(res, &[("password", "too-short")]).await;
+
+    // Rejected passwords leave the token usable
+    let res = post_with_csrf(
+        &client,
+        "/auth/password-reset/confirm",
+        json!({ "token": token, "password": "a@example.com password" }),
+        &ctx.config,
+    )
+    .await;
+    check_response(&res, StatusCode::BAD_REQUEST);
+    assert_error(res, "password", "contains-user-input").await;
+
+    let res = post_with_csrf(
+        &client,
+        "/auth/password-reset/confirm",
+        json!({ "token": token, "password": "new password" }),
+        &ctx.config,
+    )
+    .await;
+    check_response(&res, StatusCode::OK);
+    res.assert_json(json!({ "success": true, "data": null })).await;
+
+    // Sessions are revoked and the token cannot be reused
+    let res = get_with_session(&client, "/auth/sessions", &session, &ctx.config).await;
+    check_response(&res, StatusCode::UNAUTHORIZED);
+    let res = post_with_csrf(
+        &client,
+        "/auth/password-reset/confirm",
+        json!({ "token": token, "password": "another password" }),
+        &ctx.config,
+    )
+    .await;
+    check_response(&res, StatusCode::BAD_REQUEST);
+    assert_error(res, "account", "invalid-password-reset-token").await;
+
+    let res = post_with_csrf(
+        &client,
+        "/auth/login",
+        json!({ "username": user.username, "password": "new password", "remember": false }),
+        &ctx.config,
+    )
+    .await;
+    check_response(&res, StatusCode::OK);
+}
+
+#[test_with_client]
+async fn oidc_providers() {
+    let client = TestClient::new(&ctx.endpoint);
+    let res = client.get("/auth/oidc/providers").send().await;
+    check_response(&res, StatusCode::OK);
+    res.assert_json(json!({
+        "success": true,
+        "data": [{ "id": "mock", "name": "Mock" }],
+    }))
+    .await;
+}
+
+fn test_oidc_state(code_verifier: &str, nonce: &str) -> OidcState {
+    OidcState {
+        provider: "mock".to_owned(),
+        nonce: nonce.to_owned(),
+        code_verifier: code_verifier.to_owned(),
+        csrf_token_hash: String::new(),
+        remember: false,
+        user_id: None,
+    }
+}
+
+#[test_with_client]
+async fn oidc_finish_flow_success() {
+    let mock = setup::start_oidc_provider(&ctx.config).await;
+    let http = reqwest::Client::new();
+    let oidc_state = test_oidc_state("verifier", "nonce");
+    let code = mock.authorize(&code_challenge("verifier"), "nonce", "subject");
+
+    let claims = oidc::finish_flow(&mock.provider, &code, &oidc_state, "http://client", &http)
+        .await
+        .unwrap();
+    assert_eq!(claims.subject, "subject");
+
+    // Codes are single-use
+    let result = oidc::finish_flow(&mock.provider, &code, &oidc_state, "http://client", &http).await;
+    assert!(matches!(result, Err(OidcError::TokenEndpoint(_))));
+}
+
+#[test_with_client]
+async fn oidc_finish_flow_invalid() {
+    let mock = setup::start_oidc_provider(&ctx.config).await;
+    let http = reqwest::Client::new();
+
+    // PKCE verifier does not match the challenge
+    let code = mock.authorize(&code_challenge("verifier"), "nonce", "subject");
+    let oidc_state = test_oidc_state("other verifier", "nonce");
+    let result = oidc::finish_flow(&mock.provider, &code, &oidc_state, "http://client", &http).await;
+    assert!(matches!(result, Err(OidcError::TokenEndpoint(_))));
+
+    let code = mock.authorize(&code_challenge("verifier"), "other nonce", "subject");
+    let oidc_state = test_oidc_state("verifier", "nonce");
+    let result = oidc::finish_flow(&mock.provider, &code, &oidc_state, "http://client", &http).await;
+    assert!(matches!(result, Err(OidcError::NonceMismatch)));
+
+    let now = utc_now().timestamp();
+    let claims = json!({
+        "iss": mock.provider.issuer,
+        "aud": "another client",
+        "sub": "subject",
+        "nonce": "nonce",
+        "exp": now + 60,
+    });
+    let id_token = setup::sign_id_token(&claims);
+    let result = oidc::validate_id_token(&mock.provider, &id_token, "nonce", &http).await;
+    assert!(matches!(result, Err(OidcError::InvalidIdToken(_))));
+
+    let id_token = jsonwebtoken::encode(
+        &jsonwebtoken::Header::default(),
+        &json!({
+            "iss": mock.provider.issuer,
+            "aud": mock.provider.client_id,
+            "sub": "subject",
+            "nonce": "nonce",
+            "exp": now + 60,
+        }),
+        &jsonwebtoken::EncodingKey::from_secret(mock.provider.client_secret.as_bytes()),
+    )
+    .unwrap();
+    let result = oidc::validate_id_token(&mock.provider, &id_token, "nonce", &http).await;
+    assert!(matches!(result, Err(OidcError::UnsupportedAlgorithm)));
+}
+
+#[test]
+fn encryption_key_rotation() {
+    const OLD_KEY: &str = "87428fc522803d31065e7bce3cf03fe475096631e5e07bbd7a0fde60c4cf25c7";
+    const NEW_KEY: &str = "0b1c3a9d0f4e5c6b7a8f9e0d1c2b3a4f5e6d7c8b9a0f1e2d3c4b5a6f7e8d9c0b";
+    let old_config = setup::encryption_config(&[(1, OLD_KEY)], 1);
+    let rotated_config = setup::encryption_config(&[(1, OLD_KEY), (2, NEW_KEY)], 2);
+    let new_config = setup::encryption_config(&[(2, NEW_KEY)], 2);
+
+    let old = encrypt(b"secret", &old_config, &mut rand::thread_rng()).unwrap();
+    assert_eq!(encryption_key_id(&old), Some(1));
+    assert_eq!(decrypt(&old, &rotated_config).unwrap(), b"secret");
+
+    let new = encrypt(b"secret", &rotated_config, &mut rand::thread_rng()).unwrap();
+    assert_eq!(encryption_key_id(&new), Some(2));
+    assert_eq!(new.len(), old.len());
+    assert_eq!(decrypt(&new, &new_config).unwrap(), b"secret");
+
+    // Data under a key that has been removed can no longer be read
+    assert!(decrypt(&old, &new_config).is_err());
+    assert!(decrypt(&new, &old_config).is_err());
+}
+
+#[test]
+fn legacy_encryption_key() {
+    const LEGACY_KEY: &str = "87428fc522803d31065e7bce3cf03fe475096631e5e07bbd7a0fde60c4cf25c7";
+    const NEW_KEY: &str = "0b1c3a9d0f4e5c6b7a8f9e0d1c2b3a4f5e6d7c8b9a0f1e2d3c4b5a6f7e8d9c0b";
+    let config = setup::config_with(|config_data| {
+        config_data.dev.as_mut().unwrap().debug = false;
+        config_data.security.aes_key = Some(LEGACY_KEY.to_owned());
+        config_data.security.aes_keys[0].key = NEW_KEY.to_owned();
+    });
+
+    // Values from before key IDs have no prefix; pick one whose first byte
+    // looks like the ID of the configured key to check that it is not misread
+    let legacy = Aes256GcmSiv::new_from_slice(&hex::decode(LEGACY_KEY).unwrap()).unwrap();
+    let encrypted = (0..=u16::MAX)
+        .map(|i| {
+            let mut nonce = [0; 12];
+            nonce[..2].copy_from_slice(&i.to_le_bytes());
+            let mut encrypted = legacy.encrypt(Nonce::from_slice(&nonce), &b"secret"[..]).unwrap();
+            encrypted.extend_from_slice(&nonce);
+            encrypted
+        })
+        .find(|encrypted| encrypted[0] == config.aes.active_id)
+        .unwrap();
+    assert_eq!(
+        decrypt_with_key_id(&encrypted, &config).unwrap(),
+        (None, b"secret".to_vec())
+    );
+
+    let new = encrypt(b"secret", &config, &mut rand::thread_rng()).unwrap();
+    assert_eq!(
+        decrypt_with_key_id(&new, &config).unwrap(),
+        (Some(config.aes.active_id), b"secret".to_vec())
+    );
+}
@@ -1,12 +1,40 @@
-use chrono::Duration;
+// Shared by every test binary, none of which uses all of it
+#![allow(dead_code)]
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use aes::cipher::{block_padding::Pkcs7, BlockEncryptMut, KeyIvInit};
+use base64::engine::{general_purpose::URL_SAFE as BASE64_URL_SAFE, Engine};
+use chrono::{DateTime, Duration, Utc};
+use ciborium::value::Value as CborValue;
 use deadpool_postgres::{tokio_postgres::NoTls, ClientWrapper, Object};
-use poem::{Endpoint, Response};
+use hmac::{Hmac, Mac};
+use jsonwebtoken::{encode, Algorithm, EncodingKey, Header};
+use p256::ecdsa::{signature::Signer, Signature, SigningKey};
+use poem::{
+    handler,
+    http::StatusCode,
+    listener::{Acceptor, Listener, TcpListener},
+    post,
+    web::{Data, Form, Json},
+    Endpoint, EndpointExt, Response, Route, Server,
+};
 use rand::{distributions::Standard, thread_rng, Rng};
+use serde_json::{json, Value as JsonValue};
+use sha2::{Digest, Sha256};
 
 use dodatok::{
-    config::{Config, ConfigInput},
-    db::Language,
-    util::{encrypt, generate_token, generate_totp_key, hash, hash_encrypt_password, utc_now},
+    config::{AesKeyConfigInput, Config, ConfigInput, OidcProviderConfig},
+    db::{AccessTokenScope, Language},
+    oidc::code_challenge,
+    util::{
+        base64_urlsafe, encrypt, generate_token, generate_totp_key, hash, hash_encrypt_password,
+        utc_now,
+    },
+    webauthn::ES256,
 };
 
 pub struct TestUser {
@@ -34,6 +62,48 @@ pub async fn init(test_name: &str) -> (impl Endpoint<Output = Response>, ClientW
     (endpoint, db, config)
 }
 
+pub fn config_with(modify: impl FnOnce(&mut ConfigInput)) -> Config {
+    let mut config_data: ConfigInput =
+        toml::from_str(&std::fs::read_to_string("config.test.toml").unwrap()).unwrap();
+    modify(&mut config_data);
+    Config::new(&config_data)
+}
+
+// Encryption is disabled in debug mode, so test it with a config of its own
+pub fn encryption_config(keys: &[(u8, &str)], active_key: u8) -> Config {
+    config_with(|config_data| {
+        config_data.dev.as_mut().unwrap().debug = false;
+        config_data.security.aes_keys = keys
+            .iter()
+            .map(|(id, key)| AesKeyConfigInput {
+                id: *id,
+                key: key.to_string(),
+            })
+            .collect();
+        config_data.security.active_aes_key = active_key;
+    })
+}
+
+// Produces tokens like the ones server-py stored its password hashes in
+pub fn fernet_encrypt(plaintext: &[u8], key: &str) -> Vec<u8> {
+    let key = BASE64_URL_SAFE.decode(key).unwrap();
+    let (signing_key, encryption_key) = key.split_at(16);
+    let iv: [u8; 16] = thread_rng().gen();
+
+    let mut token = vec![0x80];
+    token.extend_from_slice(&utc_now().timestamp().to_be_bytes());
+    token.extend_from_slice(&iv);
+    token.extend(
+        cbc::Encryptor::<aes::Aes128>::new_from_slices(encryption_key, &iv)
+            .unwrap()
+            .encrypt_padded_vec_mut::<Pkcs7>(plaintext),
+    );
+    let mut mac = Hmac::<Sha256>::new_from_slice(signing_key).unwrap();
+    mac.update(&token);
+    token.extend_from_slice(&mac.finalize().into_bytes());
+    BASE64_URL_SAFE.encode(token).into_bytes()
+}
+
 pub async fn add_session(user: &TestUser, expired: bool, config: &Config) -> (String, String) {
     let db_pool = config.db.create_pool(None, NoTls).unwrap();
     let db = db_pool.get().await.unwrap();
@@ -48,16 +118,55 @@ pub async fn add_session(user: &TestUser, expired: bool, config: &Config) -> (St
 
     db.execute(
         r#"
-        INSERT INTO "sessions"("id", "user_id", "csrf_token", "expires")
-        VALUES ($1, $2, $3, $4)
+        INSERT INTO "sessions"("id", "user_id", "csrf_token", "expires", "created", "last_seen")
+        VALUES ($1, $2, $3, $4, $5, $5)
         "#,
-        &[&hash(&session_id), &user.id, &csrf_token, &session_expires],
+        &[
+            &hash(&session_id),
+            &user.id,
+            &csrf_token,
+            &session_expires,
+            &utc_now(),
+        ],
     )
     .await
     .unwrap();
     (session_id, csrf_token)
 }
 
+pub async fn add_access_token(
+    user: &TestUser,
+    scopes: &[AccessTokenScope],
+    config: &Config,
+) -> String {
+    let db_pool = config.db.create_pool(None, NoTls).unwrap();
+    let db = db_pool.get().await.unwrap();
+
+    let token = generate_token(config.access_token.token_length);
+    db.execute(
+        r#"
+        INSERT INTO "access_tokens"("id", "user_id", "name", "scopes", "created")
+        VALUES ($1, $2, 'test', $3, $4)
+        "#,
+        &[&hash(&token), &user.id, &scopes, &utc_now()],
+    )
+    .await
+    .unwrap();
+    token
+}
+
+pub async fn set_sudo_until(session_id: &str, sudo_until: DateTime<Utc>, config: &Config) {
+    let db_pool = config.db.create_pool(None, NoTls).unwrap();
+    let db = db_pool.get().await.unwrap();
+
+    db.execute(
+        r#"UPDATE "sessions" SET "sudo_until" = $1 WHERE "id" = $2"#,
+        &[&sudo_until, &hash(session_id)],
+    )
+    .await
+    .unwrap();
+}
+
 pub async fn add_user(username_char: char, totp: bool, config: &Config) -> TestUser {
     let db_pool = config.db.create_pool(None, NoTls).unwrap();
     let db = db_pool.get().await.unwrap();
@@ -83,7 +192,7 @@ pub async fn add_user(username_char: char, totp: bool, config: &Config) -> TestU
 
     let totp_key = if totp {
         let totp_key = generate_totp_key(config);
-        let encrypted_totp_key = encrypt(&totp_key, &config, &mut thread_rng()).unwrap();
+        let encrypted_totp_key = encrypt(&totp_key, config, &mut thread_rng()).unwrap();
         db.execute(
             r#"UPDATE "users" SET "totp_key" = $1 WHERE "id" = $2"#,
             &[&encrypted_totp_key, &id],
@@ -104,3 +213,204 @@ pub async fn add_user(username_char: char, totp: bool, config: &Config) -> TestU
         language,
     }
 }
+
+const OIDC_KEY_ID: &str = "test";
+const OIDC_KEY_N: &str = "s3Y6xPehxekrGZfEVUrs8U7jY3FBfcONoEuqhbNOlEkDtY8bNYV47Y1dNUiDH_ILXpt2aj9MEWkM3zvgWKRN94ZiF90pbsfGB-XU6XPcP0SUm1L1dH239CAmEyWCgJ8k5lVt1LamaJOOnqgiPgwWi2vgUYtfftZJhlLBMCRxNQTd1T90pi__5zAkqECgQUU6pgtvUGzh_M2W5L_Mhty1qe67NsLyCQj8QtWm2nbx0Z9FoJUig2fm-p0mXWANBmd5Kcgea4644VCkvB_zIhjv3YWZHhsnygIQsIEWIVQi56kpTe1yXQlxRFIspaAAbBTB4h8HS2scVbmK3EF6-ZtUtw";
+
+struct MockAuthorization {
+    code_challenge: String,
+    nonce: String,
+    subject: String,
+}
+
+#[derive(Clone)]
+struct MockOidcState {
+    codes: Arc<Mutex<HashMap<String, MockAuthorization>>>,
+    provider: OidcProviderConfig,
+}
+
+pub struct MockOidcProvider {
+    pub provider: OidcProviderConfig,
+    state: MockOidcState,
+}
+
+impl MockOidcProvider {
+    // Returns a code the token endpoint will exchange once for an ID token
+    pub fn authorize(&self, code_challenge: &str, nonce: &str, subject: &str) -> String {
+        let code = generate_token(32);
+        self.state.codes.lock().unwrap().insert(
+            code.clone(),
+            MockAuthorization {
+                code_challenge: code_challenge.to_owned(),
+                nonce: nonce.to_owned(),
+                subject: subject.to_owned(),
+            },
+        );
+        code
+    }
+}
+
+pub fn sign_id_token(claims: &JsonValue) -> String {
+    let header = Header {
+        kid: Some(OIDC_KEY_ID.to_owned()),
+        ..Header::new(Algorithm::RS256)
+    };
+    let key = EncodingKey::from_rsa_pem(include_bytes!("oidc_key.pem")).unwrap();
+    encode(&header, claims, &key).unwrap()
+}
+
+#[handler]
+fn mock_oidc_token(
+    state: Data<&MockOidcState>,
+    Form(form): Form<HashMap<String, String>>,
+) -> Result<Json<JsonValue>, StatusCode> {
+    let authorization = state.codes.lock().unwrap().remove(&form["code"]);
+    let Some(authorization) = authorization else {
+        return Err(StatusCode::BAD_REQUEST);
+    };
+    if form["client_id"] != state.provider.client_id
+        || form["client_secret"] != state.provider.client_secret
+        || code_challenge(&form["code_verifier"]) != authorization.code_challenge
+    {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+
+    let now = utc_now().timestamp();
+    let id_token = sign_id_token(&json!({
+        "iss": state.provider.issuer,
+        "aud": state.provider.client_id,
+        "sub": authorization.subject,
+        "nonce": authorization.nonce,
+        "iat": now,
+        "exp": now + 60,
+    }));
+    Ok(Json(json!({
+        "access_token": "unused",
+        "token_type": "Bearer",
+        "id_token": id_token,
+    })))
+}
+
+#[handler]
+fn mock_oidc_jwks() -> Json<JsonValue> {
+    Json(json!({
+        "keys": [{
+            "kty": "RSA",
+            "use": "sig",
+            "alg": "RS256",
+            "kid": OIDC_KEY_ID,
+            "n": OIDC_KEY_N,
+            "e": "AQAB",
+        }],
+    }))
+}
+
+pub async fn start_oidc_provider(config: &Config) -> MockOidcProvider {
+    let acceptor = TcpListener::bind("127.0.0.1:0")
+        .into_acceptor()
+        .await
+        .unwrap();
+    let addr = acceptor.local_addr()[0].as_socket_addr().cloned().unwrap();
+    let origin = format!("http://{}", addr);
+    let provider = OidcProviderConfig {
+        issuer: origin.clone(),
+        authorization_endpoint: format!("{}/authorize", origin),
+        token_endpoint: format!("{}/token", origin),
+        jwks_uri: format!("{}/jwks", origin),
+        ..config.oidc.providers[0].clone()
+    };
+    let state = MockOidcState {
+        codes: Arc::new(Mutex::new(HashMap::new())),
+        provider: provider.clone(),
+    };
+
+    let app = Route::new()
+        .at("/token", post(mock_oidc_token))
+        .at("/jwks", poem::get(mock_oidc_jwks))
+        .data(state.clone());
+    tokio::spawn(Server::new_with_acceptor(acceptor).run(app));
+
+    MockOidcProvider { provider, state }
+}
+
+// A software security key holding a single ES256 credential
+pub struct TestAuthenticator {
+    pub credential_id: Vec<u8>,
+    key: SigningKey,
+    sign_count: u32,
+}
+
+impl TestAuthenticator {
+    pub fn new() -> Self {
+        Self {
+            credential_id: thread_rng().sample_iter(Standard).take(16).collect(),
+            key: SigningKey::random(&mut thread_rng()),
+            sign_count: 0,
+        }
+    }
+
+    pub fn client_data(type_: &str, challenge: &str, config: &Config) -> Vec<u8> {
+        serde_json::to_vec(&json!({
+            "type": type_,
+            "challenge": base64_urlsafe(challenge.as_bytes()),
+            "origin": config.client.origin.to_str().unwrap(),
+        }))
+        .unwrap()
+    }
+
+    fn authenticator_data(&self, flags: u8, config: &Config) -> Vec<u8> {
+        [
+            &Sha256::digest(config.webauthn.rp_id.as_bytes())[..],
+            &[flags],
+            &self.sign_count.to_be_bytes(),
+        ]
+        .concat()
+    }
+
+    pub fn attestation_object(&self, config: &Config) -> Vec<u8> {
+        let point = self.key.verifying_key().to_encoded_point(false);
+        let cose_key = CborValue::Map(vec![
+            (1.into(), 2.into()),
+            (3.into(), ES256.into()),
+            ((-1).into(), 1.into()),
+            ((-2).into(), CborValue::Bytes(point.x().unwrap().to_vec())),
+            ((-3).into(), CborValue::Bytes(point.y().unwrap().to_vec())),
+        ]);
+        let mut cose_key_bytes = vec![];
+        ciborium::ser::into_writer(&cose_key, &mut cose_key_bytes).unwrap();
+
+        let credential_id_length = u16::try_from(self.credential_id.len()).unwrap();
+        let auth_data = [
+            &self.authenticator_data(0x41, config)[..],
+            &[0; 16],
+            &credential_id_length.to_be_bytes(),
+            &self.credential_id,
+            &cose_key_bytes,
+        ]
+        .concat();
+
+        let attestation = CborValue::Map(vec![
+            ("fmt".into(), "none".into()),
+            ("attStmt".into(), CborValue::Map(vec![])),
+            ("authData".into(), CborValue::Bytes(auth_data)),
+        ]);
+        let mut attestation_bytes = vec![];
+        ciborium::ser::into_writer(&attestation, &mut attestation_bytes).unwrap();
+        attestation_bytes
+    }
+
+    // Returns the authenticator data and signature for an assertion
+    pub fn assert(
+        &mut self,
+        client_data_json: &[u8],
+        user_verified: bool,
+        config: &Config,
+    ) -> (Vec<u8>, Vec<u8>) {
+        self.sign_count += 1;
+        let flags = if user_verified { 0x05 } else { 0x01 };
+        let authenticator_data = self.authenticator_data(flags, config);
+        let signature: Signature =
+            self.key.sign(&[&authenticator_data[..], &Sha256::digest(client_data_json)].concat());
+        (authenticator_data, signature.to_der().as_bytes().to_vec())
+    }
+}
@@ -1,6 +1,22 @@
-use poem::{http::StatusCode, test::TestResponse, web::cookie::Cookie};
+// Shared by every test binary, none of which uses all of it
+#![allow(dead_code)]
 
-use dodatok::config::Config;
+use poem::{
+    http::{
+        header::{AUTHORIZATION, COOKIE},
+        StatusCode,
+    },
+    test::{TestClient, TestForm, TestRequestBuilder, TestResponse},
+    web::cookie::Cookie,
+    Endpoint,
+};
+use serde_json::Value as JsonValue;
+
+use dodatok::{
+    config::{Config, MailBackendConfig},
+    mail::Email,
+    util::generate_token,
+};
 
 pub async fn assert_error(res: TestResponse, source: &str, id: &str) {
     let json = res.json().await;
@@ -25,8 +41,36 @@ pub async fn assert_error_with_details(res: TestResponse, source: &str, id: &str
     errors[0].get("details").string();
 }
 
+// Expects one alert per (field, id) pair, in the given order
+pub async fn assert_validation_errors(res: TestResponse, expected: &[(&str, &str)]) {
+    let json = res.json().await;
+    let json = json.value().object();
+    json.assert_len(1);
+    let errors = json.get("errors").object_array();
+    assert_eq!(errors.len(), expected.len());
+    for (error, (field, id)) in errors.iter().zip(expected) {
+        error.assert_len(4);
+        assert_eq!(error.get("source").string(), "validation");
+        assert_eq!(error.get("id").string(), *id);
+        assert_eq!(error.get("field").string(), *field);
+        error.get("details").string();
+    }
+}
+
+pub fn take_outbox(config: &Config) -> Vec<Email> {
+    match &config.mail.backend {
+        MailBackendConfig::Memory { outbox } => outbox.lock().unwrap().drain(..).collect(),
+        _ => panic!("tests require the memory mail backend"),
+    }
+}
+
+pub fn token_from_email(email: &Email) -> String {
+    let (_, rest) = email.body.split_once("?token=").unwrap();
+    rest.split_whitespace().next().unwrap().to_owned()
+}
+
 fn check_cookie(cookie: &Cookie, config: &Config) {
-    assert_eq!(cookie.http_only(), true);
+    assert!(cookie.http_only());
     assert_eq!(cookie.path().unwrap(), config.cookie.path);
     assert_eq!(cookie.same_site().unwrap(), config.cookie.same_site);
     assert_eq!(cookie.secure(), config.cookie.secure);
@@ -60,3 +104,135 @@ pub fn check_session_cookie_removed(cookie: &Cookie, config: &Config) {
     assert_eq!(cookie.value_str(), "");
     assert!(cookie.max_age().unwrap().is_zero());
 }
+
+pub async fn post_with_csrf<E: Endpoint>(
+    client: &TestClient<E>,
+    path: &str,
+    body: JsonValue,
+    config: &Config,
+) -> TestResponse {
+    let csrf_token = generate_token(config.csrf.token_length);
+    client
+        .post(path)
+        .body_json(&body)
+        .header(
+            COOKIE,
+            Cookie::new_with_str(&config.csrf.cookie, &csrf_token).to_string(),
+        )
+        .header(&config.csrf.header, &csrf_token)
+        .send()
+        .await
+}
+
+pub async fn post_with_session<E: Endpoint>(
+    client: &TestClient<E>,
+    path: &str,
+    body: JsonValue,
+    session: &(String, String),
+    config: &Config,
+) -> TestResponse {
+    send_with_session(client.post(path), body, session, config).await
+}
+
+pub async fn get_with_session<E: Endpoint>(
+    client: &TestClient<E>,
+    path: &str,
+    session: &(String, String),
+    config: &Config,
+) -> TestResponse {
+    let (session_id, csrf_token) = session;
+    let cookies = [
+        Cookie::new_with_str(&config.session.cookie, session_id).to_string(),
+        Cookie::new_with_str(&config.csrf.cookie, csrf_token).to_string(),
+    ];
+    client
+        .get(path)
+        .header(COOKIE, cookies.join("; "))
+        .send()
+        .await
+}
+
+pub async fn delete_with_session<E: Endpoint>(
+    client: &TestClient<E>,
+    path: &str,
+    body: JsonValue,
+    session: &(String, String),
+    config: &Config,
+) -> TestResponse {
+    send_with_session(client.delete(path), body, session, config).await
+}
+
+pub async fn put_with_session<E: Endpoint>(
+    client: &TestClient<E>,
+    path: &str,
+    body: JsonValue,
+    session: &(String, String),
+    config: &Config,
+) -> TestResponse {
+    send_with_session(client.put(path), body, session, config).await
+}
+
+pub async fn put_form_with_session<E: Endpoint>(
+    client: &TestClient<E>,
+    path: &str,
+    form: TestForm,
+    session: &(String, String),
+    config: &Config,
+) -> TestResponse {
+    let (session_id, csrf_token) = session;
+    let cookies = [
+        Cookie::new_with_str(&config.session.cookie, session_id).to_string(),
+        Cookie::new_with_str(&config.csrf.cookie, csrf_token).to_string(),
+    ];
+    client
+        .put(path)
+        .multipart(form)
+        .header(COOKIE, cookies.join("; "))
+        .header(&config.csrf.header, csrf_token)
+        .send()
+        .await
+}
+
+async fn send_with_session<E: Endpoint>(
+    req: TestRequestBuilder<'_, E>,
+    body: JsonValue,
+    session: &(String, String),
+    config: &Config,
+) -> TestResponse {
+    let (session_id, csrf_token) = session;
+    let cookies = [
+        Cookie::new_with_str(&config.session.cookie, session_id).to_string(),
+        Cookie::new_with_str(&config.csrf.cookie, csrf_token).to_string(),
+    ];
+    req.body_json(&body)
+        .header(COOKIE, cookies.join("; "))
+        .header(&config.csrf.header, csrf_token)
+        .send()
+        .await
+}
+
+pub async fn get_with_token<E: Endpoint>(
+    client: &TestClient<E>,
+    path: &str,
+    token: &str,
+) -> TestResponse {
+    client
+        .get(path)
+        .header(AUTHORIZATION, format!("Bearer {token}"))
+        .send()
+        .await
+}
+
+pub async fn post_with_token<E: Endpoint>(
+    client: &TestClient<E>,
+    path: &str,
+    body: JsonValue,
+    token: &str,
+) -> TestResponse {
+    client
+        .post(path)
+        .body_json(&body)
+        .header(AUTHORIZATION, format!("Bearer {token}"))
+        .send()
+        .await
+}